@@ -237,6 +237,7 @@ export class MyceliumBuild {
       .withDirectory("/workspace/edge-central", src.directory("edge-central").filter({include: ["src/**", "migrations/**", "Cargo.toml", "Cargo.lock"]}))
       .withDirectory("/workspace/edge-client-backend", src.directory("edge-client-backend").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
       .withDirectory("/workspace/edge-protocol", src.directory("edge-protocol").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
+      .withDirectory("/workspace/edge-peripheral-core", src.directory("edge-peripheral-core").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
       .withWorkdir("/workspace/edge-central");
   }
 
@@ -262,9 +263,27 @@ export class MyceliumBuild {
       .withExec(["sh", "-c", "espup install -t esp32"])
       .withDirectory("/workspace/edge-peripheral", src.directory("edge-peripheral").filter({include: [".cargo/config.toml", "src/**", "build.rs", "Cargo.toml", "Cargo.lock", "rust-toolchain.toml", "template.yaml"]}))
       .withDirectory("/workspace/edge-protocol", src.directory("edge-protocol").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
+      .withDirectory("/workspace/edge-peripheral-core", src.directory("edge-peripheral-core").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
       .withWorkdir("/workspace/edge-peripheral")
   }
 
+  /**
+   * Container for testing the peripheral state machine on the host
+   */
+  containerPeripheralCore(arch: string): Container {
+    const src = this.source;
+
+    return dag
+      .container({ platform: arch as any })
+      .from("rust:1.88-bookworm")
+      .withMountedCache("/usr/local/cargo/registry", dag.cacheVolume("edge-peripheral-core-cargo-registry"))
+      .withMountedCache("/usr/local/cargo/git", dag.cacheVolume("edge-peripheral-core-cargo-git"))
+      .withMountedCache("/workspace/edge-peripheral-core/target", dag.cacheVolume("edge-peripheral-core-target"))
+      .withDirectory("/workspace/edge-peripheral-core", src.directory("edge-peripheral-core").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
+      .withDirectory("/workspace/edge-protocol", src.directory("edge-protocol").filter({include: ["src/**", "Cargo.toml", "Cargo.lock"]}))
+      .withWorkdir("/workspace/edge-peripheral-core");
+  }
+
   /**
    * Execute a command in the peripheral container with ESP environment
    */
@@ -291,6 +310,32 @@ export class MyceliumBuild {
       .withExec(["cargo", "test"]).stdout();
   }
 
+  /**
+   * Test the MQTT publishing of the central against a mosquitto broker
+   */
+  @func()
+  async testCentralMqtt(@argument() arch: string = "linux/arm64"): Promise<string> {
+    const broker = dag
+      .container()
+      .from("eclipse-mosquitto:2.0.20")
+      .withExposedPort(1883)
+      .asService({ args: ["mosquitto", "-c", "/mosquitto-no-auth.conf"] });
+
+    return this.containerCentral(arch)
+      .withServiceBinding("mosquitto", broker)
+      .withEnvVariable("MQTT_HOST", "mosquitto")
+      .withExec(["cargo", "test", "--", "--ignored", "mqtt::"]).stdout();
+  }
+
+  /**
+   * Test the peripheral state machine
+   */
+  @func()
+  async testPeripheralCore(@argument() arch: string = "linux/amd64"): Promise<string> {
+    return this.containerPeripheralCore(arch)
+      .withExec(["cargo", "test"]).stdout();
+  }
+
   /**
    * Build the peripheral component for ESP32
    */
@@ -305,6 +350,8 @@ export class MyceliumBuild {
     await Promise.all([
       this.buildPeripheral(arch),
       this.testCentral(),
+      this.testCentralMqtt(),
+      this.testPeripheralCore(arch),
       this.buildBackend()
     ]);
 
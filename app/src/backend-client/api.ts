@@ -158,6 +158,12 @@ export interface Station {
      * @memberof Station
      */
     'updated'?: string;
+    /**
+     * 
+     * @type {string}
+     * @memberof Station
+     */
+    'firmwareVersion'?: string;
 }
 /**
  * 
@@ -239,37 +245,37 @@ export interface StationMeasurement {
      * @type {number}
      * @memberof StationMeasurement
      */
-    'batteryVoltage': number;
+    'batteryVoltage'?: number;
     /**
      * 
      * @type {number}
      * @memberof StationMeasurement
      */
-    'temperature': number;
+    'temperature'?: number;
     /**
      * 
      * @type {number}
      * @memberof StationMeasurement
      */
-    'humidity': number;
+    'humidity'?: number;
     /**
      * 
      * @type {number}
      * @memberof StationMeasurement
      */
-    'lux': number;
+    'lux'?: number;
     /**
      * 
      * @type {number}
      * @memberof StationMeasurement
      */
-    'soilPf': number;
+    'soilPf'?: number;
     /**
      * 
      * @type {number}
      * @memberof StationMeasurement
      */
-    'tankPf': number;
+    'tankPf'?: number;
 }
 /**
  * 
@@ -409,11 +415,12 @@ export const DefaultApiAxiosParamCreator = function (configuration?: Configurati
         /**
          * 
          * @param {string} stationId 
+         * @param {string} [firmwareVersion] 
          * @param {Array<StationMeasurement>} [stationMeasurement] 
          * @param {*} [options] Override http request option.
          * @throws {RequiredError}
          */
-        checkinStation: async (stationId: string, stationMeasurement?: Array<StationMeasurement>, options: RawAxiosRequestConfig = {}): Promise<RequestArgs> => {
+        checkinStation: async (stationId: string, firmwareVersion?: string, stationMeasurement?: Array<StationMeasurement>, options: RawAxiosRequestConfig = {}): Promise<RequestArgs> => {
             // verify required parameter 'stationId' is not null or undefined
             assertParamExists('checkinStation', 'stationId', stationId)
             const localVarPath = `/stations/{stationId}/checkin`
@@ -433,6 +440,10 @@ export const DefaultApiAxiosParamCreator = function (configuration?: Configurati
             // http bearer authentication required
             await setBearerAuthToObject(localVarHeaderParameter, configuration)
 
+            if (firmwareVersion !== undefined) {
+                localVarQueryParameter['firmwareVersion'] = firmwareVersion;
+            }
+
 
     
             localVarHeaderParameter['Content-Type'] = 'application/json';
@@ -901,12 +912,13 @@ export const DefaultApiFp = function(configuration?: Configuration) {
         /**
          * 
          * @param {string} stationId 
+         * @param {string} [firmwareVersion] 
          * @param {Array<StationMeasurement>} [stationMeasurement] 
          * @param {*} [options] Override http request option.
          * @throws {RequiredError}
          */
-        async checkinStation(stationId: string, stationMeasurement?: Array<StationMeasurement>, options?: RawAxiosRequestConfig): Promise<(axios?: AxiosInstance, basePath?: string) => AxiosPromise<Watering>> {
-            const localVarAxiosArgs = await localVarAxiosParamCreator.checkinStation(stationId, stationMeasurement, options);
+        async checkinStation(stationId: string, firmwareVersion?: string, stationMeasurement?: Array<StationMeasurement>, options?: RawAxiosRequestConfig): Promise<(axios?: AxiosInstance, basePath?: string) => AxiosPromise<Watering>> {
+            const localVarAxiosArgs = await localVarAxiosParamCreator.checkinStation(stationId, firmwareVersion, stationMeasurement, options);
             const localVarOperationServerIndex = configuration?.serverIndex ?? 0;
             const localVarOperationServerBasePath = operationServerMap['DefaultApi.checkinStation']?.[localVarOperationServerIndex]?.url;
             return (axios, basePath) => createRequestFunction(localVarAxiosArgs, globalAxios, BASE_PATH, configuration)(axios, localVarOperationServerBasePath || basePath);
@@ -1069,12 +1081,13 @@ export const DefaultApiFactory = function (configuration?: Configuration, basePa
         /**
          * 
          * @param {string} stationId 
+         * @param {string} [firmwareVersion] 
          * @param {Array<StationMeasurement>} [stationMeasurement] 
          * @param {*} [options] Override http request option.
          * @throws {RequiredError}
          */
-        checkinStation(stationId: string, stationMeasurement?: Array<StationMeasurement>, options?: RawAxiosRequestConfig): AxiosPromise<Watering> {
-            return localVarFp.checkinStation(stationId, stationMeasurement, options).then((request) => request(axios, basePath));
+        checkinStation(stationId: string, firmwareVersion?: string, stationMeasurement?: Array<StationMeasurement>, options?: RawAxiosRequestConfig): AxiosPromise<Watering> {
+            return localVarFp.checkinStation(stationId, firmwareVersion, stationMeasurement, options).then((request) => request(axios, basePath));
         },
         /**
          * 
@@ -1203,13 +1216,14 @@ export class DefaultApi extends BaseAPI {
     /**
      * 
      * @param {string} stationId 
+     * @param {string} [firmwareVersion] 
      * @param {Array<StationMeasurement>} [stationMeasurement] 
      * @param {*} [options] Override http request option.
      * @throws {RequiredError}
      * @memberof DefaultApi
      */
-    public checkinStation(stationId: string, stationMeasurement?: Array<StationMeasurement>, options?: RawAxiosRequestConfig) {
-        return DefaultApiFp(this.configuration).checkinStation(stationId, stationMeasurement, options).then((request) => request(this.axios, this.basePath));
+    public checkinStation(stationId: string, firmwareVersion?: string, stationMeasurement?: Array<StationMeasurement>, options?: RawAxiosRequestConfig) {
+        return DefaultApiFp(this.configuration).checkinStation(stationId, firmwareVersion, stationMeasurement, options).then((request) => request(this.axios, this.basePath));
     }
 
     /**
@@ -67,13 +67,20 @@ export const PlantView = () => {
 
   const splitMeasurements = (data?: Array<StationMeasurement>) => {
     if(data && data?.length > 0) {
+      // Channels a station didn't measure are left out of its graph
+      const channel = (value: (x: StationMeasurement) => number | undefined) =>
+        data.flatMap((x) => {
+          const v = value(x);
+          return v === undefined || v === null ? [] : [{ on: x.on, value: v }];
+        });
+
       return {
-        batteryVoltage: data.map((x) => ({ on: x.on, value: x.batteryVoltage })),
-        humidity: data.map((x) => ({ on: x.on, value: x.humidity })),
-        lux: data.map((x) => ({ on: x.on, value: x.lux })),
-        soilPf: data.map((x) => ({ on: x.on, value: x.soilPf })),
-        tankPf: data.map((x) => ({ on: x.on, value: x.tankPf })),
-        temperature: data.map((x) => ({ on: x.on, value: x.temperature })),
+        batteryVoltage: channel((x) => x.batteryVoltage),
+        humidity: channel((x) => x.humidity),
+        lux: channel((x) => x.lux),
+        soilPf: channel((x) => x.soilPf),
+        tankPf: channel((x) => x.tankPf),
+        temperature: channel((x) => x.temperature),
       };
     } else {
       return undefined
@@ -0,0 +1,7 @@
+ALTER TABLE station_measurements
+ALTER COLUMN battery_voltage DROP NOT NULL,
+ALTER COLUMN temperature DROP NOT NULL,
+ALTER COLUMN humidity DROP NOT NULL,
+ALTER COLUMN lux DROP NOT NULL,
+ALTER COLUMN soil_pf DROP NOT NULL,
+ALTER COLUMN tank_pf DROP NOT NULL;
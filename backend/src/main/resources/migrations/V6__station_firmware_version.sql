@@ -0,0 +1 @@
+ALTER TABLE stations ADD COLUMN firmware_version TEXT;
@@ -33,15 +33,18 @@ object DoobieStationRepository extends StationRepository[ConnectionIO] {
   }
 
   def listByUserId(userId: String): ConnectionIO[List[Station]] =
-    sql"SELECT id, mac_addr, name, location, description, user_id, created, updated FROM stations where user_id = $userId"
+    sql"SELECT id, mac_addr, name, location, description, user_id, created, updated, firmware_version FROM stations where user_id = $userId"
       .query[Station]
       .to[List]
 
   def findById(id: UUID, userId: String): ConnectionIO[Option[Station]] =
-    sql"SELECT id, mac_addr, name, location, description, user_id, created, updated FROM stations WHERE id = $id AND user_id = $userId"
+    sql"SELECT id, mac_addr, name, location, description, user_id, created, updated, firmware_version FROM stations WHERE id = $id AND user_id = $userId"
       .query[Station]
       .option
 
+  def setFirmwareVersion(id: UUID, userId: String, version: String): ConnectionIO[Int] =
+    sql"UPDATE stations SET firmware_version = $version WHERE id = $id AND user_id = $userId".update.run
+
   def delete(id: UUID, userId: String): ConnectionIO[Int] =
     sql"DELETE FROM stations WHERE id = $id AND user_id = $userId".update.run
 
@@ -13,6 +13,7 @@ final case class Station(
     description: Option[String],
     userId: String,
     created: Instant,
-    updated: Option[Instant]
+    updated: Option[Instant],
+    firmwareVersion: Option[String]
 ) derives Encoder.AsObject,
       Decoder
@@ -19,6 +19,7 @@ final case class StationInsert(
       description = None,
       userId = userId,
       created = created,
-      updated = None
+      updated = None,
+      firmwareVersion = None
     )
 }
@@ -9,12 +9,12 @@ import java.time.Instant
 
 final case class StationMeasurement(
     on: Instant,
-    batteryVoltage: Double,
-    temperature: Double,
-    humidity: Double,
-    lux: Double,
-    soilPf: Double,
-    tankPf: Double
+    batteryVoltage: Option[Double],
+    temperature: Option[Double],
+    humidity: Option[Double],
+    lux: Option[Double],
+    soilPf: Option[Double],
+    tankPf: Option[Double]
 ) derives Encoder.AsObject,
       Decoder,
       Eq
@@ -49,6 +49,7 @@ object Stations extends TapirSchemas {
       .in(path[UUID]("stationId"))
       .in("checkin")
       .put
+      .in(query[Option[String]]("firmwareVersion"))
       .in(jsonBody[List[StationMeasurement]])
       .name("checkinStation")
       .out(jsonBody[Watering])
@@ -144,7 +145,8 @@ object Stations extends TapirSchemas {
     val add     = endpoints.add.serverLogic(at => insert => svc.add(at.sub, insert).map(Right(_)))
     val delete  = endpoints.delete.serverLogic(at => id => svc.delete(at.sub, id).as(Right(())))
     val checkin = endpoints.checkIn.serverLogic(at =>
-      (id, measurements) => svc.checkin(at.sub, id, measurements).map(Right(_))
+      (id, firmwareVersion, measurements) =>
+        svc.checkin(at.sub, id, firmwareVersion, measurements).map(Right(_))
     )
     val update = endpoints.update.serverLogic(at =>
       (id, update) => svc.update(at.sub, id, update).map(Right(_))
@@ -44,6 +44,7 @@ trait StationRepository[F[_]] {
   def listByUserId(userId: String): F[List[Station]]
   def findById(id: UUID, userId: String): F[Option[Station]]
   def delete(id: UUID, userId: String): F[Int]
+  def setFirmwareVersion(id: UUID, userId: String, version: String): F[Int]
   def update(id: UUID, userId: String, update: StationUpdate, now: Instant): F[Int]
 }
 
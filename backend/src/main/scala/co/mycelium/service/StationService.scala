@@ -17,7 +17,12 @@ trait StationService[F[_]] {
   def list(userId: String): F[List[Station]]
   def delete(userId: String, stationID: UUID): F[Unit]
   def update(userId: String, stationID: UUID, update: StationUpdate): F[Unit]
-  def checkin(userId: String, stationID: UUID, measurements: List[StationMeasurement]): F[Watering]
+  def checkin(
+      userId: String,
+      stationID: UUID,
+      firmwareVersion: Option[String],
+      measurements: List[StationMeasurement]
+  ): F[Watering]
   def details(
       userId: String,
       period: Option[MeasurementPeriod],
@@ -48,11 +53,17 @@ final class StationServiceImpl[F[_]: {Concurrent}](
   override def checkin(
       userId: String,
       stationID: UUID,
+      firmwareVersion: Option[String],
       measurements: List[StationMeasurement]
   ): F[Watering] =
     repos.stations.findById(stationID, userId).flatMap {
       case Some(station) =>
-        repos.measurements.insertMany(stationID, measurements).as(Watering(None))
+        for {
+          _ <- firmwareVersion
+            .filterNot(station.firmwareVersion.contains)
+            .traverse(repos.stations.setFirmwareVersion(stationID, userId, _))
+          _ <- repos.measurements.insertMany(stationID, measurements)
+        } yield Watering(None)
       case None =>
         Monad[F].pure(Watering(None))
     }
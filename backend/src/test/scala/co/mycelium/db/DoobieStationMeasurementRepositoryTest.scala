@@ -36,21 +36,21 @@ object DoobieStationMeasurementRepositoryTest extends IOSuite with IOChecker {
       _  <- DoobieStationMeasurementRepository.insertMany(
         id,
         List(
-          StationMeasurement(now, 12.5, 20.5, 65.0, 100.0, 2.5, 3.0),
-          StationMeasurement(now.plusSeconds(60), 12.6, 21.0, 64.5, 110.0, 2.6, 3.1),
-          StationMeasurement(now.plusSeconds(120), 12.7, 21.5, 64.0, 120.0, 2.7, 3.2),
-          StationMeasurement(now.plusSeconds(180), 12.8, 22.0, 63.5, 130.0, 2.8, 3.3),
-          StationMeasurement(now.plusSeconds(240), 12.9, 22.5, 63.0, 140.0, 2.9, 3.4),
-          StationMeasurement(now.plusSeconds(300), 13.0, 23.0, 62.5, 150.0, 3.0, 3.5),
-          StationMeasurement(now.plusSeconds(360), 13.1, 23.5, 62.0, 160.0, 3.1, 3.6),
-          StationMeasurement(now.plusSeconds(420), 13.2, 24.0, 61.5, 170.0, 3.2, 3.7),
-          StationMeasurement(now.plusSeconds(480), 13.3, 24.5, 61.0, 180.0, 3.3, 3.8),
-          StationMeasurement(now.plusSeconds(540), 13.4, 25.0, 60.5, 190.0, 3.4, 3.9)
+          StationMeasurement(now, Some(12.5), Some(20.5), Some(65.0), Some(100.0), Some(2.5), Some(3.0)),
+          StationMeasurement(now.plusSeconds(60), Some(12.6), Some(21.0), Some(64.5), Some(110.0), Some(2.6), Some(3.1)),
+          StationMeasurement(now.plusSeconds(120), Some(12.7), Some(21.5), Some(64.0), Some(120.0), Some(2.7), Some(3.2)),
+          StationMeasurement(now.plusSeconds(180), Some(12.8), Some(22.0), Some(63.5), Some(130.0), Some(2.8), Some(3.3)),
+          StationMeasurement(now.plusSeconds(240), Some(12.9), Some(22.5), Some(63.0), Some(140.0), Some(2.9), Some(3.4)),
+          StationMeasurement(now.plusSeconds(300), Some(13.0), Some(23.0), Some(62.5), Some(150.0), Some(3.0), Some(3.5)),
+          StationMeasurement(now.plusSeconds(360), Some(13.1), Some(23.5), Some(62.0), Some(160.0), Some(3.1), Some(3.6)),
+          StationMeasurement(now.plusSeconds(420), Some(13.2), Some(24.0), Some(61.5), Some(170.0), Some(3.2), Some(3.7)),
+          StationMeasurement(now.plusSeconds(480), Some(13.3), Some(24.5), Some(61.0), Some(180.0), Some(3.3), Some(3.8)),
+          StationMeasurement(now.plusSeconds(540), Some(13.4), Some(25.0), Some(60.5), Some(190.0), Some(3.4), Some(3.9))
         )
       )
       avg <- DoobieStationMeasurementRepository.avg(id, MeasurementPeriod.LastMonth)
     } yield {
-      expect.eql(avg, List(StationMeasurement(timebucket, 12.95, 22.75, 62.75, 145.0, 2.95, 3.45)))
+      expect.eql(avg, List(StationMeasurement(timebucket, Some(12.95), Some(22.75), Some(62.75), Some(145.0), Some(2.95), Some(3.45))))
     }
 
     program.transact(tx)
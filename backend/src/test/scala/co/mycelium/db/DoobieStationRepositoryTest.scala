@@ -50,4 +50,18 @@ object DoobieStationRepositoryTest extends IOSuite with IOChecker {
     program.transact(tx)
   }
 
+  test("should record the firmware version") { implicit tx =>
+    val insert  = StationInsert("00:00:00:00:00:02", "Test Station")
+    val station = insert.toStation(UUID.randomUUID(), now, "some-user-id")
+
+    val program = for {
+      id        <- repo.insert(station, now)
+      _         <- repo.setFirmwareVersion(id, "some-user-id", "0.2.0")
+      _         <- repo.setFirmwareVersion(id, "other-user-id", "9.9.9")
+      retrieved <- repo.findById(id, "some-user-id")
+    } yield expect.eql(retrieved.flatMap(_.firmwareVersion), Some("0.2.0"))
+
+    program.transact(tx)
+  }
+
 }
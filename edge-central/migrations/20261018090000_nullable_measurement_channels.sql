@@ -0,0 +1,18 @@
+-- Channels are NULL when the sensor couldn't be read, soil_pf was missing from the initial table
+CREATE TABLE measurements_new (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mac BLOB NOT NULL, -- 6 bytes
+    timestamp DATETIME NOT NULL,
+    battery INTEGER,
+    lux REAL,
+    temperature REAL,
+    humidity REAL,
+    soil_pf REAL
+);
+
+INSERT INTO measurements_new (id, mac, timestamp, battery, lux, temperature, humidity)
+SELECT id, mac, timestamp, battery, lux, temperature, humidity FROM measurements;
+
+DROP TABLE measurements;
+
+ALTER TABLE measurements_new RENAME TO measurements;
@@ -0,0 +1 @@
+ALTER TABLE measurements ADD COLUMN tank_pf REAL;
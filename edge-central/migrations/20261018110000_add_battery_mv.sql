@@ -0,0 +1 @@
+ALTER TABLE measurements ADD COLUMN battery_mv INTEGER;
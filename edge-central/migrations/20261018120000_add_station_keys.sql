@@ -0,0 +1,5 @@
+CREATE TABLE station_keys (
+    mac BLOB PRIMARY KEY,
+    key BLOB NOT NULL,
+    counter INTEGER NOT NULL DEFAULT 0
+);
@@ -0,0 +1,12 @@
+CREATE TABLE station_settings (
+    mac BLOB PRIMARY KEY,
+    sampling_period_secs INTEGER NOT NULL,
+    flush_size INTEGER NOT NULL,
+    max_deviation_battery INTEGER,
+    max_deviation_lux REAL,
+    max_deviation_temperature REAL,
+    max_deviation_humidity REAL,
+    max_deviation_soil_pf REAL,
+    max_deviation_tank_pf REAL,
+    max_deviation_battery_mv INTEGER
+);
@@ -0,0 +1,10 @@
+CREATE TABLE station_commands (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mac BLOB NOT NULL,
+    opcode INTEGER NOT NULL,
+    requested_at DATETIME NOT NULL,
+    status INTEGER,
+    completed_at DATETIME
+);
+
+CREATE INDEX station_commands_pending ON station_commands (mac) WHERE status IS NULL;
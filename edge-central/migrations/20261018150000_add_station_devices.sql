@@ -0,0 +1,7 @@
+CREATE TABLE station_devices (
+    mac BLOB PRIMARY KEY,
+    model TEXT NOT NULL,
+    firmware_version TEXT NOT NULL,
+    hardware_revision TEXT NOT NULL,
+    updated_at DATETIME NOT NULL
+);
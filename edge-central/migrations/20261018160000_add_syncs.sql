@@ -0,0 +1,13 @@
+-- Every sync is stored before it is uploaded, uploaded_at stays NULL until the backend has it
+CREATE TABLE syncs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mac BLOB NOT NULL, -- 6 bytes
+    synced_at DATETIME NOT NULL,
+    time_drift_ms INTEGER NOT NULL,
+    decode_failures INTEGER NOT NULL,
+    rssi INTEGER,
+    uploaded_at DATETIME
+);
+
+-- Measurements stored before syncs were recorded have no sync
+ALTER TABLE measurements ADD COLUMN sync_id INTEGER REFERENCES syncs(id);
@@ -0,0 +1,17 @@
+-- Upload state of every measurement, entries stay until the backend has them
+CREATE TABLE outbox (
+    measurement_id INTEGER PRIMARY KEY REFERENCES measurements(id),
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at DATETIME NOT NULL,
+    last_error TEXT,
+    uploaded_at DATETIME
+);
+
+CREATE INDEX idx_outbox_pending ON outbox(uploaded_at, next_attempt_at);
+
+-- Measurements of syncs that weren't uploaded yet go out through the outbox
+INSERT INTO outbox (measurement_id, next_attempt_at)
+SELECT m.id, s.synced_at
+FROM measurements m
+JOIN syncs s ON s.id = m.sync_id
+WHERE s.uploaded_at IS NULL;
@@ -0,0 +1,6 @@
+-- The station the backend created for each MAC, so it's only created once
+CREATE TABLE stations (
+    mac BLOB PRIMARY KEY,
+    backend_id TEXT NOT NULL,
+    created_at DATETIME NOT NULL
+);
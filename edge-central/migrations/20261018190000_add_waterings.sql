@@ -0,0 +1,10 @@
+-- Every watering the backend asked for, watered_ms stays NULL until the actuator switched off
+CREATE TABLE waterings (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    mac BLOB NOT NULL, -- 6 bytes
+    requested_ms INTEGER NOT NULL,
+    started_at DATETIME NOT NULL,
+    watered_ms INTEGER,
+    error TEXT,
+    reported_at DATETIME
+);
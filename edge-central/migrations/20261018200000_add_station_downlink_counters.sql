@@ -0,0 +1,2 @@
+-- Counter of the last write the central signed for the station, stations reject a counter that isn't newer
+ALTER TABLE station_keys ADD COLUMN downlink_counter INTEGER NOT NULL DEFAULT 0;
@@ -0,0 +1,461 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{
+    extract::{Path, Query, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::get,
+    Json, Router,
+};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use edge_protocol::MeasurementSerieEntry;
+use serde::{Deserialize, Serialize};
+use sqlx::SqlitePool;
+use utoipa::{IntoParams, OpenApi, ToSchema};
+
+use crate::ble::{format_mac, parse_mac};
+use crate::data::sqlite::{SqliteMeasurementRepository, SqliteOutboxRepository, SqliteSyncRepository, StationSummaryRow, SyncRow};
+
+/// Entries returned when a request doesn't limit them
+const DEFAULT_LIMIT: u32 = 1000;
+const MAX_LIMIT: u32 = 10_000;
+
+/// The local API of the hub, read only, so the desktop app and dashboards on the LAN don't need the cloud
+#[derive(OpenApi)]
+#[openapi(
+    info(title = "Mycelium hub", description = "Stations, measurements and health of an edge-central hub"),
+    paths(list_stations, get_station, station_measurements, station_syncs, health),
+    components(schemas(StationResponse, MeasurementResponse, SyncResponse, HealthResponse, ErrorResponse))
+)]
+pub struct ApiDoc;
+
+#[derive(Clone)]
+pub struct ApiState {
+    syncs: Arc<SqliteSyncRepository>,
+    measurements: Arc<SqliteMeasurementRepository>,
+    outbox: Arc<SqliteOutboxRepository>,
+    started: Instant,
+}
+
+impl ApiState {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self {
+            syncs: Arc::new(SqliteSyncRepository::new(pool.clone())),
+            measurements: Arc::new(SqliteMeasurementRepository::new(pool.clone())),
+            outbox: Arc::new(SqliteOutboxRepository::new(pool)),
+            started: Instant::now(),
+        }
+    }
+}
+
+/// A station that synced with the hub
+#[derive(Debug, Serialize, ToSchema)]
+pub struct StationResponse {
+    /// MAC address as `aa:bb:cc:dd:ee:ff`
+    pub mac: String,
+    /// Id of the station in the backend, once it uploaded
+    pub backend_id: Option<String>,
+    pub firmware_version: Option<String>,
+    /// When the latest sync happened
+    pub last_seen: DateTime<Utc>,
+    /// Signal strength of the latest sync in dBm
+    pub rssi: Option<i64>,
+    /// How far the clock of the station was behind on the latest sync, in milliseconds
+    pub time_drift_ms: i64,
+    pub syncs: i64,
+    /// When the latest fully uploaded sync reached the backend
+    pub last_uploaded_at: Option<DateTime<Utc>>,
+    /// Measurements waiting for their upload
+    pub pending_uploads: i64,
+}
+
+impl StationResponse {
+    fn from_row(row: &StationSummaryRow) -> anyhow::Result<Self> {
+        Ok(Self {
+            mac: format_mac(&row.mac.as_slice().try_into()?),
+            backend_id: row.backend_id.clone(),
+            firmware_version: row.firmware_version.clone(),
+            last_seen: row.last_seen.and_utc(),
+            rssi: row.rssi,
+            time_drift_ms: row.time_drift_ms,
+            syncs: row.syncs,
+            last_uploaded_at: row.last_uploaded_at.map(|at| at.and_utc()),
+            pending_uploads: row.pending_uploads,
+        })
+    }
+}
+
+/// A sample, channels are absent when their sensor couldn't be read
+#[derive(Debug, Serialize, ToSchema)]
+pub struct MeasurementResponse {
+    pub timestamp: DateTime<Utc>,
+    /// Battery charge in percent
+    pub battery: Option<u8>,
+    pub battery_mv: Option<u16>,
+    pub lux: Option<f32>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub soil_pf: Option<f32>,
+    pub tank_pf: Option<f32>,
+}
+
+impl From<&MeasurementSerieEntry> for MeasurementResponse {
+    fn from(entry: &MeasurementSerieEntry) -> Self {
+        Self {
+            timestamp: entry.timestamp.and_utc(),
+            battery: entry.measurement.battery,
+            battery_mv: entry.measurement.battery_mv,
+            lux: entry.measurement.lux,
+            temperature: entry.measurement.temperature,
+            humidity: entry.measurement.humidity,
+            soil_pf: entry.measurement.soil_pf,
+            tank_pf: entry.measurement.tank_pf,
+        }
+    }
+}
+
+/// A sync of a station and whether it reached the backend
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SyncResponse {
+    pub synced_at: DateTime<Utc>,
+    pub time_drift_ms: i64,
+    /// Entries the station served that couldn't be decoded
+    pub decode_failures: i64,
+    pub rssi: Option<i64>,
+    /// Absent while measurements of the sync wait for their upload
+    pub uploaded_at: Option<DateTime<Utc>>,
+}
+
+impl From<&SyncRow> for SyncResponse {
+    fn from(row: &SyncRow) -> Self {
+        Self {
+            synced_at: row.synced_at.and_utc(),
+            time_drift_ms: row.time_drift_ms,
+            decode_failures: row.decode_failures,
+            rssi: row.rssi,
+            uploaded_at: row.uploaded_at.map(|at| at.and_utc()),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct HealthResponse {
+    /// Version of edge-central
+    pub version: String,
+    pub uptime_secs: u64,
+    /// When any station synced last
+    pub last_sync_at: Option<DateTime<Utc>>,
+    /// Measurements waiting for their upload
+    pub pending_uploads: i64,
+    /// Timestamp of the oldest measurement waiting for its upload
+    pub oldest_pending: Option<DateTime<Utc>>,
+}
+
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorResponse {
+    pub error: String,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct HistoryQuery {
+    /// Earliest measurement, inclusive
+    pub from: Option<DateTime<Utc>>,
+    /// Latest measurement, exclusive
+    pub to: Option<DateTime<Utc>>,
+    /// Most measurements returned, the latest are kept
+    pub limit: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct LimitQuery {
+    /// Most entries returned, the latest are kept
+    pub limit: Option<u32>,
+}
+
+pub enum ApiError {
+    BadRequest(String),
+    NotFound(String),
+    Internal(anyhow::Error),
+}
+
+impl From<anyhow::Error> for ApiError {
+    fn from(err: anyhow::Error) -> Self {
+        ApiError::Internal(err)
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let (status, error) = match self {
+            ApiError::BadRequest(error) => (StatusCode::BAD_REQUEST, error),
+            ApiError::NotFound(error) => (StatusCode::NOT_FOUND, error),
+            ApiError::Internal(err) => {
+                tracing::error!("API request failed {}", err);
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal error".to_string())
+            }
+        };
+
+        (status, Json(ErrorResponse { error })).into_response()
+    }
+}
+
+fn mac_param(mac: &str) -> Result<[u8; 6], ApiError> {
+    parse_mac(mac).map_err(|err| ApiError::BadRequest(err.to_string()))
+}
+
+fn limit(limit: Option<u32>) -> u32 {
+    limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT)
+}
+
+fn naive(at: Option<DateTime<Utc>>) -> Option<NaiveDateTime> {
+    at.map(|at| at.naive_utc())
+}
+
+#[utoipa::path(
+    get,
+    path = "/stations",
+    responses((status = 200, description = "Stations that synced with the hub", body = Vec<StationResponse>))
+)]
+async fn list_stations(State(state): State<ApiState>) -> Result<Json<Vec<StationResponse>>, ApiError> {
+    let stations: Vec<StationResponse> = state.syncs.summaries(None).await?.iter().map(StationResponse::from_row).collect::<anyhow::Result<_>>()?;
+    Ok(Json(stations))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stations/{mac}",
+    params(("mac" = String, Path, description = "MAC address of the station")),
+    responses(
+        (status = 200, description = "The station", body = StationResponse),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse),
+        (status = 404, description = "The station never synced", body = ErrorResponse)
+    )
+)]
+async fn get_station(State(state): State<ApiState>, Path(mac): Path<String>) -> Result<Json<StationResponse>, ApiError> {
+    let address = mac_param(&mac)?;
+
+    match state.syncs.summaries(Some(&address)).await?.first() {
+        Some(row) => Ok(Json(StationResponse::from_row(row)?)),
+        None => Err(ApiError::NotFound(format!("Station {} never synced", mac))),
+    }
+}
+
+#[utoipa::path(
+    get,
+    path = "/stations/{mac}/measurements",
+    params(("mac" = String, Path, description = "MAC address of the station"), HistoryQuery),
+    responses(
+        (status = 200, description = "Measurements of the station, oldest first", body = Vec<MeasurementResponse>),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse)
+    )
+)]
+async fn station_measurements(
+    State(state): State<ApiState>,
+    Path(mac): Path<String>,
+    Query(query): Query<HistoryQuery>,
+) -> Result<Json<Vec<MeasurementResponse>>, ApiError> {
+    let entries = state.measurements.history(&mac_param(&mac)?, naive(query.from), naive(query.to), limit(query.limit)).await?;
+    Ok(Json(entries.iter().map(MeasurementResponse::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/stations/{mac}/syncs",
+    params(("mac" = String, Path, description = "MAC address of the station"), LimitQuery),
+    responses(
+        (status = 200, description = "Syncs of the station, latest first", body = Vec<SyncResponse>),
+        (status = 400, description = "Invalid MAC address", body = ErrorResponse)
+    )
+)]
+async fn station_syncs(
+    State(state): State<ApiState>,
+    Path(mac): Path<String>,
+    Query(query): Query<LimitQuery>,
+) -> Result<Json<Vec<SyncResponse>>, ApiError> {
+    let syncs = state.syncs.find_by_mac(&mac_param(&mac)?, limit(query.limit)).await?;
+    Ok(Json(syncs.iter().map(SyncResponse::from).collect()))
+}
+
+#[utoipa::path(
+    get,
+    path = "/health",
+    responses((status = 200, description = "Health of the hub", body = HealthResponse))
+)]
+async fn health(State(state): State<ApiState>) -> Result<Json<HealthResponse>, ApiError> {
+    let (pending_uploads, oldest_pending) = state.outbox.backlog().await?;
+    let last_sync_at = state.syncs.summaries(None).await?.iter().map(|row| row.last_seen).max();
+
+    Ok(Json(HealthResponse {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        uptime_secs: state.started.elapsed().as_secs(),
+        last_sync_at: last_sync_at.map(|at| at.and_utc()),
+        pending_uploads,
+        oldest_pending: oldest_pending.map(|at| at.and_utc()),
+    }))
+}
+
+async fn openapi() -> Json<utoipa::openapi::OpenApi> {
+    Json(ApiDoc::openapi())
+}
+
+pub fn router(state: ApiState) -> Router {
+    Router::new()
+        .route("/stations", get(list_stations))
+        .route("/stations/{mac}", get(get_station))
+        .route("/stations/{mac}/measurements", get(station_measurements))
+        .route("/stations/{mac}/syncs", get(station_syncs))
+        .route("/health", get(health))
+        .route("/openapi.json", get(openapi))
+        .with_state(state)
+}
+
+/// Serve the API on `addr` until the process ends
+pub async fn serve(addr: &str, state: ApiState) -> anyhow::Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    tracing::info!(addr = %listener.local_addr()?, "Serving the hub API");
+
+    axum::serve(listener, router(state)).await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::Request};
+    use chrono::{TimeDelta, TimeZone};
+    use edge_protocol::Measurement;
+    use tower::ServiceExt;
+
+    use crate::data::sqlite::test_pool;
+    use crate::measurements::types::PeripheralSyncResult;
+
+    fn at(minutes: i64) -> NaiveDateTime {
+        Utc.timestamp_opt(1_700_000_000 + minutes * 60, 0).unwrap().naive_utc()
+    }
+
+    fn sync(address: [u8; 6], minutes: &[i64], rssi: i16) -> PeripheralSyncResult {
+        PeripheralSyncResult {
+            address,
+            time_drift: TimeDelta::milliseconds(-250),
+            measurements: minutes
+                .iter()
+                .map(|m| MeasurementSerieEntry {
+                    timestamp: at(*m),
+                    measurement: Measurement {
+                        battery: Some(80),
+                        lux: None,
+                        temperature: Some(20.0 + *m as f32),
+                        humidity: Some(50.0),
+                        soil_pf: Some(300.0),
+                        tank_pf: None,
+                        battery_mv: Some(4000),
+                    },
+                })
+                .collect(),
+            decode_failures: 0,
+            rssi: Some(rssi),
+            sync_id: None,
+        }
+    }
+
+    /// A router on an in-memory database holding syncs of two stations
+    async fn router_with_syncs() -> Router {
+        let pool = test_pool().await;
+
+        let syncs = SqliteSyncRepository::new(pool.clone());
+        syncs.insert(&sync([0xbb; 6], &[0, 1], -80), at(2)).await.unwrap();
+        syncs.insert(&sync([0xaa; 6], &[3], -70), at(4)).await.unwrap();
+        syncs.insert(&sync([0xbb; 6], &[5], -60), at(6)).await.unwrap();
+
+        router(ApiState::new(pool))
+    }
+
+    async fn get_json(router: &Router, uri: &str) -> (StatusCode, serde_json::Value) {
+        let response = router.clone().oneshot(Request::builder().uri(uri).body(Body::empty()).unwrap()).await.unwrap();
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn test_stations_with_last_seen() {
+        let router = router_with_syncs().await;
+
+        let (status, stations) = get_json(&router, "/stations").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(stations.as_array().unwrap().len(), 2);
+        assert_eq!(stations[0]["mac"], "aa:aa:aa:aa:aa:aa");
+        assert_eq!(stations[1]["mac"], "bb:bb:bb:bb:bb:bb");
+        assert_eq!(stations[1]["last_seen"], "2023-11-14T22:19:20Z");
+        assert_eq!(stations[1]["rssi"], -60);
+        assert_eq!(stations[1]["time_drift_ms"], -250);
+        assert_eq!(stations[1]["syncs"], 2);
+        assert_eq!(stations[1]["pending_uploads"], 3);
+
+        let (status, station) = get_json(&router, "/stations/bb:bb:bb:bb:bb:bb").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(station, stations[1]);
+    }
+
+    #[tokio::test]
+    async fn test_unknown_or_invalid_station() {
+        let router = router_with_syncs().await;
+
+        let (status, error) = get_json(&router, "/stations/cc:cc:cc:cc:cc:cc").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(error["error"], "Station cc:cc:cc:cc:cc:cc never synced");
+
+        let (status, _) = get_json(&router, "/stations/not-a-mac").await;
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_measurement_history_and_syncs() {
+        let router = router_with_syncs().await;
+
+        let (status, measurements) = get_json(&router, "/stations/bbbbbbbbbbbb/measurements").await;
+        assert_eq!(status, StatusCode::OK);
+        let temperatures: Vec<_> = measurements.as_array().unwrap().iter().map(|m| m["temperature"].as_f64().unwrap()).collect();
+        assert_eq!(temperatures, vec![20.0, 21.0, 25.0]);
+        assert_eq!(measurements[0]["timestamp"], "2023-11-14T22:13:20Z");
+        assert_eq!(measurements[0]["battery_mv"], 4000);
+
+        let (_, measurements) = get_json(&router, "/stations/bbbbbbbbbbbb/measurements?from=2023-11-14T22:14:20Z&limit=1").await;
+        assert_eq!(measurements.as_array().unwrap().len(), 1);
+        assert_eq!(measurements[0]["temperature"], 25.0);
+
+        let (status, syncs) = get_json(&router, "/stations/bb:bb:bb:bb:bb:bb/syncs").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(syncs.as_array().unwrap().len(), 2);
+        assert_eq!(syncs[0]["synced_at"], "2023-11-14T22:19:20Z");
+        assert_eq!(syncs[0]["uploaded_at"], serde_json::Value::Null);
+    }
+
+    #[tokio::test]
+    async fn test_health() {
+        let router = router_with_syncs().await;
+
+        let (status, health) = get_json(&router, "/health").await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(health["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(health["last_sync_at"], "2023-11-14T22:19:20Z");
+        assert_eq!(health["pending_uploads"], 4);
+        assert_eq!(health["oldest_pending"], "2023-11-14T22:13:20Z");
+    }
+
+    #[tokio::test]
+    async fn test_openapi_documents_every_route() {
+        let router = router_with_syncs().await;
+
+        let (status, spec) = get_json(&router, "/openapi.json").await;
+        assert_eq!(status, StatusCode::OK);
+        for path in ["/stations", "/stations/{mac}", "/stations/{mac}/measurements", "/stations/{mac}/syncs", "/health"] {
+            assert!(spec["paths"][path]["get"].is_object(), "{} is not documented", path);
+        }
+        assert!(spec["components"]["schemas"]["StationResponse"].is_object());
+    }
+}
@@ -1,2 +1,33 @@
 #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
-pub mod hci;
\ No newline at end of file
+pub mod hci;
+
+/// Parse a MAC address formatted as `aa:bb:cc:dd:ee:ff`, or as `aabbccddeeff` where colons don't fit like configuration keys
+pub fn parse_mac(mac: &str) -> anyhow::Result<[u8; 6]> {
+    let bytes = if mac.contains(':') {
+        mac.split(':').map(|byte| u8::from_str_radix(byte, 16).ok()).collect::<Option<Vec<u8>>>()
+    } else {
+        (0..mac.len())
+            .step_by(2)
+            .map(|i| mac.get(i..i + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok()))
+            .collect::<Option<Vec<u8>>>()
+    }
+    .ok_or_else(|| anyhow::anyhow!("Invalid MAC address {}", mac))?;
+
+    bytes.try_into().map_err(|_| anyhow::anyhow!("Invalid MAC address {}", mac))
+}
+
+/// Format a MAC address as `aa:bb:cc:dd:ee:ff`
+pub fn format_mac(mac: &[u8; 6]) -> String {
+    format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", mac[0], mac[1], mac[2], mac[3], mac[4], mac[5])
+}
+
+/// Turn the errors of the BLE stack, which only implement `Debug`, into `anyhow` errors
+pub trait ResultAny<T, E> {
+    fn anyhow(self, ctx: &'static str) -> Result<T, anyhow::Error>;
+}
+
+impl<T, E: core::fmt::Debug> ResultAny<T, E> for Result<T, E> {
+    fn anyhow(self, ctx: &'static str) -> Result<T, anyhow::Error> {
+        self.map_err(|e| anyhow::anyhow!("{}: {:?}", ctx, e))
+    }
+}
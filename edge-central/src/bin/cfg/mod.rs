@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use config::Config;
 use serde::Deserialize;
 
@@ -12,6 +14,8 @@ pub enum OnboardingStrategy {
 #[serde(rename_all = "snake_case")]
 pub enum PeripheralSyncMode {
     Ble,
+    /// Passive scan for the measurements peripherals broadcast, without connecting
+    BleBroadcast,
     Random,
 }
 
@@ -29,6 +33,82 @@ pub struct WifiConfig {
     pub password: String,
 }
 
+/// A relay or pump switched by a GPIO line
+#[derive(Debug, Deserialize, Clone)]
+pub struct ActuatorConfig {
+    /// Character device of the GPIO chip
+    #[serde(default = "default_gpio_chip")]
+    pub chip: String,
+    pub line: u32,
+    /// Relay boards commonly switch on a low level
+    #[serde(default)]
+    pub active_low: bool,
+    /// Longest the actuator runs, whatever the backend asks for
+    #[serde(default = "default_max_watering_secs")]
+    pub max_secs: u64,
+}
+
+/// A broker the measurements are published to, announced to Home Assistant through MQTT discovery
+#[derive(Debug, Deserialize, Clone)]
+pub struct MqttConfig {
+    pub host: String,
+    #[serde(default = "default_mqtt_port")]
+    pub port: u16,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    #[serde(default = "default_mqtt_client_id")]
+    pub client_id: String,
+    /// Prefix Home Assistant subscribes to for discovery
+    #[serde(default = "default_mqtt_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Prefix of the topics the measurements are published on
+    #[serde(default = "default_mqtt_topic_prefix")]
+    pub topic_prefix: String,
+}
+
+/// How the frames stations sign are verified
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct StationAuthConfig {
+    /// Fleet key the firmware is built with as `MYCELIUM_AUTH_KEY`, 64 hex characters.
+    /// Stations without a key of their own are provisioned with the key derived from it.
+    pub fleet_key: Option<String>,
+    /// Reject stations without a key instead of accepting their frames unverified
+    #[serde(default)]
+    pub required: bool,
+}
+
+fn default_api_addr() -> String {
+    "0.0.0.0:8081".to_string()
+}
+
+fn default_gpio_chip() -> String {
+    "/dev/gpiochip0".to_string()
+}
+
+fn default_max_watering_secs() -> u64 {
+    5 * 60
+}
+
+fn default_backend_upload() -> bool {
+    true
+}
+
+fn default_mqtt_port() -> u16 {
+    1883
+}
+
+fn default_mqtt_client_id() -> String {
+    "mycelium-edge-central".to_string()
+}
+
+fn default_mqtt_discovery_prefix() -> String {
+    "homeassistant".to_string()
+}
+
+fn default_mqtt_topic_prefix() -> String {
+    "mycelium".to_string()
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     pub backend_url: String,
@@ -37,6 +117,20 @@ pub struct AppConfig {
     pub peripheral_sync_mode: PeripheralSyncMode,
     pub auth0: Auth0Config,
     pub wifi: WifiConfig,
+    /// Address the local API listens on
+    #[serde(default = "default_api_addr")]
+    pub api_addr: String,
+    /// Actuators keyed by the MAC of the station they water, as `aabbccddeeff`
+    #[serde(default)]
+    pub actuators: HashMap<String, ActuatorConfig>,
+    /// Whether measurements are uploaded to the backend, when off they stay in the outbox
+    #[serde(default = "default_backend_upload")]
+    pub backend_upload: bool,
+    /// Publish the measurements to an MQTT broker as well
+    #[serde(default)]
+    pub mqtt: Option<MqttConfig>,
+    #[serde(default)]
+    pub station_auth: StationAuthConfig,
 }
 
 impl AppConfig {
@@ -82,6 +176,10 @@ mod tests {
 
         // Verify the configuration was loaded correctly
         assert_eq!(config.database_url, "postgres://localhost/test");
+        assert_eq!(config.api_addr, "0.0.0.0:8081");
+        assert!(config.backend_upload);
+        assert!(config.mqtt.is_none());
+        assert!(config.station_auth.fleet_key.is_none() && !config.station_auth.required);
         match config.onboarding_strategy {
             OnboardingStrategy::Ble => {}
             _ => panic!("Expected OnboardingStrategy::Ble"),
@@ -156,6 +254,122 @@ mod tests {
         env::remove_var("APP.AUTH0.AUDIENCE");
     }
 
+    #[test]
+    #[serial]
+    fn test_from_env_actuators() {
+        env::set_var("APP.BACKEND_URL", "http://localhost:8080/api");
+        env::set_var("APP.DATABASE_URL", "sqlite://mycelium.db");
+        env::set_var("APP.ONBOARDING_STRATEGY", "local");
+        env::set_var("APP.PERIPHERAL_SYNC_MODE", "ble");
+        env::set_var("APP.AUTH0.DOMAIN", "test.auth0.com");
+        env::set_var("APP.AUTH0.CLIENT_ID", "test-client-id");
+        env::set_var("APP.AUTH0.SCOPE", "openid profile");
+        env::set_var("APP.AUTH0.AUDIENCE", "test-audience");
+        env::set_var("APP.WIFI.SSID", "test-wifi");
+        env::set_var("APP.WIFI.PASSWORD", "test-password");
+        env::set_var("APP.ACTUATORS.AABBCCDDEEFF.LINE", "17");
+        env::set_var("APP.ACTUATORS.AABBCCDDEEFF.ACTIVE_LOW", "true");
+
+        let config = AppConfig::from_env().unwrap();
+
+        let actuator = &config.actuators["aabbccddeeff"];
+        assert_eq!(actuator.chip, "/dev/gpiochip0");
+        assert_eq!(actuator.line, 17);
+        assert!(actuator.active_low);
+        assert_eq!(actuator.max_secs, 300);
+
+        env::remove_var("APP.BACKEND_URL");
+        env::remove_var("APP.DATABASE_URL");
+        env::remove_var("APP.ONBOARDING_STRATEGY");
+        env::remove_var("APP.PERIPHERAL_SYNC_MODE");
+        env::remove_var("APP.AUTH0.DOMAIN");
+        env::remove_var("APP.AUTH0.CLIENT_ID");
+        env::remove_var("APP.AUTH0.SCOPE");
+        env::remove_var("APP.AUTH0.AUDIENCE");
+        env::remove_var("APP.WIFI.SSID");
+        env::remove_var("APP.WIFI.PASSWORD");
+        env::remove_var("APP.ACTUATORS.AABBCCDDEEFF.LINE");
+        env::remove_var("APP.ACTUATORS.AABBCCDDEEFF.ACTIVE_LOW");
+    }
+
+    #[test]
+    #[serial]
+    fn test_from_env_mqtt() {
+        env::set_var("APP.BACKEND_URL", "http://localhost:8080/api");
+        env::set_var("APP.DATABASE_URL", "sqlite://mycelium.db");
+        env::set_var("APP.ONBOARDING_STRATEGY", "local");
+        env::set_var("APP.PERIPHERAL_SYNC_MODE", "ble");
+        env::set_var("APP.AUTH0.DOMAIN", "test.auth0.com");
+        env::set_var("APP.AUTH0.CLIENT_ID", "test-client-id");
+        env::set_var("APP.AUTH0.SCOPE", "openid profile");
+        env::set_var("APP.AUTH0.AUDIENCE", "test-audience");
+        env::set_var("APP.WIFI.SSID", "test-wifi");
+        env::set_var("APP.WIFI.PASSWORD", "test-password");
+        env::set_var("APP.BACKEND_UPLOAD", "false");
+        env::set_var("APP.MQTT.HOST", "localhost");
+        env::set_var("APP.MQTT.USERNAME", "mycelium");
+
+        let config = AppConfig::from_env().unwrap();
+
+        assert!(!config.backend_upload);
+        let mqtt = config.mqtt.unwrap();
+        assert_eq!(mqtt.host, "localhost");
+        assert_eq!(mqtt.port, 1883);
+        assert_eq!(mqtt.username.as_deref(), Some("mycelium"));
+        assert_eq!(mqtt.password, None);
+        assert_eq!(mqtt.discovery_prefix, "homeassistant");
+        assert_eq!(mqtt.topic_prefix, "mycelium");
+
+        env::remove_var("APP.BACKEND_URL");
+        env::remove_var("APP.DATABASE_URL");
+        env::remove_var("APP.ONBOARDING_STRATEGY");
+        env::remove_var("APP.PERIPHERAL_SYNC_MODE");
+        env::remove_var("APP.AUTH0.DOMAIN");
+        env::remove_var("APP.AUTH0.CLIENT_ID");
+        env::remove_var("APP.AUTH0.SCOPE");
+        env::remove_var("APP.AUTH0.AUDIENCE");
+        env::remove_var("APP.WIFI.SSID");
+        env::remove_var("APP.WIFI.PASSWORD");
+        env::remove_var("APP.BACKEND_UPLOAD");
+        env::remove_var("APP.MQTT.HOST");
+        env::remove_var("APP.MQTT.USERNAME");
+    }
+
+    #[test]
+    #[serial]
+    fn test_from_env_station_auth() {
+        env::set_var("APP.BACKEND_URL", "http://localhost:8080/api");
+        env::set_var("APP.DATABASE_URL", "sqlite://mycelium.db");
+        env::set_var("APP.ONBOARDING_STRATEGY", "local");
+        env::set_var("APP.PERIPHERAL_SYNC_MODE", "ble");
+        env::set_var("APP.AUTH0.DOMAIN", "test.auth0.com");
+        env::set_var("APP.AUTH0.CLIENT_ID", "test-client-id");
+        env::set_var("APP.AUTH0.SCOPE", "openid profile");
+        env::set_var("APP.AUTH0.AUDIENCE", "test-audience");
+        env::set_var("APP.WIFI.SSID", "test-wifi");
+        env::set_var("APP.WIFI.PASSWORD", "test-password");
+        env::set_var("APP.STATION_AUTH.FLEET_KEY", "42".repeat(32));
+        env::set_var("APP.STATION_AUTH.REQUIRED", "true");
+
+        let config = AppConfig::from_env().unwrap();
+
+        assert_eq!(config.station_auth.fleet_key, Some("42".repeat(32)));
+        assert!(config.station_auth.required);
+
+        env::remove_var("APP.BACKEND_URL");
+        env::remove_var("APP.DATABASE_URL");
+        env::remove_var("APP.ONBOARDING_STRATEGY");
+        env::remove_var("APP.PERIPHERAL_SYNC_MODE");
+        env::remove_var("APP.AUTH0.DOMAIN");
+        env::remove_var("APP.AUTH0.CLIENT_ID");
+        env::remove_var("APP.AUTH0.SCOPE");
+        env::remove_var("APP.AUTH0.AUDIENCE");
+        env::remove_var("APP.WIFI.SSID");
+        env::remove_var("APP.WIFI.PASSWORD");
+        env::remove_var("APP.STATION_AUTH.FLEET_KEY");
+        env::remove_var("APP.STATION_AUTH.REQUIRED");
+    }
+
     #[test]
     #[serial]
     fn test_from_env_missing_values() {
@@ -0,0 +1,102 @@
+use anyhow::anyhow;
+use edge_protocol::auth::station_key;
+use edge_protocol::{AuthKey, Command};
+
+use crate::ble::parse_mac;
+use crate::data::sqlite::SqliteStationKeyRepository;
+use crate::measurements::commands::parse_command;
+
+pub const USAGE: &str = "Usage: main [command <mac> <identify|sample-now|reboot|factory-reset> | key <mac> <64 hex digits of the firmware key>]";
+
+/// What edge-central was started to do
+#[derive(Debug, PartialEq)]
+pub enum Cli {
+    /// Sync the stations and upload their measurements
+    Run,
+    /// Queue a command, it is delivered the next time the station syncs
+    Command { mac: [u8; 6], command: Command },
+    /// Provision a station whose firmware is built with a key other than the fleet key
+    Key { mac: [u8; 6], key: AuthKey },
+}
+
+impl Cli {
+    /// Parse the arguments following the name of the binary
+    pub fn parse(args: &[String]) -> anyhow::Result<Self> {
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+        match args.as_slice() {
+            [] => Ok(Cli::Run),
+            ["command", mac, command] => Ok(Cli::Command { mac: parse_mac(mac)?, command: parse_command(command)? }),
+            ["key", mac, key] => {
+                let key = edge_protocol::auth::parse_key(key).map_err(|err| anyhow!("Invalid key: {}", err))?;
+                Ok(Cli::Key { mac: parse_mac(mac)?, key })
+            }
+            _ => Err(anyhow!("Unexpected arguments {:?}\n{}", args, USAGE)),
+        }
+    }
+}
+
+/// Provision a station with the key its firmware derives from the key it is built with,
+/// replacing the one derived from the fleet key
+pub async fn provision_key(station_keys: &SqliteStationKeyRepository, mac: &[u8; 6], build_key: &AuthKey) -> anyhow::Result<()> {
+    station_keys.set_key(mac, &station_key(build_key, mac)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use edge_protocol::Signer;
+
+    use crate::data::sqlite::{test_pool, SqliteStationCommandRepository, SqliteStationDeviceRepository, SqliteStationSettingsRepository, SqliteSyncRepository};
+    use crate::measurements::types::SyncContext;
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+
+    fn parse(args: &[&str]) -> anyhow::Result<Cli> {
+        Cli::parse(&args.iter().map(|arg| arg.to_string()).collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn test_parse_subcommands() {
+        assert_eq!(parse(&[]).unwrap(), Cli::Run);
+        assert_eq!(parse(&["command", "aa:bb:cc:dd:ee:ff", "reboot"]).unwrap(), Cli::Command { mac: MAC, command: Command::Reboot });
+        assert_eq!(parse(&["key", "aabbccddeeff", &"42".repeat(32)]).unwrap(), Cli::Key { mac: MAC, key: [0x42; 32] });
+    }
+
+    #[tokio::test]
+    async fn test_provisioned_key_verifies_frames_of_the_firmware() {
+        let pool = test_pool().await;
+        let context = SyncContext {
+            station_keys: Arc::new(SqliteStationKeyRepository::new(pool.clone())),
+            station_settings: Arc::new(SqliteStationSettingsRepository::new(pool.clone())),
+            station_commands: Arc::new(SqliteStationCommandRepository::new(pool.clone())),
+            station_devices: Arc::new(SqliteStationDeviceRepository::new(pool.clone())),
+            syncs: Arc::new(SqliteSyncRepository::new(pool)),
+            fleet_key: Some([0x24; 32]),
+            require_authentication: true,
+        };
+        let build_key = [0x42; 32];
+
+        provision_key(&context.station_keys, &MAC, &build_key).await.unwrap();
+
+        // Signed the way the firmware signs, with the key derived from the one it is built with
+        let mut signer = Signer::new(station_key(&build_key, &MAC), 0);
+        signer.seed(1_700_000_000);
+        let mut buffer = [0u8; 64];
+        buffer[..5].copy_from_slice(b"frame");
+        let len = signer.sign(&mut buffer, 5).unwrap();
+
+        let mut verifier = context.verifier(&MAC).await.unwrap().unwrap();
+        assert_eq!(verifier.verify(&buffer[..len]).unwrap(), b"frame");
+    }
+
+    #[test]
+    fn test_parse_rejects_unexpected_arguments() {
+        assert!(parse(&["command", "aa:bb:cc:dd:ee:ff"]).is_err());
+        assert!(parse(&["command", "aa:bb:cc:dd:ee:ff", "blink"]).is_err());
+        assert!(parse(&["key", "aa:bb:cc:dd:ee:ff", "42"]).is_err());
+        assert!(parse(&["reboot", "aa:bb:cc:dd:ee:ff", "now"]).is_err());
+    }
+}
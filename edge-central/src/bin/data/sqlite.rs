@@ -1,21 +1,24 @@
 use std::sync::Arc;
 
 use chrono::NaiveDateTime;
-use edge_protocol::MeasurementSerieEntry;
-use sqlx::SqlitePool;
+use edge_protocol::{AuthKey, Command, CommandRequest, CommandStatus, Measurement, MeasurementSerieEntry, PeripheralConfig, Verifier};
+use sqlx::{SqliteConnection, SqlitePool};
 
-use crate::data::types::EdgeState;
+use crate::data::types::{DeviceInformation, EdgeState};
+use crate::measurements::types::PeripheralSyncResult;
 
 #[derive(Debug, sqlx::FromRow)]
 pub struct MeasurementSerieEntryRow {
     pub id: i64,
     pub mac: Vec<u8>,
     pub timestamp: NaiveDateTime,
-    pub battery: i64,
-    pub lux: f64,
-    pub temperature: f64,
-    pub humidity: f64,
-    pub soil_pf: f64
+    pub battery: Option<i64>,
+    pub lux: Option<f64>,
+    pub temperature: Option<f64>,
+    pub humidity: Option<f64>,
+    pub soil_pf: Option<f64>,
+    pub tank_pf: Option<f64>,
+    pub battery_mv: Option<i64>
 }
 
 impl MeasurementSerieEntryRow {
@@ -28,11 +31,13 @@ impl MeasurementSerieEntryRow {
             id,
             mac: mac.to_vec(),
             timestamp: entry.timestamp,
-            battery: entry.measurement.battery as i64,
-            lux: entry.measurement.lux as f64,
-            temperature: entry.measurement.temperature as f64,
-            humidity: entry.measurement.humidity as f64,
-            soil_pf: entry.measurement.soil_pf as f64
+            battery: entry.measurement.battery_percentage().map(i64::from),
+            lux: entry.measurement.lux.map(f64::from),
+            temperature: entry.measurement.temperature.map(f64::from),
+            humidity: entry.measurement.humidity.map(f64::from),
+            soil_pf: entry.measurement.soil_pf.map(f64::from),
+            tank_pf: entry.measurement.tank_pf.map(f64::from),
+            battery_mv: entry.measurement.battery_mv.map(i64::from)
         }
     }
 
@@ -40,11 +45,13 @@ impl MeasurementSerieEntryRow {
         edge_protocol::MeasurementSerieEntry {
             timestamp: self.timestamp,
             measurement: edge_protocol::Measurement {
-                battery: self.battery.try_into().unwrap_or(100),
-                lux: self.lux as f32,
-                temperature: self.temperature as f32,
-                humidity: self.humidity as f32,
-                soil_pf: self.soil_pf as f32
+                battery: self.battery.map(|b| b.try_into().unwrap_or(100)),
+                lux: self.lux.map(|v| v as f32),
+                temperature: self.temperature.map(|v| v as f32),
+                humidity: self.humidity.map(|v| v as f32),
+                soil_pf: self.soil_pf.map(|v| v as f32),
+                tank_pf: self.tank_pf.map(|v| v as f32),
+                battery_mv: self.battery_mv.and_then(|mv| mv.try_into().ok())
             },
         }
     }
@@ -149,77 +156,978 @@ impl SqliteMeasurementRepository {
         mac: &[u8; 6],
         entries: Vec<edge_protocol::MeasurementSerieEntry>,
     ) -> anyhow::Result<u64> {
-        let mut inserted = 0u64;
         let mut tx = self.pool.begin().await?;
+        let inserted = insert_measurements(&mut tx, mac, &entries, None).await?;
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    pub async fn find_by_mac(&self, mac: &[u8; 6]) -> anyhow::Result<Vec<MeasurementSerieEntry>> {
+        let mac_ref = mac.as_ref();
+        let rows: Vec<MeasurementSerieEntryRow> = sqlx::query_as(
+            "
+            SELECT id, mac, timestamp, battery, lux, temperature, humidity, soil_pf, tank_pf, battery_mv
+            FROM measurements
+            WHERE mac = ?
+            ",
+        )
+        .bind(mac_ref)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows
+            .iter()
+            .map(|x| x.to_measurement_serie_entry())
+            .collect())
+    }
+
+    /// The latest `limit` measurements from `from` until before `to`, oldest first
+    pub async fn history(
+        &self,
+        mac: &[u8; 6],
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+        limit: u32,
+    ) -> anyhow::Result<Vec<MeasurementSerieEntry>> {
+        let rows: Vec<MeasurementSerieEntryRow> = sqlx::query_as(
+            "
+            SELECT * FROM (
+                SELECT id, mac, timestamp, battery, lux, temperature, humidity, soil_pf, tank_pf, battery_mv
+                FROM measurements
+                WHERE mac = ?1 AND (?2 IS NULL OR timestamp >= ?2) AND (?3 IS NULL OR timestamp < ?3)
+                ORDER BY timestamp DESC, id DESC
+                LIMIT ?4
+            )
+            ORDER BY timestamp, id
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows.iter().map(|x| x.to_measurement_serie_entry()).collect())
+    }
+}
+
+/// Insert entries on a connection, `sync_id` links them to the sync that retrieved them
+async fn insert_measurements(
+    conn: &mut SqliteConnection,
+    mac: &[u8; 6],
+    entries: &[MeasurementSerieEntry],
+    sync_id: Option<i64>,
+) -> anyhow::Result<u64> {
+    let mut inserted = 0u64;
+
+    for entry in entries {
+        let row = MeasurementSerieEntryRow::from_measurement_serie_entry(mac, entry, 0);
+        let res = sqlx::query(
+            "
+            INSERT INTO measurements (mac, timestamp, battery, lux, temperature, humidity, soil_pf, tank_pf, battery_mv, sync_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            ",
+        )
+        .bind(row.mac)
+        .bind(row.timestamp)
+        .bind(row.battery)
+        .bind(row.lux)
+        .bind(row.temperature)
+        .bind(row.humidity)
+        .bind(row.soil_pf)
+        .bind(row.tank_pf)
+        .bind(row.battery_mv)
+        .bind(sync_id)
+        .execute(&mut *conn)
+        .await?;
+
+        inserted += res.rows_affected();
+    }
+
+    Ok(inserted)
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct SyncRow {
+    pub id: i64,
+    pub mac: Vec<u8>,
+    pub synced_at: NaiveDateTime,
+    pub time_drift_ms: i64,
+    pub decode_failures: i64,
+    pub rssi: Option<i64>,
+    pub uploaded_at: Option<NaiveDateTime>,
+}
+
+/// What the hub knows of a station, from its latest sync
+#[derive(Debug, sqlx::FromRow)]
+pub struct StationSummaryRow {
+    pub mac: Vec<u8>,
+    pub last_seen: NaiveDateTime,
+    pub time_drift_ms: i64,
+    pub rssi: Option<i64>,
+    pub syncs: i64,
+    pub last_uploaded_at: Option<NaiveDateTime>,
+    pub pending_uploads: i64,
+    pub firmware_version: Option<String>,
+    pub backend_id: Option<String>,
+}
+
+/// Syncs are stored before they're uploaded, so a crash or an unreachable backend loses nothing
+pub struct SqliteSyncRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteSyncRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    /// Store a sync with its measurements and queue them for upload, returns the id of the sync
+    pub async fn insert(&self, result: &PeripheralSyncResult, synced_at: NaiveDateTime) -> anyhow::Result<i64> {
+        let mut tx = self.pool.begin().await?;
+
+        let id = sqlx::query(
+            "
+            INSERT INTO syncs (mac, synced_at, time_drift_ms, decode_failures, rssi)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ",
+        )
+        .bind(result.address.as_ref())
+        .bind(synced_at)
+        .bind(result.time_drift.num_milliseconds())
+        .bind(i64::from(result.decode_failures))
+        .bind(result.rssi.map(i64::from))
+        .execute(&mut *tx)
+        .await?
+        .last_insert_rowid();
+
+        insert_measurements(&mut tx, &result.address, &result.measurements, Some(id)).await?;
+        queue_sync(&mut tx, id, synced_at).await?;
+
+        // A sync without measurements has nothing left to upload
+        complete_uploaded_syncs(&mut tx, synced_at).await?;
+        tx.commit().await?;
+
+        Ok(id)
+    }
+
+    /// Store a sync before its history is transferred, the pages are appended as the peripheral serves them
+    pub async fn begin(&self, mac: &[u8; 6], synced_at: NaiveDateTime, time_drift: chrono::Duration) -> anyhow::Result<i64> {
+        let id = sqlx::query("INSERT INTO syncs (mac, synced_at, time_drift_ms, decode_failures) VALUES (?1, ?2, ?3, 0)")
+            .bind(mac.as_ref())
+            .bind(synced_at)
+            .bind(time_drift.num_milliseconds())
+            .execute(&*self.pool)
+            .await?
+            .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    /// Store a page of a sync and queue it for upload, committed before the peripheral may drop it
+    pub async fn append(&self, id: i64, mac: &[u8; 6], entries: &[MeasurementSerieEntry], at: NaiveDateTime) -> anyhow::Result<u64> {
+        let mut tx = self.pool.begin().await?;
+        let inserted = insert_measurements(&mut tx, mac, entries, Some(id)).await?;
+        queue_sync(&mut tx, id, at).await?;
+
+        // The uploader may have completed the sync while it had no measurements yet
+        sqlx::query("UPDATE syncs SET uploaded_at = NULL WHERE id = ?1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+
+        Ok(inserted)
+    }
+
+    /// Complete a sync stored page by page with what is only known once the transfer is done
+    pub async fn finish(&self, id: i64, result: &PeripheralSyncResult, at: NaiveDateTime) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query("UPDATE syncs SET decode_failures = ?2, rssi = ?3 WHERE id = ?1")
+            .bind(id)
+            .bind(i64::from(result.decode_failures))
+            .bind(result.rssi.map(i64::from))
+            .execute(&mut *tx)
+            .await?;
+
+        complete_uploaded_syncs(&mut tx, at).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// The latest `limit` syncs of a station, latest first
+    pub async fn find_by_mac(&self, mac: &[u8; 6], limit: u32) -> anyhow::Result<Vec<SyncRow>> {
+        let rows = sqlx::query_as(
+            "
+            SELECT id, mac, synced_at, time_drift_ms, decode_failures, rssi, uploaded_at
+            FROM syncs
+            WHERE mac = ?1
+            ORDER BY id DESC
+            LIMIT ?2
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+
+    /// Every station that synced, or only `mac`, ordered by MAC
+    pub async fn summaries(&self, mac: Option<&[u8; 6]>) -> anyhow::Result<Vec<StationSummaryRow>> {
+        let rows = sqlx::query_as(
+            "
+            SELECT
+                s.mac,
+                s.synced_at AS last_seen,
+                s.time_drift_ms,
+                s.rssi,
+                (SELECT COUNT(*) FROM syncs WHERE mac = s.mac) AS syncs,
+                (SELECT MAX(uploaded_at) FROM syncs WHERE mac = s.mac) AS last_uploaded_at,
+                (
+                    SELECT COUNT(*) FROM outbox o
+                    JOIN measurements m ON m.id = o.measurement_id
+                    WHERE m.mac = s.mac AND o.uploaded_at IS NULL
+                ) AS pending_uploads,
+                d.firmware_version,
+                st.backend_id
+            FROM syncs s
+            LEFT JOIN station_devices d ON d.mac = s.mac
+            LEFT JOIN stations st ON st.mac = s.mac
+            WHERE s.id = (SELECT MAX(id) FROM syncs WHERE mac = s.mac)
+            AND (?1 IS NULL OR s.mac = ?1)
+            ORDER BY s.mac
+            ",
+        )
+        .bind(mac.map(|mac| mac.as_ref()))
+        .fetch_all(&*self.pool)
+        .await?;
+
+        Ok(rows)
+    }
+}
+
+/// Queue the measurements of a sync for upload, those already queued are kept as they are
+async fn queue_sync(conn: &mut SqliteConnection, sync_id: i64, at: NaiveDateTime) -> anyhow::Result<()> {
+    sqlx::query(
+        "
+        INSERT OR IGNORE INTO outbox (measurement_id, next_attempt_at)
+        SELECT id, ?2 FROM measurements WHERE sync_id = ?1
+        ",
+    )
+    .bind(sync_id)
+    .bind(at)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+/// Mark the syncs whose measurements all reached the backend as uploaded
+async fn complete_uploaded_syncs(conn: &mut SqliteConnection, uploaded_at: NaiveDateTime) -> anyhow::Result<()> {
+    sqlx::query(
+        "
+        UPDATE syncs SET uploaded_at = ?1
+        WHERE uploaded_at IS NULL
+        AND NOT EXISTS (
+            SELECT 1 FROM measurements m
+            JOIN outbox o ON o.measurement_id = m.id
+            WHERE m.sync_id = syncs.id AND o.uploaded_at IS NULL
+        )
+        ",
+    )
+    .bind(uploaded_at)
+    .execute(&mut *conn)
+    .await?;
+
+    Ok(())
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct OutboxRow {
+    #[sqlx(flatten)]
+    pub measurement: MeasurementSerieEntryRow,
+    pub attempts: i64,
+}
+
+/// A measurement waiting for its upload
+#[derive(Debug, Clone, PartialEq)]
+pub struct OutboxEntry {
+    pub measurement_id: i64,
+    pub mac: [u8; 6],
+    pub entry: MeasurementSerieEntry,
+    /// Failed uploads so far
+    pub attempts: u32,
+}
+
+impl OutboxRow {
+    pub fn to_outbox_entry(&self) -> anyhow::Result<OutboxEntry> {
+        Ok(OutboxEntry {
+            measurement_id: self.measurement.id,
+            mac: self.measurement.mac.as_slice().try_into()?,
+            entry: self.measurement.to_measurement_serie_entry(),
+            attempts: self.attempts.try_into()?,
+        })
+    }
+}
+
+/// Per measurement upload state, failed uploads are retried once their next attempt is due
+pub struct SqliteOutboxRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteOutboxRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    /// Pending measurements due at `now`, oldest first so an outage is backfilled in order
+    pub async fn due(&self, now: NaiveDateTime, limit: u32) -> anyhow::Result<Vec<OutboxEntry>> {
+        let rows: Vec<OutboxRow> = sqlx::query_as(
+            "
+            SELECT m.id, m.mac, m.timestamp, m.battery, m.lux, m.temperature, m.humidity, m.soil_pf, m.tank_pf, m.battery_mv, o.attempts
+            FROM outbox o
+            JOIN measurements m ON m.id = o.measurement_id
+            WHERE o.uploaded_at IS NULL AND o.next_attempt_at <= ?1
+            ORDER BY m.timestamp, m.id
+            LIMIT ?2
+            ",
+        )
+        .bind(now)
+        .bind(limit)
+        .fetch_all(&*self.pool)
+        .await?;
+
+        rows.iter().map(|row| row.to_outbox_entry()).collect()
+    }
+
+    /// When the earliest pending measurement is due, `None` when the outbox is drained
+    pub async fn next_attempt_at(&self) -> anyhow::Result<Option<NaiveDateTime>> {
+        let next: Option<NaiveDateTime> = sqlx::query_scalar("SELECT MIN(next_attempt_at) FROM outbox WHERE uploaded_at IS NULL")
+            .fetch_one(&*self.pool)
+            .await?;
+
+        Ok(next)
+    }
+
+    /// Number of measurements waiting for their upload and the timestamp of the oldest
+    pub async fn backlog(&self) -> anyhow::Result<(i64, Option<NaiveDateTime>)> {
+        let backlog = sqlx::query_as(
+            "
+            SELECT COUNT(*), MIN(m.timestamp)
+            FROM outbox o
+            JOIN measurements m ON m.id = o.measurement_id
+            WHERE o.uploaded_at IS NULL
+            ",
+        )
+        .fetch_one(&*self.pool)
+        .await?;
+
+        Ok(backlog)
+    }
+
+    /// Mark measurements as uploaded, completing the syncs they came with
+    pub async fn mark_uploaded(&self, measurement_ids: &[i64], uploaded_at: NaiveDateTime) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for id in measurement_ids {
+            sqlx::query("UPDATE outbox SET uploaded_at = ?1, last_error = NULL WHERE measurement_id = ?2")
+                .bind(uploaded_at)
+                .bind(id)
+                .execute(&mut *tx)
+                .await?;
+        }
+
+        complete_uploaded_syncs(&mut tx, uploaded_at).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Record a failed upload and postpone the next attempt
+    pub async fn mark_failed(&self, measurement_ids: &[i64], error: &str, next_attempt_at: NaiveDateTime) -> anyhow::Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        for id in measurement_ids {
+            sqlx::query(
+                "
+                UPDATE outbox SET attempts = attempts + 1, last_error = ?1, next_attempt_at = ?2
+                WHERE measurement_id = ?3
+                ",
+            )
+            .bind(error)
+            .bind(next_attempt_at)
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+        }
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct StationKeyRow {
+    pub mac: Vec<u8>,
+    pub key: Vec<u8>,
+    pub counter: i64,
+}
+
+impl StationKeyRow {
+    pub fn to_verifier(&self) -> anyhow::Result<Verifier> {
+        let key: AuthKey = self.key.as_slice().try_into()?;
+        Ok(Verifier::new(key, self.counter.try_into()?))
+    }
+}
+
+pub struct SqliteStationKeyRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteStationKeyRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    /// Verifier for the frames of a station, `None` when no key is provisioned
+    pub async fn find_verifier(&self, mac: &[u8; 6]) -> anyhow::Result<Option<Verifier>> {
+        let row: Option<StationKeyRow> = sqlx::query_as(
+            "
+            SELECT mac, key, counter
+            FROM station_keys
+            WHERE mac = ?
+            ",
+        )
+        .bind(mac.as_ref())
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        row.map(|r| r.to_verifier()).transpose()
+    }
+
+    /// Provision a station with a new key, counters of the previous key no longer apply
+    pub async fn set_key(&self, mac: &[u8; 6], key: &AuthKey) -> anyhow::Result<u64> {
+        let res = sqlx::query(
+            "
+            INSERT INTO station_keys (mac, key, counter)
+            VALUES (?1, ?2, 0)
+            ON CONFLICT(mac) DO UPDATE SET
+                key = excluded.key,
+                counter = 0
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(key.as_ref())
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Store the last accepted counter, a counter that isn't newer than the stored one is rejected
+    pub async fn advance_counter(&self, mac: &[u8; 6], counter: u32) -> anyhow::Result<bool> {
+        let res = sqlx::query(
+            "
+            UPDATE station_keys
+            SET counter = ?2
+            WHERE mac = ?1 AND counter < ?2
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(i64::from(counter))
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected() == 1)
+    }
+
+    /// Reserve the next `count` counters to sign writes to a station with, returns the first.
+    ///
+    /// Counters are never handed out twice, whatever the clock of the hub does. They don't go below
+    /// `floor` either, the unix time the writes were signed with before the counters were kept.
+    /// `None` when no key is provisioned.
+    pub async fn reserve_downlink_counters(&self, mac: &[u8; 6], count: u32, floor: u32) -> anyhow::Result<Option<u32>> {
+        let last: Option<i64> = sqlx::query_scalar(
+            "
+            UPDATE station_keys
+            SET downlink_counter = MAX(downlink_counter, ?3) + ?2
+            WHERE mac = ?1
+            RETURNING downlink_counter
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(i64::from(count))
+        .bind(i64::from(floor))
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(last.map(|last| u32::try_from(last - i64::from(count) + 1)).transpose()?)
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct StationSettingsRow {
+    pub mac: Vec<u8>,
+    pub sampling_period_secs: i64,
+    pub flush_size: i64,
+    pub max_deviation_battery: Option<i64>,
+    pub max_deviation_lux: Option<f64>,
+    pub max_deviation_temperature: Option<f64>,
+    pub max_deviation_humidity: Option<f64>,
+    pub max_deviation_soil_pf: Option<f64>,
+    pub max_deviation_tank_pf: Option<f64>,
+    pub max_deviation_battery_mv: Option<i64>
+}
+
+impl StationSettingsRow {
+    pub fn from_config(mac: &[u8; 6], config: &PeripheralConfig) -> Self {
+        let max_deviation = &config.max_deviation;
+
+        StationSettingsRow {
+            mac: mac.to_vec(),
+            sampling_period_secs: config.sampling_period_secs.into(),
+            flush_size: config.flush_size.into(),
+            max_deviation_battery: max_deviation.battery.map(i64::from),
+            max_deviation_lux: max_deviation.lux.map(f64::from),
+            max_deviation_temperature: max_deviation.temperature.map(f64::from),
+            max_deviation_humidity: max_deviation.humidity.map(f64::from),
+            max_deviation_soil_pf: max_deviation.soil_pf.map(f64::from),
+            max_deviation_tank_pf: max_deviation.tank_pf.map(f64::from),
+            max_deviation_battery_mv: max_deviation.battery_mv.map(i64::from)
+        }
+    }
+
+    pub fn to_config(&self) -> anyhow::Result<PeripheralConfig> {
+        let config = PeripheralConfig {
+            sampling_period_secs: self.sampling_period_secs.try_into()?,
+            flush_size: self.flush_size.try_into()?,
+            max_deviation: Measurement {
+                battery: self.max_deviation_battery.map(|v| v.try_into()).transpose()?,
+                lux: self.max_deviation_lux.map(|v| v as f32),
+                temperature: self.max_deviation_temperature.map(|v| v as f32),
+                humidity: self.max_deviation_humidity.map(|v| v as f32),
+                soil_pf: self.max_deviation_soil_pf.map(|v| v as f32),
+                tank_pf: self.max_deviation_tank_pf.map(|v| v as f32),
+                battery_mv: self.max_deviation_battery_mv.map(|v| v.try_into()).transpose()?
+            },
+        };
+
+        config.validate()?;
+
+        Ok(config)
+    }
+}
+
+pub struct SqliteStationSettingsRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteStationSettingsRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    /// Configuration to write to a station, `None` when the station keeps its defaults
+    pub async fn find(&self, mac: &[u8; 6]) -> anyhow::Result<Option<PeripheralConfig>> {
+        let row: Option<StationSettingsRow> = sqlx::query_as(
+            "
+            SELECT mac, sampling_period_secs, flush_size, max_deviation_battery, max_deviation_lux, max_deviation_temperature,
+                max_deviation_humidity, max_deviation_soil_pf, max_deviation_tank_pf, max_deviation_battery_mv
+            FROM station_settings
+            WHERE mac = ?
+            ",
+        )
+        .bind(mac.as_ref())
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        row.map(|r| r.to_config()).transpose()
+    }
+
+    pub async fn set(&self, mac: &[u8; 6], config: &PeripheralConfig) -> anyhow::Result<u64> {
+        config.validate()?;
+
+        let row = StationSettingsRow::from_config(mac, config);
+        let res = sqlx::query(
+            "
+            INSERT INTO station_settings (mac, sampling_period_secs, flush_size, max_deviation_battery, max_deviation_lux,
+                max_deviation_temperature, max_deviation_humidity, max_deviation_soil_pf, max_deviation_tank_pf, max_deviation_battery_mv)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            ON CONFLICT(mac) DO UPDATE SET
+                sampling_period_secs = excluded.sampling_period_secs,
+                flush_size = excluded.flush_size,
+                max_deviation_battery = excluded.max_deviation_battery,
+                max_deviation_lux = excluded.max_deviation_lux,
+                max_deviation_temperature = excluded.max_deviation_temperature,
+                max_deviation_humidity = excluded.max_deviation_humidity,
+                max_deviation_soil_pf = excluded.max_deviation_soil_pf,
+                max_deviation_tank_pf = excluded.max_deviation_tank_pf,
+                max_deviation_battery_mv = excluded.max_deviation_battery_mv
+            ",
+        )
+        .bind(row.mac)
+        .bind(row.sampling_period_secs)
+        .bind(row.flush_size)
+        .bind(row.max_deviation_battery)
+        .bind(row.max_deviation_lux)
+        .bind(row.max_deviation_temperature)
+        .bind(row.max_deviation_humidity)
+        .bind(row.max_deviation_soil_pf)
+        .bind(row.max_deviation_tank_pf)
+        .bind(row.max_deviation_battery_mv)
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct StationCommandRow {
+    pub id: i64,
+    pub mac: Vec<u8>,
+    pub opcode: i64,
+    pub requested_at: NaiveDateTime,
+    pub status: Option<i64>,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
+impl StationCommandRow {
+    /// Request to send to the peripheral, the id wraps to the byte the protocol carries
+    pub fn to_request(&self) -> anyhow::Result<CommandRequest> {
+        Ok(CommandRequest {
+            command: Command::try_from(u8::try_from(self.opcode)?)?,
+            id: self.id as u8,
+        })
+    }
+
+    /// Status the peripheral replied with, `None` while the command is pending
+    pub fn to_status(&self) -> anyhow::Result<Option<CommandStatus>> {
+        self.status
+            .map(|status| Ok(CommandStatus::try_from(u8::try_from(status)?)?))
+            .transpose()
+    }
+}
+
+/// Commands operators requested for a station, delivered on its next sync
+pub struct SqliteStationCommandRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteStationCommandRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    /// Queue a command for a station, returns its id
+    pub async fn request(&self, mac: &[u8; 6], command: Command) -> anyhow::Result<i64> {
+        let res = sqlx::query(
+            "
+            INSERT INTO station_commands (mac, opcode, requested_at)
+            VALUES (?1, ?2, ?3)
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(i64::from(command.opcode()))
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.last_insert_rowid())
+    }
+
+    pub async fn find(&self, id: i64) -> anyhow::Result<Option<StationCommandRow>> {
+        let row = sqlx::query_as(
+            "
+            SELECT id, mac, opcode, requested_at, status, completed_at
+            FROM station_commands
+            WHERE id = ?
+            ",
+        )
+        .bind(id)
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(row)
+    }
+
+    /// Commands the station hasn't replied to yet, oldest first
+    pub async fn pending(&self, mac: &[u8; 6]) -> anyhow::Result<Vec<StationCommandRow>> {
+        let rows = sqlx::query_as(
+            "
+            SELECT id, mac, opcode, requested_at, status, completed_at
+            FROM station_commands
+            WHERE mac = ? AND status IS NULL
+            ORDER BY id
+            ",
+        )
+        .bind(mac.as_ref())
+        .fetch_all(&*self.pool)
+        .await?;
 
-        for entry in &entries {
-            let row = MeasurementSerieEntryRow::from_measurement_serie_entry(&mac, entry, 0);
-            let res = sqlx::query(
-                "
-                INSERT INTO measurements (mac, timestamp, battery, lux, temperature, humidity)
-                VALUES (?1, ?2, ?3, ?4, ?5, ?6)
-                ",
-            )
-            .bind(row.mac)
-            .bind(row.timestamp)
-            .bind(row.battery)
-            .bind(row.lux)
-            .bind(row.temperature)
-            .bind(row.humidity)
-            .execute(&mut *tx)
-            .await?;
+        Ok(rows)
+    }
+
+    /// Record the status the station replied with
+    pub async fn complete(&self, id: i64, status: CommandStatus) -> anyhow::Result<u64> {
+        let res = sqlx::query(
+            "
+            UPDATE station_commands
+            SET status = ?2, completed_at = ?3
+            WHERE id = ?1 AND status IS NULL
+            ",
+        )
+        .bind(id)
+        .bind(status as i64)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct StationDeviceRow {
+    pub mac: Vec<u8>,
+    pub model: String,
+    pub firmware_version: String,
+    pub hardware_revision: String,
+    pub updated_at: NaiveDateTime,
+}
 
-            inserted += res.rows_affected();
+impl StationDeviceRow {
+    pub fn to_device_information(&self) -> DeviceInformation {
+        DeviceInformation {
+            model: self.model.clone(),
+            firmware_version: self.firmware_version.clone(),
+            hardware_revision: self.hardware_revision.clone(),
         }
+    }
+}
 
-        tx.commit().await?;
+/// Device information stations served on their last sync
+pub struct SqliteStationDeviceRepository {
+    pool: Arc<SqlitePool>,
+}
 
-        Ok(inserted)
+impl SqliteStationDeviceRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
     }
 
-    pub async fn find_by_mac(&self, mac: &[u8; 6]) -> anyhow::Result<Vec<MeasurementSerieEntry>> {
-        let mac_ref = mac.as_ref();
-        let rows: Vec<MeasurementSerieEntryRow> = sqlx::query_as(
+    pub async fn find(&self, mac: &[u8; 6]) -> anyhow::Result<Option<DeviceInformation>> {
+        let row: Option<StationDeviceRow> = sqlx::query_as(
             "
-            SELECT id, mac, timestamp, battery, lux, temperature, humidity
-            FROM measurements
+            SELECT mac, model, firmware_version, hardware_revision, updated_at
+            FROM station_devices
             WHERE mac = ?
             ",
         )
-        .bind(mac_ref)
+        .bind(mac.as_ref())
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(row.map(|r| r.to_device_information()))
+    }
+
+    pub async fn set(&self, mac: &[u8; 6], device: &DeviceInformation) -> anyhow::Result<u64> {
+        let res = sqlx::query(
+            "
+            INSERT INTO station_devices (mac, model, firmware_version, hardware_revision, updated_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT(mac) DO UPDATE SET
+                model = excluded.model,
+                firmware_version = excluded.firmware_version,
+                hardware_revision = excluded.hardware_revision,
+                updated_at = excluded.updated_at
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(&device.model)
+        .bind(&device.firmware_version)
+        .bind(&device.hardware_revision)
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+#[derive(Debug, sqlx::FromRow)]
+pub struct StationRow {
+    pub mac: Vec<u8>,
+    pub backend_id: String,
+    pub created_at: NaiveDateTime,
+}
+
+/// The backend station id of each MAC
+pub struct SqliteStationRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteStationRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    pub async fn find_backend_id(&self, mac: &[u8; 6]) -> anyhow::Result<Option<uuid::Uuid>> {
+        let row: Option<StationRow> = sqlx::query_as(
+            "
+            SELECT mac, backend_id, created_at
+            FROM stations
+            WHERE mac = ?
+            ",
+        )
+        .bind(mac.as_ref())
+        .fetch_optional(&*self.pool)
+        .await?;
+
+        Ok(row.map(|r| uuid::Uuid::parse_str(&r.backend_id)).transpose()?)
+    }
+
+    pub async fn set_backend_id(&self, mac: &[u8; 6], backend_id: &uuid::Uuid) -> anyhow::Result<u64> {
+        let res = sqlx::query(
+            "
+            INSERT INTO stations (mac, backend_id, created_at)
+            VALUES (?1, ?2, ?3)
+            ON CONFLICT(mac) DO UPDATE SET
+                backend_id = excluded.backend_id,
+                created_at = excluded.created_at
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(backend_id.to_string())
+        .bind(chrono::Utc::now().naive_utc())
+        .execute(&*self.pool)
+        .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Forget the backend station, the next upload creates it again
+    pub async fn remove(&self, mac: &[u8; 6]) -> anyhow::Result<u64> {
+        let res = sqlx::query("DELETE FROM stations WHERE mac = ?")
+            .bind(mac.as_ref())
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(res.rows_affected())
+    }
+}
+
+/// A watering the backend still has to be told about
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnreportedWatering {
+    pub id: i64,
+    pub mac: [u8; 6],
+    /// How long the actuator ran
+    pub watered: std::time::Duration,
+}
+
+/// Local log of the waterings, so each one can be told apart from the measurements it causes
+pub struct SqliteWateringRepository {
+    pool: Arc<SqlitePool>,
+}
+
+impl SqliteWateringRepository {
+    pub fn new(pool: Arc<SqlitePool>) -> Self {
+        Self { pool }
+    }
+
+    /// Log a watering that is about to start, returns its id
+    pub async fn start(&self, mac: &[u8; 6], requested: std::time::Duration, started_at: NaiveDateTime) -> anyhow::Result<i64> {
+        let id = sqlx::query(
+            "
+            INSERT INTO waterings (mac, requested_ms, started_at)
+            VALUES (?1, ?2, ?3)
+            ",
+        )
+        .bind(mac.as_ref())
+        .bind(i64::try_from(requested.as_millis())?)
+        .bind(started_at)
+        .execute(&*self.pool)
+        .await?
+        .last_insert_rowid();
+
+        Ok(id)
+    }
+
+    /// Record how long the actuator ran, or why it couldn't
+    pub async fn finish(&self, id: i64, watered: Option<std::time::Duration>, error: Option<&str>) -> anyhow::Result<u64> {
+        let res = sqlx::query("UPDATE waterings SET watered_ms = ?1, error = ?2 WHERE id = ?3")
+            .bind(watered.map(|watered| i64::try_from(watered.as_millis())).transpose()?)
+            .bind(error)
+            .bind(id)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    pub async fn mark_reported(&self, id: i64, reported_at: NaiveDateTime) -> anyhow::Result<u64> {
+        let res = sqlx::query("UPDATE waterings SET reported_at = ?1 WHERE id = ?2")
+            .bind(reported_at)
+            .bind(id)
+            .execute(&*self.pool)
+            .await?;
+
+        Ok(res.rows_affected())
+    }
+
+    /// Waterings that ran but the backend wasn't told about, oldest first
+    pub async fn unreported(&self) -> anyhow::Result<Vec<UnreportedWatering>> {
+        let rows: Vec<(i64, Vec<u8>, i64)> = sqlx::query_as(
+            "
+            SELECT id, mac, watered_ms
+            FROM waterings
+            WHERE watered_ms IS NOT NULL AND reported_at IS NULL
+            ORDER BY id
+            ",
+        )
         .fetch_all(&*self.pool)
         .await?;
 
-        Ok(rows
-            .iter()
-            .map(|x| x.to_measurement_serie_entry())
-            .collect())
+        rows.into_iter()
+            .map(|(id, mac, watered_ms)| {
+                Ok(UnreportedWatering { id, mac: mac.as_slice().try_into()?, watered: std::time::Duration::from_millis(watered_ms.try_into()?) })
+            })
+            .collect()
     }
 }
 
+/// A migrated in-memory database of its own, for the tests of every module
+#[cfg(test)]
+pub async fn test_pool() -> Arc<SqlitePool> {
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .expect("Failed to create pool");
+
+    sqlx::migrate!().run(&pool).await.expect("Failed to run migrations");
+
+    Arc::new(pool)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use chrono::{TimeZone, Utc};
     use edge_protocol::{Measurement, MeasurementSerieEntry};
-    use sqlx::{sqlite::SqlitePoolOptions, Executor};
+    use sqlx::Executor;
 
     #[tokio::test]
     async fn test_insert_and_find_by_mac() {
         // Create in-memory SQLite database
-        let pool = Arc::new(
-            SqlitePoolOptions::new()
-            .max_connections(1)
-            .connect("sqlite::memory:?cache=shared")
-            .await
-                .expect("Failed to create pool")
-        );
-
-        // Run migrations instead of creating the table directly
-        sqlx::migrate!()
-            .run(&*pool)
-            .await
-            .expect("Failed to run migrations");
+        let pool = test_pool().await;
 
         let repo = super::SqliteMeasurementRepository::new(pool.clone());
 
@@ -228,10 +1136,13 @@ mod tests {
         let entry = MeasurementSerieEntry {
             timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap().naive_utc(),
             measurement: Measurement {
-                battery: 30,
-                lux: 123.4,
-                temperature: 22.5,
-                humidity: 55.0,
+                battery: Some(30),
+                lux: Some(123.4),
+                temperature: Some(22.5),
+                humidity: Some(55.0),
+                soil_pf: None,
+                tank_pf: Some(410.5),
+                battery_mv: None,
             },
         };
 
@@ -259,23 +1170,33 @@ mod tests {
             entry.measurement.temperature
         );
         assert_eq!(found_entry.measurement.humidity, entry.measurement.humidity);
+        assert_eq!(found_entry.measurement.soil_pf, None);
+        assert_eq!(found_entry.measurement.tank_pf, entry.measurement.tank_pf);
+    }
+
+    #[test]
+    fn test_row_derives_battery_percentage() {
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let mut measurement = Measurement::EMPTY;
+        measurement.battery_mv = Some(4200);
+
+        let entry = MeasurementSerieEntry {
+            timestamp: Utc.timestamp_opt(1_700_000_000, 0).unwrap().naive_utc(),
+            measurement,
+        };
+
+        let row = MeasurementSerieEntryRow::from_measurement_serie_entry(&mac, &entry, 0);
+        assert_eq!(row.battery, Some(100));
+        assert_eq!(row.battery_mv, Some(4200));
+
+        let decoded = row.to_measurement_serie_entry();
+        assert_eq!(decoded.measurement.battery_mv, Some(4200));
+        assert_eq!(decoded.measurement.battery_percentage(), Some(100));
     }
 
     #[tokio::test]
     async fn test_find_by_mac_empty() {
-        let pool = Arc::new(
-            SqlitePoolOptions::new()
-                .max_connections(1)
-                .connect("sqlite::memory:?cache=shared")
-            .await
-                .expect("Failed to create pool")
-        );
-
-        // Run migrations instead of creating the table directly
-        sqlx::migrate!()
-            .run(&*pool)
-            .await
-            .expect("Failed to run migrations");
+        let pool = test_pool().await;
         let repo = super::SqliteMeasurementRepository::new(pool.clone());
 
         let mac = [0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f];
@@ -286,19 +1207,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_edge_state_get_none() {
-        let pool = Arc::new(
-            SqlitePoolOptions::new()
-                .max_connections(1)
-                .connect("sqlite::memory:?cache=shared")
-                .await
-                .expect("Failed to create pool")
-        );
-
-        // Run migrations to create the edge_state table
-        sqlx::migrate!()
-            .run(&*pool)
-            .await
-            .expect("Failed to run migrations");
+        let pool = test_pool().await;
 
         let repo = SqliteEdgeStateRepository::new(pool.clone());
 
@@ -309,18 +1218,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_edge_state_set_and_get_some() {
-        let pool = Arc::new(
-            SqlitePoolOptions::new()
-                .max_connections(1)
-                .connect("sqlite::memory:?cache=shared")
-                .await
-                .expect("Failed to create pool")
-        );
-
-        sqlx::migrate!()
-            .run(&*pool)
-            .await
-            .expect("Failed to run migrations");
+        let pool = test_pool().await;
 
         let repo = SqliteEdgeStateRepository::new(pool.clone());
 
@@ -348,18 +1246,7 @@ mod tests {
 
     #[tokio::test]
     async fn test_edge_state_set_overwrites() {
-        let pool = Arc::new(
-            SqlitePoolOptions::new()
-                .max_connections(1)
-                .connect("sqlite::memory:?cache=shared")
-                .await
-                .expect("Failed to create pool")
-        );
-
-        sqlx::migrate!()
-            .run(&*pool)
-            .await
-            .expect("Failed to run migrations");
+        let pool = test_pool().await;
 
         let repo = SqliteEdgeStateRepository::new(pool.clone());
 
@@ -397,4 +1284,351 @@ mod tests {
         assert_eq!(loaded.auth0_refresh_token, state2.auth0_refresh_token);
         assert_eq!(loaded.auth0_expires_at, state2.auth0_expires_at);
 }
+
+    #[tokio::test]
+    async fn test_station_key_counter_only_advances() {
+        let pool = test_pool().await;
+
+        let repo = SqliteStationKeyRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+        assert!(repo.find_verifier(&mac).await.unwrap().is_none());
+
+        repo.set_key(&mac, &[0x42; 32]).await.expect("Unable to set key");
+        assert_eq!(repo.find_verifier(&mac).await.unwrap().map(|v| v.counter()), Some(0));
+
+        assert!(repo.advance_counter(&mac, 10).await.unwrap());
+        assert!(!repo.advance_counter(&mac, 10).await.unwrap());
+        assert!(!repo.advance_counter(&mac, 5).await.unwrap());
+        assert_eq!(repo.find_verifier(&mac).await.unwrap().map(|v| v.counter()), Some(10));
+
+        // A new key starts counting again
+        repo.set_key(&mac, &[0x24; 32]).await.expect("Unable to set key");
+        assert_eq!(repo.find_verifier(&mac).await.unwrap().map(|v| v.counter()), Some(0));
+    }
+
+    #[tokio::test]
+    async fn test_downlink_counters_are_never_reused() {
+        let pool = test_pool().await;
+
+        let repo = SqliteStationKeyRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+        assert_eq!(repo.reserve_downlink_counters(&mac, 1, 1_700_000_000).await.unwrap(), None);
+
+        repo.set_key(&mac, &[0x42; 32]).await.expect("Unable to set key");
+        assert_eq!(repo.reserve_downlink_counters(&mac, 1, 1_700_000_000).await.unwrap(), Some(1_700_000_001));
+        assert_eq!(repo.reserve_downlink_counters(&mac, 3, 1_700_000_000).await.unwrap(), Some(1_700_000_002));
+
+        // A clock set back after a reboot doesn't hand out counters the station already accepted
+        assert_eq!(repo.reserve_downlink_counters(&mac, 1, 1_600_000_000).await.unwrap(), Some(1_700_000_005));
+
+        // The station remembers the counters of its previous key
+        repo.set_key(&mac, &[0x24; 32]).await.expect("Unable to set key");
+        assert_eq!(repo.reserve_downlink_counters(&mac, 1, 0).await.unwrap(), Some(1_700_000_006));
+    }
+
+    #[tokio::test]
+    async fn test_station_settings_set_and_find() {
+        let pool = test_pool().await;
+
+        let repo = SqliteStationSettingsRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+        assert!(repo.find(&mac).await.unwrap().is_none());
+
+        // An outdoor station samples less often and ignores small light changes
+        let config = PeripheralConfig {
+            sampling_period_secs: 300,
+            flush_size: 6,
+            max_deviation: Measurement { lux: Some(500.0), tank_pf: None, ..Measurement::MAX_DEVIATION },
+        };
+        repo.set(&mac, &config).await.expect("Unable to set settings");
+        assert_eq!(repo.find(&mac).await.unwrap(), Some(config));
+
+        let invalid = PeripheralConfig { flush_size: 0, ..config };
+        assert!(repo.set(&mac, &invalid).await.is_err());
+        assert_eq!(repo.find(&mac).await.unwrap(), Some(config));
+    }
+
+    #[tokio::test]
+    async fn test_station_commands_request_and_complete() {
+        let pool = test_pool().await;
+
+        let repo = SqliteStationCommandRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let other = [0x06, 0x05, 0x04, 0x03, 0x02, 0x01];
+
+        let identify = repo.request(&mac, Command::Identify).await.expect("Unable to request command");
+        let reboot = repo.request(&mac, Command::Reboot).await.expect("Unable to request command");
+        repo.request(&other, Command::FactoryReset).await.expect("Unable to request command");
+
+        let pending = repo.pending(&mac).await.unwrap();
+        assert_eq!(pending.iter().map(|r| r.id).collect::<Vec<_>>(), vec![identify, reboot]);
+        assert_eq!(pending[0].to_request().unwrap(), CommandRequest { command: Command::Identify, id: identify as u8 });
+        assert_eq!(pending[0].to_status().unwrap(), None);
+
+        assert_eq!(repo.complete(identify, CommandStatus::Accepted).await.unwrap(), 1);
+        // The first reply counts
+        assert_eq!(repo.complete(identify, CommandStatus::Rejected).await.unwrap(), 0);
+
+        let row = repo.find(identify).await.unwrap().expect("Command not found");
+        assert_eq!(row.to_status().unwrap(), Some(CommandStatus::Accepted));
+        assert!(row.completed_at.is_some());
+        assert_eq!(repo.pending(&mac).await.unwrap().iter().map(|r| r.id).collect::<Vec<_>>(), vec![reboot]);
+    }
+
+    #[tokio::test]
+    async fn test_station_device_set_and_find() {
+        let pool = test_pool().await;
+
+        let repo = SqliteStationDeviceRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+        assert!(repo.find(&mac).await.unwrap().is_none());
+
+        let device = DeviceInformation { model: "Mycelium".to_string(), firmware_version: "v0.1.0-3-gabcdef".to_string(), hardware_revision: "1".to_string() };
+        repo.set(&mac, &device).await.expect("Unable to set device information");
+        assert_eq!(repo.find(&mac).await.unwrap(), Some(device.clone()));
+
+        // An update replaces the firmware version
+        let updated = DeviceInformation { firmware_version: "v0.2.0".to_string(), ..device };
+        repo.set(&mac, &updated).await.expect("Unable to set device information");
+        assert_eq!(repo.find(&mac).await.unwrap(), Some(updated));
+    }
+
+    #[tokio::test]
+    async fn test_station_backend_id() {
+        let pool = test_pool().await;
+
+        let repo = SqliteStationRepository::new(pool);
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let id = uuid::Uuid::from_u128(0x0b9c4e4e_2f6a_4a43_9a53_8f0c3f0a6b11);
+
+        assert_eq!(repo.find_backend_id(&mac).await.unwrap(), None);
+
+        repo.set_backend_id(&mac, &id).await.expect("Unable to set backend id");
+        assert_eq!(repo.find_backend_id(&mac).await.unwrap(), Some(id));
+
+        // A re-created station replaces the deleted one
+        let recreated = uuid::Uuid::from_u128(1);
+        repo.set_backend_id(&mac, &recreated).await.expect("Unable to set backend id");
+        assert_eq!(repo.find_backend_id(&mac).await.unwrap(), Some(recreated));
+
+        assert_eq!(repo.remove(&mac).await.unwrap(), 1);
+        assert_eq!(repo.find_backend_id(&mac).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_syncs_are_queued_in_outbox_until_uploaded() {
+        let pool = test_pool().await;
+
+        let syncs = SqliteSyncRepository::new(pool.clone());
+        let outbox = SqliteOutboxRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let synced_at = Utc.timestamp_opt(1_700_000_600, 0).unwrap().naive_utc();
+
+        // Every channel is kept, soil_pf included
+        let entries: Vec<_> = (0..2)
+            .map(|i| MeasurementSerieEntry {
+                timestamp: Utc.timestamp_opt(1_700_000_000 + i * 60, 0).unwrap().naive_utc(),
+                measurement: Measurement {
+                    battery: Some(100),
+                    lux: Some(123.5),
+                    temperature: Some(22.5),
+                    humidity: Some(55.0),
+                    soil_pf: Some(250.0 + i as f32),
+                    tank_pf: Some(410.5),
+                    battery_mv: Some(4200),
+                },
+            })
+            .collect();
+
+        let result = PeripheralSyncResult {
+            address: mac,
+            time_drift: chrono::TimeDelta::milliseconds(-1500),
+            measurements: entries.clone(),
+            decode_failures: 1,
+            rssi: Some(-72),
+            sync_id: None,
+        };
+        let id = syncs.insert(&result, synced_at).await.expect("Unable to store sync");
+        let empty = PeripheralSyncResult { measurements: vec![], rssi: None, ..result };
+        let empty_id = syncs.insert(&empty, synced_at).await.expect("Unable to store sync");
+
+        let sync: (i64, Option<i64>, Option<NaiveDateTime>) = sqlx::query_as("SELECT time_drift_ms, rssi, uploaded_at FROM syncs WHERE id = ?")
+            .bind(id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(sync, (-1500, Some(-72), None));
+
+        // Nothing to upload for a sync without measurements
+        let uploaded: Option<NaiveDateTime> = sqlx::query_scalar("SELECT uploaded_at FROM syncs WHERE id = ?")
+            .bind(empty_id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(uploaded, Some(synced_at));
+
+        let due = outbox.due(synced_at, 100).await.unwrap();
+        assert_eq!(due.iter().map(|e| e.entry.clone()).collect::<Vec<_>>(), entries);
+        assert!(due.iter().all(|e| e.mac == mac && e.attempts == 0));
+        assert!(outbox.due(synced_at - chrono::TimeDelta::seconds(1), 100).await.unwrap().is_empty());
+
+        // A failure postpones only the measurements it concerns
+        let retry_at = synced_at + chrono::TimeDelta::seconds(30);
+        outbox.mark_failed(&[due[0].measurement_id], "503 Service Unavailable", retry_at).await.unwrap();
+        assert_eq!(outbox.due(synced_at, 100).await.unwrap(), vec![due[1].clone()]);
+        assert_eq!(outbox.next_attempt_at().await.unwrap(), Some(synced_at));
+
+        outbox.mark_uploaded(&[due[1].measurement_id], synced_at).await.unwrap();
+        assert_eq!(outbox.next_attempt_at().await.unwrap(), Some(retry_at));
+        assert_eq!(outbox.due(retry_at, 100).await.unwrap(), vec![OutboxEntry { attempts: 1, ..due[0].clone() }]);
+
+        outbox.mark_uploaded(&[due[0].measurement_id], retry_at).await.unwrap();
+        assert_eq!(outbox.next_attempt_at().await.unwrap(), None);
+
+        let uploaded: Option<NaiveDateTime> = sqlx::query_scalar("SELECT uploaded_at FROM syncs WHERE id = ?")
+            .bind(id)
+            .fetch_one(&*pool)
+            .await
+            .unwrap();
+        assert_eq!(uploaded, Some(retry_at));
+
+        // The measurements stay around for the station history
+        assert_eq!(SqliteMeasurementRepository::new(pool).find_by_mac(&mac).await.unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_sync_stored_page_by_page() {
+        let pool = test_pool().await;
+
+        let syncs = SqliteSyncRepository::new(pool.clone());
+        let outbox = SqliteOutboxRepository::new(pool.clone());
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let synced_at = Utc.timestamp_opt(1_700_000_600, 0).unwrap().naive_utc();
+        let entries: Vec<_> = (0..3)
+            .map(|i| MeasurementSerieEntry {
+                timestamp: Utc.timestamp_opt(1_700_000_000 + i * 60, 0).unwrap().naive_utc(),
+                measurement: Measurement { temperature: Some(20.0 + i as f32), ..Measurement::EMPTY },
+            })
+            .collect();
+
+        let id = syncs.begin(&mac, synced_at, chrono::TimeDelta::milliseconds(800)).await.unwrap();
+        assert_eq!(syncs.append(id, &mac, &entries[..2], synced_at).await.unwrap(), 2);
+
+        // A page is queued for upload as soon as it is stored, before the transfer is done
+        let due = outbox.due(synced_at, 100).await.unwrap();
+        assert_eq!(due.iter().map(|e| e.entry).collect::<Vec<_>>(), entries[..2]);
+        outbox.mark_uploaded(&[due[0].measurement_id, due[1].measurement_id], synced_at).await.unwrap();
+
+        // The next page reopens the sync the uploader completed meanwhile
+        syncs.append(id, &mac, &entries[2..], synced_at).await.unwrap();
+        let result = PeripheralSyncResult {
+            address: mac,
+            time_drift: chrono::TimeDelta::milliseconds(800),
+            measurements: entries.clone(),
+            decode_failures: 1,
+            rssi: Some(-60),
+            sync_id: Some(id),
+        };
+        syncs.finish(id, &result, synced_at).await.unwrap();
+
+        let sync = syncs.find_by_mac(&mac, 1).await.unwrap().remove(0);
+        assert_eq!((sync.id, sync.time_drift_ms, sync.decode_failures, sync.rssi, sync.uploaded_at), (id, 800, 1, Some(-60), None));
+        assert_eq!(outbox.due(synced_at, 100).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_measurement_history() {
+        let pool = test_pool().await;
+
+        let repo = SqliteMeasurementRepository::new(pool);
+        let mac = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+        let at = |minutes: i64| Utc.timestamp_opt(1_700_000_000 + minutes * 60, 0).unwrap().naive_utc();
+        let entries: Vec<_> = (0..5)
+            .map(|i| MeasurementSerieEntry {
+                timestamp: at(i),
+                measurement: Measurement {
+                    battery: None,
+                    lux: None,
+                    temperature: Some(20.0 + i as f32),
+                    humidity: None,
+                    soil_pf: None,
+                    tank_pf: None,
+                    battery_mv: None,
+                },
+            })
+            .collect();
+        repo.insert(&mac, entries.clone()).await.expect("Unable to insert");
+        repo.insert(&[0xff; 6], entries.clone()).await.expect("Unable to insert");
+
+        assert_eq!(repo.history(&mac, None, None, 100).await.unwrap(), entries);
+        assert_eq!(repo.history(&mac, Some(at(1)), Some(at(3)), 100).await.unwrap(), entries[1..3].to_vec());
+
+        // The limit keeps the latest, still oldest first
+        assert_eq!(repo.history(&mac, None, None, 2).await.unwrap(), entries[3..].to_vec());
+        assert!(repo.history(&[0xaa; 6], None, None, 100).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_station_summaries() {
+        let pool = test_pool().await;
+
+        let syncs = SqliteSyncRepository::new(pool.clone());
+        let outbox = SqliteOutboxRepository::new(pool.clone());
+        let at = |minutes: i64| Utc.timestamp_opt(1_700_000_000 + minutes * 60, 0).unwrap().naive_utc();
+        let result = |address: [u8; 6], minutes: &[i64], rssi: i16| PeripheralSyncResult {
+            address,
+            time_drift: chrono::TimeDelta::milliseconds(i64::from(rssi) * 10),
+            measurements: minutes
+                .iter()
+                .map(|m| MeasurementSerieEntry {
+                    timestamp: at(*m),
+                    measurement: Measurement {
+                        battery: None,
+                        lux: None,
+                        temperature: Some(20.0),
+                        humidity: None,
+                        soil_pf: None,
+                        tank_pf: None,
+                        battery_mv: None,
+                    },
+                })
+                .collect(),
+            decode_failures: 0,
+            rssi: Some(rssi),
+            sync_id: None,
+        };
+
+        assert!(syncs.summaries(None).await.unwrap().is_empty());
+        assert_eq!(outbox.backlog().await.unwrap(), (0, None));
+
+        syncs.insert(&result([0xbb; 6], &[0, 1], -80), at(2)).await.unwrap();
+        syncs.insert(&result([0xaa; 6], &[3], -70), at(4)).await.unwrap();
+        syncs.insert(&result([0xbb; 6], &[5], -60), at(6)).await.unwrap();
+        let uploaded: Vec<i64> = outbox.due(at(2), 100).await.unwrap().iter().map(|e| e.measurement_id).collect();
+        outbox.mark_uploaded(&uploaded, at(7)).await.unwrap();
+        SqliteStationDeviceRepository::new(pool.clone())
+            .set(&[0xbb; 6], &DeviceInformation { model: "m".to_string(), firmware_version: "v1.2.0".to_string(), hardware_revision: "r".to_string() })
+            .await
+            .unwrap();
+
+        let summaries = syncs.summaries(None).await.unwrap();
+        assert_eq!(summaries.iter().map(|s| s.mac.clone()).collect::<Vec<_>>(), vec![vec![0xaa; 6], vec![0xbb; 6]]);
+
+        let bb = &summaries[1];
+        assert_eq!((bb.last_seen, bb.rssi, bb.time_drift_ms, bb.syncs), (at(6), Some(-60), -600, 2));
+        assert_eq!((bb.last_uploaded_at, bb.pending_uploads), (Some(at(7)), 1));
+        assert_eq!(bb.firmware_version.as_deref(), Some("v1.2.0"));
+        assert_eq!(summaries[0].firmware_version, None);
+
+        assert_eq!(syncs.summaries(Some(&[0xaa; 6])).await.unwrap().len(), 1);
+        assert!(syncs.summaries(Some(&[0xcc; 6])).await.unwrap().is_empty());
+
+        let latest = syncs.find_by_mac(&[0xbb; 6], 1).await.unwrap();
+        assert_eq!((latest.len(), latest[0].synced_at, latest[0].uploaded_at), (1, at(6), None));
+        assert_eq!(outbox.backlog().await.unwrap(), (2, Some(at(3))));
+    }
 }
@@ -8,3 +8,11 @@ pub struct EdgeState {
     pub auth0_refresh_token: String,
     pub auth0_expires_at: NaiveDateTime,
 }
+
+/// Contents of the device information service of a station
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DeviceInformation {
+    pub model: String,
+    pub firmware_version: String,
+    pub hardware_revision: String,
+}
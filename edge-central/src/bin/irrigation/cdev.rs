@@ -0,0 +1,25 @@
+use linux_embedded_hal::gpio_cdev::{Chip, LineHandle, LineRequestFlags};
+
+use crate::irrigation::Gpio;
+
+/// A GPIO line requested through the character device
+pub struct CdevGpio {
+    handle: LineHandle,
+}
+
+impl CdevGpio {
+    /// Request the line as output, driven at `initial` right away so the actuator starts switched off
+    pub fn new(chip: &str, line: u32, initial: bool) -> anyhow::Result<Self> {
+        let mut chip = Chip::new(chip)?;
+        let handle = chip.get_line(line)?.request(LineRequestFlags::OUTPUT, initial.into(), "mycelium-irrigation")?;
+
+        Ok(Self { handle })
+    }
+}
+
+impl Gpio for CdevGpio {
+    fn write(&mut self, high: bool) -> anyhow::Result<()> {
+        self.handle.set_value(high.into())?;
+        Ok(())
+    }
+}
@@ -0,0 +1,42 @@
+use std::sync::{Arc, Mutex};
+
+use crate::irrigation::Gpio;
+
+/// Records the levels written to a line, clones share the record
+#[derive(Clone, Default)]
+pub struct MockGpio {
+    levels: Arc<Mutex<Vec<bool>>>,
+    failing: bool,
+    off_failures: usize,
+}
+
+impl MockGpio {
+    /// A line that can't be driven high
+    pub fn failing() -> Self {
+        Self { failing: true, ..Self::default() }
+    }
+
+    /// A line that can't be driven low the first `times` it is asked to
+    pub fn failing_off(times: usize) -> Self {
+        Self { off_failures: times, ..Self::default() }
+    }
+
+    pub fn levels(&self) -> Vec<bool> {
+        self.levels.lock().unwrap().clone()
+    }
+}
+
+impl Gpio for MockGpio {
+    fn write(&mut self, high: bool) -> anyhow::Result<()> {
+        if self.failing && high {
+            anyhow::bail!("Line is busy");
+        }
+        if !high && self.off_failures > 0 {
+            self.off_failures -= 1;
+            anyhow::bail!("Line is busy");
+        }
+
+        self.levels.lock().unwrap().push(high);
+        Ok(())
+    }
+}
@@ -0,0 +1,385 @@
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub mod cdev;
+#[cfg(test)]
+pub mod mock;
+#[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+pub mod noop;
+
+use std::{collections::HashMap, time::Duration};
+
+use anyhow::{anyhow, Result};
+use chrono::Utc;
+use edge_client_backend::{apis::configuration::Configuration, models::Watering};
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+use crate::ble::{format_mac, parse_mac};
+use crate::cfg::ActuatorConfig;
+use crate::data::sqlite::{SqliteStationRepository, SqliteWateringRepository};
+
+/// An output line of a GPIO chip
+pub trait Gpio: Send {
+    fn write(&mut self, high: bool) -> Result<()>;
+}
+
+pub fn make_gpio(config: &ActuatorConfig) -> Result<Box<dyn Gpio>> {
+    {
+        #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
+        {
+            anyhow::Ok(Box::new(noop::NoopGpio::new(config.line)))
+        }
+
+        #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+        {
+            // Requested at the inactive level, the actuator stays off until a watering
+            let gpio = cdev::CdevGpio::new(&config.chip, config.line, config.active_low)?;
+            Ok(Box::new(gpio))
+        }
+    }
+}
+
+/// The first wait before switching an actuator off again, doubled after every failure
+const SWITCH_OFF_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_SWITCH_OFF_BACKOFF: Duration = Duration::from_secs(10);
+
+/// A relay or pump and how long it may run
+pub struct Actuator {
+    gpio: Box<dyn Gpio>,
+    active_low: bool,
+    max: Duration,
+}
+
+impl Actuator {
+    pub fn new(gpio: Box<dyn Gpio>, active_low: bool, max: Duration) -> Self {
+        Self { gpio, active_low, max }
+    }
+
+    fn switch(&mut self, on: bool) -> Result<()> {
+        self.gpio.write(on != self.active_low)
+    }
+
+    /// Run for `duration`, when switching on fails the actuator is switched off again.
+    /// Once it ran, switching off is retried until it succeeds: a stuck actuator keeps watering
+    async fn run(&mut self, duration: Duration) -> Result<()> {
+        if let Err(err) = self.switch(true) {
+            if let Err(off) = self.switch(false) {
+                tracing::error!("Failed to switch the actuator off {}", off);
+            }
+            return Err(err);
+        }
+
+        tokio::time::sleep(duration).await;
+
+        let mut backoff = SWITCH_OFF_BACKOFF;
+        while let Err(err) = self.switch(false) {
+            tracing::error!(?backoff, "Failed to switch the actuator off, retrying {}", err);
+            tokio::time::sleep(backoff).await;
+            backoff = (backoff * 2).min(MAX_SWITCH_OFF_BACKOFF);
+        }
+        Ok(())
+    }
+}
+
+/// The actuators of the configuration, keyed by the MAC of their station
+pub fn make_actuators(configs: &HashMap<String, ActuatorConfig>) -> Result<HashMap<[u8; 6], Actuator>> {
+    configs
+        .iter()
+        .map(|(mac, config)| {
+            let actuator = Actuator::new(make_gpio(config)?, config.active_low, Duration::from_secs(config.max_secs));
+            Ok((parse_mac(mac)?, actuator))
+        })
+        .collect()
+}
+
+/// Parse a duration the way the backend writes them, like `5 seconds` or `1.5 minutes`
+pub fn parse_duration(duration: &str) -> Result<Duration> {
+    let invalid = || anyhow!("Invalid duration {}", duration);
+    let trimmed = duration.trim();
+    let (number, unit) = trimmed.split_at(trimmed.find(|c: char| !(c.is_ascii_digit() || c == '.')).unwrap_or(trimmed.len()));
+
+    let nanos: u64 = match unit.trim() {
+        "d" | "day" | "days" => 86_400_000_000_000,
+        "h" | "hr" | "hrs" | "hour" | "hours" => 3_600_000_000_000,
+        "m" | "min" | "mins" | "minute" | "minutes" => 60_000_000_000,
+        "s" | "sec" | "secs" | "second" | "seconds" => 1_000_000_000,
+        "ms" | "milli" | "millis" | "millisecond" | "milliseconds" => 1_000_000,
+        "µs" | "micro" | "micros" | "microsecond" | "microseconds" => 1_000,
+        "ns" | "nano" | "nanos" | "nanosecond" | "nanoseconds" => 1,
+        _ => return Err(invalid()),
+    };
+
+    match number.parse::<u64>() {
+        Ok(whole) => whole.checked_mul(nanos).map(Duration::from_nanos),
+        Err(_) => number.parse::<f64>().ok().and_then(|n| Duration::try_from_secs_f64(n * nanos as f64 / 1e9).ok()),
+    }
+    .ok_or_else(invalid)
+}
+
+/// Format a duration the way the backend reads them
+pub fn format_duration(duration: Duration) -> String {
+    if duration.subsec_nanos() == 0 {
+        format!("{} seconds", duration.as_secs())
+    } else {
+        format!("{} milliseconds", duration.as_millis())
+    }
+}
+
+/// Waters the stations as the backend decides, one watering at a time per actuator
+pub struct Irrigation {
+    configuration: Configuration,
+    waterings: SqliteWateringRepository,
+    actuators: HashMap<[u8; 6], Mutex<Actuator>>,
+}
+
+impl Irrigation {
+    pub fn new(configuration: Configuration, waterings: SqliteWateringRepository, actuators: HashMap<[u8; 6], Actuator>) -> Self {
+        let actuators = actuators.into_iter().map(|(mac, actuator)| (mac, Mutex::new(actuator))).collect();
+        Self { configuration, waterings, actuators }
+    }
+
+    /// Water the station for `requested`, capped to the maximum of its actuator, and report it to the backend.
+    /// Stations without an actuator are left alone, as are stations already watering: every checkin
+    /// during a watering asks for one again, running them all back to back would flood the station
+    pub async fn water(&self, mac: &[u8; 6], station_id: Uuid, requested: Duration) -> Result<()> {
+        let Some(actuator) = self.actuators.get(mac) else {
+            tracing::info!(mac = format_mac(mac), ?requested, "No actuator for the station, watering skipped");
+            return Ok(());
+        };
+
+        let Ok(mut actuator) = actuator.try_lock() else {
+            tracing::info!(mac = format_mac(mac), ?requested, "Station already watering, watering skipped");
+            return Ok(());
+        };
+        let duration = requested.min(actuator.max);
+        let id = self.waterings.start(mac, requested, Utc::now().naive_utc()).await?;
+
+        tracing::info!(mac = format_mac(mac), ?duration, "Watering");
+        if let Err(err) = actuator.run(duration).await {
+            self.waterings.finish(id, None, Some(&err.to_string())).await?;
+            return Err(err);
+        }
+        self.waterings.finish(id, Some(duration), None).await?;
+
+        self.report(id, station_id, duration).await
+    }
+
+    /// Report the waterings the backend wasn't told about, because the report failed or the process
+    /// stopped before it. Returns the number of waterings reported
+    pub async fn report_unreported(&self, stations: &SqliteStationRepository) -> Result<usize> {
+        let mut reported = 0;
+
+        for watering in self.waterings.unreported().await? {
+            // A running watering reports itself, the actuator stays locked until it did
+            let _actuator = match self.actuators.get(&watering.mac).map(Mutex::try_lock) {
+                Some(Err(_)) => continue,
+                actuator => actuator,
+            };
+
+            let Some(station_id) = stations.find_backend_id(&watering.mac).await? else {
+                continue;
+            };
+
+            self.report(watering.id, station_id, watering.watered).await?;
+            reported += 1;
+        }
+
+        Ok(reported)
+    }
+
+    async fn report(&self, id: i64, station_id: Uuid, watered: Duration) -> Result<()> {
+        let watering = Watering { watering: Some(format_duration(watered)) };
+        edge_client_backend::apis::default_api::watered_at_station(&self.configuration, station_id.to_string().as_str(), Some(watering)).await?;
+        self.waterings.mark_reported(id, Utc::now().naive_utc()).await?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use chrono::NaiveDateTime;
+    use sqlx::SqlitePool;
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use crate::data::sqlite::test_pool;
+    use crate::irrigation::mock::MockGpio;
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+    const STATION_ID: &str = "0b9c4e4e-2f6a-4a43-9a53-8f0c3f0a6b11";
+
+    async fn irrigation(server: &MockServer, actuators: HashMap<[u8; 6], Actuator>) -> (Irrigation, Arc<SqlitePool>) {
+        let pool = test_pool().await;
+
+        Mock::given(method("POST"))
+            .and(path(format!("/stations/{}/watered", STATION_ID)))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(server)
+            .await;
+
+        let configuration = Configuration { base_path: server.uri(), ..Configuration::default() };
+        (Irrigation::new(configuration, SqliteWateringRepository::new(pool.clone()), actuators), pool)
+    }
+
+    /// The durations reported to the backend
+    async fn reported(server: &MockServer) -> Vec<Option<String>> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .map(|request| request.body_json::<Watering>().unwrap().watering)
+            .collect()
+    }
+
+    async fn waterings(pool: &SqlitePool) -> Vec<(i64, Option<i64>, Option<String>, Option<NaiveDateTime>)> {
+        sqlx::query_as("SELECT requested_ms, watered_ms, error, reported_at FROM waterings ORDER BY id")
+            .fetch_all(pool)
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("5 seconds").unwrap(), Duration::from_secs(5));
+        assert_eq!(parse_duration("1 minute").unwrap(), Duration::from_secs(60));
+        assert_eq!(parse_duration("1.5 minutes").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_duration("500 milliseconds").unwrap(), Duration::from_millis(500));
+        assert_eq!(parse_duration("2h").unwrap(), Duration::from_secs(7200));
+        assert_eq!(parse_duration(" 30 s ").unwrap(), Duration::from_secs(30));
+
+        assert!(parse_duration("Inf").is_err());
+        assert!(parse_duration("-5 seconds").is_err());
+        assert!(parse_duration("5 fortnights").is_err());
+        assert!(parse_duration("seconds").is_err());
+    }
+
+    #[test]
+    fn test_format_duration_round_trips() {
+        for duration in [Duration::from_secs(90), Duration::from_millis(1500), Duration::ZERO] {
+            assert_eq!(parse_duration(&format_duration(duration)).unwrap(), duration);
+        }
+        assert_eq!(format_duration(Duration::from_secs(30)), "30 seconds");
+    }
+
+    #[tokio::test]
+    async fn test_waters_and_reports() {
+        let server = MockServer::start().await;
+        let gpio = MockGpio::default();
+        let actuator = Actuator::new(Box::new(gpio.clone()), true, Duration::from_secs(60));
+        let (irrigation, pool) = irrigation(&server, HashMap::from([(MAC, actuator)])).await;
+
+        let started = std::time::Instant::now();
+        irrigation.water(&MAC, Uuid::parse_str(STATION_ID).unwrap(), Duration::from_millis(20)).await.unwrap();
+
+        // Active low, the line is pulled low while watering
+        assert!(started.elapsed() >= Duration::from_millis(20));
+        assert_eq!(gpio.levels(), vec![false, true]);
+        assert_eq!(reported(&server).await, vec![Some("20 milliseconds".to_string())]);
+
+        let waterings = waterings(&pool).await;
+        assert_eq!(waterings.len(), 1);
+        assert_eq!((waterings[0].0, waterings[0].1, waterings[0].2.as_deref()), (20, Some(20), None));
+        assert!(waterings[0].3.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_watering_capped_to_actuator_max() {
+        let server = MockServer::start().await;
+        let gpio = MockGpio::default();
+        let actuator = Actuator::new(Box::new(gpio.clone()), false, Duration::from_millis(10));
+        let (irrigation, pool) = irrigation(&server, HashMap::from([(MAC, actuator)])).await;
+
+        irrigation.water(&MAC, Uuid::parse_str(STATION_ID).unwrap(), Duration::from_secs(600)).await.unwrap();
+
+        assert_eq!(gpio.levels(), vec![true, false]);
+        assert_eq!(reported(&server).await, vec![Some("10 milliseconds".to_string())]);
+        assert_eq!((waterings(&pool).await[0].0, waterings(&pool).await[0].1), (600_000, Some(10)));
+    }
+
+    #[tokio::test]
+    async fn test_failed_actuator_is_logged_not_reported() {
+        let server = MockServer::start().await;
+        let gpio = MockGpio::failing();
+        let actuator = Actuator::new(Box::new(gpio.clone()), false, Duration::from_secs(60));
+        let (irrigation, pool) = irrigation(&server, HashMap::from([(MAC, actuator)])).await;
+
+        assert!(irrigation.water(&MAC, Uuid::parse_str(STATION_ID).unwrap(), Duration::from_millis(20)).await.is_err());
+
+        // Switched off again after the failure
+        assert_eq!(gpio.levels(), vec![false]);
+        assert!(reported(&server).await.is_empty());
+        assert_eq!(waterings(&pool).await, vec![(20, None, Some("Line is busy".to_string()), None)]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_switch_off_is_retried() {
+        let server = MockServer::start().await;
+        let gpio = MockGpio::failing_off(1);
+        let actuator = Actuator::new(Box::new(gpio.clone()), false, Duration::from_secs(60));
+        let (irrigation, pool) = irrigation(&server, HashMap::from([(MAC, actuator)])).await;
+
+        let started = std::time::Instant::now();
+        irrigation.water(&MAC, Uuid::parse_str(STATION_ID).unwrap(), Duration::from_millis(20)).await.unwrap();
+
+        // Switched off after waiting out the backoff, the watering itself went fine
+        assert!(started.elapsed() >= Duration::from_millis(20) + SWITCH_OFF_BACKOFF);
+        assert_eq!(gpio.levels(), vec![true, false]);
+        assert_eq!(reported(&server).await, vec![Some("20 milliseconds".to_string())]);
+        assert_eq!((waterings(&pool).await[0].1, waterings(&pool).await[0].2.as_deref()), (Some(20), None));
+    }
+
+    #[tokio::test]
+    async fn test_failed_report_is_retried() {
+        let server = MockServer::start().await;
+        Mock::given(method("POST")).respond_with(ResponseTemplate::new(503)).up_to_n_times(1).mount(&server).await;
+        let actuator = Actuator::new(Box::new(MockGpio::default()), false, Duration::from_secs(60));
+        let (irrigation, pool) = irrigation(&server, HashMap::from([(MAC, actuator)])).await;
+        let stations = SqliteStationRepository::new(pool.clone());
+
+        assert!(irrigation.water(&MAC, Uuid::parse_str(STATION_ID).unwrap(), Duration::from_millis(20)).await.is_err());
+        assert!(waterings(&pool).await[0].3.is_none());
+
+        // Not reported until the station has an id on the backend
+        assert_eq!(irrigation.report_unreported(&stations).await.unwrap(), 0);
+        stations.set_backend_id(&MAC, &Uuid::parse_str(STATION_ID).unwrap()).await.unwrap();
+        assert_eq!(irrigation.report_unreported(&stations).await.unwrap(), 1);
+
+        assert_eq!(reported(&server).await, vec![Some("20 milliseconds".to_string()); 2]);
+        assert!(waterings(&pool).await[0].3.is_some());
+        assert_eq!(irrigation.report_unreported(&stations).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_watering_requested_while_watering_is_skipped() {
+        let server = MockServer::start().await;
+        let gpio = MockGpio::default();
+        let actuator = Actuator::new(Box::new(gpio.clone()), false, Duration::from_secs(60));
+        let (irrigation, pool) = irrigation(&server, HashMap::from([(MAC, actuator)])).await;
+        let station_id = Uuid::parse_str(STATION_ID).unwrap();
+
+        let (first, second) = tokio::join!(
+            irrigation.water(&MAC, station_id, Duration::from_millis(50)),
+            irrigation.water(&MAC, station_id, Duration::from_millis(50)),
+        );
+        first.unwrap();
+        second.unwrap();
+
+        assert_eq!(gpio.levels(), vec![true, false]);
+        assert_eq!(waterings(&pool).await.len(), 1);
+        assert_eq!(reported(&server).await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_station_without_actuator_is_skipped() {
+        let server = MockServer::start().await;
+        let (irrigation, pool) = irrigation(&server, HashMap::new()).await;
+
+        irrigation.water(&MAC, Uuid::parse_str(STATION_ID).unwrap(), Duration::from_secs(5)).await.unwrap();
+
+        assert!(reported(&server).await.is_empty());
+        assert!(waterings(&pool).await.is_empty());
+    }
+}
@@ -0,0 +1,19 @@
+use crate::irrigation::Gpio;
+
+/// Stands in for the GPIO lines where there are none, logging what would be switched
+pub struct NoopGpio {
+    line: u32,
+}
+
+impl NoopGpio {
+    pub fn new(line: u32) -> Self {
+        Self { line }
+    }
+}
+
+impl Gpio for NoopGpio {
+    fn write(&mut self, high: bool) -> anyhow::Result<()> {
+        tracing::info!(line = self.line, high, "No GPIO, line not switched");
+        Ok(())
+    }
+}
@@ -1,29 +1,41 @@
+pub mod api;
 pub mod ble;
 pub mod cfg;
+pub mod cli;
 pub mod data;
+pub mod irrigation;
 pub mod auth;
 pub mod measurements;
+pub mod mqtt;
 pub mod onboarding;
 pub mod status;
+pub mod upload;
 
 use aliri_reqwest::AccessTokenMiddleware;
 use aliri_tokens::{backoff, jitter, sources::{self, oauth2::dto::RefreshTokenCredentialsSource}, ClientId, RefreshToken, TokenLifetimeConfig, TokenWatcher};
 use anyhow::*;
 use dotenv::dotenv;
-use edge_client_backend::{apis::configuration::{Configuration}, models::{StationInsert, StationMeasurement}};
+use edge_protocol::battery::LOW_BATTERY_PERCENTAGE;
+use edge_client_backend::apis::configuration::Configuration;
 use futures::{stream, StreamExt};
 use reqwest::{Client, Request, Url};
 use reqwest_middleware::ClientBuilder;
 use reqwest_tracing::TracingMiddleware;
 use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool};
 use std::{str::FromStr, sync::Arc};
-use crate::measurements::types::PeripheralSyncResult;
-use crate::data::sqlite::SqliteEdgeStateRepository;
+use crate::measurements::types::{PeripheralSyncResult, SyncContext};
+use crate::data::sqlite::{SqliteEdgeStateRepository, SqliteStationCommandRepository, SqliteStationDeviceRepository, SqliteStationKeyRepository, SqliteStationSettingsRepository, SqliteSyncRepository, SqliteOutboxRepository, SqliteStationRepository, SqliteWateringRepository};
 use crate::cfg::AppConfig;
 use crate::status::StatusSummary;
+use crate::ble::format_mac;
+use crate::cli::Cli;
 use crate::measurements::make_peripheral_sync_stream_provider;
 use crate::onboarding::make_onboarding;
 use crate::status::make_status;
+use crate::upload::{Backoff, Uploader};
+use crate::irrigation::{make_actuators, Irrigation};
+use crate::mqtt::{rumqtt::RumqttPublisher, MqttSink};
+use crate::data::types::EdgeState;
 
 #[tokio::main]
 async fn main() {
@@ -55,6 +67,34 @@ async fn work() -> anyhow::Result<()> {
 
     sqlx::migrate!().run(&*pool).await?;
 
+    let station_commands = Arc::new(SqliteStationCommandRepository::new(pool.clone()));
+    let station_devices = Arc::new(SqliteStationDeviceRepository::new(pool.clone()));
+
+    let station_keys = Arc::new(SqliteStationKeyRepository::new(pool.clone()));
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match Cli::parse(&args)? {
+        Cli::Run => {}
+        Cli::Command { mac, command } => {
+            let id = station_commands.request(&mac, command).await?;
+            tracing::info!(id, ?command, mac = %format_mac(&mac), "Queued command until the station syncs");
+            return Ok(());
+        }
+        Cli::Key { mac, key } => {
+            cli::provision_key(&station_keys, &mac, &key).await?;
+            tracing::info!(mac = %format_mac(&mac), "Provisioned the key of the station");
+            return Ok(());
+        }
+    }
+
+    let fleet_key = match &app_config.station_auth.fleet_key {
+        Some(key) => Some(edge_protocol::auth::parse_key(key).map_err(|err| anyhow!("Invalid APP.STATION_AUTH.FLEET_KEY: {}", err))?),
+        None => None,
+    };
+    if fleet_key.is_none() && !app_config.station_auth.required {
+        tracing::warn!("Neither APP.STATION_AUTH.FLEET_KEY nor APP.STATION_AUTH.REQUIRED is set, frames of stations without a key are accepted unverified");
+    }
+
     let edge_state_repo = SqliteEdgeStateRepository::new(pool.clone());
     let _edge_state = match edge_state_repo.get_state().await? {
         Some(state) => state,
@@ -66,10 +106,92 @@ async fn work() -> anyhow::Result<()> {
         }
     };
 
+    let syncs = Arc::new(SqliteSyncRepository::new(pool.clone()));
+    let provider = make_peripheral_sync_stream_provider(
+        &app_config.peripheral_sync_mode,
+        SyncContext {
+            station_keys,
+            station_settings: Arc::new(SqliteStationSettingsRepository::new(pool.clone())),
+            station_commands,
+            station_devices: station_devices.clone(),
+            syncs: syncs.clone(),
+            fleet_key,
+            require_authentication: app_config.station_auth.required,
+        },
+    ).await?;
+    let stream = provider.stream().flat_map(stream::iter);
+
+    let api = api::ApiState::new(pool.clone());
+    let api_addr = app_config.api_addr.clone();
+    tokio::spawn(async move {
+        if let Err(err) = api::serve(&api_addr, api).await {
+            tracing::error!("Local API stopped {}", err);
+        }
+    });
+
+    // Measurements left over from before a crash or an outage are backfilled first
+    let uploads = if app_config.backend_upload {
+        let configuration = make_configuration(&app_config, &_edge_state).await?;
+        let irrigation = Irrigation::new(configuration.clone(), SqliteWateringRepository::new(pool.clone()), make_actuators(&app_config.actuators)?);
+        let uploader = Uploader::new(
+            configuration,
+            SqliteOutboxRepository::new(pool.clone()),
+            SqliteStationRepository::new(pool.clone()),
+            station_devices.clone(),
+            Arc::new(irrigation),
+            Backoff::default(),
+        );
+        let uploads = uploader.notifier();
+        tokio::spawn(uploader.run());
+        Some(uploads)
+    } else {
+        tracing::info!("Upload to the backend is off, measurements are kept in the outbox");
+        None
+    };
+
+    let mqtt = app_config.mqtt.as_ref().map(|config| MqttSink::new(config.clone(), Box::new(RumqttPublisher::spawn(config))).spawn(64));
+
+    let uploads = &uploads;
+    let mqtt = &mqtt;
+    let station_devices = &station_devices;
+    let syncs = &syncs;
+
+    stream
+        .for_each(|m| async move {
+            // Stored before the upload, the uploader retries from the database until the backend has it.
+            // Connected syncs stored their pages during the transfer, only the summary is left.
+            let now = chrono::Utc::now().naive_utc();
+            let stored = match m.sync_id {
+                Some(id) => syncs.finish(id, &m, now).await,
+                None => syncs.insert(&m, now).await.map(|_| ()),
+            };
+            if let Err(err) = stored {
+                tracing::error!("Failed to store sync {}", err);
+            }
+            if let Some(uploads) = uploads {
+                uploads.notify_one();
+            }
+
+            if let Some(mqtt) = mqtt {
+                let device = station_devices.find(&m.address).await.unwrap_or_default();
+                mqtt.push(&m, device);
+            }
+
+            if let Err(err) = show_sync(&m) {
+                tracing::error!("Failed to show sync status {}", err);
+            }
+        })
+        .await;
+
+    Ok(())
+}
+
+/// A client of the backend, authenticated with the refresh token of the onboarding
+async fn make_configuration(app_config: &AppConfig, edge_state: &EdgeState) -> anyhow::Result<Configuration> {
     let jitter_source = jitter::NullJitter;
-    let refresh_token = _edge_state.auth0_refresh_token.clone();
+    let refresh_token = edge_state.auth0_refresh_token.clone();
     let refresh_token_ref = RefreshToken::new(refresh_token).into_boxed_ref();
-    let client_id = ClientId::new(app_config.auth0.client_id);
+    let client_id = ClientId::new(app_config.auth0.client_id.clone());
     let token_url = Url::parse(format!("https://{}/oauth/token", &app_config.auth0.domain).as_str())?;
     let credentials = RefreshTokenCredentialsSource { 
         client_id: client_id,
@@ -91,61 +213,36 @@ async fn work() -> anyhow::Result<()> {
         .with(TracingMiddleware::default())
         .build();
 
-    let configuration: Configuration = Configuration {
-        base_path: app_config.backend_url,
+    Ok(Configuration {
+        base_path: app_config.backend_url.clone(),
         user_agent: None,
         client: client,
         basic_auth: None,
         oauth_access_token: None,
         bearer_access_token: None,
         api_key: None                
-    };
-
-    let provider = make_peripheral_sync_stream_provider(&app_config.peripheral_sync_mode).await?;
-    let stream = provider.stream().flat_map(stream::iter);
+    })
+}
 
-    stream
-        .for_each(|m| async {
-            if let Err(err) = sync_measurements(&configuration, m).await {
-                tracing::error!("Failed to sync measurements {}", err);
-            }
-        })
-        .await;
+fn show_sync(m: &PeripheralSyncResult) -> anyhow::Result<()> {
+    let mac = format_mac(&m.address);
 
-    Ok(())
-}
+    if m.decode_failures > 0 {
+        tracing::warn!(%mac, decode_failures = m.decode_failures, "Peripheral served entries that couldn't be decoded");
+    }
 
-async fn sync_measurements(configuration: &Configuration, m: PeripheralSyncResult) -> anyhow::Result<()> {
-
-    let mac = format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", m.address[0], m.address[1], m.address[2], m.address[3], m.address[4], m.address[5]);
-    let station_insert = StationInsert::new(mac, "Unnamed".to_string());
-
-    let id = edge_client_backend::apis::default_api::add_station(&configuration, station_insert).await?;
-    let mut measurements = vec![];
-    let summary = StatusSummary::from_measurements(&m.measurements);
-
-    for measurement in m.measurements {
-        measurements.push(StationMeasurement {
-            on: measurement.timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
-            battery_voltage: 0_f64,
-            temperature: measurement.measurement.temperature as f64,
-            humidity: measurement.measurement.humidity as f64,
-            lux: measurement.measurement.lux as f64,
-            soil_pf: measurement.measurement.soil_pf as f64,
-            tank_pf: 0_f64
-        });
+    if let Some(percentage) = m.measurements.last().and_then(|e| e.measurement.battery_percentage()) {
+        if percentage < LOW_BATTERY_PERCENTAGE {
+            tracing::warn!(%mac, percentage, "Peripheral battery is low");
+        }
     }
 
-    edge_client_backend::apis::default_api::checkin_station(&configuration, id.to_string().as_str(), Some(measurements)).await?;
-    match summary {
-        Some(m) => {
-            let mut status = make_status()?;
-            status.show(&m)?
-        },
-        None => ()
+    if let Some(summary) = StatusSummary::from_measurements(&m.measurements) {
+        let mut status = make_status()?;
+        status.show(&summary)?;
     }
 
-    Ok(())            
+    Ok(())
 }
 
 #[derive(Debug, Clone)]
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, TimeDelta, Utc};
+use edge_protocol::{Broadcast, MeasurementSerieEntry};
+use tracing::warn;
+
+use crate::measurements::types::PeripheralSyncResult;
+
+/// Collects the measurements peripherals broadcast in their advertising data.
+///
+/// A peripheral repeats the same advertisement many times, only a new sequence number
+/// yields a new measurement. Broadcast values are timed by their age at the time they were
+/// received, and keyed by the MAC of the peripheral like a connected sync.
+#[derive(Default)]
+pub struct BroadcastTracker {
+    sequences: HashMap<[u8; 6], u8>,
+    /// Advertised address, MAC and entry of every new measurement
+    received: Vec<([u8; 6], [u8; 6], MeasurementSerieEntry)>,
+    /// Keyed by the advertised address, a broadcast that fails to decode carries no MAC
+    decode_failures: HashMap<[u8; 6], u32>,
+}
+
+impl BroadcastTracker {
+    /// Handle the raw advertising data of a device, the address is the advertised one as sent over HCI in little endian
+    pub fn on_adv_data(&mut self, address: [u8; 6], data: &[u8], now: DateTime<Utc>) {
+        match Broadcast::from_adv_data(data) {
+            None => {}
+            Some(Err(err)) => {
+                warn!(%err, ?address, "Error decoding broadcast");
+                *self.decode_failures.entry(address).or_default() += 1;
+            }
+            Some(Ok(broadcast)) => {
+                let mac = broadcast.mac(address);
+                if self.sequences.insert(mac, broadcast.sequence) == Some(broadcast.sequence) {
+                    return;
+                }
+
+                let timestamp = now - TimeDelta::seconds(broadcast.age_secs.into());
+                let entry = MeasurementSerieEntry { timestamp: timestamp.naive_utc(), measurement: broadcast.measurement };
+                self.received.push((address, mac, entry));
+            }
+        }
+    }
+
+    /// Take the measurements received since the last call, grouped per device
+    pub fn take(&mut self) -> Vec<PeripheralSyncResult> {
+        let mut results: Vec<PeripheralSyncResult> = vec![];
+
+        for (address, mac, entry) in self.received.drain(..) {
+            match results.iter_mut().find(|r| r.address == mac) {
+                Some(result) => result.measurements.push(entry),
+                None => results.push(PeripheralSyncResult {
+                    address: mac,
+                    time_drift: TimeDelta::zero(),
+                    measurements: vec![entry],
+                    decode_failures: self.decode_failures.remove(&address).unwrap_or(0),
+                    rssi: None,
+                    sync_id: None,
+                }),
+            }
+        }
+
+        results
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edge_protocol::{static_random_address, Measurement, MAX_BROADCAST_LEN, MEASUREMENT_SERVICE_UUID_16};
+
+    const A: [u8; 6] = [0xaa; 6];
+    const B: [u8; 6] = [0x3b; 6];
+
+    fn adv_data(sequence: u8, temperature: f32) -> Vec<u8> {
+        adv_data_of(A, sequence, 0, temperature)
+    }
+
+    fn adv_data_of(mac: [u8; 6], sequence: u8, age_secs: u16, temperature: f32) -> Vec<u8> {
+        let broadcast = Broadcast::new(sequence, age_secs, mac, Measurement { temperature: Some(temperature), ..Measurement::EMPTY });
+        let mut payload = [0u8; MAX_BROADCAST_LEN];
+        let len = broadcast.to_bytes(&mut payload).unwrap();
+        let uuid = MEASUREMENT_SERVICE_UUID_16.to_le_bytes();
+
+        let mut data = vec![2, 0x01, 0x06, (3 + len) as u8, 0x16, uuid[0], uuid[1]];
+        data.extend_from_slice(&payload[..len]);
+        data
+    }
+
+    #[test]
+    fn test_tracker_skips_repeated_advertisements() {
+        let mut tracker = BroadcastTracker::default();
+        let now = Utc::now();
+        let a = static_random_address(A);
+        let b = static_random_address(B);
+
+        tracker.on_adv_data(a, &adv_data(1, 20.0), now);
+        tracker.on_adv_data(a, &adv_data(1, 20.0), now);
+        tracker.on_adv_data(b, &adv_data_of(B, 1, 0, 18.0), now);
+        tracker.on_adv_data(a, &adv_data(2, 21.0), now);
+
+        let results = tracker.take();
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].address, A);
+        assert_eq!(results[1].address, B);
+        let temperatures: Vec<_> = results[0].measurements.iter().map(|e| e.measurement.temperature).collect();
+        assert_eq!(temperatures, vec![Some(20.0), Some(21.0)]);
+        assert_eq!(results[1].measurements.len(), 1);
+
+        // Nothing new until the sequence number changes
+        tracker.on_adv_data(a, &adv_data(2, 21.0), now);
+        assert!(tracker.take().is_empty());
+    }
+
+    #[test]
+    fn test_tracker_times_measurements_by_age() {
+        let mut tracker = BroadcastTracker::default();
+        let now = Utc::now();
+
+        tracker.on_adv_data(static_random_address(A), &adv_data_of(A, 1, 90, 20.0), now);
+
+        let results = tracker.take();
+        assert_eq!(results[0].measurements[0].timestamp, (now - TimeDelta::seconds(90)).naive_utc());
+    }
+
+    #[test]
+    fn test_tracker_ignores_other_advertisements_and_counts_failures() {
+        let mut tracker = BroadcastTracker::default();
+        let now = Utc::now();
+        let address = static_random_address(A);
+
+        tracker.on_adv_data(address, &[2, 0x01, 0x06], now);
+        assert!(tracker.take().is_empty());
+
+        let mut corrupt = adv_data(1, 20.0);
+        corrupt[7] = 0x7f;
+        tracker.on_adv_data(address, &corrupt, now);
+        tracker.on_adv_data(address, &adv_data(2, 20.0), now);
+
+        let results = tracker.take();
+        assert_eq!(results[0].decode_failures, 1);
+        assert_eq!(results[0].measurements.len(), 1);
+    }
+}
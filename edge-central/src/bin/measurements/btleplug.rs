@@ -2,6 +2,7 @@ use std::pin::Pin;
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use async_trait::async_trait;
 use btleplug::api::bleuuid::uuid_from_u16;
 use btleplug::api::{
     Central, Characteristic, Manager as _, Peripheral as _, ScanFilter, WriteType,
@@ -11,24 +12,36 @@ use chrono::{DateTime, Utc};
 use edge_protocol::*;
 use futures::Stream;
 use tokio::time::{sleep, Duration};
-use tracing::info;
+use tracing::{info, warn};
 use uuid::Uuid;
 
-use crate::measurements::types::{PeripheralSyncResult, PeripheralSyncResultStreamProvider};
+use crate::data::types::DeviceInformation;
+use crate::measurements::commands::{deliver_commands, CommandTransport};
+use crate::measurements::history::{drain_history, HistoryTransport, SyncSink};
+use crate::measurements::types::{PeripheralSyncResult, PeripheralSyncResultStreamProvider, SyncContext};
 
 const CURRENT_TIME_SERVICE: Uuid = uuid_from_u16(CURRENT_TIME_SERVICE_UUID);
 const CURRENT_TIME_CHAR: Uuid = uuid_from_u16(CURRENT_TIME_CHARACTERISTIC_UUID);
 const MEASUREMENT_SERVICE: Uuid = uuid_from_u16(MEASUREMENT_SERVICE_UUID_16);
 const MEASUREMENT_CHAR: Uuid = uuid_from_u16(MEASUREMENT_CHARACTERISTIC_UUID_16);
+const PROTOCOL_CHAR: Uuid = uuid_from_u16(PROTOCOL_CHARACTERISTIC_UUID_16);
+const HISTORY_ACK_CHAR: Uuid = uuid_from_u16(HISTORY_ACK_CHARACTERISTIC_UUID_16);
+const CONFIG_CHAR: Uuid = uuid_from_u16(CONFIG_CHARACTERISTIC_UUID_16);
+const COMMAND_CHAR: Uuid = uuid_from_u16(COMMAND_CHARACTERISTIC_UUID_16);
+const DEVICE_INFORMATION_SERVICE: Uuid = uuid_from_u16(DEVICE_INFORMATION_SERVICE_UUID);
+const MODEL_NUMBER_CHAR: Uuid = uuid_from_u16(MODEL_NUMBER_CHARACTERISTIC_UUID);
+const FIRMWARE_REVISION_CHAR: Uuid = uuid_from_u16(FIRMWARE_REVISION_CHARACTERISTIC_UUID);
+const HARDWARE_REVISION_CHAR: Uuid = uuid_from_u16(HARDWARE_REVISION_CHARACTERISTIC_UUID);
 const ADDRESS_SERVICE: Uuid = uuid_from_u16(ADDRESS_SERVICE_UUID_16);
 const ADDRESS_CHAR: Uuid = uuid_from_u16(ADDRESS_CHARACTERISTIC_UUID_16);
 
 pub struct BtleplugPeripheralSyncResultStreamProvider {
     adapter: Arc<Adapter>,
+    context: SyncContext,
 }
 
 impl BtleplugPeripheralSyncResultStreamProvider {
-    pub async fn new() -> anyhow::Result<Self> {
+    pub async fn new(context: SyncContext) -> anyhow::Result<Self> {
         let manager = Manager::new().await?;
         let adapters = manager.adapters().await?;
         let adapter = adapters
@@ -38,6 +51,7 @@ impl BtleplugPeripheralSyncResultStreamProvider {
 
         Ok(BtleplugPeripheralSyncResultStreamProvider {
             adapter: Arc::new(adapter),
+            context,
         })
     }
 }
@@ -45,7 +59,8 @@ impl BtleplugPeripheralSyncResultStreamProvider {
 impl PeripheralSyncResultStreamProvider for BtleplugPeripheralSyncResultStreamProvider {
     fn stream(self: Box<Self>) -> Pin<Box<dyn Stream<Item = Vec<PeripheralSyncResult>>>> {
         let adapter = self.adapter.clone();
-        let stream = futures::stream::unfold(adapter, |adapter| async {
+        let context = self.context.clone();
+        let stream = futures::stream::unfold((adapter, context), |(adapter, context)| async move {
             if let Err(err) = adapter
                 .start_scan(ScanFilter {
                     services: vec![CURRENT_TIME_SERVICE],
@@ -64,7 +79,7 @@ impl PeripheralSyncResultStreamProvider for BtleplugPeripheralSyncResultStreamPr
 
             for peripheral in peripherals {
                 let now = Utc::now();
-                match sync(peripheral, now).await {
+                match sync(peripheral, now, &context).await {
                     Err(err) => tracing::warn!(?err, "Sync error occurred"),
                     Ok(result) => results.push(result)
                 }
@@ -77,14 +92,74 @@ impl PeripheralSyncResultStreamProvider for BtleplugPeripheralSyncResultStreamPr
                 return None
             };
 
-            Some((results, adapter))
+            Some((results, (adapter, context)))
         });
 
         Box::pin(stream)
     }
 }
 
-async fn sync(peripheral: Peripheral, now: DateTime<Utc>) -> anyhow::Result<PeripheralSyncResult> {
+struct BtleplugHistoryTransport<'a> {
+    peripheral: &'a Peripheral,
+    measurement_char: Characteristic,
+    history_ack_char: Option<Characteristic>,
+}
+
+#[async_trait(?Send)]
+impl HistoryTransport for BtleplugHistoryTransport<'_> {
+    async fn read_page(&mut self) -> anyhow::Result<Vec<u8>> {
+        Ok(self.peripheral.read(&self.measurement_char).await?)
+    }
+
+    async fn write_ack(&mut self, ack: &[u8]) -> anyhow::Result<()> {
+        let history_ack_char = self
+            .history_ack_char
+            .as_ref()
+            .ok_or(anyhow!("Device does not have {} characteristic", HISTORY_ACK_CHAR))?;
+
+        Ok(self.peripheral.write(history_ack_char, ack, WriteType::WithResponse).await?)
+    }
+}
+
+struct BtleplugCommandTransport<'a> {
+    peripheral: &'a Peripheral,
+    command_char: Characteristic,
+}
+
+#[async_trait(?Send)]
+impl CommandTransport for BtleplugCommandTransport<'_> {
+    async fn send(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>> {
+        self.peripheral.write(&self.command_char, request, WriteType::WithResponse).await?;
+        Ok(self.peripheral.read(&self.command_char).await?)
+    }
+}
+
+/// Read the device information service, `None` for peripherals predating it
+async fn read_device_information(peripheral: &Peripheral) -> anyhow::Result<Option<DeviceInformation>> {
+    async fn read_string(peripheral: &Peripheral, uuid: Uuid) -> anyhow::Result<Option<String>> {
+        let characteristic = peripheral
+            .characteristics()
+            .into_iter()
+            .find(|c| c.service_uuid == DEVICE_INFORMATION_SERVICE && c.uuid == uuid);
+
+        match characteristic {
+            Some(characteristic) => Ok(Some(String::from_utf8_lossy(&peripheral.read(&characteristic).await?).into_owned())),
+            None => Ok(None),
+        }
+    }
+
+    let Some(firmware_version) = read_string(peripheral, FIRMWARE_REVISION_CHAR).await? else {
+        return Ok(None);
+    };
+
+    Ok(Some(DeviceInformation {
+        model: read_string(peripheral, MODEL_NUMBER_CHAR).await?.unwrap_or_default(),
+        firmware_version,
+        hardware_revision: read_string(peripheral, HARDWARE_REVISION_CHAR).await?.unwrap_or_default(),
+    }))
+}
+
+async fn sync(peripheral: Peripheral, now: DateTime<Utc>, context: &SyncContext) -> anyhow::Result<PeripheralSyncResult> {
     async fn find_characteristic_or_disconnect(
         peripheral: &Peripheral,
         service: Uuid,
@@ -133,13 +208,22 @@ async fn sync(peripheral: Peripheral, now: DateTime<Utc>) -> anyhow::Result<Peri
         .try_into()
         .map_err(|_| anyhow!("Address data is not 6 bytes"))?;
 
+    match read_device_information(&peripheral).await {
+        Ok(Some(device)) => {
+            info!(?device, "Read device information");
+            context.station_devices.set(&address, &device).await?;
+        }
+        Ok(None) => info!("Device does not have the device information service"),
+        Err(err) => warn!(?err, "Unable to read device information"),
+    }
+
     let current_time_char =
         find_characteristic_or_disconnect(&peripheral, CURRENT_TIME_SERVICE, CURRENT_TIME_CHAR)
             .await?;
     let data = peripheral.read(&current_time_char).await?;
     let bytes = data.as_slice();
-    let current_time = CurrentTime::from_bytes(bytes);
-    let datetime = current_time.to_naivedatetime();
+    let current_time = CurrentTime::try_from_bytes(bytes)?;
+    let datetime = current_time.to_naivedatetime()?;
 
     let duration = now.naive_utc() - datetime;
     let ct = CurrentTime::from_naivedatetime(now.naive_utc());
@@ -151,29 +235,85 @@ async fn sync(peripheral: Peripheral, now: DateTime<Utc>) -> anyhow::Result<Peri
     let measurement_char =
         find_characteristic_or_disconnect(&peripheral, MEASUREMENT_SERVICE, MEASUREMENT_CHAR)
             .await?;
-    let measurement_data = peripheral.read(&measurement_char).await?;
 
-    if measurement_data.len() != 198 {
-        peripheral.disconnect().await?;
-        return Err(anyhow!("Measurement data is not 198 bytes"));
+    // Peripherals running v1 firmware don't expose the protocol characteristic
+    let protocol_char = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.service_uuid == MEASUREMENT_SERVICE && c.uuid == PROTOCOL_CHAR);
+
+    let negotiated = match protocol_char {
+        Some(protocol_char) => {
+            let data = peripheral.read(&protocol_char).await?;
+            let hello = ProtocolHello::from_bytes(&data)?;
+            peripheral
+                .write(&protocol_char, &ProtocolHello::current().to_bytes(), WriteType::WithResponse)
+                .await?;
+            ProtocolHello::current().negotiate(&hello)
+        }
+        None => ProtocolHello::legacy(),
+    };
+
+    info!("Negotiated protocol {:?}", negotiated);
+
+    // Peripherals predating remote configuration don't expose the config characteristic
+    let config_char = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.service_uuid == MEASUREMENT_SERVICE && c.uuid == CONFIG_CHAR);
+
+    let mut verifier = context.verifier(&address).await?;
+
+    if let (Some(config_char), Some((config, value))) = (config_char, context.config_write(&address, verifier.as_ref()).await?) {
+        peripheral
+            .write(&config_char, &value, WriteType::WithResponse)
+            .await?;
+        info!("Wrote configuration {:?}", config);
     }
 
-    let mut measurements = Vec::<MeasurementSerieEntry>::new();
+    let history_ack_char = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.service_uuid == MEASUREMENT_SERVICE && c.uuid == HISTORY_ACK_CHAR);
+
+    let mut transport = BtleplugHistoryTransport { peripheral: &peripheral, measurement_char, history_ack_char };
+
+    let sync_id = context.syncs.begin(&address, chrono::Utc::now().naive_utc(), duration).await?;
+    let mut sink = SyncSink { syncs: &context.syncs, id: sync_id, address };
+
+    let history = match drain_history(&mut transport, negotiated.capabilities, verifier.as_mut(), &mut sink).await {
+        Ok(history) => history,
+        Err(err) => {
+            peripheral.disconnect().await?;
+            return Err(err);
+        }
+    };
+
+    if let Some(verifier) = &verifier {
+        if !context.station_keys.advance_counter(&address, verifier.counter()).await? {
+            return Err(anyhow!("Frame counter of {:02x?} didn't advance, possible replay", address));
+        }
+    }
 
-    for i in 0..6 {
-        let start = i * 33;
-        let end = start + 33;
-        let segment = &measurement_data[start..end];
+    // Peripherals predating commands don't expose the command characteristic, pending commands wait for an update
+    let command_char = peripheral
+        .characteristics()
+        .into_iter()
+        .find(|c| c.service_uuid == MEASUREMENT_SERVICE && c.uuid == COMMAND_CHAR);
 
-        match MeasurementSerieEntry::from_tlv(segment) {
-            Ok(entry) => measurements.push(entry),
-            Err(err) => tracing::warn!("Error decoding measurement entry {:?}", err),
+    if let Some(command_char) = command_char {
+        let mut transport = BtleplugCommandTransport { peripheral: &peripheral, command_char };
+        if let Err(err) = deliver_commands(&mut transport, &context, &address, verifier.as_ref().map(Verifier::key)).await {
+            warn!(?err, "Unable to deliver commands, keeping them for the next sync");
         }
     }
 
     Ok(PeripheralSyncResult {
         address: address,
         time_drift: duration,
-        measurements: measurements,
+        measurements: history.measurements,
+        decode_failures: history.decode_failures,
+        rssi: peripheral.properties().await?.and_then(|properties| properties.rssi),
+        sync_id: Some(sync_id),
     })
 }
\ No newline at end of file
@@ -0,0 +1,195 @@
+use async_trait::async_trait;
+use edge_protocol::{AuthKey, Command, CommandRequest, CommandResponse};
+use tracing::{info, warn};
+
+use crate::measurements::types::SyncContext;
+
+/// Access to the command characteristic of a connected peripheral
+#[async_trait(?Send)]
+pub trait CommandTransport {
+    /// Write a request and read back the response
+    async fn send(&mut self, request: &[u8]) -> anyhow::Result<Vec<u8>>;
+}
+
+/// Parse the name operators use for a command
+pub fn parse_command(name: &str) -> anyhow::Result<Command> {
+    match name {
+        "identify" => Ok(Command::Identify),
+        "sample-now" => Ok(Command::SampleNow),
+        "reboot" => Ok(Command::Reboot),
+        "factory-reset" => Ok(Command::FactoryReset),
+        _ => Err(anyhow::anyhow!("Unknown command {}, expected identify, sample-now, reboot or factory-reset", name)),
+    }
+}
+
+/// Send the commands pending for a station and record the status it replies with.
+///
+/// A command stays pending when the connection drops before the reply, so it is sent
+/// again on the next sync. Returns the number of commands the station replied to.
+///
+/// With the key of the station, the commands disrupting it are signed, a keyed station
+/// rejects an unsigned reboot or factory reset.
+pub async fn deliver_commands<T: CommandTransport>(transport: &mut T, context: &SyncContext, mac: &[u8; 6], key: Option<&AuthKey>) -> anyhow::Result<usize> {
+    let commands = &context.station_commands;
+    let mut delivered = 0;
+
+    for row in commands.pending(mac).await? {
+        let request = row.to_request()?;
+        let mut buffer = [0u8; CommandRequest::MAX_SIGNED_LEN];
+        let len = match key {
+            Some(key) if request.command.requires_authentication() => request.to_signed_bytes(key, context.downlink_counters(mac, 1).await?, &mut buffer)?,
+            _ => {
+                buffer[..CommandRequest::LEN].copy_from_slice(&request.to_bytes());
+                CommandRequest::LEN
+            }
+        };
+        let data = transport.send(&buffer[..len]).await?;
+        let response = CommandResponse::from_bytes(&data)?;
+
+        if !response.answers(&request) {
+            warn!(?request, ?response, "Peripheral answered another command");
+            continue;
+        }
+
+        info!(?request, status = ?response.status, "Peripheral replied to command");
+        commands.complete(row.id, response.status).await?;
+        delivered += 1;
+    }
+
+    Ok(delivered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edge_protocol::auth::downlink_key;
+    use edge_protocol::{CommandStatus, ProtocolError, Verifier};
+
+    use std::sync::Arc;
+
+    use crate::data::sqlite::{test_pool, SqliteStationCommandRepository, SqliteStationDeviceRepository, SqliteStationKeyRepository, SqliteStationSettingsRepository, SqliteSyncRepository};
+
+    const MAC: [u8; 6] = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+    /// Peripheral side, accepts the commands it is built to accept
+    struct MockPeripheral {
+        accepted: Vec<Command>,
+        received: Vec<Command>,
+        /// Number of requests answered before the connection drops
+        replies_left: usize,
+        /// Set once the peripheral has a key, disruptive commands have to be signed then
+        verifier: Option<Verifier>,
+    }
+
+    #[async_trait(?Send)]
+    impl CommandTransport for MockPeripheral {
+        async fn send(&mut self, data: &[u8]) -> anyhow::Result<Vec<u8>> {
+            if self.replies_left == 0 {
+                anyhow::bail!("Disconnected");
+            }
+            self.replies_left -= 1;
+
+            let request = match self.verifier.as_mut() {
+                Some(verifier) if data.len() > CommandRequest::LEN => CommandRequest::from_signed_bytes(data, verifier),
+                Some(_) => CommandRequest::from_bytes(data)
+                    .and_then(|request| if request.command.requires_authentication() { Err(ProtocolError::Unauthenticated) } else { Ok(request) }),
+                None => CommandRequest::from_bytes(data),
+            };
+            let status = match request {
+                Ok(request) if self.accepted.contains(&request.command) => {
+                    self.received.push(request.command);
+                    CommandStatus::Accepted
+                }
+                Ok(_) | Err(ProtocolError::Unauthenticated) => CommandStatus::Rejected,
+                Err(err) => return Err(err.into()),
+            };
+
+            Ok(CommandResponse::for_request(data, status).to_bytes().to_vec())
+        }
+    }
+
+    async fn context() -> SyncContext {
+        let pool = test_pool().await;
+
+        SyncContext {
+            station_keys: Arc::new(SqliteStationKeyRepository::new(pool.clone())),
+            station_settings: Arc::new(SqliteStationSettingsRepository::new(pool.clone())),
+            station_commands: Arc::new(SqliteStationCommandRepository::new(pool.clone())),
+            station_devices: Arc::new(SqliteStationDeviceRepository::new(pool.clone())),
+            syncs: Arc::new(SqliteSyncRepository::new(pool)),
+            fleet_key: None,
+            require_authentication: false,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_deliver_commands_records_status() {
+        let context = context().await;
+        let commands = &context.station_commands;
+        let identify = commands.request(&MAC, Command::Identify).await.unwrap();
+        let sample_now = commands.request(&MAC, Command::SampleNow).await.unwrap();
+
+        // A peripheral waiting for its clock has nothing to sample into
+        let mut peripheral = MockPeripheral { accepted: vec![Command::Identify], received: vec![], replies_left: usize::MAX, verifier: None };
+
+        assert_eq!(deliver_commands(&mut peripheral, &context, &MAC, None).await.unwrap(), 2);
+        assert_eq!(peripheral.received, vec![Command::Identify]);
+        assert_eq!(commands.find(identify).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Accepted));
+        assert_eq!(commands.find(sample_now).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Rejected));
+        assert!(commands.pending(&MAC).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_unanswered_commands_stay_pending() {
+        let context = context().await;
+        let commands = &context.station_commands;
+        commands.request(&MAC, Command::Identify).await.unwrap();
+        let reboot = commands.request(&MAC, Command::Reboot).await.unwrap();
+
+        let mut peripheral = MockPeripheral { accepted: vec![Command::Identify, Command::Reboot], received: vec![], replies_left: 1, verifier: None };
+
+        assert!(deliver_commands(&mut peripheral, &context, &MAC, None).await.is_err());
+        assert_eq!(commands.pending(&MAC).await.unwrap().iter().map(|r| r.id).collect::<Vec<_>>(), vec![reboot]);
+
+        peripheral.replies_left = usize::MAX;
+        assert_eq!(deliver_commands(&mut peripheral, &context, &MAC, None).await.unwrap(), 1);
+        assert_eq!(peripheral.received, vec![Command::Identify, Command::Reboot]);
+    }
+
+    #[tokio::test]
+    async fn test_disruptive_commands_are_signed_for_keyed_stations() {
+        let key = [0x42; 32];
+        let context = context().await;
+        let commands = &context.station_commands;
+        let identify = commands.request(&MAC, Command::Identify).await.unwrap();
+        let reboot = commands.request(&MAC, Command::Reboot).await.unwrap();
+
+        let accepted = vec![Command::Identify, Command::Reboot];
+        let mut peripheral = MockPeripheral { accepted, received: vec![], replies_left: usize::MAX, verifier: Some(Verifier::new(downlink_key(&key), 0)) };
+
+        // Without the key of the station the reboot goes out unsigned and is rejected
+        assert_eq!(deliver_commands(&mut peripheral, &context, &MAC, None).await.unwrap(), 2);
+        assert_eq!(commands.find(identify).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Accepted));
+        assert_eq!(commands.find(reboot).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Rejected));
+
+        context.station_keys.set_key(&MAC, &key).await.unwrap();
+        let reboot = commands.request(&MAC, Command::Reboot).await.unwrap();
+        assert_eq!(deliver_commands(&mut peripheral, &context, &MAC, Some(&key)).await.unwrap(), 1);
+        assert_eq!(commands.find(reboot).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Accepted));
+        assert_eq!(peripheral.received, vec![Command::Identify, Command::Reboot]);
+
+        // Delivered again within the same second, the counter still advances
+        let factory_reset = commands.request(&MAC, Command::FactoryReset).await.unwrap();
+        let reboot = commands.request(&MAC, Command::Reboot).await.unwrap();
+        peripheral.accepted.push(Command::FactoryReset);
+        assert_eq!(deliver_commands(&mut peripheral, &context, &MAC, Some(&key)).await.unwrap(), 2);
+        assert_eq!(commands.find(factory_reset).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Accepted));
+        assert_eq!(commands.find(reboot).await.unwrap().unwrap().to_status().unwrap(), Some(CommandStatus::Accepted));
+    }
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!(parse_command("sample-now").unwrap(), Command::SampleNow);
+        assert!(parse_command("blink").is_err());
+    }
+}
@@ -0,0 +1,382 @@
+use async_trait::async_trait;
+use edge_protocol::{Capabilities, Frame, HistoryAck, MeasurementSerieEntry, Verifier};
+use tracing::{info, warn};
+
+use crate::data::sqlite::SqliteSyncRepository;
+
+/// Access to the measurement and history ack characteristics of a connected peripheral
+#[async_trait(?Send)]
+pub trait HistoryTransport {
+    async fn read_page(&mut self) -> anyhow::Result<Vec<u8>>;
+    async fn write_ack(&mut self, ack: &[u8]) -> anyhow::Result<()>;
+}
+
+/// Where the pages of a transfer are stored, the peripheral only drops a page once it is
+#[async_trait(?Send)]
+pub trait HistorySink {
+    async fn store(&mut self, entries: &[MeasurementSerieEntry]) -> anyhow::Result<()>;
+}
+
+/// Stores every page in a sync begun for the transfer
+pub struct SyncSink<'a> {
+    pub syncs: &'a SqliteSyncRepository,
+    pub id: i64,
+    pub address: [u8; 6],
+}
+
+#[async_trait(?Send)]
+impl HistorySink for SyncSink<'_> {
+    async fn store(&mut self, entries: &[MeasurementSerieEntry]) -> anyhow::Result<()> {
+        self.syncs.append(self.id, &self.address, entries, chrono::Utc::now().naive_utc()).await?;
+        Ok(())
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct History {
+    pub measurements: Vec<MeasurementSerieEntry>,
+    /// Number of entries the peripheral served that couldn't be decoded
+    pub decode_failures: u32,
+}
+
+/// Verify the authentication trailer of a page and strip it.
+///
+/// A station that signs its frames has to be provisioned first, accepting its frames unverified
+/// would accept those of any device claiming its MAC too.
+fn frame_data<'a>(data: &'a [u8], capabilities: Capabilities, verifier: Option<&mut Verifier>) -> anyhow::Result<&'a [u8]> {
+    match (capabilities.contains(Capabilities::AUTHENTICATED), verifier) {
+        (true, Some(verifier)) => verifier
+            .verify(data)
+            .map_err(|err| anyhow::anyhow!("Rejected measurement frame: {}", err)),
+        (true, None) => Err(anyhow::anyhow!("Station signs its frames but has no key provisioned, rejecting them")),
+        (false, Some(_)) => Err(anyhow::anyhow!("Station has a key provisioned but serves unauthenticated frames")),
+        (false, None) => Ok(data),
+    }
+}
+
+/// A decoded page of the history
+struct Page {
+    entries: Vec<MeasurementSerieEntry>,
+    decode_failures: u32,
+    /// Entries that decoded before the first failure
+    complete: usize,
+}
+
+impl Page {
+    fn decode(data: &[u8], capabilities: Capabilities, verifier: Option<&mut Verifier>) -> anyhow::Result<Self> {
+        let data = frame_data(data, capabilities, verifier)?;
+        let frame = Frame::decode(data)
+            .map_err(|err| anyhow::anyhow!("Unable to decode measurement frame: {}", err))?;
+        let mut page = Page { entries: vec![], decode_failures: 0, complete: 0 };
+
+        for entry in frame.entries() {
+            match entry {
+                Ok(entry) => {
+                    page.entries.push(entry);
+                    if page.decode_failures == 0 {
+                        page.complete += 1;
+                    }
+                }
+                Err(err) => {
+                    warn!(%err, "Error decoding measurement entry");
+                    page.decode_failures += 1;
+                }
+            }
+        }
+
+        Ok(page)
+    }
+}
+
+/// Read, decode and store the next page
+async fn next_page<T: HistoryTransport, S: HistorySink>(transport: &mut T, capabilities: Capabilities, verifier: Option<&mut Verifier>, sink: &mut S) -> anyhow::Result<Page> {
+    let data = transport.read_page().await?;
+    let mut page = Page::decode(&data, capabilities, verifier)?;
+
+    // Entries past a failure stay on the peripheral, acknowledging them would drop the one that failed.
+    // Without paging the peripheral dropped the page as it was read, whatever decoded is kept.
+    if capabilities.contains(Capabilities::PAGED_HISTORY) {
+        page.entries.truncate(page.complete);
+    }
+
+    sink.store(&page.entries).await?;
+    Ok(page)
+}
+
+/// Retrieve the buffered history of a peripheral.
+///
+/// Without `PAGED_HISTORY` the peripheral serves a single page and drops it once read.
+/// With it every page is stored in the sink, acknowledged, and the next one is read until
+/// the peripheral serves an empty page. When the connection drops halfway the pages stored
+/// so far are returned, the peripheral keeps everything that wasn't acknowledged. A page
+/// is only acknowledged up to its first entry that couldn't be decoded, the transfer stops
+/// there so the peripheral keeps that entry.
+///
+/// A station with a key provisioned gets a verifier, pages that fail verification are
+/// never acknowledged so a forged or replayed page can't make the peripheral drop data.
+pub async fn drain_history<T: HistoryTransport, S: HistorySink>(transport: &mut T, capabilities: Capabilities, mut verifier: Option<&mut Verifier>, sink: &mut S) -> anyhow::Result<History> {
+    let mut history = History::default();
+    let mut acked = None;
+
+    loop {
+        let page = match next_page(transport, capabilities, verifier.as_deref_mut(), sink).await {
+            Ok(page) => page,
+            Err(err) if acked.is_none() => return Err(err),
+            Err(err) => {
+                warn!(?err, "History transfer interrupted, keeping the pages stored so far");
+                break;
+            }
+        };
+        history.measurements.extend_from_slice(&page.entries);
+        history.decode_failures += page.decode_failures;
+
+        if !capabilities.contains(Capabilities::PAGED_HISTORY) {
+            break;
+        }
+
+        let Some(timestamp) = page.entries.last().map(|entry| entry.timestamp) else {
+            break;
+        };
+
+        // A peripheral serving the same page again would never drain
+        if acked.is_some_and(|acked| timestamp <= acked) {
+            warn!(%timestamp, "Peripheral made no progress on the history ack");
+            break;
+        }
+
+        if let Err(err) = transport.write_ack(&HistoryAck { timestamp }.to_bytes()).await {
+            warn!(?err, "History transfer interrupted, keeping the pages stored so far");
+            break;
+        }
+        acked = Some(timestamp);
+
+        if page.decode_failures > 0 {
+            warn!(%timestamp, "Stopping the history transfer at an entry that couldn't be decoded");
+            break;
+        }
+    }
+
+    info!("Retrieved {} measurements", history.measurements.len());
+
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edge_protocol::{HistoryCursor, Measurement, Signer, FRAME_HEADER_LEN, MAX_AUTHENTICATED_FRAME_LEN};
+
+    const KEY: [u8; 32] = [0x42; 32];
+
+    /// Peripheral side of the transfer, backed by the same cursor the firmware uses
+    struct MockPeripheral {
+        entries: Vec<MeasurementSerieEntry>,
+        capabilities: Capabilities,
+        cursor: HistoryCursor,
+        signer: Signer,
+        /// Number of reads served before the connection drops
+        reads_left: usize,
+        /// Entry served in a form that can't be decoded
+        corrupt: Option<usize>,
+    }
+
+    impl MockPeripheral {
+        fn new(count: i64, capabilities: Capabilities) -> Self {
+            let entries = (0..count)
+                .map(|i| MeasurementSerieEntry {
+                    timestamp: chrono::DateTime::from_timestamp(1_700_000_000 + i * 60, 0).unwrap().naive_utc(),
+                    measurement: Measurement { temperature: Some(20.0 + i as f32), ..Measurement::EMPTY },
+                })
+                .collect();
+
+            MockPeripheral { entries, capabilities, cursor: HistoryCursor::new(), signer: Signer::new(KEY, 0), reads_left: usize::MAX, corrupt: None }
+        }
+    }
+
+    #[async_trait(?Send)]
+    impl HistoryTransport for MockPeripheral {
+        async fn read_page(&mut self) -> anyhow::Result<Vec<u8>> {
+            if self.reads_left == 0 {
+                anyhow::bail!("Disconnected");
+            }
+            self.reads_left -= 1;
+
+            let page = self.cursor.page(&self.entries, self.capabilities);
+            let mut buffer = [0u8; MAX_AUTHENTICATED_FRAME_LEN];
+            let mut len = Frame::encode(self.capabilities, page, &mut buffer)?;
+            if let Some(corrupt) = self.corrupt.and_then(|index| page.iter().position(|e| *e == self.entries[index])) {
+                len = corrupt_entry(&mut buffer, len, corrupt);
+            }
+            if self.capabilities.contains(Capabilities::AUTHENTICATED) {
+                len = self.signer.sign(&mut buffer, len)?;
+            }
+
+            // Legacy semantics, reading a page acknowledges it
+            if !self.capabilities.contains(Capabilities::PAGED_HISTORY) {
+                if let Some(last) = page.last() {
+                    self.cursor.ack(HistoryAck { timestamp: last.timestamp });
+                }
+            }
+
+            Ok(buffer[..len].to_vec())
+        }
+
+        async fn write_ack(&mut self, ack: &[u8]) -> anyhow::Result<()> {
+            self.cursor.ack(HistoryAck::from_bytes(ack)?);
+            Ok(())
+        }
+    }
+
+    /// Replace an entry of a TLV frame by an empty one, which lacks every field
+    fn corrupt_entry(buffer: &mut [u8], len: usize, index: usize) -> usize {
+        let mut start = FRAME_HEADER_LEN;
+        for _ in 0..index {
+            start += 1 + buffer[start] as usize;
+        }
+        let end = start + 1 + buffer[start] as usize;
+
+        buffer.copy_within(end..len, start + 1);
+        buffer[start] = 0;
+        len - (end - start - 1)
+    }
+
+    /// Stores pages in memory, or fails once it stored `capacity` pages
+    #[derive(Default)]
+    struct MockSink {
+        pages: Vec<Vec<MeasurementSerieEntry>>,
+        capacity: Option<usize>,
+    }
+
+    #[async_trait(?Send)]
+    impl HistorySink for MockSink {
+        async fn store(&mut self, entries: &[MeasurementSerieEntry]) -> anyhow::Result<()> {
+            if self.capacity.is_some_and(|capacity| self.pages.len() >= capacity) {
+                anyhow::bail!("Database is locked");
+            }
+            self.pages.push(entries.to_vec());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_drain_history_reads_every_page() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+        let mut peripheral = MockPeripheral::new(12, capabilities);
+        let mut sink = MockSink::default();
+
+        let history = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut sink).await.unwrap();
+
+        assert_eq!(history.measurements, peripheral.entries);
+        assert_eq!(history.decode_failures, 0);
+        assert!(peripheral.cursor.is_drained(&peripheral.entries));
+        assert_eq!(sink.pages.concat(), peripheral.entries);
+    }
+
+    #[tokio::test]
+    async fn test_pages_are_acknowledged_once_stored() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+        let mut peripheral = MockPeripheral::new(12, capabilities);
+
+        let mut full = MockSink { capacity: Some(0), ..MockSink::default() };
+        assert!(drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut full).await.is_err());
+        assert!(peripheral.cursor.acked_until().is_none());
+
+        // The second page fails to store, only the first is dropped by the peripheral
+        let mut sink = MockSink { capacity: Some(1), ..MockSink::default() };
+        let history = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut sink).await.unwrap();
+
+        assert_eq!(history.measurements, peripheral.entries[..5]);
+        assert_eq!(sink.pages.concat(), peripheral.entries[..5]);
+        assert_eq!(peripheral.cursor.pending(&peripheral.entries), &peripheral.entries[5..]);
+    }
+
+    #[tokio::test]
+    async fn test_decode_failure_stops_the_transfer() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES).difference(Capabilities::AUTHENTICATED);
+        let mut peripheral = MockPeripheral::new(12, capabilities);
+        peripheral.corrupt = Some(7);
+        let mut sink = MockSink::default();
+
+        let history = drain_history(&mut peripheral, capabilities, None, &mut sink).await.unwrap();
+
+        // The entries after the failure on the same page are read again on the next sync
+        assert_eq!(history.decode_failures, 1);
+        assert_eq!(history.measurements, peripheral.entries[..7]);
+        assert_eq!(sink.pages.concat(), peripheral.entries[..7]);
+        assert_eq!(peripheral.cursor.pending(&peripheral.entries), &peripheral.entries[7..]);
+
+        // Nothing is acknowledged while the failing entry comes first
+        let history = drain_history(&mut peripheral, capabilities, None, &mut MockSink::default()).await.unwrap();
+        assert!(history.measurements.is_empty());
+        assert_eq!(peripheral.cursor.pending(&peripheral.entries), &peripheral.entries[7..]);
+    }
+
+    #[tokio::test]
+    async fn test_drain_history_without_paging_reads_once() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::PAGED_HISTORY);
+        let mut peripheral = MockPeripheral::new(8, capabilities);
+
+        let history = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut MockSink::default()).await.unwrap();
+
+        assert_eq!(history.measurements, peripheral.entries);
+    }
+
+    #[tokio::test]
+    async fn test_dropped_connection_keeps_unacknowledged_entries() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+        let mut peripheral = MockPeripheral::new(12, capabilities);
+        peripheral.reads_left = 2;
+
+        let history = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut MockSink::default()).await.unwrap();
+
+        // Both pages received are acknowledged, the rest stays on the peripheral
+        assert_eq!(history.measurements, peripheral.entries[..10]);
+        assert_eq!(peripheral.cursor.pending(&peripheral.entries), &peripheral.entries[10..]);
+
+        peripheral.reads_left = usize::MAX;
+        let history = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut MockSink::default()).await.unwrap();
+
+        assert_eq!(history.measurements, peripheral.entries[10..]);
+        assert!(peripheral.cursor.is_drained(&peripheral.entries));
+    }
+
+    #[tokio::test]
+    async fn test_rejected_pages_are_never_acknowledged() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+        let mut peripheral = MockPeripheral::new(12, capabilities);
+
+        // Forged by a device without the station key
+        let forged = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new([0x24; 32], 0)), &mut MockSink::default()).await;
+        assert!(forged.is_err());
+        assert!(peripheral.cursor.acked_until().is_none());
+
+        // A counter the central already accepted is a replay
+        let replayed = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, u32::MAX)), &mut MockSink::default()).await;
+        assert!(replayed.is_err());
+        assert!(peripheral.cursor.acked_until().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_keyed_station_requires_authenticated_frames() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::AUTHENTICATED);
+        let mut peripheral = MockPeripheral::new(4, capabilities);
+
+        let result = drain_history(&mut peripheral, capabilities, Some(&mut Verifier::new(KEY, 0)), &mut MockSink::default()).await;
+        assert!(result.is_err());
+
+        // Stations without a key keep syncing as before
+        let history = drain_history(&mut peripheral, capabilities, None, &mut MockSink::default()).await.unwrap();
+        assert_eq!(history.measurements, peripheral.entries);
+    }
+
+    #[tokio::test]
+    async fn test_signing_station_without_key_is_rejected() {
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+        let mut peripheral = MockPeripheral::new(4, capabilities);
+        let mut sink = MockSink::default();
+
+        assert!(drain_history(&mut peripheral, capabilities, None, &mut sink).await.is_err());
+        assert!(sink.pages.is_empty());
+        assert!(peripheral.cursor.acked_until().is_none());
+    }
+}
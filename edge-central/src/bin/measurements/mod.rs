@@ -1,9 +1,13 @@
 use chrono::TimeDelta;
 use crate::cfg::PeripheralSyncMode;
 use crate::measurements::random::RandomPeripheralSyncResultStreamProvider;
-use crate::measurements::types::PeripheralSyncResultStreamProvider;
+use crate::measurements::types::{PeripheralSyncResultStreamProvider, SyncContext};
 
+pub mod broadcast;
+pub mod commands;
+pub mod history;
 pub mod random;
+pub mod scan;
 pub mod types;
 
 #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
@@ -13,13 +17,14 @@ pub mod btleplug;
 
 pub async fn make_peripheral_sync_stream_provider(
     mode: &PeripheralSyncMode,
+    context: SyncContext,
 ) -> anyhow::Result<Box<dyn PeripheralSyncResultStreamProvider>> {
     match mode {
         PeripheralSyncMode::Ble => {
             {
                 #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
                 {
-                    let provider = btleplug::BtleplugPeripheralSyncResultStreamProvider::new().await?;
+                    let provider = btleplug::BtleplugPeripheralSyncResultStreamProvider::new(context).await?;
 
                     anyhow::Ok(Box::new(provider))
                 }
@@ -27,18 +32,40 @@ pub async fn make_peripheral_sync_stream_provider(
                 #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
                 {
                     use bt_hci::controller::ExternalController;
-                    use tokio::task::LocalSet;
                     use crate::{ble::hci::Transport, measurements::trouble::TroublePeripheralSyncResultStreamProvider};
 
-                    let transport = Transport::new(0)?;
-                    let controller = ExternalController::<_, 8>::new(transport);
-                    let ls = LocalSet::new();
-                    let provider = TroublePeripheralSyncResultStreamProvider::new(controller, ls).await;
+                    // The socket registers with the runtime of the worker thread
+                    let controller = || anyhow::Ok(ExternalController::<_, 8>::new(Transport::new(0)?));
+                    let provider = TroublePeripheralSyncResultStreamProvider::new(controller, context)?;
                     anyhow::Ok(Box::new(provider))
                 }
             }
 
         }
+        PeripheralSyncMode::BleBroadcast => {
+            // Broadcasts aren't signed, listening to them would bypass the authentication of the stations
+            if context.fleet_key.is_some() || context.require_authentication {
+                return Err(anyhow::anyhow!("Broadcast mode can't authenticate stations, unset APP.STATION_AUTH to use it"));
+            }
+
+            {
+                #[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+                {
+                    use bt_hci::controller::ExternalController;
+                    use crate::{ble::hci::Transport, measurements::trouble::TroublePeripheralSyncResultStreamProvider};
+
+                    // The socket registers with the runtime of the worker thread
+                    let controller = || anyhow::Ok(ExternalController::<_, 8>::new(Transport::new(0)?));
+                    let provider = TroublePeripheralSyncResultStreamProvider::passive(controller, context)?;
+                    anyhow::Ok(Box::new(provider))
+                }
+
+                #[cfg(not(all(target_os = "linux", target_arch = "aarch64")))]
+                {
+                    Err(anyhow::anyhow!("Broadcast mode requires the HCI transport"))
+                }
+            }
+        }
         PeripheralSyncMode::Random => {
             let provider = RandomPeripheralSyncResultStreamProvider::new(
                 [0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa],
@@ -39,6 +39,9 @@ impl PeripheralSyncResultStreamProvider for RandomPeripheralSyncResultStreamProv
                 address: mac,
                 time_drift: TimeDelta::zero(),
                 measurements,
+                decode_failures: 0,
+                rssi: None,
+                sync_id: None,
             };
 
             sleep(delay).await;
@@ -52,10 +55,12 @@ impl PeripheralSyncResultStreamProvider for RandomPeripheralSyncResultStreamProv
 
 fn random_measurement() -> Measurement {
     Measurement {
-        battery: (rand::random::<u8>() % 101) as u8,
-        lux: (rand::random::<u32>() % 100001) as f32,
-        temperature: (rand::random::<u32>() % 46) as f32,
-        humidity: (rand::random::<u32>() % 101) as f32,
-        soil_pf: (rand::random::<u32>() % 450) as f32,
+        battery: Some((rand::random::<u8>() % 101) as u8),
+        lux: Some((rand::random::<u32>() % 100001) as f32),
+        temperature: Some((rand::random::<u32>() % 46) as f32),
+        humidity: Some((rand::random::<u32>() % 101) as f32),
+        soil_pf: Some((rand::random::<u32>() % 450) as f32),
+        tank_pf: Some((rand::random::<u32>() % 450) as f32),
+        battery_mv: Some(3300 + rand::random::<u16>() % 900),
     }
 }
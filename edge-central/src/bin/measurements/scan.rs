@@ -0,0 +1,179 @@
+use edge_protocol::{DEVICE_NAME, MEASUREMENT_SERVICE_UUID_16};
+
+const AD_TYPE_INCOMPLETE_SERVICE_UUIDS_16: u8 = 0x02;
+const AD_TYPE_COMPLETE_SERVICE_UUIDS_16: u8 = 0x03;
+const AD_TYPE_SHORTENED_LOCAL_NAME: u8 = 0x08;
+const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+
+/// Shortest shortened name taken for the name of a station, a name cut to a letter or two matches too many devices
+const MIN_SHORTENED_NAME_LEN: usize = 4;
+
+/// The AD structures of an advertisement that tell a station apart from other devices
+#[derive(Debug, Default, PartialEq)]
+pub struct Advertisement {
+    /// 16-bit UUIDs of the advertised services, including those carrying service data
+    pub service_uuids: Vec<u16>,
+    pub local_name: Option<String>,
+    /// Whether the advertised name is cut short
+    pub shortened_name: bool,
+}
+
+impl Advertisement {
+    /// Parse raw advertising data, a truncated structure ends it
+    pub fn parse(data: &[u8]) -> Self {
+        let mut advertisement = Advertisement::default();
+        let mut rest = data;
+
+        while let [len, tail @ ..] = rest {
+            let len = *len as usize;
+            if len == 0 || tail.len() < len {
+                break;
+            }
+
+            let (structure, next) = tail.split_at(len);
+            rest = next;
+
+            match structure {
+                [AD_TYPE_INCOMPLETE_SERVICE_UUIDS_16 | AD_TYPE_COMPLETE_SERVICE_UUIDS_16, uuids @ ..] => advertisement
+                    .service_uuids
+                    .extend(uuids.chunks_exact(2).map(|uuid| u16::from_le_bytes([uuid[0], uuid[1]]))),
+                [AD_TYPE_SERVICE_DATA_16, lo, hi, ..] => advertisement.service_uuids.push(u16::from_le_bytes([*lo, *hi])),
+                [kind @ (AD_TYPE_SHORTENED_LOCAL_NAME | AD_TYPE_COMPLETE_LOCAL_NAME), name @ ..] => {
+                    advertisement.local_name = Some(String::from_utf8_lossy(name).into_owned());
+                    advertisement.shortened_name = *kind == AD_TYPE_SHORTENED_LOCAL_NAME;
+                }
+                _ => {}
+            }
+        }
+
+        advertisement
+    }
+
+    /// Whether the advertisement comes from a station, by its measurement service or its name
+    pub fn is_station(&self) -> bool {
+        let name_matches = match &self.local_name {
+            Some(name) if self.shortened_name => name.len() >= MIN_SHORTENED_NAME_LEN && DEVICE_NAME.starts_with(name.as_str()),
+            Some(name) => name == DEVICE_NAME,
+            None => false,
+        };
+
+        name_matches || self.service_uuids.contains(&MEASUREMENT_SERVICE_UUID_16)
+    }
+}
+
+/// A station seen while scanning
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discovered<A> {
+    pub addr: A,
+    /// Signal strength of the latest report in dBm
+    pub rssi: i8,
+}
+
+/// Stations seen during a scan, each listed once however often it advertises
+#[derive(Debug)]
+pub struct Discoveries<A> {
+    devices: Vec<Discovered<A>>,
+}
+
+impl<A> Default for Discoveries<A> {
+    fn default() -> Self {
+        Self { devices: vec![] }
+    }
+}
+
+impl<A: PartialEq> Discoveries<A> {
+    /// Handle an advertising report, devices that aren't stations are ignored
+    pub fn on_report(&mut self, addr: A, data: &[u8], rssi: i8) {
+        if !Advertisement::parse(data).is_station() {
+            return;
+        }
+
+        match self.devices.iter_mut().find(|device| device.addr == addr) {
+            Some(device) => device.rssi = rssi,
+            None => self.devices.push(Discovered { addr, rssi }),
+        }
+    }
+
+    /// Take the stations seen since the last call, in the order they were first seen
+    pub fn take(&mut self) -> Vec<Discovered<A>> {
+        std::mem::take(&mut self.devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edge_protocol::CURRENT_TIME_SERVICE_UUID;
+
+    /// Advertising data of a station that isn't broadcasting
+    fn station() -> Vec<u8> {
+        let [time_lo, time_hi] = CURRENT_TIME_SERVICE_UUID.to_le_bytes();
+        let [lo, hi] = MEASUREMENT_SERVICE_UUID_16.to_le_bytes();
+
+        let mut data = vec![2, 0x01, 0x06, 5, AD_TYPE_INCOMPLETE_SERVICE_UUIDS_16, time_lo, time_hi, lo, hi];
+        data.extend([1 + DEVICE_NAME.len() as u8, AD_TYPE_COMPLETE_LOCAL_NAME]);
+        data.extend(DEVICE_NAME.as_bytes());
+        data
+    }
+
+    #[test]
+    fn test_parse_station() {
+        let advertisement = Advertisement::parse(&station());
+
+        assert_eq!(advertisement.service_uuids, vec![CURRENT_TIME_SERVICE_UUID, MEASUREMENT_SERVICE_UUID_16]);
+        assert_eq!(advertisement.local_name.as_deref(), Some(DEVICE_NAME));
+        assert!(advertisement.is_station());
+    }
+
+    #[test]
+    fn test_station_matches_by_service_or_name() {
+        let [lo, hi] = MEASUREMENT_SERVICE_UUID_16.to_le_bytes();
+
+        // Broadcasting stations carry service data, the name is in the scan response
+        assert!(Advertisement::parse(&[2, 0x01, 0x06, 4, AD_TYPE_SERVICE_DATA_16, lo, hi, 1]).is_station());
+        assert!(Advertisement::parse(&[5, AD_TYPE_SHORTENED_LOCAL_NAME, b'M', b'y', b'c', b'e']).is_station());
+        assert!(Advertisement::parse(&[9, AD_TYPE_COMPLETE_LOCAL_NAME, b'M', b'y', b'c', b'e', b'l', b'i', b'u', b'm']).is_station());
+    }
+
+    #[test]
+    fn test_other_devices_are_not_stations() {
+        let [time_lo, time_hi] = CURRENT_TIME_SERVICE_UUID.to_le_bytes();
+
+        assert!(!Advertisement::parse(&[2, 0x01, 0x06]).is_station());
+        assert!(!Advertisement::parse(&[3, AD_TYPE_COMPLETE_SERVICE_UUIDS_16, time_lo, time_hi]).is_station());
+        assert!(!Advertisement::parse(&[5, AD_TYPE_COMPLETE_LOCAL_NAME, b'M', b'y', b'c', b'e']).is_station());
+        assert!(!Advertisement::parse(&[1, AD_TYPE_SHORTENED_LOCAL_NAME]).is_station());
+        assert!(!Advertisement::parse(&[2, AD_TYPE_SHORTENED_LOCAL_NAME, b'M']).is_station());
+        assert!(!Advertisement::parse(&[4, AD_TYPE_SHORTENED_LOCAL_NAME, b'M', b'y', b'c']).is_station());
+        assert!(!Advertisement::parse(&[]).is_station());
+    }
+
+    #[test]
+    fn test_parse_stops_at_truncated_structure() {
+        let mut data = station();
+        let len = data.len();
+        data[len - DEVICE_NAME.len() - 2] += 1;
+
+        let advertisement = Advertisement::parse(&data);
+
+        assert_eq!(advertisement.service_uuids.len(), 2);
+        assert_eq!(advertisement.local_name, None);
+    }
+
+    #[test]
+    fn test_discoveries_are_deduplicated_with_latest_rssi() {
+        let mut discoveries = Discoveries::default();
+
+        discoveries.on_report([0xaa; 6], &station(), -70);
+        discoveries.on_report([0xcc; 6], &[2, 0x01, 0x06], -40);
+        discoveries.on_report([0xbb; 6], &station(), -80);
+        discoveries.on_report([0xaa; 6], &station(), -65);
+
+        assert_eq!(
+            discoveries.take(),
+            vec![Discovered { addr: [0xaa; 6], rssi: -65 }, Discovered { addr: [0xbb; 6], rssi: -80 }]
+        );
+        assert!(discoveries.take().is_empty());
+    }
+}
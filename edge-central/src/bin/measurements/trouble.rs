@@ -3,6 +3,7 @@ use std::cell::RefCell;
 use bt_hci::cmd::le::LeSetScanParams;
 use bt_hci::controller::ControllerCmdSync;
 use futures::future::Either;
+use futures::future::select;
 use futures::future::try_select;
 use tracing::error;
 use tracing::info;
@@ -10,52 +11,218 @@ use trouble_host::prelude::*;
 use embassy_time::Duration;
 
 use tokio::{sync::mpsc, task::LocalSet};
-use crate::measurements::types::{PeripheralSyncResult, PeripheralSyncResultStreamProvider};
+use crate::ble::ResultAny;
+use crate::measurements::broadcast::BroadcastTracker;
+use crate::data::types::DeviceInformation;
+use crate::measurements::commands::{deliver_commands, CommandTransport};
+use crate::measurements::history::{drain_history, HistoryTransport, SyncSink};
+use crate::measurements::scan::{Discovered, Discoveries};
+use crate::measurements::types::{PeripheralSyncResult, PeripheralSyncResultStreamProvider, SyncContext};
 use edge_protocol::*;
 use anyhow::*;
-use futures::future::try_join;
-use futures::future::FutureExt;
+use async_trait::async_trait;
+
+#[cfg(test)]
+mod mock;
 
 /// Max number of connections
 const CONNECTIONS_MAX: usize = 1;
 const L2CAP_CHANNELS_MAX: usize = 1;
 
+/// Time a device gets to accept a connection, one that went out of range would block the sync otherwise
+const CONNECT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(10);
+const DISCONNECT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(2);
+
 pub struct TroublePeripheralSyncResultStreamProvider {
     rx: mpsc::Receiver<Vec<PeripheralSyncResult>>
 }
 
 impl TroublePeripheralSyncResultStreamProvider
 {
-    async fn retrieve<'a, C : Controller, P : PacketPool, const MAX_SERVICES: usize>(client: &GattClient<'a, C, P, MAX_SERVICES>) -> std::result::Result<PeripheralSyncResult, anyhow::Error> {
+    async fn retrieve<'a, C : Controller, P : PacketPool, const MAX_SERVICES: usize>(client: &GattClient<'a, C, P, MAX_SERVICES>, context: &SyncContext) -> std::result::Result<PeripheralSyncResult, anyhow::Error> {
+
+        let services = client.services_by_uuid(&Uuid::new_short(ADDRESS_SERVICE_UUID_16))
+            .await
+            .anyhow("Failed to retrieve address service")?;
+
+        let service = services.first().ok_or_else(|| anyhow!("No address service found"))?;
+
+        let characteristic = client.characteristic_by_uuid::<[u8; 6]>(service, &Uuid::new_short(ADDRESS_CHARACTERISTIC_UUID_16))
+            .await
+            .anyhow("Couldn't find address characteristic")?;
 
-        info!("Starting to scan for services...");        
+        let mut address = [0u8; 6];
+        client.read_characteristic(&characteristic, &mut address).await.anyhow("Failed to read address")?;
+
+        match TroublePeripheralSyncResultStreamProvider::read_device_information(client).await {
+            std::result::Result::Ok(Some(device)) => {
+                info!(?device, "Read device information");
+                context.station_devices.set(&address, &device).await?;
+            }
+            std::result::Result::Ok(None) => info!("Device does not have the device information service"),
+            Err(err) => tracing::warn!(?err, "Unable to read device information"),
+        }
 
         let services = client.services_by_uuid(&Uuid::new_short(CURRENT_TIME_SERVICE_UUID))
             .await
-            .anyhow("Failed to retrieve services")?;
+            .anyhow("Failed to retrieve current time service")?;
+
+        let service = services.first().ok_or_else(|| anyhow!("No current time service found"))?;
+
+        let current_time = client.characteristic_by_uuid::<[u8; CurrentTime::LEN]>(service, &Uuid::new_short(CURRENT_TIME_CHARACTERISTIC_UUID))
+            .await
+            .anyhow("Couldn't find current time characteristic")?;
+
+        let now = chrono::Utc::now().naive_utc();
+        let mut buffer = [0u8; CurrentTime::LEN];
+        let len = client.read_characteristic(&current_time, &mut buffer).await.anyhow("Failed to read current time")?;
+        let time_drift = now - CurrentTime::try_from_bytes(&buffer[..len])?.to_naivedatetime()?;
 
-        for service in services {
-            info!("Found service {:?}", service)
+        client.write_characteristic(&current_time, &CurrentTime::from_naivedatetime(now).to_bytes())
+            .await
+            .anyhow("Failed to write current time")?;
+
+        info!(%time_drift, "Corrected time of peripheral");
+
+        let services = client.services_by_uuid(&Uuid::new_short(MEASUREMENT_SERVICE_UUID_16))
+            .await
+            .anyhow("Failed to retrieve measurement service")?;
+
+        let service = services.first().ok_or_else(|| anyhow!("No measurement service found"))?;
+
+        let measurement = client.characteristic_by_uuid::<[u8; MAX_AUTHENTICATED_FRAME_LEN]>(service, &Uuid::new_short(MEASUREMENT_CHARACTERISTIC_UUID_16))
+            .await
+            .anyhow("Couldn't find measurement characteristic")?;
+
+        // Peripherals running v1 firmware don't expose the protocol and history ack characteristics
+        let negotiated = match client.characteristic_by_uuid::<[u8; ProtocolHello::LEN]>(service, &Uuid::new_short(PROTOCOL_CHARACTERISTIC_UUID_16)).await {
+            std::result::Result::Ok(protocol) => {
+                let mut buffer = [0u8; ProtocolHello::LEN];
+                let len = client.read_characteristic(&protocol, &mut buffer).await.anyhow("Failed to read protocol")?;
+                let hello = ProtocolHello::from_bytes(&buffer[..len])?;
+                client.write_characteristic(&protocol, &ProtocolHello::current().to_bytes()).await.anyhow("Failed to write protocol")?;
+                ProtocolHello::current().negotiate(&hello)
+            }
+            Err(_) => ProtocolHello::legacy(),
+        };
+
+        info!("Negotiated protocol {:?}", negotiated);
+
+        // Peripherals predating remote configuration don't expose the config characteristic
+        let mut verifier = context.verifier(&address).await?;
+        if let std::result::Result::Ok(config_characteristic) = client.characteristic_by_uuid::<[u8; PeripheralConfig::MAX_SIGNED_TLV_LEN]>(service, &Uuid::new_short(CONFIG_CHARACTERISTIC_UUID_16)).await {
+            if let Some((config, value)) = context.config_write(&address, verifier.as_ref()).await? {
+                client.write_characteristic(&config_characteristic, &value).await.anyhow("Failed to write config")?;
+                info!("Wrote configuration {:?}", config);
+            }
         }
 
-        // let service: &ServiceHandle = services.first().ok_or_else(|| anyhow!("No service found"))?;
+        let history_ack = client.characteristic_by_uuid::<[u8; HistoryAck::LEN]>(service, &Uuid::new_short(HISTORY_ACK_CHARACTERISTIC_UUID_16))
+            .await
+            .ok();
+
+        let sync_id = context.syncs.begin(&address, now, time_drift).await?;
+        let mut sink = SyncSink { syncs: &context.syncs, id: sync_id, address };
+        let mut transport = TroubleHistoryTransport { client, measurement, history_ack };
+        let history = drain_history(&mut transport, negotiated.capabilities, verifier.as_mut(), &mut sink).await?;
 
-        // info!("Found time service");
+        if let Some(verifier) = &verifier {
+            if !context.station_keys.advance_counter(&address, verifier.counter()).await? {
+                return Err(anyhow!("Frame counter of {:02x?} didn't advance, possible replay", address));
+            }
+        }
 
-        // let _characteristic = client.characteristic_by_uuid::<u8>(&service, &Uuid::new_short(CURRENT_TIME_CHARACTERISTIC_UUID))
-        //     .await
-        //     .anyhow("Couldn't find charachteristic");
+        // Peripherals predating commands don't expose the command characteristic, pending commands wait for an update
+        if let std::result::Result::Ok(command) = client.characteristic_by_uuid::<[u8; CommandRequest::MAX_SIGNED_LEN]>(service, &Uuid::new_short(COMMAND_CHARACTERISTIC_UUID_16)).await {
+            let mut transport = TroubleCommandTransport { client, command };
+            if let Err(err) = deliver_commands(&mut transport, &context, &address, verifier.as_ref().map(Verifier::key)).await {
+                tracing::warn!(?err, "Unable to deliver commands, keeping them for the next sync");
+            }
+        }
 
-        let result = PeripheralSyncResult { address: [0x00, 0x00, 0x00, 0x00, 0x00, 0x00], time_drift: chrono::TimeDelta::seconds(0), measurements: vec![] };
+        let result = PeripheralSyncResult { address, time_drift, measurements: history.measurements, decode_failures: history.decode_failures, rssi: None, sync_id: Some(sync_id) };
 
         Ok(result)
     }
 
-    async fn worker<C : Controller + ControllerCmdSync<LeSetScanParams> + 'static>(controller: C, tx: mpsc::Sender<Vec<PeripheralSyncResult>>) -> Result<()> {
+    /// Read the device information service, `None` for peripherals predating it
+    async fn read_device_information<'a, C : Controller, P : PacketPool, const MAX_SERVICES: usize>(client: &GattClient<'a, C, P, MAX_SERVICES>) -> Result<Option<DeviceInformation>> {
+        let services = client.services_by_uuid(&Uuid::new_short(DEVICE_INFORMATION_SERVICE_UUID))
+            .await
+            .anyhow("Failed to retrieve device information service")?;
+
+        let Some(service) = services.first() else {
+            return Ok(None);
+        };
+
+        let mut values = vec![];
+        for uuid in [MODEL_NUMBER_CHARACTERISTIC_UUID, FIRMWARE_REVISION_CHARACTERISTIC_UUID, HARDWARE_REVISION_CHARACTERISTIC_UUID] {
+            let characteristic = client.characteristic_by_uuid::<[u8; MAX_DEVICE_INFORMATION_LEN]>(service, &Uuid::new_short(uuid))
+                .await
+                .anyhow("Couldn't find device information characteristic")?;
+
+            let mut buffer = [0u8; MAX_DEVICE_INFORMATION_LEN];
+            let len = client.read_characteristic(&characteristic, &mut buffer).await.anyhow("Failed to read device information")?;
+            values.push(String::from_utf8_lossy(&buffer[..len]).into_owned());
+        }
+
+        let [model, firmware_version, hardware_revision]: [String; 3] = values.try_into().map_err(|_| anyhow!("Incomplete device information"))?;
+
+        Ok(Some(DeviceInformation { model, firmware_version, hardware_revision }))
+    }
+
+    /// Connect to a device and retrieve its measurements, the host runner has to run meanwhile
+    async fn sync<C : Controller>(stack: &Stack<'_, C, DefaultPacketPool>, central: &mut Central<'_, C, DefaultPacketPool>, addr_kind: AddrKind, addr: &BdAddr, context: &SyncContext) -> Result<PeripheralSyncResult> {
+        let connect_config = ConnectConfig {
+            connect_params: ConnectParams {
+                min_connection_interval: Duration::from_micros(7500),
+                max_connection_interval: Duration::from_micros(7500),
+                max_latency: 500,
+                supervision_timeout: Duration::from_secs(10),
+                ..Default::default()
+            },
+            scan_config: ScanConfig {
+                filter_accept_list: &[(addr_kind, addr)],
+                ..Default::default()
+            },
+        };
+
+        let conn = tokio::time::timeout(CONNECT_TIMEOUT, central.connect(&connect_config))
+            .await
+            .map_err(|_| anyhow!("Device didn't accept the connection in time"))?
+            .anyhow("Failed to connected to BLE device")?;
+
+        let result = async {
+            let client: GattClient::<_, DefaultPacketPool, 10> = GattClient::new(stack, &conn)
+                .await
+                .anyhow("Failed to construct GATT client")?;
+
+            match select(Box::pin(client.task()), Box::pin(TroublePeripheralSyncResultStreamProvider::retrieve(&client, context))).await {
+                Either::Left((res, _)) => Err(anyhow!("Connection lost: {:?}", res)),
+                Either::Right((result, _)) => result,
+            }
+        }.await;
+
+        // The peripheral only goes back to sleep once the link is gone, also after a failed sync
+        conn.disconnect();
+        let disconnected = tokio::time::timeout(DISCONNECT_TIMEOUT, async {
+            while conn.is_connected() {
+                tokio::time::sleep(tokio::time::Duration::from_millis(10)).await;
+            }
+        }).await;
+
+        if disconnected.is_err() {
+            tracing::warn!(?addr, "Device didn't disconnect in time");
+        }
+
+        result
+    }
+
+    async fn worker<C : Controller + ControllerCmdSync<LeSetScanParams> + 'static>(controller: C, tx: mpsc::Sender<Vec<PeripheralSyncResult>>, context: SyncContext) -> Result<()> {
         
         let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> = HostResources::new();
         let stack = trouble_host::new(controller, &mut resources);
-        let tracker = BdAddrTracker { devices: RefCell::new(Vec::new()) };
+        let tracker = BdAddrTracker::new();
         let mut config = ScanConfig::default();
         config.active = false;
         config.phys = PhySet::M1;
@@ -66,6 +233,9 @@ impl TroublePeripheralSyncResultStreamProvider
             let Host { central, mut runner, .. } = stack.build();
             let mut scanner = Scanner::new(central);
 
+            // Stations that stopped advertising since the last round aren't connected to
+            tracker.devices.borrow_mut().take();
+
             info!("Start scanning for devices");
 
             let run = Box::pin(runner.run_with_handler(&tracker));
@@ -84,55 +254,130 @@ impl TroublePeripheralSyncResultStreamProvider
 
             info!("Finished scanning device");
 
+            // Connected syncs retrieve the full history, broadcasts seen meanwhile add nothing
+            tracker.broadcasts.borrow_mut().take();
+
+            let devices = tracker.devices.borrow_mut().take();
             let Host { mut central, mut runner, .. } = stack.build();
-            let mut results = vec![];
 
-            for (addr_kind, addr) in tracker.devices.borrow().clone() {
-                let connect_config = ConnectConfig {
-                    connect_params: ConnectParams {
-                        min_connection_interval: Duration::from_micros(7500),
-                        max_connection_interval: Duration::from_micros(7500),
-                        max_latency: 500,
-                        supervision_timeout: Duration::from_secs(10),
-                        ..Default::default()
-                    },
-                    scan_config: ScanConfig {
-                        filter_accept_list: &[(addr_kind, &addr)],
-                        ..Default::default()
-                    },
-                };
-
-                let (_, conn) = try_join(runner.run(), central.connect(&connect_config))
-                    .await
-                    .anyhow("Failed to connected to BLE device")?;
-
-                let client: GattClient::<_, DefaultPacketPool, 10> = GattClient::new(&stack, &conn)
-                    .await
-                    .anyhow("Failed to construct GATT client")?;
-
-                let (_, res) = try_join(client.task().map(|x| x.anyhow("Sync failed")), TroublePeripheralSyncResultStreamProvider::retrieve(&client))
-                    .await?;
-                
-                results.push(res);
+            // A device failing to sync is retried next round, it doesn't hold up the others
+            let sync = Box::pin(async {
+                let mut results = vec![];
+
+                for Discovered { addr: (addr_kind, addr), rssi } in devices {
+                    match TroublePeripheralSyncResultStreamProvider::sync(&stack, &mut central, addr_kind, &addr, &context).await {
+                        std::result::Result::Ok(result) => results.push(PeripheralSyncResult { rssi: Some(rssi.into()), ..result }),
+                        Err(err) => tracing::warn!(?err, ?addr, "Unable to sync device"),
+                    }
+                }
+
+                results
+            });
+
+            let results = match select(Box::pin(runner.run()), sync).await {
+                Either::Left((res, _)) => return Err(anyhow!("BLE host stopped while syncing: {:?}", res)),
+                Either::Right((results, _)) => results,
+            };
+
+            tx.send(results).await.anyhow("Push failed")?;
+        }
+    }
+
+    /// Only listens to the measurements peripherals broadcast, without ever connecting to them
+    /// Broadcasts can't be signed, those of stations with a key are dropped rather than trusted
+    async fn passive_worker<C : Controller + ControllerCmdSync<LeSetScanParams> + 'static>(controller: C, tx: mpsc::Sender<Vec<PeripheralSyncResult>>, context: SyncContext) -> Result<()> {
+
+        let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> = HostResources::new();
+        let stack = trouble_host::new(controller, &mut resources);
+        let tracker = BdAddrTracker::new();
+        let mut config = ScanConfig::default();
+        config.active = false;
+        config.phys = PhySet::M1;
+        config.interval = Duration::from_secs(1);
+        config.window = Duration::from_secs(1);
+
+        loop {
+            let Host { central, mut runner, .. } = stack.build();
+            let mut scanner = Scanner::new(central);
+
+            let run = Box::pin(runner.run_with_handler(&tracker));
+            let scan = Box::pin(async {
+                let session = scanner.scan(&config).await;
+                tokio::time::sleep(tokio::time::Duration::from_secs(10)).await;
+                session.map(|_| ())
+            });
+
+            match try_select(run, scan).await {
+                std::result::Result::Ok(Either::Left(_)) => Err(anyhow!("Error")),
+                std::result::Result::Ok(Either::Right(_)) => Ok(()),
+                std::result::Result::Err(Either::Left(_err)) => Err(anyhow!("Error occured")),
+                std::result::Result::Err(Either::Right(_err)) => Err(anyhow!("Error occured"))
+            }?;
+
+            let broadcasts = tracker.broadcasts.borrow_mut().take();
+            let mut results = vec![];
+            for result in broadcasts {
+                if context.station_keys.find_verifier(&result.address).await?.is_some() {
+                    tracing::warn!(address = ?result.address, "Dropping unauthenticated broadcast of a station with a key");
+                    continue;
+                }
+                results.push(result);
             }
 
+            info!("Received broadcasts from {} devices", results.len());
+
             tx.send(results).await.anyhow("Push failed")?;
         }
     }
 
-    pub async fn new<C : Controller + ControllerCmdSync<LeSetScanParams> + 'static>(controller: C, ls: LocalSet) -> Self {
+    /// Run a worker on a thread of its own, the BLE host isn't `Send` so it lives in a local task set
+    fn spawn<F, W>(name: &str, worker: F) -> Result<()>
+    where
+        F: FnOnce() -> W + Send + 'static,
+        W: std::future::Future<Output = Result<()>> + 'static,
+    {
+        let runtime = tokio::runtime::Builder::new_current_thread().enable_all().build()?;
+
+        std::thread::Builder::new().name(name.to_string()).spawn(move || {
+            if let Err(err) = LocalSet::new().block_on(&runtime, worker()) {
+                error!(?err, "BLE worker stopped");
+            }
+        })?;
 
+        Ok(())
+    }
+
+    /// Connect to every device in range each round, the controller is created on the worker thread
+    pub fn new<C, F>(controller: F, context: SyncContext) -> Result<Self>
+    where
+        C: Controller + ControllerCmdSync<LeSetScanParams> + 'static,
+        F: FnOnce() -> Result<C> + Send + 'static,
+    {
         let (tx, rx) = mpsc::channel(32);
 
-        ls.run_until(async move {
-            if let Err(err) = tokio::task::spawn_local(TroublePeripheralSyncResultStreamProvider::worker(controller, tx)).await {
-                error!("Doesn't compute {:?}", err)
-            }
-        }).await;
+        TroublePeripheralSyncResultStreamProvider::spawn("ble-sync", move || async move {
+            TroublePeripheralSyncResultStreamProvider::worker(controller()?, tx, context).await
+        })?;
 
-        info!("Succesfully spawned local background task");
+        info!("Succesfully spawned background BLE worker");
 
-        Self { rx }
+        Ok(Self { rx })
+    }
+
+    pub fn passive<C, F>(controller: F, context: SyncContext) -> Result<Self>
+    where
+        C: Controller + ControllerCmdSync<LeSetScanParams> + 'static,
+        F: FnOnce() -> Result<C> + Send + 'static,
+    {
+        let (tx, rx) = mpsc::channel(32);
+
+        TroublePeripheralSyncResultStreamProvider::spawn("ble-passive", move || async move {
+            TroublePeripheralSyncResultStreamProvider::passive_worker(controller()?, tx, context).await
+        })?;
+
+        info!("Succesfully spawned passive scan worker");
+
+        Ok(Self { rx })
     }
 }
 
@@ -144,31 +389,452 @@ impl PeripheralSyncResultStreamProvider for TroublePeripheralSyncResultStreamPro
     }
 }
 
+struct TroubleHistoryTransport<'a, 'b, C: Controller, P: PacketPool, const MAX_SERVICES: usize> {
+    client: &'b GattClient<'a, C, P, MAX_SERVICES>,
+    measurement: Characteristic<[u8; MAX_AUTHENTICATED_FRAME_LEN]>,
+    history_ack: Option<Characteristic<[u8; HistoryAck::LEN]>>,
+}
+
+#[async_trait(?Send)]
+impl<C: Controller, P: PacketPool, const MAX_SERVICES: usize> HistoryTransport for TroubleHistoryTransport<'_, '_, C, P, MAX_SERVICES> {
+    async fn read_page(&mut self) -> Result<Vec<u8>> {
+        let mut buffer = [0u8; MAX_AUTHENTICATED_FRAME_LEN];
+        let len = self.client.read_characteristic(&self.measurement, &mut buffer)
+            .await
+            .anyhow("Failed to read measurement page")?;
+
+        Ok(buffer[..len].to_vec())
+    }
+
+    async fn write_ack(&mut self, ack: &[u8]) -> Result<()> {
+        let history_ack = self.history_ack.as_ref().ok_or_else(|| anyhow!("No history ack characteristic found"))?;
+
+        self.client.write_characteristic(history_ack, ack)
+            .await
+            .anyhow("Failed to write history ack")
+    }
+}
+
+struct TroubleCommandTransport<'a, 'b, C: Controller, P: PacketPool, const MAX_SERVICES: usize> {
+    client: &'b GattClient<'a, C, P, MAX_SERVICES>,
+    command: Characteristic<[u8; CommandRequest::MAX_SIGNED_LEN]>,
+}
+
+#[async_trait(?Send)]
+impl<C: Controller, P: PacketPool, const MAX_SERVICES: usize> CommandTransport for TroubleCommandTransport<'_, '_, C, P, MAX_SERVICES> {
+    async fn send(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        self.client.write_characteristic(&self.command, request)
+            .await
+            .anyhow("Failed to write command")?;
+
+        let mut buffer = [0u8; CommandResponse::LEN];
+        let len = self.client.read_characteristic(&self.command, &mut buffer)
+            .await
+            .anyhow("Failed to read command response")?;
+
+        Ok(buffer[..len].to_vec())
+    }
+}
+
 struct BdAddrTracker {
-    pub devices: RefCell<Vec<(AddrKind, BdAddr)>>
+    pub devices: RefCell<Discoveries<(AddrKind, BdAddr)>>,
+    pub broadcasts: RefCell<BroadcastTracker>,
+}
+
+impl BdAddrTracker {
+    fn new() -> Self {
+        Self { devices: RefCell::new(Discoveries::default()), broadcasts: RefCell::new(BroadcastTracker::default()) }
+    }
 }
 
 impl EventHandler for BdAddrTracker {
     fn on_adv_reports(&self, mut it: LeAdvReportsIter<'_>) {
         let mut devices = self.devices.borrow_mut();
-        devices.clear();
+        let mut broadcasts = self.broadcasts.borrow_mut();
+        let now = chrono::Utc::now();
         while let Some(std::result::Result::Ok(report)) = it.next() {
-            //TODO: see in report if it matches
+            tracing::debug!("Advertising data for {:?} --> {:02x?}", report.addr, report.data);
 
-            info!("Advertising data for {:?} --> {:02x?}", report.addr, report.data);
+            let mut address = [0u8; 6];
+            address.copy_from_slice(report.addr.raw());
+            broadcasts.on_adv_data(address, report.data, now);
 
-            devices.push((report.addr_kind, report.addr));
+            devices.on_report((report.addr_kind, report.addr), report.data, report.rssi);
         }
     }
 }
 
-pub trait ResultAny<T, E> {
-    fn anyhow(self, ctx: &'static str) -> Result<T, anyhow::Error>;
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    use bt_hci::controller::ExternalController;
+    use chrono::Timelike;
+
+    use crate::data::sqlite::{test_pool, SqliteStationCommandRepository, SqliteStationDeviceRepository, SqliteStationKeyRepository, SqliteStationSettingsRepository, SqliteSyncRepository};
+    use crate::measurements::trouble::mock::{att_error, Gatt, MockPeripheral, MockTransport, Server, ATT_READ_REQ, CONNECTION_FAILED_TO_BE_ESTABLISHED, READ_NOT_PERMITTED};
+    use edge_peripheral_core::gatt::{self, Access, GattServer};
+    use edge_peripheral_core::{Clock, CommandQueue, Session};
+    use edge_protocol::auth::downlink_key;
+
+    const STATION: [u8; 6] = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01];
+    const OTHER_STATION: [u8; 6] = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x02];
+
+    async fn context() -> SyncContext {
+        let pool = test_pool().await;
+
+        SyncContext {
+            station_keys: Arc::new(SqliteStationKeyRepository::new(pool.clone())),
+            station_settings: Arc::new(SqliteStationSettingsRepository::new(pool.clone())),
+            station_commands: Arc::new(SqliteStationCommandRepository::new(pool.clone())),
+            station_devices: Arc::new(SqliteStationDeviceRepository::new(pool.clone())),
+            syncs: Arc::new(SqliteSyncRepository::new(pool)),
+            fleet_key: None,
+            require_authentication: false,
+        }
+    }
 
-impl<T, E: core::fmt::Debug> ResultAny<T, E> for Result<T, E> {
-    fn anyhow(self, ctx: &'static str) -> Result<T, anyhow::Error> {
-        self.map_err(|e| anyhow::anyhow!("{}: {:?}", ctx, e))
+    fn entries(count: i64) -> Vec<MeasurementSerieEntry> {
+        (0..count)
+            .map(|i| MeasurementSerieEntry {
+                timestamp: chrono::DateTime::from_timestamp(1_700_000_000 + i * 60, 0).unwrap().naive_utc(),
+                measurement: Measurement { temperature: Some(20.0 + i as f32), ..Measurement::EMPTY },
+            })
+            .collect()
+    }
+
+    /// Clock of the stations, an hour behind
+    fn station_time() -> chrono::NaiveDateTime {
+        chrono::Utc::now().naive_utc() - chrono::TimeDelta::hours(1)
+    }
+
+    /// Station running v1 firmware, serving a single page of history
+    fn station(address: [u8; 6], entries: &[MeasurementSerieEntry]) -> MockPeripheral {
+        let mut page = [0u8; MAX_AUTHENTICATED_FRAME_LEN];
+        let len = Frame::encode(Capabilities::LEGACY, entries, &mut page).unwrap();
+
+        let gatt = Gatt::default()
+            .service(ADDRESS_SERVICE_UUID_16)
+            .characteristic(ADDRESS_CHARACTERISTIC_UUID_16, &address)
+            .service(CURRENT_TIME_SERVICE_UUID)
+            .characteristic(CURRENT_TIME_CHARACTERISTIC_UUID, &CurrentTime::from_naivedatetime(station_time()).to_bytes())
+            .service(MEASUREMENT_SERVICE_UUID_16)
+            .characteristic(MEASUREMENT_CHARACTERISTIC_UUID_16, &page[..len]);
+
+        MockPeripheral::new(address, gatt)
+    }
+
+    /// Clock of a simulated station
+    struct StationClock(chrono::NaiveDateTime);
+
+    impl Clock for StationClock {
+        fn now(&self) -> chrono::NaiveDateTime {
+            self.0
+        }
+
+        fn set(&mut self, now: chrono::NaiveDateTime) {
+            self.0 = now;
+        }
+    }
+
+    /// Station serving its history through the GATT server of the peripheral core, like the simulator does
+    struct Simulated {
+        address: [u8; 6],
+        entries: Vec<MeasurementSerieEntry>,
+        cursor: HistoryCursor,
+        signer: Signer,
+        config: edge_protocol::PeripheralConfig,
+        downlink_verifier: Option<Verifier>,
+        commands: CommandQueue,
+        clock: StationClock,
+        /// Started by the first access of the central
+        server: Option<GattServer>,
+    }
+
+    impl Simulated {
+        fn access(&mut self, gatt: &mut Gatt, access: impl FnOnce(&mut GattServer, &mut StationClock, &mut Session<'_>, &mut Gatt) -> Access) {
+            let mut session = Session {
+                entries: &self.entries,
+                cursor: &mut self.cursor,
+                broadcast: None,
+                signer: Some(&mut self.signer),
+                config: &mut self.config,
+                downlink_verifier: self.downlink_verifier.take(),
+                accepted: &[Command::Identify],
+                commands: std::mem::take(&mut self.commands),
+                time_synced: false,
+            };
+
+            if self.server.is_none() {
+                self.server = Some(GattServer::start(&mut session, self.address, &DEVICE, gatt).unwrap());
+            }
+            let server = self.server.as_mut().unwrap();
+            if let Access::Responded(response) = access(server, &mut self.clock, &mut session, gatt) {
+                gatt::respond(gatt, &response).unwrap();
+            }
+
+            self.downlink_verifier = session.downlink_verifier;
+            self.commands = session.commands;
+        }
+    }
+
+    impl Server for Simulated {
+        fn read(&mut self, uuid: u16, gatt: &mut Gatt) {
+            if let Some(characteristic) = gatt::Characteristic::from_uuid(uuid) {
+                self.access(gatt, |server, clock, session, gatt| server.read(characteristic, &*clock, session, gatt).unwrap());
+            }
+        }
+
+        fn write(&mut self, uuid: u16, data: &[u8], gatt: &mut Gatt) {
+            if let Some(characteristic) = gatt::Characteristic::from_uuid(uuid) {
+                self.access(gatt, |server, clock, session, gatt| server.write(characteristic, data, clock, session, gatt).unwrap());
+            }
+        }
+    }
+
+    const DEVICE: gatt::DeviceInformation = gatt::DeviceInformation { model: "Mycelium", firmware_version: "0.1.0-simulator", hardware_revision: "simulator" };
+
+    const KEY: AuthKey = [0x42; 32];
+
+    /// Station running the GATT server of the simulator and the firmware, with every service they declare
+    fn simulated(address: [u8; 6], entries: &[MeasurementSerieEntry]) -> MockPeripheral {
+        let mut gatt = Gatt::default();
+        let services: [(u16, &[gatt::Characteristic]); 6] = [
+            (ADDRESS_SERVICE_UUID_16, &[gatt::Characteristic::Address]),
+            (CURRENT_TIME_SERVICE_UUID, &[gatt::Characteristic::CurrentTime]),
+            (
+                MEASUREMENT_SERVICE_UUID_16,
+                &[gatt::Characteristic::Measurement, gatt::Characteristic::Protocol, gatt::Characteristic::HistoryAck, gatt::Characteristic::Config, gatt::Characteristic::Command],
+            ),
+            (ENVIRONMENTAL_SENSING_SERVICE_UUID, &[gatt::Characteristic::Temperature, gatt::Characteristic::Humidity, gatt::Characteristic::Illuminance]),
+            (BATTERY_SERVICE_UUID, &[gatt::Characteristic::BatteryLevel]),
+            (DEVICE_INFORMATION_SERVICE_UUID, &[gatt::Characteristic::Model, gatt::Characteristic::FirmwareVersion, gatt::Characteristic::HardwareRevision]),
+        ];
+        for (service, characteristics) in services {
+            gatt = gatt.service(service);
+            for characteristic in characteristics {
+                gatt = gatt.characteristic(characteristic.uuid(), &[]);
+            }
+        }
+
+        let station = Simulated {
+            address,
+            entries: entries.to_vec(),
+            cursor: HistoryCursor::new(),
+            signer: Signer::new(KEY, 0),
+            config: edge_protocol::PeripheralConfig::DEFAULT,
+            downlink_verifier: Some(Verifier::new(downlink_key(&KEY), 0)),
+            commands: CommandQueue::new(),
+            clock: StationClock(station_time()),
+            server: None,
+        };
+
+        MockPeripheral::new(address, gatt.served_by(station))
+    }
+
+    /// Run the worker against the mock controller and collect the results of its first `rounds` scans
+    async fn sync(transport: MockTransport, rounds: usize) -> Vec<Vec<PeripheralSyncResult>> {
+        sync_with(transport, rounds, context().await).await
     }
-}
 
+    async fn sync_with(transport: MockTransport, rounds: usize, context: SyncContext) -> Vec<Vec<PeripheralSyncResult>> {
+        collect(rounds, move |tx| TroublePeripheralSyncResultStreamProvider::worker(ExternalController::<_, 8>::new(transport), tx, context)).await
+    }
+
+    /// Run the passive worker against the mock controller and collect the broadcasts of its first `rounds` scans
+    async fn listen(transport: MockTransport, rounds: usize, context: SyncContext) -> Vec<Vec<PeripheralSyncResult>> {
+        collect(rounds, move |tx| TroublePeripheralSyncResultStreamProvider::passive_worker(ExternalController::<_, 8>::new(transport), tx, context)).await
+    }
+
+    async fn collect<F, W>(rounds: usize, worker: F) -> Vec<Vec<PeripheralSyncResult>>
+    where
+        F: FnOnce(mpsc::Sender<Vec<PeripheralSyncResult>>) -> W,
+        W: std::future::Future<Output = Result<()>> + 'static,
+    {
+        let (tx, mut rx) = mpsc::channel(rounds);
+
+        LocalSet::new()
+            .run_until(async move {
+                let worker = tokio::task::spawn_local(worker(tx));
+
+                let mut results = vec![];
+                for _ in 0..rounds {
+                    let round = tokio::time::timeout(tokio::time::Duration::from_secs(30), rx.recv())
+                        .await
+                        .expect("No sync within 30 seconds")
+                        .expect("Worker stopped");
+                    results.push(round);
+                }
+
+                worker.abort();
+                results
+            })
+            .await
+    }
+
+    fn addresses(results: &[PeripheralSyncResult]) -> Vec<[u8; 6]> {
+        results.iter().map(|result| result.address).collect()
+    }
+
+    #[tokio::test]
+    async fn test_scan_connect_retrieve() {
+        let transport = MockTransport::new(vec![station(STATION, &entries(3))]);
+
+        let results = sync(transport, 1).await.remove(0);
+
+        assert_eq!(addresses(&results), vec![STATION]);
+        assert_eq!(results[0].measurements, entries(3));
+        assert_eq!(results[0].decode_failures, 0);
+    }
+
+    /// Station advertising the broadcast of its first entry from its static random address
+    fn broadcasting(mac: [u8; 6]) -> (MockPeripheral, Broadcast) {
+        let mut advertised = static_random_address(mac);
+        advertised.reverse();
+
+        let broadcast = Broadcast::new(1, 0, mac, entries(1)[0].measurement);
+        let mut payload = [0u8; MAX_BROADCAST_LEN];
+        let len = broadcast.to_bytes(&mut payload).unwrap();
+        let [lo, hi] = Broadcast::SERVICE_UUID_16.to_le_bytes();
+
+        let mut broadcasting = station(mac, &entries(1));
+        broadcasting.address = advertised;
+        broadcasting.adv_data = [&[0x02, 0x01, 0x06, 3 + len as u8, 0x16, lo, hi][..], &payload[..len]].concat();
+        (broadcasting, broadcast)
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_and_connected_sync_resolve_to_the_same_mac() {
+        // Espressif MAC with the top bits clear, the station advertises from its static random address
+        let mac = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+        let (broadcasting, broadcast) = broadcasting(mac);
+        assert_ne!(broadcasting.address, mac);
+        let transport = MockTransport::new(vec![broadcasting]);
+
+        let connected = sync(transport.clone(), 1).await.remove(0);
+        let broadcasts = listen(transport, 1, context().await).await.remove(0);
+
+        assert_eq!(addresses(&connected), vec![mac]);
+        assert_eq!(addresses(&broadcasts), vec![mac]);
+        assert_eq!(broadcasts[0].measurements[0].measurement, broadcast.measurement);
+    }
+
+    #[tokio::test]
+    async fn test_drops_broadcasts_of_stations_with_a_key() {
+        let mac = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+        let (broadcasting, _) = broadcasting(mac);
+        let transport = MockTransport::new(vec![broadcasting]);
+        let context = context().await;
+        context.station_keys.set_key(&mac, &[0x42; 32]).await.unwrap();
+
+        let broadcasts = listen(transport, 1, context).await.remove(0);
+
+        assert!(broadcasts.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_corrects_time_and_disconnects() {
+        let transport = MockTransport::new(vec![station(STATION, &entries(1))]);
+        let started = chrono::Utc::now().naive_utc();
+
+        let results = sync(transport.clone(), 1).await.remove(0);
+
+        // The station clock only has a resolution of seconds
+        let drift = results[0].time_drift;
+        assert!(drift > chrono::TimeDelta::minutes(59) && drift < chrono::TimeDelta::minutes(61), "Drift {}", drift);
+
+        let written = transport.value(STATION, CURRENT_TIME_CHARACTERISTIC_UUID).unwrap();
+        let written = CurrentTime::try_from_bytes(&written).unwrap().to_naivedatetime().unwrap();
+        assert!(written >= started.with_nanosecond(0).unwrap() && written <= chrono::Utc::now().naive_utc());
+
+        assert_eq!(transport.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_sync_every_station_in_range() {
+        let transport = MockTransport::new(vec![station(STATION, &entries(2)), station(OTHER_STATION, &entries(1))]);
+
+        let results = sync(transport, 1).await.remove(0);
+
+        assert_eq!(addresses(&results), vec![STATION, OTHER_STATION]);
+        assert_eq!(results[0].measurements.len(), 2);
+        assert_eq!(results[1].measurements.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_only_connects_to_stations() {
+        let mut headphones = MockPeripheral::new([0x11; 6], Gatt::default());
+        headphones.adv_data = vec![0x02, 0x01, 0x06, 0x0b, 0x09, b'H', b'e', b'a', b'd', b'p', b'h', b'o', b'n', b'e', b's'];
+        let mut nearby = station(STATION, &entries(1));
+        nearby.rssi = -72;
+        let transport = MockTransport::new(vec![headphones, nearby]);
+
+        let results = sync(transport.clone(), 1).await.remove(0);
+
+        assert_eq!(addresses(&results), vec![STATION]);
+        assert_eq!(results[0].rssi, Some(-72));
+        assert_eq!(transport.connection_attempts(), vec![STATION]);
+    }
+
+    #[tokio::test]
+    async fn test_failed_connection_skips_station() {
+        let mut unreachable = station(STATION, &entries(2));
+        unreachable.connect_status = CONNECTION_FAILED_TO_BE_ESTABLISHED;
+        let transport = MockTransport::new(vec![unreachable, station(OTHER_STATION, &entries(1))]);
+
+        let results = sync(transport, 1).await.remove(0);
+
+        assert_eq!(addresses(&results), vec![OTHER_STATION]);
+    }
+
+    #[tokio::test]
+    async fn test_link_lost_during_retrieve_keeps_syncing() {
+        // The link drops a few requests in, before the measurements are read
+        let mut flaky = station(STATION, &entries(2));
+        flaky.disconnect_after = Some(4);
+        let transport = MockTransport::new(vec![flaky, station(OTHER_STATION, &entries(1))]);
+
+        let rounds = sync(transport, 2).await;
+
+        assert_eq!(addresses(&rounds[0]), vec![OTHER_STATION]);
+        assert_eq!(addresses(&rounds[1]), vec![OTHER_STATION]);
+    }
+
+    #[tokio::test]
+    async fn test_syncs_the_gatt_server_of_the_simulator() {
+        // More entries than a page holds, the history goes over in several acknowledged pages
+        let entries = entries(MAX_COMPACT_FRAME_ENTRIES as i64 * 2 + 1);
+        let transport = MockTransport::new(vec![simulated(STATION, &entries)]);
+        let context = context().await;
+        context.station_keys.set_key(&STATION, &KEY).await.unwrap();
+        let config = edge_protocol::PeripheralConfig { sampling_period_secs: 300, ..edge_protocol::PeripheralConfig::DEFAULT };
+        context.station_settings.set(&STATION, &config).await.unwrap();
+
+        let results = sync_with(transport.clone(), 1, context.clone()).await.remove(0);
+
+        assert_eq!(addresses(&results), vec![STATION]);
+        assert_eq!(results[0].measurements, entries);
+        assert_eq!(results[0].decode_failures, 0);
+        let drift = results[0].time_drift;
+        assert!(drift > chrono::TimeDelta::minutes(59) && drift < chrono::TimeDelta::minutes(61), "Drift {}", drift);
+
+        // The station applied the signed configuration and serves it back
+        let served = transport.value(STATION, CONFIG_CHARACTERISTIC_UUID_16).unwrap();
+        assert_eq!(edge_protocol::PeripheralConfig::from_tlv(&served), Ok(config));
+        assert!(context.station_keys.find_verifier(&STATION).await.unwrap().unwrap().counter() > 0);
+        assert_eq!(transport.connections(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_att_error_recovers_next_round() {
+        let transport = MockTransport::new(vec![station(STATION, &entries(2))]);
+        transport.respond_att(ATT_READ_REQ, att_error(ATT_READ_REQ, 0, READ_NOT_PERMITTED));
+
+        let rounds = sync(transport, 2).await;
+
+        assert!(rounds[0].is_empty());
+        assert_eq!(addresses(&rounds[1]), vec![STATION]);
+        assert_eq!(rounds[1][0].measurements, entries(2));
+    }
+}
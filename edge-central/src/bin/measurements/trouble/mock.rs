@@ -0,0 +1,588 @@
+//! In-memory HCI controller to test the trouble provider without a radio.
+//!
+//! [`MockTransport`] stands in for [`crate::ble::hci::Transport`], wrapped in an
+//! `ExternalController` it provides every bt_hci `Controller` trait the host needs. It
+//! answers like a controller with the scripted peripherals in range:
+//!
+//! - commands complete successfully, with zeroed return parameters besides the buffer sizes
+//! - enabling a scan reports the advertising data of every peripheral in a single event
+//! - creating a connection completes with the peripheral on the filter accept list
+//! - ATT requests are answered from the attribute table of the connected peripheral, canned
+//!   responses take precedence
+//! - a peripheral with a [`Server`] has it handle reads and writes of its characteristics, as
+//!   its GATT server would
+//!
+//! Every answer is sent as soon as the host writes, so a test sees the same exchange on every run.
+
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::io;
+use std::sync::{Arc, Mutex};
+
+use bt_hci::transport::{self, WithIndicator};
+use bt_hci::{ControllerToHostPacket, FromHciBytes as _, HostToControllerPacket, WriteHci as _};
+use edge_peripheral_core::gatt;
+use edge_protocol::{DEVICE_NAME, MEASUREMENT_SERVICE_UUID_16};
+use tokio::sync::mpsc;
+
+use crate::ble::hci::Error;
+
+const COMMAND_PACKET: u8 = 0x01;
+const ACL_PACKET: u8 = 0x02;
+const EVENT_PACKET: u8 = 0x04;
+
+const DISCONNECTION_COMPLETE_EVENT: u8 = 0x05;
+const COMMAND_COMPLETE_EVENT: u8 = 0x0E;
+const COMMAND_STATUS_EVENT: u8 = 0x0F;
+const NUMBER_OF_COMPLETED_PACKETS_EVENT: u8 = 0x13;
+const LE_META_EVENT: u8 = 0x3E;
+const LE_CONNECTION_COMPLETE: u8 = 0x01;
+const LE_ADVERTISING_REPORT: u8 = 0x02;
+
+const DISCONNECT: u16 = 0x0406;
+const READ_BUFFER_SIZE: u16 = 0x1005;
+const LE_READ_BUFFER_SIZE: u16 = 0x2002;
+const LE_SET_SCAN_ENABLE: u16 = 0x200C;
+const LE_CREATE_CONNECTION: u16 = 0x200D;
+const LE_CREATE_CONNECTION_CANCEL: u16 = 0x200E;
+const LE_CLEAR_FILTER_ACCEPT_LIST: u16 = 0x2010;
+const LE_ADD_DEVICE_TO_FILTER_ACCEPT_LIST: u16 = 0x2011;
+const LE_EXT_CREATE_CONNECTION: u16 = 0x2043;
+const LE_READ_BUFFER_SIZE_V2: u16 = 0x2060;
+
+/// Commands acknowledged with a command status, their outcome follows as a separate event
+const ASYNC_COMMANDS: [u16; 7] = [DISCONNECT, 0x041D, LE_CREATE_CONNECTION, 0x2013, 0x2016, 0x2032, LE_EXT_CREATE_CONNECTION];
+
+pub const SUCCESS: u8 = 0x00;
+const UNKNOWN_CONNECTION_IDENTIFIER: u8 = 0x02;
+const CONNECTION_TIMEOUT: u8 = 0x08;
+const LOCAL_HOST_TERMINATED: u8 = 0x16;
+pub const CONNECTION_FAILED_TO_BE_ESTABLISHED: u8 = 0x3E;
+
+/// Largest ACL payload in either direction, fits an ATT MTU of 247 without fragmentation
+const ACL_MTU: u16 = 251;
+const ACL_BUFFERS: u8 = 8;
+
+const ATT_CID: u16 = 0x0004;
+const ATT_MTU: u16 = 247;
+const ATT_MTU_MIN: u16 = 23;
+
+pub const ATT_ERROR_RSP: u8 = 0x01;
+const ATT_EXCHANGE_MTU_REQ: u8 = 0x02;
+const ATT_EXCHANGE_MTU_RSP: u8 = 0x03;
+const ATT_FIND_INFORMATION_REQ: u8 = 0x04;
+const ATT_FIND_INFORMATION_RSP: u8 = 0x05;
+const ATT_FIND_BY_TYPE_VALUE_REQ: u8 = 0x06;
+const ATT_FIND_BY_TYPE_VALUE_RSP: u8 = 0x07;
+const ATT_READ_BY_TYPE_REQ: u8 = 0x08;
+const ATT_READ_BY_TYPE_RSP: u8 = 0x09;
+pub const ATT_READ_REQ: u8 = 0x0A;
+const ATT_READ_RSP: u8 = 0x0B;
+const ATT_READ_BY_GROUP_TYPE_REQ: u8 = 0x10;
+const ATT_READ_BY_GROUP_TYPE_RSP: u8 = 0x11;
+const ATT_WRITE_REQ: u8 = 0x12;
+const ATT_WRITE_RSP: u8 = 0x13;
+const ATT_WRITE_CMD: u8 = 0x52;
+
+const INVALID_HANDLE: u8 = 0x01;
+pub const READ_NOT_PERMITTED: u8 = 0x02;
+const REQUEST_NOT_SUPPORTED: u8 = 0x06;
+const ATTRIBUTE_NOT_FOUND: u8 = 0x0A;
+
+const PRIMARY_SERVICE: u16 = 0x2800;
+const CHARACTERISTIC: u16 = 0x2803;
+/// Read and write
+const CHARACTERISTIC_PROPERTIES: u8 = 0x02 | 0x08;
+
+/// Encode an ATT error response to `request`
+pub fn att_error(request: u8, handle: u16, code: u8) -> Vec<u8> {
+    let [lo, hi] = handle.to_le_bytes();
+    vec![ATT_ERROR_RSP, request, lo, hi, code]
+}
+
+struct Attribute {
+    handle: u16,
+    uuid: u16,
+    /// Values served by the next reads, the last one keeps being served
+    values: VecDeque<Vec<u8>>,
+}
+
+impl Attribute {
+    fn value(&self) -> &[u8] {
+        self.values.front().map(Vec::as_slice).unwrap_or_default()
+    }
+}
+
+/// Handles the access of a central to the characteristics of a peripheral, ahead of its attribute table
+pub trait Server: Send {
+    /// Called before a read of the characteristic `uuid` is answered
+    fn read(&mut self, uuid: u16, gatt: &mut Gatt);
+
+    /// Called once a write to the characteristic `uuid` is stored
+    fn write(&mut self, uuid: u16, data: &[u8], gatt: &mut Gatt);
+}
+
+/// Attribute table of a peripheral, laid out like a GATT server does
+#[derive(Default)]
+pub struct Gatt {
+    attributes: Vec<Attribute>,
+    server: Option<Box<dyn Server>>,
+}
+
+impl gatt::Attributes for Gatt {
+    type Error = std::convert::Infallible;
+
+    fn set(&mut self, characteristic: gatt::Characteristic, value: &[u8]) -> Result<(), Self::Error> {
+        if let Some(attribute) = self.attributes.iter_mut().find(|a| a.uuid == characteristic.uuid()) {
+            attribute.values = VecDeque::from([value.to_vec()]);
+        }
+        Ok(())
+    }
+}
+
+impl Gatt {
+    fn push(&mut self, uuid: u16, values: Vec<Vec<u8>>) -> u16 {
+        let handle = self.attributes.len() as u16 + 1;
+        self.attributes.push(Attribute { handle, uuid, values: values.into() });
+        handle
+    }
+
+    /// Start a primary service, the characteristics that follow belong to it
+    pub fn service(mut self, uuid: u16) -> Self {
+        self.push(PRIMARY_SERVICE, vec![uuid.to_le_bytes().to_vec()]);
+        self
+    }
+
+    /// Add a readable and writable characteristic to the current service
+    pub fn characteristic(self, uuid: u16, value: &[u8]) -> Self {
+        self.characteristic_reads(uuid, vec![value.to_vec()])
+    }
+
+    /// Add a characteristic serving `values` on successive reads, the last one sticks
+    pub fn characteristic_reads(mut self, uuid: u16, values: Vec<Vec<u8>>) -> Self {
+        let [lo, hi] = (self.attributes.len() as u16 + 2).to_le_bytes();
+        let [uuid_lo, uuid_hi] = uuid.to_le_bytes();
+
+        self.push(CHARACTERISTIC, vec![vec![CHARACTERISTIC_PROPERTIES, lo, hi, uuid_lo, uuid_hi]]);
+        self.push(uuid, values);
+        self
+    }
+
+    /// Have `server` handle the reads and writes of the characteristics
+    pub fn served_by(mut self, server: impl Server + 'static) -> Self {
+        self.server = Some(Box::new(server));
+        self
+    }
+
+    /// Let the server handle an access to the attribute at `handle`
+    fn serve(&mut self, handle: u16, access: impl FnOnce(&mut dyn Server, u16, &mut Gatt)) {
+        let Some(uuid) = self.attributes.iter().find(|a| a.handle == handle).map(|a| a.uuid) else {
+            return;
+        };
+
+        if let Some(mut server) = self.server.take() {
+            access(server.as_mut(), uuid, self);
+            self.server = Some(server);
+        }
+    }
+
+    /// Last handle of the group the attribute at `index` starts
+    fn group_end(&self, index: usize) -> u16 {
+        self.attributes[index + 1..]
+            .iter()
+            .find(|attribute| attribute.uuid == PRIMARY_SERVICE)
+            .map(|attribute| attribute.handle - 1)
+            .unwrap_or(self.attributes.len() as u16)
+    }
+
+    fn in_range(&self, start: u16, end: u16) -> impl Iterator<Item = (usize, &Attribute)> {
+        self.attributes
+            .iter()
+            .enumerate()
+            .filter(move |(_, attribute)| (start..=end).contains(&attribute.handle))
+    }
+
+    /// Attribute at `handle`, once the server had a chance to update it
+    fn read(&mut self, handle: u16) -> Option<&mut Attribute> {
+        self.serve(handle, |server, uuid, gatt| server.read(uuid, gatt));
+        self.attributes.iter_mut().find(|a| a.handle == handle)
+    }
+
+    /// Answer an ATT request, `None` for commands which go unanswered
+    fn respond(&mut self, request: &[u8], mtu: &mut u16) -> Option<Vec<u8>> {
+        let (&opcode, params) = request.split_first()?;
+        let max = *mtu as usize;
+        let u16_at = |lo: u8, hi: u8| u16::from_le_bytes([lo, hi]);
+
+        let response = match *params {
+            [lo, hi] if opcode == ATT_EXCHANGE_MTU_REQ => {
+                *mtu = u16_at(lo, hi).clamp(ATT_MTU_MIN, ATT_MTU);
+                let [lo, hi] = ATT_MTU.to_le_bytes();
+                Ok(vec![ATT_EXCHANGE_MTU_RSP, lo, hi])
+            }
+            [s0, s1, e0, e1] if opcode == ATT_FIND_INFORMATION_REQ => {
+                let mut response = vec![ATT_FIND_INFORMATION_RSP, 0x01];
+                for (_, attribute) in self.in_range(u16_at(s0, s1), u16_at(e0, e1)) {
+                    if response.len() + 4 > max {
+                        break;
+                    }
+                    response.extend(attribute.handle.to_le_bytes());
+                    response.extend(attribute.uuid.to_le_bytes());
+                }
+                found(response, 2, u16_at(s0, s1))
+            }
+            [s0, s1, e0, e1, t0, t1, ref value @ ..] if opcode == ATT_FIND_BY_TYPE_VALUE_REQ => {
+                let mut response = vec![ATT_FIND_BY_TYPE_VALUE_RSP];
+                for (index, attribute) in self.in_range(u16_at(s0, s1), u16_at(e0, e1)) {
+                    if attribute.uuid != u16_at(t0, t1) || attribute.value() != value {
+                        continue;
+                    }
+                    if response.len() + 4 > max {
+                        break;
+                    }
+                    response.extend(attribute.handle.to_le_bytes());
+                    response.extend(self.group_end(index).to_le_bytes());
+                }
+                found(response, 1, u16_at(s0, s1))
+            }
+            [s0, s1, e0, e1, t0, t1] if opcode == ATT_READ_BY_TYPE_REQ || opcode == ATT_READ_BY_GROUP_TYPE_REQ => {
+                let group = opcode == ATT_READ_BY_GROUP_TYPE_REQ;
+                let mut response = vec![if group { ATT_READ_BY_GROUP_TYPE_RSP } else { ATT_READ_BY_TYPE_RSP }, 0];
+                for (index, attribute) in self.in_range(u16_at(s0, s1), u16_at(e0, e1)) {
+                    if attribute.uuid != u16_at(t0, t1) {
+                        continue;
+                    }
+
+                    let mut item = attribute.handle.to_le_bytes().to_vec();
+                    if group {
+                        item.extend(self.group_end(index).to_le_bytes());
+                    }
+                    item.extend(attribute.value());
+
+                    // All items of a response have the same length
+                    if response[1] == 0 {
+                        item.truncate(max - 2);
+                        response[1] = item.len() as u8;
+                    } else if item.len() != response[1] as usize || response.len() + item.len() > max {
+                        break;
+                    }
+                    response.extend(item);
+                }
+                found(response, 2, u16_at(s0, s1))
+            }
+            [lo, hi] if opcode == ATT_READ_REQ => match self.read(u16_at(lo, hi)) {
+                Some(attribute) => {
+                    let value = if attribute.values.len() > 1 { attribute.values.pop_front() } else { attribute.values.front().cloned() };
+                    let mut response = vec![ATT_READ_RSP];
+                    response.extend(value.unwrap_or_default().into_iter().take(max - 1));
+                    Ok(response)
+                }
+                None => Err((u16_at(lo, hi), INVALID_HANDLE)),
+            },
+            [lo, hi, ref value @ ..] if opcode == ATT_WRITE_REQ || opcode == ATT_WRITE_CMD => {
+                match self.attributes.iter_mut().find(|a| a.handle == u16_at(lo, hi)) {
+                    Some(attribute) => {
+                        attribute.values = VecDeque::from([value.to_vec()]);
+                        self.serve(u16_at(lo, hi), |server, uuid, gatt| server.write(uuid, value, gatt));
+                        Ok(vec![ATT_WRITE_RSP])
+                    }
+                    None => Err((u16_at(lo, hi), INVALID_HANDLE)),
+                }
+            }
+            _ => Err((0, REQUEST_NOT_SUPPORTED)),
+        };
+
+        if opcode == ATT_WRITE_CMD {
+            return None;
+        }
+
+        Some(response.unwrap_or_else(|(handle, code)| att_error(opcode, handle, code)))
+    }
+}
+
+/// A response listing no attribute reports that none was found
+fn found(response: Vec<u8>, header: usize, start: u16) -> Result<Vec<u8>, (u16, u8)> {
+    if response.len() > header {
+        Ok(response)
+    } else {
+        Err((start, ATTRIBUTE_NOT_FOUND))
+    }
+}
+
+/// A peripheral in range of the controller
+pub struct MockPeripheral {
+    /// Address in big endian, as printed
+    pub address: [u8; 6],
+    pub adv_data: Vec<u8>,
+    pub rssi: i8,
+    pub gatt: Gatt,
+    /// Status connections complete with, anything but [`SUCCESS`] fails them
+    pub connect_status: u8,
+    /// Number of ATT requests answered before the link is lost, `None` keeps it up
+    pub disconnect_after: Option<usize>,
+}
+
+impl MockPeripheral {
+    /// A peripheral advertising like a station that isn't broadcasting
+    pub fn new(address: [u8; 6], gatt: Gatt) -> Self {
+        let [lo, hi] = MEASUREMENT_SERVICE_UUID_16.to_le_bytes();
+
+        // General discoverable, BR/EDR not supported, then the services and the name
+        let mut adv_data = vec![0x02, 0x01, 0x06, 0x03, 0x02, lo, hi, DEVICE_NAME.len() as u8 + 1, 0x09];
+        adv_data.extend(DEVICE_NAME.as_bytes());
+
+        Self { address, adv_data, rssi: -60, gatt, connect_status: SUCCESS, disconnect_after: None }
+    }
+}
+
+struct Link {
+    peripheral: usize,
+    mtu: u16,
+    requests: usize,
+}
+
+#[derive(Default)]
+struct State {
+    peripherals: Vec<MockPeripheral>,
+    /// Big endian addresses on the filter accept list
+    accept_list: Vec<[u8; 6]>,
+    links: HashMap<u16, Link>,
+    /// Addresses of the peripherals the host tried to connect to
+    connection_attempts: Vec<[u8; 6]>,
+    next_handle: u16,
+    /// Canned responses by ATT request opcode, served before the attribute table answers
+    att_responses: HashMap<u8, VecDeque<Vec<u8>>>,
+}
+
+/// Encode an HCI event packet
+fn event(code: u8, params: &[u8]) -> Vec<u8> {
+    let mut packet = vec![EVENT_PACKET, code, params.len() as u8];
+    packet.extend_from_slice(params);
+    packet
+}
+
+/// Addresses go over HCI in little endian
+fn reversed(address: &[u8]) -> Option<[u8; 6]> {
+    let mut address: [u8; 6] = address.get(..6)?.try_into().ok()?;
+    address.reverse();
+    Some(address)
+}
+
+impl State {
+    fn command(&mut self, opcode: u16, params: &[u8], out: &mut Vec<Vec<u8>>) {
+        let [op_lo, op_hi] = opcode.to_le_bytes();
+
+        if ASYNC_COMMANDS.contains(&opcode) {
+            out.push(event(COMMAND_STATUS_EVENT, &[SUCCESS, 1, op_lo, op_hi]));
+        } else {
+            let mut complete = vec![1, op_lo, op_hi, SUCCESS];
+            complete.extend(return_parameters(opcode));
+            out.push(event(COMMAND_COMPLETE_EVENT, &complete));
+        }
+
+        match (opcode, params) {
+            (LE_SET_SCAN_ENABLE, [1, ..]) => self.advertise(out),
+            (LE_CLEAR_FILTER_ACCEPT_LIST, _) => self.accept_list.clear(),
+            (LE_ADD_DEVICE_TO_FILTER_ACCEPT_LIST, [_kind, address @ ..]) => self.accept_list.extend(reversed(address)),
+            (LE_CREATE_CONNECTION, [_, _, _, _, policy, _kind, peer @ ..]) => self.connect(*policy, reversed(peer), out),
+            (LE_EXT_CREATE_CONNECTION, [policy, _own, _kind, peer @ ..]) => self.connect(*policy, reversed(peer), out),
+            (LE_CREATE_CONNECTION_CANCEL, _) => out.push(connection_complete(UNKNOWN_CONNECTION_IDENTIFIER, 0, [0; 6])),
+            (DISCONNECT, [lo, hi, ..]) => {
+                let handle = u16::from_le_bytes([*lo, *hi]);
+                if self.links.remove(&handle).is_some() {
+                    out.push(event(DISCONNECTION_COMPLETE_EVENT, &[SUCCESS, *lo, *hi, LOCAL_HOST_TERMINATED]));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn advertise(&self, out: &mut Vec<Vec<u8>>) {
+        if self.peripherals.is_empty() {
+            return;
+        }
+
+        let mut params = vec![LE_ADVERTISING_REPORT, self.peripherals.len() as u8];
+        for peripheral in &self.peripherals {
+            // Connectable undirected advertising from a random address
+            params.extend([0x00, 0x01]);
+            params.extend(reversed(&peripheral.address).unwrap_or_default());
+            params.push(peripheral.adv_data.len() as u8);
+            params.extend(&peripheral.adv_data);
+            params.push(peripheral.rssi as u8);
+        }
+
+        out.push(event(LE_META_EVENT, &params));
+    }
+
+    fn connect(&mut self, policy: u8, peer: Option<[u8; 6]>, out: &mut Vec<Vec<u8>>) {
+        // With the filter accept list policy the peer address parameter is ignored
+        let candidates = if policy == 0x01 { self.accept_list.clone() } else { peer.into_iter().collect() };
+        let Some(index) = self.peripherals.iter().position(|p| candidates.contains(&p.address)) else {
+            // Nothing in range answers, the host cancels once it gives up
+            return;
+        };
+
+        let peripheral = &self.peripherals[index];
+        self.connection_attempts.push(peripheral.address);
+        if peripheral.connect_status != SUCCESS {
+            out.push(connection_complete(peripheral.connect_status, 0, peripheral.address));
+            return;
+        }
+
+        self.next_handle += 1;
+        let handle = self.next_handle;
+        self.links.insert(handle, Link { peripheral: index, mtu: ATT_MTU_MIN, requests: 0 });
+        out.push(connection_complete(SUCCESS, handle, peripheral.address));
+    }
+
+    fn acl(&mut self, header: u16, data: &[u8], out: &mut Vec<Vec<u8>>) {
+        let handle = header & 0x0FFF;
+        let [lo, hi] = handle.to_le_bytes();
+
+        // The packet leaves the controller buffer right away
+        out.push(event(NUMBER_OF_COMPLETED_PACKETS_EVENT, &[1, lo, hi, 1, 0]));
+
+        let [_, _, cid_lo, cid_hi, request @ ..] = data else {
+            return;
+        };
+        if u16::from_le_bytes([*cid_lo, *cid_hi]) != ATT_CID {
+            return;
+        }
+        let Some(link) = self.links.get_mut(&handle) else {
+            return;
+        };
+
+        link.requests += 1;
+        let peripheral = &mut self.peripherals[link.peripheral];
+        if peripheral.disconnect_after.is_some_and(|after| link.requests > after) {
+            self.links.remove(&handle);
+            out.push(event(DISCONNECTION_COMPLETE_EVENT, &[SUCCESS, lo, hi, CONNECTION_TIMEOUT]));
+            return;
+        }
+
+        let canned = request
+            .first()
+            .and_then(|opcode| self.att_responses.get_mut(opcode))
+            .and_then(VecDeque::pop_front);
+        let Some(response) = canned.or_else(|| peripheral.gatt.respond(request, &mut link.mtu)) else {
+            return;
+        };
+
+        let mut packet = vec![ACL_PACKET, lo, hi | 0x20];
+        packet.extend((response.len() as u16 + 4).to_le_bytes());
+        packet.extend((response.len() as u16).to_le_bytes());
+        packet.extend(ATT_CID.to_le_bytes());
+        packet.extend(response);
+        out.push(packet);
+    }
+
+    /// Handle a packet the host wrote, including its indicator
+    fn receive(&mut self, packet: &[u8], out: &mut Vec<Vec<u8>>) {
+        match *packet {
+            [COMMAND_PACKET, lo, hi, _len, ref params @ ..] => self.command(u16::from_le_bytes([lo, hi]), params, out),
+            [ACL_PACKET, lo, hi, _, _, ref data @ ..] => self.acl(u16::from_le_bytes([lo, hi]), data, out),
+            _ => {}
+        }
+    }
+}
+
+fn connection_complete(status: u8, handle: u16, address: [u8; 6]) -> Vec<u8> {
+    let mut params = vec![LE_CONNECTION_COMPLETE, status];
+    params.extend(handle.to_le_bytes());
+    // Central role, random peer address
+    params.extend([0x00, 0x01]);
+    params.extend(reversed(&address).unwrap_or_default());
+    // 7.5 ms interval, no latency, 10 s supervision timeout, 500 ppm clock accuracy
+    params.extend(6u16.to_le_bytes());
+    params.extend(0u16.to_le_bytes());
+    params.extend(1000u16.to_le_bytes());
+    params.push(0x00);
+
+    event(LE_META_EVENT, &params)
+}
+
+/// Return parameters of a successful command, after its status
+fn return_parameters(opcode: u16) -> Vec<u8> {
+    let [acl_lo, acl_hi] = ACL_MTU.to_le_bytes();
+
+    match opcode {
+        READ_BUFFER_SIZE => vec![acl_lo, acl_hi, 0, ACL_BUFFERS, 0, 0, 0],
+        LE_READ_BUFFER_SIZE => vec![acl_lo, acl_hi, ACL_BUFFERS],
+        LE_READ_BUFFER_SIZE_V2 => vec![acl_lo, acl_hi, ACL_BUFFERS, 0, 0, 0],
+        // Zeroes are a valid answer to everything else the host asks, like absent features.
+        // The longest return parameters are the 64 bytes of the supported commands.
+        _ => vec![0; 64],
+    }
+}
+
+/// In-memory transport, clones share the same controller so tests can script it while the host runs
+#[derive(Clone)]
+pub struct MockTransport {
+    state: Arc<Mutex<State>>,
+    tx: mpsc::UnboundedSender<Vec<u8>>,
+    rx: Arc<tokio::sync::Mutex<mpsc::UnboundedReceiver<Vec<u8>>>>,
+}
+
+impl MockTransport {
+    pub fn new(peripherals: Vec<MockPeripheral>) -> Self {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let state = State { peripherals, ..Default::default() };
+
+        Self { state: Arc::new(Mutex::new(state)), tx, rx: Arc::new(tokio::sync::Mutex::new(rx)) }
+    }
+
+    /// Value a peripheral serves for the characteristic `uuid`, reflects what the host wrote
+    pub fn value(&self, address: [u8; 6], uuid: u16) -> Option<Vec<u8>> {
+        let state = self.state.lock().unwrap();
+        let peripheral = state.peripherals.iter().find(|p| p.address == address)?;
+        let attribute = peripheral.gatt.attributes.iter().find(|a| a.uuid == uuid)?;
+
+        Some(attribute.value().to_vec())
+    }
+
+    /// Addresses of the peripherals the host tried to connect to, in order
+    pub fn connection_attempts(&self) -> Vec<[u8; 6]> {
+        self.state.lock().unwrap().connection_attempts.clone()
+    }
+
+    /// Number of links the host left open
+    pub fn connections(&self) -> usize {
+        self.state.lock().unwrap().links.len()
+    }
+
+    /// Answer the next ATT request with `opcode` by `response` instead of the attribute table
+    pub fn respond_att(&self, opcode: u8, response: Vec<u8>) {
+        self.state.lock().unwrap().att_responses.entry(opcode).or_default().push_back(response);
+    }
+}
+
+impl transport::Transport for MockTransport {
+    async fn read<'a>(&self, rx: &'a mut [u8]) -> Result<ControllerToHostPacket<'a>, Self::Error> {
+        let packet = self.rx.lock().await.recv().await.ok_or_else(|| Error::Io(io::ErrorKind::BrokenPipe.into()))?;
+        let buffer = rx
+            .get_mut(..packet.len())
+            .ok_or_else(|| Error::Io(io::Error::other("Packet exceeds the read buffer")))?;
+        buffer.copy_from_slice(&packet);
+
+        ControllerToHostPacket::from_hci_bytes_complete(buffer).map_err(Error::FromHciBytesError)
+    }
+
+    fn write<T: HostToControllerPacket>(&self, val: &T) -> impl Future<Output = Result<(), Self::Error>> {
+        let mut buf = Vec::<u8>::new();
+        WithIndicator::new(val).write_hci(&mut buf).unwrap();
+
+        let mut out = vec![];
+        self.state.lock().unwrap().receive(&buf, &mut out);
+        for packet in out {
+            // The receiver lives as long as this transport
+            let _ = self.tx.send(packet);
+        }
+
+        async { Ok(()) }
+    }
+}
+
+impl embedded_io::ErrorType for MockTransport {
+    type Error = Error;
+}
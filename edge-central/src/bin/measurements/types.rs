@@ -1,15 +1,157 @@
 use std::pin::Pin;
+use std::sync::Arc;
 
 use chrono::Duration;
-use edge_protocol::MeasurementSerieEntry;
+use edge_protocol::auth::station_key;
+use edge_protocol::{AuthKey, MeasurementSerieEntry, PeripheralConfig, Verifier};
 use futures::Stream;
 
+use crate::data::sqlite::{SqliteStationCommandRepository, SqliteStationDeviceRepository, SqliteStationKeyRepository, SqliteStationSettingsRepository, SqliteSyncRepository};
+
 pub struct PeripheralSyncResult {
     pub address: [u8; 6],
     pub time_drift: Duration,
     pub measurements: Vec<MeasurementSerieEntry>,
+    /// Number of entries the peripheral served that couldn't be decoded
+    pub decode_failures: u32,
+    /// Signal strength the peripheral was seen with in dBm, when known
+    pub rssi: Option<i16>,
+    /// Set when the provider stored the sync page by page while it transferred the history
+    pub sync_id: Option<i64>,
 }
 
 pub trait PeripheralSyncResultStreamProvider {
     fn stream(self: Box<Self>) -> Pin<Box<dyn Stream<Item = Vec<PeripheralSyncResult>>>>;
 }
+
+/// Per-station data a provider consults while it syncs a peripheral
+#[derive(Clone)]
+pub struct SyncContext {
+    pub station_keys: Arc<SqliteStationKeyRepository>,
+    pub station_settings: Arc<SqliteStationSettingsRepository>,
+    pub station_commands: Arc<SqliteStationCommandRepository>,
+    pub station_devices: Arc<SqliteStationDeviceRepository>,
+    /// Pages are stored before the peripheral is told to drop them
+    pub syncs: Arc<SqliteSyncRepository>,
+    /// Key the firmware is built with, stations without a key of their own get the one derived from it
+    pub fleet_key: Option<AuthKey>,
+    /// Stations without a key are rejected rather than synced unverified
+    pub require_authentication: bool,
+}
+
+impl SyncContext {
+    /// Verifier for the frames of a station, provisioning the key derived from the fleet key
+    /// the first time the station syncs. `None` lets an unprovisioned station sync unverified.
+    pub async fn verifier(&self, mac: &[u8; 6]) -> anyhow::Result<Option<Verifier>> {
+        if let Some(verifier) = self.station_keys.find_verifier(mac).await? {
+            return Ok(Some(verifier));
+        }
+
+        if let Some(fleet_key) = &self.fleet_key {
+            self.station_keys.set_key(mac, &station_key(fleet_key, mac)).await?;
+            return self.station_keys.find_verifier(mac).await;
+        }
+
+        if self.require_authentication {
+            anyhow::bail!("No key provisioned for station {:02x?}, rejecting it", mac);
+        }
+
+        Ok(None)
+    }
+
+    /// Configuration set for the station encoded for the write, signed with the key of the station when it has one
+    pub async fn config_write(&self, mac: &[u8; 6], verifier: Option<&Verifier>) -> anyhow::Result<Option<(PeripheralConfig, Vec<u8>)>> {
+        let Some(config) = self.station_settings.find(mac).await? else {
+            return Ok(None);
+        };
+
+        let mut buffer = [0u8; PeripheralConfig::MAX_SIGNED_TLV_LEN];
+        let len = match verifier {
+            Some(verifier) => config.to_signed_tlv(verifier.key(), self.downlink_counters(mac, 1).await?, &mut buffer)?,
+            None => config.to_tlv(&mut buffer)?,
+        };
+
+        Ok(Some((config, buffer[..len].to_vec())))
+    }
+
+    /// Reserve `count` counters to sign writes to a station with, returns the first
+    pub async fn downlink_counters(&self, mac: &[u8; 6], count: u32) -> anyhow::Result<u32> {
+        let floor = u32::try_from(chrono::Utc::now().timestamp()).unwrap_or(0);
+        self.station_keys
+            .reserve_downlink_counters(mac, count, floor)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("No key provisioned for station {:02x?} to sign with", mac))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edge_protocol::auth::downlink_key;
+
+    use crate::data::sqlite::test_pool;
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+
+    async fn context(fleet_key: Option<AuthKey>, require_authentication: bool) -> SyncContext {
+        let pool = test_pool().await;
+
+        SyncContext {
+            station_keys: Arc::new(SqliteStationKeyRepository::new(pool.clone())),
+            station_settings: Arc::new(SqliteStationSettingsRepository::new(pool.clone())),
+            station_commands: Arc::new(SqliteStationCommandRepository::new(pool.clone())),
+            station_devices: Arc::new(SqliteStationDeviceRepository::new(pool.clone())),
+            syncs: Arc::new(SqliteSyncRepository::new(pool)),
+            fleet_key,
+            require_authentication,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_verifier_provisions_key_derived_from_fleet_key() {
+        let context = context(Some([0x42; 32]), true).await;
+
+        let verifier = context.verifier(&MAC).await.unwrap().unwrap();
+        assert_eq!(verifier.counter(), 0);
+        context.station_keys.advance_counter(&MAC, 7).await.unwrap();
+
+        // Provisioned once, the counter of the station is kept
+        assert_eq!(context.verifier(&MAC).await.unwrap().unwrap().counter(), 7);
+
+        let mut signer = edge_protocol::Signer::new(station_key(&[0x42; 32], &MAC), 7);
+        let mut buffer = [0u8; 64];
+        let len = signer.sign(&mut buffer, 5).unwrap();
+        assert!(context.verifier(&MAC).await.unwrap().unwrap().verify(&buffer[..len]).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_config_write_is_signed_for_stations_with_a_key() {
+        let context = context(Some([0x42; 32]), false).await;
+        assert!(context.config_write(&MAC, None).await.unwrap().is_none());
+
+        let config = PeripheralConfig { sampling_period_secs: 300, ..PeripheralConfig::DEFAULT };
+        context.station_settings.set(&MAC, &config).await.unwrap();
+        let (_, unsigned) = context.config_write(&MAC, None).await.unwrap().unwrap();
+        assert_eq!(PeripheralConfig::from_tlv(&unsigned), Ok(config));
+
+        let verifier = context.verifier(&MAC).await.unwrap().unwrap();
+        let (_, signed) = context.config_write(&MAC, Some(&verifier)).await.unwrap().unwrap();
+        let mut peripheral = Verifier::new(downlink_key(&station_key(&[0x42; 32], &MAC)), 0);
+        assert_eq!(PeripheralConfig::from_signed_tlv(&signed, &mut peripheral), Ok(config));
+
+        // Signed again within the same second, the station accepts it
+        let (_, again) = context.config_write(&MAC, Some(&verifier)).await.unwrap().unwrap();
+        assert_eq!(PeripheralConfig::from_signed_tlv(&again, &mut peripheral), Ok(config));
+    }
+
+    #[tokio::test]
+    async fn test_verifier_policy_without_fleet_key() {
+        assert!(context(None, false).await.verifier(&MAC).await.unwrap().is_none());
+        assert!(context(None, true).await.verifier(&MAC).await.is_err());
+
+        // A key provisioned for the station alone is used whatever the policy
+        let context = context(None, true).await;
+        context.station_keys.set_key(&MAC, &[0x24; 32]).await.unwrap();
+        assert!(context.verifier(&MAC).await.unwrap().is_some());
+    }
+}
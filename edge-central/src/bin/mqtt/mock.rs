@@ -0,0 +1,35 @@
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+
+use crate::mqtt::Publisher;
+
+/// Records the messages published as topic, payload and retain flag, clones share the record
+#[derive(Clone, Default)]
+pub struct MockPublisher {
+    published: Arc<Mutex<Vec<(String, String, bool)>>>,
+}
+
+impl MockPublisher {
+    pub fn published(&self) -> Vec<(String, String, bool)> {
+        self.published.lock().unwrap().clone()
+    }
+}
+
+#[async_trait]
+impl Publisher for MockPublisher {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> anyhow::Result<()> {
+        self.published.lock().unwrap().push((topic.to_string(), String::from_utf8(payload)?, retain));
+        Ok(())
+    }
+}
+
+/// A broker that never answers, every publish waits forever
+pub struct PendingPublisher;
+
+#[async_trait]
+impl Publisher for PendingPublisher {
+    async fn publish(&self, _topic: &str, _payload: Vec<u8>, _retain: bool) -> anyhow::Result<()> {
+        std::future::pending().await
+    }
+}
@@ -0,0 +1,405 @@
+#[cfg(test)]
+pub mod mock;
+pub mod rumqtt;
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use edge_protocol::{Measurement, MeasurementSerieEntry};
+use serde_json::{json, Map, Value};
+use tokio::sync::{mpsc, Mutex};
+
+use crate::ble::format_mac;
+use crate::cfg::MqttConfig;
+use crate::data::types::DeviceInformation;
+use crate::measurements::types::PeripheralSyncResult;
+
+/// A connection to a broker
+#[async_trait]
+pub trait Publisher: Send + Sync {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<()>;
+}
+
+/// A channel of the measurements, a field of the state of the station announced as a Home Assistant sensor
+struct Sensor {
+    key: &'static str,
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: &'static str,
+    icon: Option<&'static str>,
+    value: fn(&Measurement) -> Option<Value>,
+}
+
+const SENSORS: [Sensor; 5] = [
+    Sensor {
+        key: "temperature",
+        name: "Temperature",
+        device_class: Some("temperature"),
+        unit: "°C",
+        icon: None,
+        value: |m| m.temperature.map(|v| json!(v)),
+    },
+    Sensor {
+        key: "humidity",
+        name: "Humidity",
+        device_class: Some("humidity"),
+        unit: "%",
+        icon: None,
+        value: |m| m.humidity.map(|v| json!(v)),
+    },
+    Sensor {
+        key: "lux",
+        name: "Illuminance",
+        device_class: Some("illuminance"),
+        unit: "lx",
+        icon: None,
+        value: |m| m.lux.map(|v| json!(v)),
+    },
+    // Home Assistant has no device class for the capacitance of the soil probe
+    Sensor {
+        key: "soil_pf",
+        name: "Soil moisture",
+        device_class: None,
+        unit: "pF",
+        icon: Some("mdi:water-percent"),
+        value: |m| m.soil_pf.map(|v| json!(v)),
+    },
+    Sensor {
+        key: "battery",
+        name: "Battery",
+        device_class: Some("battery"),
+        unit: "%",
+        icon: None,
+        value: |m| m.battery_percentage().map(|v| json!(v)),
+    },
+];
+
+/// The MAC without separators, as used in topics and ids
+fn node_id(mac: &[u8; 6]) -> String {
+    format_mac(mac).replace(':', "")
+}
+
+fn state_topic(config: &MqttConfig, mac: &[u8; 6]) -> String {
+    format!("{}/{}/state", config.topic_prefix, node_id(mac))
+}
+
+/// Retained discovery configs announcing each sensor of the station, grouped as one device
+pub fn discovery_messages(config: &MqttConfig, mac: &[u8; 6], device: Option<&DeviceInformation>) -> Vec<(String, Value)> {
+    let node_id = node_id(mac);
+    let mut station = json!({
+        "identifiers": [format!("mycelium_{}", node_id)],
+        "name": format!("Mycelium {}", format_mac(mac)),
+        "manufacturer": "Mycelium",
+    });
+    if let Some(device) = device {
+        station["model"] = json!(device.model);
+        station["sw_version"] = json!(device.firmware_version);
+        station["hw_version"] = json!(device.hardware_revision);
+    }
+
+    SENSORS
+        .iter()
+        .map(|sensor| {
+            let mut payload = json!({
+                "name": sensor.name,
+                "unique_id": format!("mycelium_{}_{}", node_id, sensor.key),
+                "state_topic": state_topic(config, mac),
+                "value_template": format!("{{{{ value_json.{} }}}}", sensor.key),
+                "unit_of_measurement": sensor.unit,
+                "state_class": "measurement",
+                "device": station,
+            });
+            if let Some(device_class) = sensor.device_class {
+                payload["device_class"] = json!(device_class);
+            }
+            if let Some(icon) = sensor.icon {
+                payload["icon"] = json!(icon);
+            }
+
+            let topic = format!("{}/sensor/mycelium_{}/{}/config", config.discovery_prefix, node_id, sensor.key);
+            (topic, payload)
+        })
+        .collect()
+}
+
+/// State of the station with the time of the measurement and the value of each channel it holds, absent channels are left out
+pub fn state_message(config: &MqttConfig, mac: &[u8; 6], entry: &MeasurementSerieEntry) -> (String, Value) {
+    let mut state = Map::new();
+    state.insert("timestamp".to_string(), json!(entry.timestamp.and_utc()));
+    for sensor in &SENSORS {
+        if let Some(value) = (sensor.value)(&entry.measurement) {
+            state.insert(sensor.key.to_string(), value);
+        }
+    }
+
+    (state_topic(config, mac), Value::Object(state))
+}
+
+/// Publishes every measurement of a sync oldest first, announcing a station the first time it is seen
+/// and again when its device information changes
+pub struct MqttSink {
+    config: MqttConfig,
+    publisher: Box<dyn Publisher>,
+    announced: Mutex<HashMap<[u8; 6], Option<DeviceInformation>>>,
+}
+
+impl MqttSink {
+    pub fn new(config: MqttConfig, publisher: Box<dyn Publisher>) -> Self {
+        Self { config, publisher, announced: Mutex::new(HashMap::new()) }
+    }
+
+    /// Publish an entry of a station, retained so the latest one is kept across restarts along with its time
+    pub async fn publish(&self, mac: &[u8; 6], entry: &MeasurementSerieEntry, device: Option<&DeviceInformation>) -> Result<()> {
+        let mut announced = self.announced.lock().await;
+        if announced.get(mac) != Some(&device.cloned()) {
+            for (topic, payload) in discovery_messages(&self.config, mac, device) {
+                self.publisher.publish(&topic, serde_json::to_vec(&payload)?, true).await?;
+            }
+            announced.insert(*mac, device.cloned());
+        }
+        drop(announced);
+
+        let (topic, state) = state_message(&self.config, mac, entry);
+        self.publisher.publish(&topic, serde_json::to_vec(&state)?, true).await?;
+
+        Ok(())
+    }
+
+    /// Publish on a task of its own, a broker that is down doesn't hold up the syncs
+    pub fn spawn(self, capacity: usize) -> MqttQueue {
+        let (tx, mut rx) = mpsc::channel::<Publication>(capacity);
+        tokio::spawn(async move {
+            while let Some(Publication { mac, entries, device }) = rx.recv().await {
+                for entry in &entries {
+                    // Published in order or not at all, a later entry must not be overwritten by an older one
+                    if let Err(err) = self.publish(&mac, entry, device.as_ref()).await {
+                        tracing::error!("Failed to publish sync to MQTT {}", err);
+                        break;
+                    }
+                }
+            }
+        });
+
+        MqttQueue { tx }
+    }
+}
+
+struct Publication {
+    mac: [u8; 6],
+    entries: Vec<MeasurementSerieEntry>,
+    device: Option<DeviceInformation>,
+}
+
+/// Syncs waiting for the publishing task of an [`MqttSink`]
+pub struct MqttQueue {
+    tx: mpsc::Sender<Publication>,
+}
+
+impl MqttQueue {
+    /// Queue the entries of the sync in timestamp order, returns false when they were dropped because the queue is full
+    pub fn push(&self, result: &PeripheralSyncResult, device: Option<DeviceInformation>) -> bool {
+        if result.measurements.is_empty() {
+            return true;
+        }
+
+        let mut entries = result.measurements.clone();
+        entries.sort_by_key(|entry| entry.timestamp);
+
+        let publication = Publication { mac: result.address, entries, device };
+        match self.tx.try_send(publication) {
+            Ok(()) => true,
+            Err(err) => {
+                tracing::warn!(mac = %format_mac(&result.address), "Dropping MQTT publication, the broker is behind {}", err);
+                false
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::{NaiveDate, TimeDelta};
+
+    use crate::mqtt::mock::{MockPublisher, PendingPublisher};
+
+    const MAC: [u8; 6] = [0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff];
+
+    fn config() -> MqttConfig {
+        MqttConfig {
+            host: "localhost".to_string(),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: "test".to_string(),
+            discovery_prefix: "homeassistant".to_string(),
+            topic_prefix: "mycelium".to_string(),
+        }
+    }
+
+    fn device(firmware_version: &str) -> DeviceInformation {
+        DeviceInformation { model: "Mycelium".to_string(), firmware_version: firmware_version.to_string(), hardware_revision: "1".to_string() }
+    }
+
+    /// Entries a minute apart, starting at noon
+    fn entries(measurements: Vec<Measurement>) -> Vec<MeasurementSerieEntry> {
+        let noon = NaiveDate::from_ymd_opt(2026, 10, 18).unwrap().and_hms_opt(12, 0, 0).unwrap();
+        measurements
+            .into_iter()
+            .enumerate()
+            .map(|(i, measurement)| MeasurementSerieEntry { timestamp: noon + TimeDelta::minutes(i as i64), measurement })
+            .collect()
+    }
+
+    fn sync(measurements: Vec<Measurement>) -> PeripheralSyncResult {
+        PeripheralSyncResult {
+            address: MAC,
+            time_drift: chrono::Duration::zero(),
+            measurements: entries(measurements),
+            decode_failures: 0,
+            rssi: None,
+            sync_id: None,
+        }
+    }
+
+    fn measurement(temperature: Option<f32>) -> Measurement {
+        Measurement {
+            battery: Some(80),
+            lux: Some(1200.0),
+            temperature,
+            humidity: Some(55.5),
+            soil_pf: Some(310.25),
+            tank_pf: None,
+            battery_mv: None,
+        }
+    }
+
+    fn states(publisher: &MockPublisher) -> Vec<Value> {
+        publisher
+            .published()
+            .iter()
+            .filter(|(topic, _, _)| topic == "mycelium/aabbccddeeff/state")
+            .map(|(_, payload, _)| serde_json::from_str(payload).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_discovery_messages() {
+        let messages = discovery_messages(&config(), &MAC, Some(&device("v0.1.0")));
+
+        assert_eq!(messages.len(), 5);
+        let (topic, payload) = &messages[0];
+        assert_eq!(topic, "homeassistant/sensor/mycelium_aabbccddeeff/temperature/config");
+        assert_eq!(payload["unique_id"], "mycelium_aabbccddeeff_temperature");
+        assert_eq!(payload["state_topic"], "mycelium/aabbccddeeff/state");
+        assert_eq!(payload["value_template"], "{{ value_json.temperature }}");
+        assert_eq!(payload["device_class"], "temperature");
+        assert_eq!(payload["unit_of_measurement"], "°C");
+        assert_eq!(payload["device"]["identifiers"], json!(["mycelium_aabbccddeeff"]));
+        assert_eq!(payload["device"]["name"], "Mycelium aa:bb:cc:dd:ee:ff");
+        assert_eq!(payload["device"]["sw_version"], "v0.1.0");
+
+        // All sensors belong to the same device
+        assert!(messages.iter().all(|(_, payload)| payload["device"] == messages[0].1["device"]));
+
+        let (_, soil) = messages.iter().find(|(topic, _)| topic.contains("/soil_pf/")).unwrap();
+        assert!(soil.get("device_class").is_none());
+        assert_eq!(soil["unit_of_measurement"], "pF");
+    }
+
+    #[test]
+    fn test_discovery_without_device_information() {
+        let messages = discovery_messages(&config(), &MAC, None);
+
+        assert!(messages.iter().all(|(_, payload)| payload["device"].get("sw_version").is_none()));
+    }
+
+    #[test]
+    fn test_state_message_skips_absent_channels() {
+        let (topic, state) = state_message(&config(), &MAC, &entries(vec![measurement(None)])[0]);
+
+        assert_eq!(topic, "mycelium/aabbccddeeff/state");
+        assert_eq!(
+            state,
+            json!({
+                "timestamp": "2026-10-18T12:00:00Z",
+                "humidity": 55.5,
+                "lux": 1200.0,
+                "soil_pf": 310.25,
+                "battery": 80,
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sink_announces_once_and_publishes_retained_state() {
+        let publisher = MockPublisher::default();
+        let sink = MqttSink::new(config(), Box::new(publisher.clone()));
+        let entries = entries(vec![measurement(Some(20.0)), measurement(Some(21.5))]);
+
+        sink.publish(&MAC, &entries[0], Some(&device("v0.1.0"))).await.unwrap();
+        sink.publish(&MAC, &entries[1], Some(&device("v0.1.0"))).await.unwrap();
+
+        let published = publisher.published();
+        assert_eq!(published.iter().filter(|(topic, _, _)| topic.ends_with("/config")).count(), 5);
+        assert!(published.iter().all(|(_, _, retain)| *retain));
+
+        let temperatures: Vec<Value> = states(&publisher).iter().map(|state| state["temperature"].clone()).collect();
+        assert_eq!(temperatures, vec![json!(20.0), json!(21.5)]);
+    }
+
+    #[tokio::test]
+    async fn test_sink_announces_again_after_firmware_update() {
+        let publisher = MockPublisher::default();
+        let sink = MqttSink::new(config(), Box::new(publisher.clone()));
+        let entry = entries(vec![measurement(Some(20.0))])[0];
+
+        sink.publish(&MAC, &entry, Some(&device("v0.1.0"))).await.unwrap();
+        sink.publish(&MAC, &entry, Some(&device("v0.2.0"))).await.unwrap();
+
+        let configs: Vec<Value> = publisher
+            .published()
+            .iter()
+            .filter(|(topic, _, _)| topic.ends_with("/temperature/config"))
+            .map(|(_, payload, _)| serde_json::from_str(payload).unwrap())
+            .collect();
+        assert_eq!(configs.len(), 2);
+        assert_eq!(configs[1]["device"]["sw_version"], "v0.2.0");
+    }
+
+    #[tokio::test]
+    async fn test_queue_publishes_every_entry_in_timestamp_order() {
+        let publisher = MockPublisher::default();
+        let queue = MqttSink::new(config(), Box::new(publisher.clone())).spawn(8);
+        let mut sync = sync(vec![measurement(Some(20.0)), measurement(Some(21.5)), measurement(Some(19.0))]);
+        sync.measurements.reverse();
+
+        assert!(queue.push(&sync, None));
+
+        tokio::time::timeout(std::time::Duration::from_secs(5), async {
+            while states(&publisher).len() < 3 {
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("Nothing published");
+
+        // The newest entry is published last, it is the one the broker retains
+        let states = states(&publisher);
+        let timestamps: Vec<Value> = states.iter().map(|state| state["timestamp"].clone()).collect();
+        assert_eq!(timestamps, vec![json!("2026-10-18T12:00:00Z"), json!("2026-10-18T12:01:00Z"), json!("2026-10-18T12:02:00Z")]);
+        assert_eq!(states[2]["temperature"], json!(19.0));
+    }
+
+    #[tokio::test]
+    async fn test_queue_drops_publications_when_the_broker_hangs() {
+        let queue = MqttSink::new(config(), Box::new(PendingPublisher)).spawn(1);
+
+        // The task waits on the first publication forever, the queue holds one more
+        let pushed: Vec<bool> = (0..3).map(|_| queue.push(&sync(vec![measurement(Some(20.0))]), None)).collect();
+
+        assert!(pushed[0]);
+        assert!(!pushed[2]);
+    }
+}
@@ -0,0 +1,129 @@
+use std::time::Duration;
+
+use anyhow::Result;
+use async_trait::async_trait;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+
+use crate::cfg::MqttConfig;
+use crate::mqtt::Publisher;
+
+/// Publishes with QoS 1, the event loop reconnects in the background when the broker goes away
+pub struct RumqttPublisher {
+    client: AsyncClient,
+}
+
+impl RumqttPublisher {
+    /// Spawn the event loop of a client for the broker of the configuration
+    pub fn spawn(config: &MqttConfig) -> Self {
+        let mut options = MqttOptions::new(&config.client_id, &config.host, config.port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let Some(username) = &config.username {
+            options.set_credentials(username, config.password.clone().unwrap_or_default());
+        }
+
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let broker = format!("{}:{}", config.host, config.port);
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = eventloop.poll().await {
+                    tracing::warn!(%broker, "MQTT connection failed {}", err);
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+            }
+        });
+
+        Self { client }
+    }
+}
+
+#[async_trait]
+impl Publisher for RumqttPublisher {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, retain: bool) -> Result<()> {
+        self.client.publish(topic, QoS::AtLeastOnce, retain, payload).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDate;
+    use edge_protocol::{Measurement, MeasurementSerieEntry};
+    use rumqttc::{Event, Packet};
+
+    use crate::mqtt::MqttSink;
+
+    /// Run `mosquitto -p 1883` before `cargo test -- --ignored`, `MQTT_HOST` points to a broker elsewhere
+    #[tokio::test]
+    #[ignore = "needs an MQTT broker on localhost:1883"]
+    async fn test_publishes_to_broker() {
+        let run = chrono::Utc::now().timestamp_millis();
+        let host = std::env::var("MQTT_HOST").unwrap_or_else(|_| "localhost".to_string());
+        let config = MqttConfig {
+            host: host.clone(),
+            port: 1883,
+            username: None,
+            password: None,
+            client_id: format!("mycelium-test-{}", run),
+            discovery_prefix: format!("homeassistant-test-{}", run),
+            topic_prefix: format!("mycelium-test-{}", run),
+        };
+
+        let (subscriber, mut eventloop) = AsyncClient::new(MqttOptions::new(format!("subscriber-{}", run), &host, 1883), 16);
+        subscriber.subscribe(format!("{}/#", config.discovery_prefix), QoS::AtLeastOnce).await.unwrap();
+        subscriber.subscribe(format!("{}/#", config.topic_prefix), QoS::AtLeastOnce).await.unwrap();
+        let mut subscriptions = 0;
+        while subscriptions < 2 {
+            if let Event::Incoming(Packet::SubAck(_)) = eventloop.poll().await.unwrap() {
+                subscriptions += 1;
+            }
+        }
+
+        let sink = MqttSink::new(config.clone(), Box::new(RumqttPublisher::spawn(&config)));
+        let measurement = Measurement {
+            battery: Some(80),
+            lux: Some(1200.0),
+            temperature: Some(21.5),
+            humidity: Some(55.5),
+            soil_pf: Some(310.25),
+            tank_pf: None,
+            battery_mv: None,
+        };
+        let entry = MeasurementSerieEntry {
+            timestamp: NaiveDate::from_ymd_opt(2026, 10, 18).unwrap().and_hms_opt(12, 0, 0).unwrap(),
+            measurement,
+        };
+        sink.publish(&[0xaa, 0xbb, 0xcc, 0xdd, 0xee, 0xff], &entry, None).await.unwrap();
+
+        // Discovery of the five sensors and the state
+        let mut received = vec![];
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while received.len() < 6 {
+                if let Event::Incoming(Packet::Publish(publish)) = eventloop.poll().await.unwrap() {
+                    received.push((publish.topic, String::from_utf8(publish.payload.to_vec()).unwrap()));
+                }
+            }
+        })
+        .await
+        .expect("Timed out waiting for the broker");
+
+        let state = format!("{}/aabbccddeeff/state", config.topic_prefix);
+        let (_, state) = received.iter().find(|(topic, _)| *topic == state).expect("No state published");
+        let state: serde_json::Value = serde_json::from_str(state).unwrap();
+        assert_eq!(state["temperature"], 21.5);
+        assert_eq!(state["timestamp"], "2026-10-18T12:00:00Z");
+        let discovery = format!("{}/sensor/mycelium_aabbccddeeff/temperature/config", config.discovery_prefix);
+        assert!(received.iter().any(|(topic, _)| *topic == discovery));
+
+        // Clear the retained messages of the run
+        for (topic, _) in &received {
+            subscriber.publish(topic, QoS::AtLeastOnce, true, Vec::new()).await.unwrap();
+        }
+        let mut cleared = 0;
+        while cleared < received.len() {
+            if let Event::Incoming(Packet::PubAck(_)) = eventloop.poll().await.unwrap() {
+                cleared += 1;
+            }
+        }
+    }
+}
@@ -0,0 +1,296 @@
+//! GATT services of edge-peripheral's `ble.rs`, on the host stack the central uses.
+//!
+//! The firmware declares the same table on an older trouble release, what the characteristics
+//! do is left to [`edge_peripheral_core::gatt`] so both serve a central alike.
+
+use edge_peripheral_core::gatt::{self, Access, AdvertisingData, DeviceInformation, GattError, GattServer};
+use edge_peripheral_core::{Clock, Session, Transport};
+use edge_protocol::{
+    ess, Broadcast, CommandRequest, HistoryAck, ProtocolHello, ADDRESS_CHARACTERISTIC_UUID_16, ADDRESS_SERVICE_UUID_16, BATTERY_LEVEL_CHARACTERISTIC_UUID,
+    BATTERY_SERVICE_UUID, COMMAND_CHARACTERISTIC_UUID_16, CONFIG_CHARACTERISTIC_UUID_16, CURRENT_TIME_CHARACTERISTIC_UUID, CURRENT_TIME_SERVICE_UUID,
+    DEVICE_INFORMATION_SERVICE_UUID, DEVICE_NAME, ENVIRONMENTAL_SENSING_SERVICE_UUID, FIRMWARE_REVISION_CHARACTERISTIC_UUID,
+    HARDWARE_REVISION_CHARACTERISTIC_UUID, HISTORY_ACK_CHARACTERISTIC_UUID_16, HUMIDITY_CHARACTERISTIC_UUID, ILLUMINANCE_CHARACTERISTIC_UUID,
+    MAX_AUTHENTICATED_FRAME_LEN, MAX_DEVICE_INFORMATION_LEN, MEASUREMENT_CHARACTERISTIC_UUID_16, MEASUREMENT_SERVICE_UUID_16,
+    MODEL_NUMBER_CHARACTERISTIC_UUID, PROTOCOL_CHARACTERISTIC_UUID_16, TEMPERATURE_CHARACTERISTIC_UUID,
+};
+use embassy_futures::select::select3;
+use heapless::Vec;
+use tracing::{error, info, warn};
+use trouble_host::prelude::*;
+use trouble_host::Stack;
+
+use crate::ble::ResultAny;
+
+/// The configuration of the protocol, the trouble prelude has a GAP one by the same name
+type StationConfig = edge_protocol::PeripheralConfig;
+
+const CONFIG_LEN: usize = StationConfig::MAX_SIGNED_TLV_LEN;
+
+const COMMAND_LEN: usize = CommandRequest::MAX_SIGNED_LEN;
+
+/// The firmware version tells a simulator apart from a station
+const DEVICE_INFORMATION: DeviceInformation = DeviceInformation {
+    model: "Mycelium",
+    firmware_version: concat!(env!("CARGO_PKG_VERSION"), "-simulator"),
+    hardware_revision: "simulator",
+};
+
+/// Time a central has to connect and sync before the peripheral goes back to sleep
+const SESSION_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+
+/// Max number of connections
+pub const CONNECTIONS_MAX: usize = 1;
+
+/// Max number of L2CAP channels.
+pub const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
+
+#[gatt_server]
+struct Server {
+    address_service: AddressService,
+    time_service: TimeService,
+    measurement_service: MeasurementService,
+    environmental_sensing_service: EnvironmentalSensingService,
+    battery_service: BatteryService,
+    device_information_service: DeviceInformationService
+}
+
+#[gatt_service(uuid = ADDRESS_SERVICE_UUID_16)]
+struct AddressService {
+    #[characteristic(uuid = ADDRESS_CHARACTERISTIC_UUID_16, read)]
+    address: [u8; 6]
+}
+
+/// Time service
+#[gatt_service(uuid = BluetoothUuid16::new(CURRENT_TIME_SERVICE_UUID))]
+struct TimeService {
+    #[characteristic(uuid = BluetoothUuid16::new(CURRENT_TIME_CHARACTERISTIC_UUID), write, read)]
+    current_time: [u8; 10]
+}
+
+/// Environmental sensing service, the latest measurement for generic BLE tools
+#[gatt_service(uuid = BluetoothUuid16::new(ENVIRONMENTAL_SENSING_SERVICE_UUID))]
+struct EnvironmentalSensingService {
+    #[characteristic(uuid = BluetoothUuid16::new(TEMPERATURE_CHARACTERISTIC_UUID), read)]
+    temperature: [u8; ess::TEMPERATURE_LEN],
+    #[characteristic(uuid = BluetoothUuid16::new(HUMIDITY_CHARACTERISTIC_UUID), read)]
+    humidity: [u8; ess::HUMIDITY_LEN],
+    #[characteristic(uuid = BluetoothUuid16::new(ILLUMINANCE_CHARACTERISTIC_UUID), read)]
+    illuminance: [u8; ess::ILLUMINANCE_LEN]
+}
+
+/// Battery service
+#[gatt_service(uuid = BluetoothUuid16::new(BATTERY_SERVICE_UUID))]
+struct BatteryService {
+    #[characteristic(uuid = BluetoothUuid16::new(BATTERY_LEVEL_CHARACTERISTIC_UUID), read)]
+    level: [u8; 1]
+}
+
+/// Device information service, lets a central tell which firmware a station runs
+#[gatt_service(uuid = BluetoothUuid16::new(DEVICE_INFORMATION_SERVICE_UUID))]
+struct DeviceInformationService {
+    #[characteristic(uuid = BluetoothUuid16::new(MODEL_NUMBER_CHARACTERISTIC_UUID), read)]
+    model: Vec<u8, MAX_DEVICE_INFORMATION_LEN>,
+    #[characteristic(uuid = BluetoothUuid16::new(FIRMWARE_REVISION_CHARACTERISTIC_UUID), read)]
+    firmware_version: Vec<u8, MAX_DEVICE_INFORMATION_LEN>,
+    #[characteristic(uuid = BluetoothUuid16::new(HARDWARE_REVISION_CHARACTERISTIC_UUID), read)]
+    hardware_revision: Vec<u8, MAX_DEVICE_INFORMATION_LEN>
+}
+
+#[gatt_service(uuid = MEASUREMENT_SERVICE_UUID_16)]
+struct MeasurementService {
+    /// Encoded and signed frame of the page being served
+    #[characteristic(uuid = MEASUREMENT_CHARACTERISTIC_UUID_16, read)]
+    measurement: Vec<u8, MAX_AUTHENTICATED_FRAME_LEN>,
+    #[characteristic(uuid = PROTOCOL_CHARACTERISTIC_UUID_16, write, read)]
+    protocol: [u8; ProtocolHello::LEN],
+    #[characteristic(uuid = HISTORY_ACK_CHARACTERISTIC_UUID_16, write)]
+    history_ack: [u8; HistoryAck::LEN],
+    #[characteristic(uuid = CONFIG_CHARACTERISTIC_UUID_16, write, read)]
+    config: Vec<u8, CONFIG_LEN>,
+    #[characteristic(uuid = COMMAND_CHARACTERISTIC_UUID_16, write, read)]
+    command: Vec<u8, COMMAND_LEN>
+}
+
+/// BLE transport of the simulator, every session builds a fresh host on the same stack
+pub struct Ble<'s, 'r, C: Controller> {
+    stack: &'s Stack<'r, C, DefaultPacketPool>,
+    address: [u8; 6],
+}
+
+impl<'s, 'r, C: Controller> Ble<'s, 'r, C> {
+    pub fn new(stack: &'s Stack<'r, C, DefaultPacketPool>, address: [u8; 6]) -> Self {
+        Self { stack, address }
+    }
+}
+
+impl<C: Controller> Transport for Ble<'_, '_, C> {
+    fn mac(&self) -> [u8; 6] {
+        self.address
+    }
+
+    async fn serve<K: Clock>(&mut self, clock: &mut K, session: &mut Session<'_>) {
+        let address = self.address;
+        let Host { mut peripheral, runner, .. } = self.stack.build();
+
+        let server = match Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
+            name: DEVICE_NAME,
+            appearance: &appearance::UNKNOWN,
+        })) {
+            Ok(server) => server,
+            Err(err) => {
+                error!(?err, "Unable to start GATT service");
+                return;
+            }
+        };
+
+        let sync = async {
+            match advertise(&mut peripheral, &server, session.broadcast).await {
+                Ok(conn) => {
+                    info!("Got gatt connection");
+                    if let Err(err) = gatt_events_task(&server, &conn, clock, address, session).await {
+                        error!(?err, "[gatt] error");
+                    }
+                }
+                Err(err) => error!(?err, "[adv] error"),
+            }
+        };
+
+        select3(ble_task(runner), sync, tokio::time::sleep(SESSION_TIMEOUT)).await;
+    }
+}
+
+async fn ble_task<C: Controller>(mut runner: Runner<'_, C, DefaultPacketPool>) {
+    if let Err(err) = runner.run().await {
+        error!(?err, "[ble_task] error");
+    }
+}
+
+/// Stream events until the connection closes, what a read or write does is up to the GATT server of the core
+async fn gatt_events_task<K: Clock>(server: &Server<'_>, conn: &GattConnection<'_, '_, DefaultPacketPool>, clock: &mut K, address: [u8; 6], session: &mut Session<'_>) -> anyhow::Result<()> {
+    let mut attributes = server;
+    let mut gatt = GattServer::start(session, address, &DEVICE_INFORMATION, &mut attributes).map_err(gatt_error)?;
+
+    let reason = loop {
+        match conn.next().await {
+            GattConnectionEvent::Disconnected { reason } => break Some(reason),
+            GattConnectionEvent::Gatt { event } => {
+                let access = match &event {
+                    GattEvent::Read(read) => characteristic(server, read.handle()).map(|characteristic| gatt.read(characteristic, &*clock, session, &mut attributes)),
+                    GattEvent::Write(write) => {
+                        characteristic(server, write.handle()).map(|characteristic| gatt.write(characteristic, write.data(), clock, session, &mut attributes))
+                    }
+                    _ => None,
+                };
+                let access = access.transpose().map_err(gatt_error)?.unwrap_or(Access::Served);
+
+                match access {
+                    Access::TimeSet(now) => info!(%now, "[gatt] Write Event to current time Characteristic"),
+                    Access::Negotiated(negotiated) => info!(?negotiated, "[gatt] Write Event to protocol Characteristic"),
+                    Access::Configured(config) => info!(?config, "[gatt] Write Event to config Characteristic"),
+                    Access::Acknowledged(ack) => info!(timestamp = %ack.timestamp, "[gatt] Write Event to history ack Characteristic"),
+                    Access::Responded(response) => info!(status = ?response.status, "[gatt] Write Event to command Characteristic"),
+                    Access::Invalid(characteristic, err) => warn!(?characteristic, %err, "[gatt] invalid write"),
+                    Access::Served | Access::Drained => {}
+                }
+
+                match event.accept() {
+                    Ok(reply) => reply.send().await,
+                    Err(err) => warn!(?err, "[gatt] error sending response"),
+                };
+
+                match access {
+                    Access::Responded(response) => gatt::respond(&mut attributes, &response).map_err(gatt_error)?,
+                    Access::Drained => {
+                        tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                        break None;
+                    }
+                    _ => {}
+                }
+            }
+            _ => {} // ignore other Gatt Connection Events
+        }
+    };
+    info!(?reason, "[gatt] disconnected");
+    Ok(())
+}
+
+/// Characteristic of the core a handle of the table belongs to, when the central can read or write it
+fn characteristic(server: &Server<'_>, handle: u16) -> Option<gatt::Characteristic> {
+    let measurement_service = &server.measurement_service;
+    let handles = [
+        (server.time_service.current_time.handle, gatt::Characteristic::CurrentTime),
+        (measurement_service.measurement.handle, gatt::Characteristic::Measurement),
+        (measurement_service.protocol.handle, gatt::Characteristic::Protocol),
+        (measurement_service.history_ack.handle, gatt::Characteristic::HistoryAck),
+        (measurement_service.config.handle, gatt::Characteristic::Config),
+        (measurement_service.command.handle, gatt::Characteristic::Command),
+    ];
+
+    handles.into_iter().find(|(characteristic, _)| *characteristic == handle).map(|(_, characteristic)| characteristic)
+}
+
+impl gatt::Attributes for &Server<'_> {
+    type Error = anyhow::Error;
+
+    fn set(&mut self, characteristic: gatt::Characteristic, value: &[u8]) -> anyhow::Result<()> {
+        let server = *self;
+        match characteristic {
+            gatt::Characteristic::Address => set(server, &server.address_service.address, value),
+            gatt::Characteristic::CurrentTime => set(server, &server.time_service.current_time, value),
+            gatt::Characteristic::Measurement => set(server, &server.measurement_service.measurement, value),
+            gatt::Characteristic::Protocol => set(server, &server.measurement_service.protocol, value),
+            gatt::Characteristic::HistoryAck => set(server, &server.measurement_service.history_ack, value),
+            gatt::Characteristic::Config => set(server, &server.measurement_service.config, value),
+            gatt::Characteristic::Command => set(server, &server.measurement_service.command, value),
+            gatt::Characteristic::Temperature => set(server, &server.environmental_sensing_service.temperature, value),
+            gatt::Characteristic::Humidity => set(server, &server.environmental_sensing_service.humidity, value),
+            gatt::Characteristic::Illuminance => set(server, &server.environmental_sensing_service.illuminance, value),
+            gatt::Characteristic::BatteryLevel => set(server, &server.battery_service.level, value),
+            gatt::Characteristic::Model => set(server, &server.device_information_service.model, value),
+            gatt::Characteristic::FirmwareVersion => set(server, &server.device_information_service.firmware_version, value),
+            gatt::Characteristic::HardwareRevision => set(server, &server.device_information_service.hardware_revision, value),
+        }
+    }
+}
+
+fn set<T: FromGatt + for<'a> TryFrom<&'a [u8]>>(server: &Server<'_>, characteristic: &Characteristic<T>, value: &[u8]) -> anyhow::Result<()> {
+    let value = T::try_from(value).map_err(|_| anyhow::anyhow!("Value of {} bytes doesn't fit the characteristic", value.len()))?;
+    characteristic.set(server, &value).anyhow("Unable to set characteristic")
+}
+
+fn gatt_error(err: GattError<anyhow::Error>) -> anyhow::Error {
+    match err {
+        GattError::Protocol(err) => err.into(),
+        GattError::Attributes(err) => err,
+    }
+}
+
+/// Advertise like the firmware, with the latest measurement as service data when there is one
+async fn advertise<'values, 'server, C: Controller>(
+    peripheral: &mut Peripheral<'values, C, DefaultPacketPool>,
+    server: &'server Server<'values>,
+    broadcast: Option<Broadcast>,
+) -> Result<GattConnection<'values, 'server, DefaultPacketPool>, BleHostError<C::Error>> {
+    let data = match broadcast.map(|broadcast| AdvertisingData::broadcasting(&broadcast)) {
+        Some(Ok(data)) => data,
+        Some(Err(err)) => {
+            warn!(%err, "[adv] unable to encode broadcast");
+            AdvertisingData::connectable()
+        }
+        None => AdvertisingData::connectable(),
+    };
+
+    let advertiser = peripheral
+        .advertise(
+            &Default::default(),
+            Advertisement::ConnectableScannableUndirected {
+                adv_data: &data.adv_data,
+                scan_data: &data.scan_data,
+            },
+        )
+        .await?;
+    info!("[adv] advertising");
+    let conn = advertiser.accept().await?.with_attribute_server(server)?;
+    info!("[adv] connection established");
+    Ok(conn)
+}
@@ -0,0 +1,133 @@
+//! Virtual peripheral, runs the state machine of edge-peripheral with simulated sensors and
+//! serves the same GATT services, so the central can sync without flashing an ESP32.
+
+use chrono::{DateTime, NaiveDateTime, TimeDelta, Utc};
+use edge_peripheral_core::{Clock, Device, DeviceState, Sensors};
+use edge_protocol::battery::lipo_percentage;
+use edge_protocol::{static_random_address, AuthKey, Measurement};
+use tracing::{info, warn};
+use trouble_host::prelude::*;
+
+use crate::peripheral::gatt::{Ble, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX};
+
+pub mod gatt;
+
+/// Clock of a peripheral that just booted, the RTC counts from the unix epoch until a central sets it
+pub struct SimulatedClock {
+    offset: TimeDelta,
+}
+
+impl SimulatedClock {
+    pub fn boot() -> Self {
+        Self { offset: DateTime::UNIX_EPOCH.naive_utc() - Utc::now().naive_utc() }
+    }
+}
+
+impl Clock for SimulatedClock {
+    fn now(&self) -> NaiveDateTime {
+        Utc::now().naive_utc() + self.offset
+    }
+
+    fn set(&mut self, now: NaiveDateTime) {
+        self.offset = now - Utc::now().naive_utc();
+    }
+}
+
+/// Sensors drifting slowly around plausible values, the battery drains a little every sample
+pub struct SimulatedSensors {
+    last: Measurement,
+}
+
+impl Default for SimulatedSensors {
+    fn default() -> Self {
+        let battery_mv = 4100;
+
+        Self {
+            last: Measurement {
+                battery: Some(lipo_percentage(battery_mv)),
+                lux: Some(2000.0),
+                temperature: Some(21.0),
+                humidity: Some(55.0),
+                soil_pf: Some(250.0),
+                tank_pf: Some(300.0),
+                battery_mv: Some(battery_mv),
+            },
+        }
+    }
+}
+
+/// Random step in `-max..max`
+fn drift(max: f32) -> f32 {
+    (rand::random::<f32>() * 2.0 - 1.0) * max
+}
+
+impl Sensors for SimulatedSensors {
+    async fn sample(&mut self) -> Measurement {
+        let last = self.last;
+        let battery_mv = last.battery_mv.map(|mv| mv.saturating_sub(rand::random::<u16>() % 2).max(3300));
+
+        self.last = Measurement {
+            battery: battery_mv.map(lipo_percentage),
+            lux: last.lux.map(|lux| (lux + drift(250.0)).clamp(0.0, 100_000.0)),
+            temperature: last.temperature.map(|temperature| (temperature + drift(0.5)).clamp(-10.0, 45.0)),
+            humidity: last.humidity.map(|humidity| (humidity + drift(1.0)).clamp(0.0, 100.0)),
+            soil_pf: last.soil_pf.map(|pf| (pf + drift(5.0)).clamp(0.0, 450.0)),
+            tank_pf: last.tank_pf.map(|pf| (pf + drift(5.0)).clamp(0.0, 450.0)),
+            battery_mv,
+        };
+
+        self.last
+    }
+}
+
+/// Run the peripheral.
+///
+/// Wake-ups follow the firmware: a session of at most 10 seconds while awaiting time sync
+/// or flushing, then a sleep of the sampling period. The device starts out awaiting time
+/// sync, like a station that was just powered on.
+pub async fn run<C: Controller>(controller: C, address: [u8; 6], key: Option<AuthKey>) {
+    let mut resources: HostResources<DefaultPacketPool, CONNECTIONS_MAX, L2CAP_CHANNELS_MAX> = HostResources::new();
+    let stack = trouble_host::new(controller, &mut resources).set_random_address(Address::random(static_random_address(address)));
+    let mut ble = Ble::new(&stack, address);
+
+    let mut device = Device::INITIAL;
+    let mut clock = SimulatedClock::boot();
+    let mut sensors = SimulatedSensors::default();
+
+    info!(?address, authenticated = key.is_some(), "Simulating peripheral");
+
+    loop {
+        // A configuration written during this wake-up applies from the next one
+        let sampling_period = device.config.sampling_period();
+
+        let effects = match device.state {
+            DeviceState::AwaitingTimeSync => {
+                info!("Awaiting time sync");
+                device.await_time_sync(&mut clock, &mut ble, key).await
+            }
+            DeviceState::Buffering(_) => device.buffer(&clock, &mut sensors).await,
+            DeviceState::Flush(..) => {
+                info!("Flushing");
+                device.flush(&mut clock, &mut sensors, &mut ble, key).await
+            }
+        };
+
+        match &device.state {
+            DeviceState::AwaitingTimeSync => {}
+            DeviceState::Buffering(measurements) => info!(entries = measurements.buckets.len(), "Buffering"),
+            DeviceState::Flush(measurements, _) => info!(entries = measurements.buckets.len(), "Waiting for the next flush"),
+        }
+
+        if effects.identify {
+            info!("Identifying, the LED of a station would blink now");
+        }
+        if effects.dropped_sample {
+            warn!("History full, sample dropped until the central acknowledges it");
+        }
+        if effects.reboot {
+            info!("Rebooting");
+        }
+
+        tokio::time::sleep(sampling_period).await;
+    }
+}
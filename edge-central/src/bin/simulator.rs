@@ -0,0 +1,55 @@
+//! Virtual peripheral for testing the BLE path of the central without an ESP32.
+//!
+//! `simulator [hci device] [address]` serves the GATT services of edge-peripheral on a
+//! Linux HCI controller. A pair of linked virtual controllers is created with BlueZ's
+//! `btvirt -L -l2`, the simulator takes one and the central the other.
+//!
+//! Measurements are signed when `MYCELIUM_AUTH_KEY` holds the fleet key, the station derives
+//! its own key from it like the firmware built with the same key does.
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub mod ble;
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+pub mod peripheral;
+
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .init();
+
+    if let Err(e) = work().await {
+        tracing::error!(?e, "Simulator crashed");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(all(target_os = "linux", target_arch = "aarch64"))]
+async fn work() -> anyhow::Result<()> {
+    use bt_hci::controller::ExternalController;
+    use edge_protocol::auth::{parse_key, station_key};
+    use crate::ble::{format_mac, hci::Transport, parse_mac};
+
+    /// Address of the simulated station when none is given
+    const ADDRESS: [u8; 6] = [0xde, 0xad, 0xbe, 0xef, 0x00, 0x01];
+
+    let args: Vec<String> = std::env::args().collect();
+    let dev = args.get(1).map(|dev| dev.parse::<u16>()).transpose()?.unwrap_or(1);
+    let address = args.get(2).map(|mac| parse_mac(mac)).transpose()?.unwrap_or(ADDRESS);
+    let key = match std::env::var("MYCELIUM_AUTH_KEY") {
+        Ok(hex) => Some(station_key(&parse_key(&hex).map_err(|err| anyhow::anyhow!("Invalid MYCELIUM_AUTH_KEY: {}", err))?, &address)),
+        Err(_) => None,
+    };
+
+    tracing::info!(dev, address = format_mac(&address), signed = key.is_some(), "Simulating station");
+    let transport = Transport::new(dev)?;
+    let controller = ExternalController::<_, 8>::new(transport);
+    peripheral::run(controller, address, key).await;
+
+    Ok(())
+}
+
+#[cfg(not(all(target_os = "linux", target_arch = "aarch64")))]
+async fn work() -> anyhow::Result<()> {
+    Err(anyhow::anyhow!("The simulator requires the HCI transport"))
+}
@@ -47,8 +47,8 @@ impl Status for I2cStatus {
 
         // --- Sensor rows ---
         let lines = [
-            format!("T {:.0} C  | S {:.0} pF", status.temperature, status.soil_moisture),
-            format!("H {:.0} RH | L {:.0} lx", status.humidity, status.light)
+            format!("T {} C  | S {} pF", reading(status.temperature), reading(status.soil_moisture)),
+            format!("H {} RH | L {} lx", reading(status.humidity), reading(status.light))
         ];
 
         let mut y = 20;
@@ -64,4 +64,9 @@ impl Status for I2cStatus {
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Channels without a reading show a dash
+fn reading(value: Option<f32>) -> String {
+    value.map_or_else(|| "-".to_string(), |v| format!("{:.0}", v))
+}
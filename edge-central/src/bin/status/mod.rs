@@ -28,10 +28,10 @@ pub fn make_status() -> Result<Box<dyn Status>> {
 pub struct StatusSummary {
     pub from: DateTime<Utc>,
     pub till: DateTime<Utc>,
-    pub temperature: f32,
-    pub humidity: f32,
-    pub soil_moisture: f32,
-    pub light: f32
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub soil_moisture: Option<f32>,
+    pub light: Option<f32>
 }
 
 impl StatusSummary {
@@ -47,28 +47,24 @@ impl StatusSummary {
         let from = DateTime::<Utc>::from_naive_utc_and_offset(from_naive, Utc);
         let till = DateTime::<Utc>::from_naive_utc_and_offset(till_naive, Utc);
 
-        // Summation
-        let mut temp_sum = 0.0;
-        let mut humidity_sum = 0.0;
-        let mut soil_sum = 0.0;
-        let mut light_sum = 0.0;
-
-        for m in measurements {
-            temp_sum += m.measurement.temperature;
-            humidity_sum += m.measurement.humidity;
-            soil_sum += m.measurement.soil_pf;
-            light_sum += m.measurement.lux;
-        }
-
-        let count = measurements.len() as f32;
-
         Some(StatusSummary {
             from,
             till,
-            temperature: temp_sum / count,
-            humidity: humidity_sum / count,
-            soil_moisture: soil_sum / count,
-            light: light_sum / count,
+            temperature: average(measurements.iter().filter_map(|m| m.measurement.temperature)),
+            humidity: average(measurements.iter().filter_map(|m| m.measurement.humidity)),
+            soil_moisture: average(measurements.iter().filter_map(|m| m.measurement.soil_pf)),
+            light: average(measurements.iter().filter_map(|m| m.measurement.lux)),
         })
     }
-}
\ No newline at end of file
+}
+
+/// Average of the values a channel reported, `None` when it never reported any
+fn average(values: impl Iterator<Item = f32>) -> Option<f32> {
+    let (sum, count) = values.fold((0.0, 0), |(sum, count), v| (sum + v, count + 1));
+
+    if count == 0 {
+        None
+    } else {
+        Some(sum / count as f32)
+    }
+}
@@ -0,0 +1,446 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{NaiveDateTime, TimeDelta, Utc};
+use edge_client_backend::{apis::{configuration::Configuration, Error}, models::{StationInsert, StationMeasurement, Watering}};
+use reqwest::StatusCode;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+use crate::ble::format_mac;
+use crate::data::sqlite::{OutboxEntry, SqliteOutboxRepository, SqliteStationDeviceRepository, SqliteStationRepository};
+use crate::irrigation::{parse_duration, Irrigation};
+
+/// Measurements uploaded per round, a long outage is backfilled over several rounds
+const BATCH_SIZE: u32 = 500;
+
+/// Exponential delays between failed uploads
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    pub initial: Duration,
+    pub max: Duration,
+}
+
+impl Default for Backoff {
+    fn default() -> Self {
+        Self { initial: Duration::from_secs(5), max: Duration::from_secs(15 * 60) }
+    }
+}
+
+impl Backoff {
+    /// Delay before the next attempt after `failures` failed uploads
+    pub fn delay(&self, failures: u32) -> Duration {
+        let factor = 1u32.checked_shl(failures.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.initial.saturating_mul(factor).min(self.max)
+    }
+}
+
+/// Uploads the outbox to the backend in the background, so syncs don't wait on flaky Wi-Fi
+pub struct Uploader {
+    configuration: Configuration,
+    outbox: SqliteOutboxRepository,
+    stations: SqliteStationRepository,
+    station_devices: Arc<SqliteStationDeviceRepository>,
+    irrigation: Arc<Irrigation>,
+    backoff: Backoff,
+    notify: Arc<Notify>,
+}
+
+impl Uploader {
+    pub fn new(
+        configuration: Configuration,
+        outbox: SqliteOutboxRepository,
+        stations: SqliteStationRepository,
+        station_devices: Arc<SqliteStationDeviceRepository>,
+        irrigation: Arc<Irrigation>,
+        backoff: Backoff,
+    ) -> Self {
+        Self { configuration, outbox, stations, station_devices, irrigation, backoff, notify: Arc::new(Notify::new()) }
+    }
+
+    /// Wakes the uploader once new measurements were stored
+    pub fn notifier(&self) -> Arc<Notify> {
+        self.notify.clone()
+    }
+
+    /// Upload until the process ends, sleeping until the next attempt is due or new measurements are stored.
+    /// Waterings whose report failed are reported again along the way
+    pub async fn run(self) {
+        loop {
+            if let Err(err) = self.irrigation.report_unreported(&self.stations).await {
+                tracing::warn!("Failed to report waterings {}", err);
+            }
+
+            let wait = match self.upload_due(Utc::now().naive_utc()).await {
+                Ok(_) => match self.outbox.next_attempt_at().await {
+                    Ok(next) => next.map(|at| (at - Utc::now().naive_utc()).to_std().unwrap_or(Duration::ZERO)),
+                    Err(err) => {
+                        tracing::error!("Failed to read the outbox {}", err);
+                        Some(self.backoff.initial)
+                    }
+                },
+                Err(err) => {
+                    tracing::error!("Failed to upload measurements {}", err);
+                    Some(self.backoff.initial)
+                }
+            };
+
+            let sleep = async {
+                match wait {
+                    Some(wait) => tokio::time::sleep(wait).await,
+                    None => std::future::pending().await,
+                }
+            };
+
+            tokio::select! {
+                _ = self.notify.notified() => {}
+                _ = sleep => {}
+            }
+        }
+    }
+
+    /// Upload the measurements due at `now`, station by station starting with the oldest measurement.
+    /// A failed station is postponed with backoff, returns the number of measurements uploaded
+    pub async fn upload_due(&self, now: NaiveDateTime) -> anyhow::Result<usize> {
+        let mut stations: Vec<([u8; 6], Vec<OutboxEntry>)> = vec![];
+
+        for entry in self.outbox.due(now, BATCH_SIZE).await? {
+            match stations.iter_mut().find(|(mac, _)| *mac == entry.mac) {
+                Some((_, entries)) => entries.push(entry),
+                None => stations.push((entry.mac, vec![entry])),
+            }
+        }
+
+        let mut uploaded = 0;
+
+        for (mac, entries) in stations {
+            let ids: Vec<i64> = entries.iter().map(|entry| entry.measurement_id).collect();
+
+            match self.checkin(&mac, &entries).await {
+                Ok((id, watering)) => {
+                    self.outbox.mark_uploaded(&ids, now).await?;
+                    uploaded += ids.len();
+                    self.water(mac, id, watering);
+                }
+                Err(err) => {
+                    let failures = entries.iter().map(|entry| entry.attempts).max().unwrap_or(0) + 1;
+                    let delay = self.backoff.delay(failures);
+                    tracing::warn!(mac = format_mac(&mac), failures, retry_in = ?delay, "Failed to upload measurements {}", err);
+                    self.outbox.mark_failed(&ids, &err.to_string(), now + TimeDelta::from_std(delay)?).await?;
+                }
+            }
+        }
+
+        Ok(uploaded)
+    }
+
+    /// Check the measurements in, returns the station they went to with the watering the backend decided on
+    async fn checkin(&self, mac: &[u8; 6], entries: &[OutboxEntry]) -> anyhow::Result<(Uuid, Watering)> {
+        let measurements: Vec<StationMeasurement> = entries
+            .iter()
+            .map(|entry| StationMeasurement {
+                on: entry.entry.timestamp.format("%Y-%m-%dT%H:%M:%SZ").to_string(),
+                battery_voltage: entry.entry.measurement.battery_mv.map(|mv| f64::from(mv) / 1000.0),
+                temperature: entry.entry.measurement.temperature.map(f64::from),
+                humidity: entry.entry.measurement.humidity.map(f64::from),
+                lux: entry.entry.measurement.lux.map(f64::from),
+                soil_pf: entry.entry.measurement.soil_pf.map(f64::from),
+                tank_pf: entry.entry.measurement.tank_pf.map(f64::from),
+            })
+            .collect();
+
+        // Broadcasts don't carry the device information, the version read on the last connected sync still applies
+        let firmware_version = self.station_devices.find(mac).await?.map(|device| device.firmware_version);
+
+        let id = self.station_id(mac).await?;
+
+        match edge_client_backend::apis::default_api::checkin_station(&self.configuration, id.to_string().as_str(), firmware_version.as_deref(), Some(measurements.clone())).await {
+            Err(Error::ResponseError(content)) if content.status == StatusCode::NOT_FOUND => {
+                // The user deleted the station, it is created again for the measurements still coming in
+                tracing::warn!(mac = format_mac(mac), %id, "Station no longer exists, creating it again");
+                self.stations.remove(mac).await?;
+
+                let id = self.station_id(mac).await?;
+                let watering = edge_client_backend::apis::default_api::checkin_station(&self.configuration, id.to_string().as_str(), firmware_version.as_deref(), Some(measurements)).await?;
+                Ok((id, watering))
+            }
+            result => Ok((id, result?)),
+        }
+    }
+
+    /// Act on the watering the backend decided on, in the background as it runs for a while.
+    /// Checkins while the station is watering don't start another one
+    fn water(&self, mac: [u8; 6], station_id: Uuid, watering: Watering) {
+        let Some(watering) = watering.watering else {
+            return;
+        };
+
+        let duration = match parse_duration(&watering) {
+            Ok(duration) => duration,
+            Err(err) => {
+                tracing::warn!(mac = format_mac(&mac), "Ignoring watering {}", err);
+                return;
+            }
+        };
+
+        let irrigation = self.irrigation.clone();
+        tokio::spawn(async move {
+            if let Err(err) = irrigation.water(&mac, station_id, duration).await {
+                tracing::error!(mac = format_mac(&mac), "Failed to water {}", err);
+            }
+        });
+    }
+
+    /// The backend id of the station, created the first time it uploads
+    async fn station_id(&self, mac: &[u8; 6]) -> anyhow::Result<Uuid> {
+        if let Some(id) = self.stations.find_backend_id(mac).await? {
+            return Ok(id);
+        }
+
+        let station_insert = StationInsert::new(format_mac(mac), "Unnamed".to_string());
+        let id = edge_client_backend::apis::default_api::add_station(&self.configuration, station_insert).await?;
+        self.stations.set_backend_id(mac, &id).await?;
+
+        Ok(id)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use edge_protocol::{Measurement, MeasurementSerieEntry};
+    use wiremock::matchers::{method, path};
+    use wiremock::{Mock, MockServer, ResponseTemplate};
+
+    use std::collections::HashMap;
+
+    use crate::data::sqlite::{test_pool, SqliteSyncRepository, SqliteWateringRepository};
+    use crate::irrigation::{mock::MockGpio, Actuator};
+    use crate::measurements::types::PeripheralSyncResult;
+
+    const STATION_ID: &str = "0b9c4e4e-2f6a-4a43-9a53-8f0c3f0a6b11";
+
+    fn at(secs: i64) -> NaiveDateTime {
+        Utc.timestamp_opt(1_700_000_000 + secs, 0).unwrap().naive_utc()
+    }
+
+    async fn uploader(server: &MockServer) -> (Uploader, SqliteSyncRepository) {
+        uploader_with(server, HashMap::new()).await
+    }
+
+    async fn uploader_with(server: &MockServer, actuators: HashMap<[u8; 6], Actuator>) -> (Uploader, SqliteSyncRepository) {
+        let pool = test_pool().await;
+
+        let configuration = Configuration { base_path: server.uri(), ..Configuration::default() };
+        let irrigation = Irrigation::new(configuration.clone(), SqliteWateringRepository::new(pool.clone()), actuators);
+        let uploader = Uploader::new(
+            configuration,
+            SqliteOutboxRepository::new(pool.clone()),
+            SqliteStationRepository::new(pool.clone()),
+            Arc::new(SqliteStationDeviceRepository::new(pool.clone())),
+            Arc::new(irrigation),
+            Backoff::default(),
+        );
+
+        (uploader, SqliteSyncRepository::new(pool))
+    }
+
+    fn sync(address: [u8; 6], offsets: &[i64]) -> PeripheralSyncResult {
+        PeripheralSyncResult {
+            address,
+            time_drift: TimeDelta::zero(),
+            measurements: offsets
+                .iter()
+                .map(|offset| MeasurementSerieEntry {
+                    timestamp: at(*offset),
+                    measurement: Measurement {
+                        battery: Some(90),
+                        lux: None,
+                        temperature: Some(21.5),
+                        humidity: Some(60.0),
+                        soil_pf: Some(300.0),
+                        tank_pf: None,
+                        battery_mv: Some(4100),
+                    },
+                })
+                .collect(),
+            decode_failures: 0,
+            rssi: None,
+            sync_id: None,
+        }
+    }
+
+    async fn mount_backend(server: &MockServer) {
+        Mock::given(method("POST"))
+            .and(path("/stations"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(STATION_ID))
+            .mount(server)
+            .await;
+        Mock::given(method("PUT"))
+            .and(path(format!("/stations/{}/checkin", STATION_ID)))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({})))
+            .mount(server)
+            .await;
+    }
+
+    /// Number of requests the backend received for `method` and `path`
+    async fn requests(server: &MockServer, method: &str, path: &str) -> usize {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.method.as_str() == method && request.url.path() == path)
+            .count()
+    }
+
+    /// The timestamps of every checkin, in the order the backend received them
+    async fn checkins(server: &MockServer) -> Vec<Vec<String>> {
+        server
+            .received_requests()
+            .await
+            .unwrap()
+            .iter()
+            .filter(|request| request.url.path().ends_with("/checkin"))
+            .map(|request| {
+                let measurements: Vec<StationMeasurement> = request.body_json().unwrap();
+                measurements.into_iter().map(|m| m.on).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_backoff_doubles_up_to_max() {
+        let backoff = Backoff::default();
+
+        assert_eq!(backoff.delay(1), Duration::from_secs(5));
+        assert_eq!(backoff.delay(2), Duration::from_secs(10));
+        assert_eq!(backoff.delay(5), Duration::from_secs(80));
+        assert_eq!(backoff.delay(9), Duration::from_secs(15 * 60));
+        assert_eq!(backoff.delay(u32::MAX), Duration::from_secs(15 * 60));
+    }
+
+    #[tokio::test]
+    async fn test_uploads_oldest_first_per_station() {
+        let server = MockServer::start().await;
+        mount_backend(&server).await;
+        let (uploader, syncs) = uploader(&server).await;
+
+        syncs.insert(&sync([0xaa; 6], &[120, 180]), at(200)).await.unwrap();
+        syncs.insert(&sync([0xbb; 6], &[60]), at(200)).await.unwrap();
+        syncs.insert(&sync([0xaa; 6], &[0]), at(300)).await.unwrap();
+
+        assert_eq!(uploader.upload_due(at(300)).await.unwrap(), 4);
+        assert_eq!(
+            checkins(&server).await,
+            vec![
+                vec!["2023-11-14T22:13:20Z".to_string(), "2023-11-14T22:15:20Z".to_string(), "2023-11-14T22:16:20Z".to_string()],
+                vec!["2023-11-14T22:14:20Z".to_string()],
+            ]
+        );
+
+        // Nothing goes out twice
+        assert_eq!(uploader.upload_due(at(400)).await.unwrap(), 0);
+        assert_eq!(uploader.outbox.next_attempt_at().await.unwrap(), None);
+        assert_eq!(checkins(&server).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_failed_upload_backs_off_and_backfills() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(503))
+            .up_to_n_times(2)
+            .mount(&server)
+            .await;
+        mount_backend(&server).await;
+        let (uploader, syncs) = uploader(&server).await;
+
+        syncs.insert(&sync([0xaa; 6], &[0]), at(0)).await.unwrap();
+        assert_eq!(uploader.upload_due(at(0)).await.unwrap(), 0);
+        assert_eq!(uploader.outbox.next_attempt_at().await.unwrap(), Some(at(5)));
+
+        // Nothing is retried before its backoff has passed, later syncs are backfilled with it
+        syncs.insert(&sync([0xaa; 6], &[60]), at(60)).await.unwrap();
+        assert_eq!(uploader.upload_due(at(4)).await.unwrap(), 0);
+        assert_eq!(uploader.upload_due(at(5)).await.unwrap(), 0);
+        assert_eq!(uploader.outbox.next_attempt_at().await.unwrap(), Some(at(15)));
+
+        assert_eq!(uploader.upload_due(at(60)).await.unwrap(), 2);
+        assert_eq!(uploader.outbox.next_attempt_at().await.unwrap(), None);
+        assert_eq!(checkins(&server).await.last().unwrap(), &vec!["2023-11-14T22:13:20Z".to_string(), "2023-11-14T22:14:20Z".to_string()]);
+    }
+
+    #[tokio::test]
+    async fn test_creates_station_once() {
+        let server = MockServer::start().await;
+        mount_backend(&server).await;
+        let (uploader, syncs) = uploader(&server).await;
+
+        syncs.insert(&sync([0xaa; 6], &[0]), at(0)).await.unwrap();
+        assert_eq!(uploader.upload_due(at(0)).await.unwrap(), 1);
+        syncs.insert(&sync([0xaa; 6], &[60]), at(60)).await.unwrap();
+        assert_eq!(uploader.upload_due(at(60)).await.unwrap(), 1);
+
+        assert_eq!(requests(&server, "POST", "/stations").await, 1);
+        assert_eq!(checkins(&server).await.len(), 2);
+        assert_eq!(uploader.stations.find_backend_id(&[0xaa; 6]).await.unwrap(), Some(Uuid::parse_str(STATION_ID).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_recreates_deleted_station() {
+        const DELETED_ID: &str = "6f1d2a3b-4c5d-4e6f-8a9b-0c1d2e3f4a5b";
+
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .and(path(format!("/stations/{}/checkin", DELETED_ID)))
+            .respond_with(ResponseTemplate::new(404))
+            .mount(&server)
+            .await;
+        mount_backend(&server).await;
+        let (uploader, syncs) = uploader(&server).await;
+
+        uploader.stations.set_backend_id(&[0xaa; 6], &Uuid::parse_str(DELETED_ID).unwrap()).await.unwrap();
+        syncs.insert(&sync([0xaa; 6], &[0]), at(0)).await.unwrap();
+
+        assert_eq!(uploader.upload_due(at(0)).await.unwrap(), 1);
+        assert_eq!(requests(&server, "PUT", &format!("/stations/{}/checkin", DELETED_ID)).await, 1);
+        assert_eq!(requests(&server, "POST", "/stations").await, 1);
+        assert_eq!(requests(&server, "PUT", &format!("/stations/{}/checkin", STATION_ID)).await, 1);
+        assert_eq!(uploader.stations.find_backend_id(&[0xaa; 6]).await.unwrap(), Some(Uuid::parse_str(STATION_ID).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn test_checkin_watering_drives_actuator() {
+        let server = MockServer::start().await;
+        Mock::given(method("PUT"))
+            .respond_with(ResponseTemplate::new(200).set_body_json(serde_json::json!({ "watering": "20 milliseconds" })))
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path(format!("/stations/{}/watered", STATION_ID)))
+            .respond_with(ResponseTemplate::new(200))
+            .mount(&server)
+            .await;
+        mount_backend(&server).await;
+
+        let gpio = MockGpio::default();
+        let actuator = Actuator::new(Box::new(gpio.clone()), false, Duration::from_secs(60));
+        let (uploader, syncs) = uploader_with(&server, HashMap::from([([0xaa; 6], actuator)])).await;
+
+        syncs.insert(&sync([0xaa; 6], &[0]), at(0)).await.unwrap();
+        assert_eq!(uploader.upload_due(at(0)).await.unwrap(), 1);
+
+        // The watering runs in the background, reported once the actuator is off
+        let watered = format!("/stations/{}/watered", STATION_ID);
+        for _ in 0..200 {
+            if requests(&server, "POST", &watered).await > 0 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_eq!(requests(&server, "POST", &watered).await, 1);
+        assert_eq!(gpio.levels(), vec![true, false]);
+    }
+}
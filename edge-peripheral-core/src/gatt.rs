@@ -0,0 +1,659 @@
+//! GATT server of the peripheral, independent of the BLE stack serving it.
+//!
+//! The firmware and the simulator declare the same services on different trouble releases,
+//! the attribute tables can't be shared between them. Each forwards the reads and writes of
+//! the central to [`GattServer`], which decides what they do to the session and sets the
+//! values served through [`Attributes`]. The stack only logs the [`Access`] and acts on it
+//! once it replied.
+
+use chrono::NaiveDateTime;
+use edge_protocol::{
+    battery, ess, Broadcast, Capabilities, CommandResponse, CommandStatus, CurrentTime, Frame, HistoryAck, MeasurementSerieEntry,
+    PeripheralConfig, ProtocolError, ProtocolHello, ADDRESS_CHARACTERISTIC_UUID_16, BATTERY_LEVEL_CHARACTERISTIC_UUID, COMMAND_CHARACTERISTIC_UUID_16,
+    CONFIG_CHARACTERISTIC_UUID_16, CURRENT_TIME_CHARACTERISTIC_UUID, CURRENT_TIME_SERVICE_UUID, DEVICE_NAME, FIRMWARE_REVISION_CHARACTERISTIC_UUID,
+    HARDWARE_REVISION_CHARACTERISTIC_UUID, HISTORY_ACK_CHARACTERISTIC_UUID_16, HUMIDITY_CHARACTERISTIC_UUID, ILLUMINANCE_CHARACTERISTIC_UUID,
+    MAX_AUTHENTICATED_FRAME_LEN, MAX_BROADCAST_LEN, MAX_DEVICE_INFORMATION_LEN, MEASUREMENT_CHARACTERISTIC_UUID_16, MEASUREMENT_SERVICE_UUID_16,
+    MODEL_NUMBER_CHARACTERISTIC_UUID, PROTOCOL_CHARACTERISTIC_UUID_16, TEMPERATURE_CHARACTERISTIC_UUID,
+};
+use heapless::Vec;
+
+use crate::{Clock, Session};
+
+/// Size of the advertising data and of the scan response
+pub const MAX_ADVERTISING_LEN: usize = 31;
+
+const AD_TYPE_FLAGS: u8 = 0x01;
+const AD_TYPE_COMPLETE_SERVICE_UUIDS_16: u8 = 0x03;
+const AD_TYPE_COMPLETE_LOCAL_NAME: u8 = 0x09;
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+
+/// LE general discoverable, BR/EDR not supported
+const AD_FLAGS: u8 = 0x06;
+
+/// Characteristics the server sets or handles the access of
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Characteristic {
+    Address,
+    CurrentTime,
+    /// Encoded and signed frame of the page being served
+    Measurement,
+    Protocol,
+    HistoryAck,
+    Config,
+    Command,
+    Temperature,
+    Humidity,
+    Illuminance,
+    BatteryLevel,
+    Model,
+    FirmwareVersion,
+    HardwareRevision,
+}
+
+impl Characteristic {
+    pub const ALL: [Characteristic; 14] = [
+        Characteristic::Address,
+        Characteristic::CurrentTime,
+        Characteristic::Measurement,
+        Characteristic::Protocol,
+        Characteristic::HistoryAck,
+        Characteristic::Config,
+        Characteristic::Command,
+        Characteristic::Temperature,
+        Characteristic::Humidity,
+        Characteristic::Illuminance,
+        Characteristic::BatteryLevel,
+        Characteristic::Model,
+        Characteristic::FirmwareVersion,
+        Characteristic::HardwareRevision,
+    ];
+
+    /// 16-bit UUID the characteristic is declared with
+    pub const fn uuid(self) -> u16 {
+        match self {
+            Characteristic::Address => ADDRESS_CHARACTERISTIC_UUID_16,
+            Characteristic::CurrentTime => CURRENT_TIME_CHARACTERISTIC_UUID,
+            Characteristic::Measurement => MEASUREMENT_CHARACTERISTIC_UUID_16,
+            Characteristic::Protocol => PROTOCOL_CHARACTERISTIC_UUID_16,
+            Characteristic::HistoryAck => HISTORY_ACK_CHARACTERISTIC_UUID_16,
+            Characteristic::Config => CONFIG_CHARACTERISTIC_UUID_16,
+            Characteristic::Command => COMMAND_CHARACTERISTIC_UUID_16,
+            Characteristic::Temperature => TEMPERATURE_CHARACTERISTIC_UUID,
+            Characteristic::Humidity => HUMIDITY_CHARACTERISTIC_UUID,
+            Characteristic::Illuminance => ILLUMINANCE_CHARACTERISTIC_UUID,
+            Characteristic::BatteryLevel => BATTERY_LEVEL_CHARACTERISTIC_UUID,
+            Characteristic::Model => MODEL_NUMBER_CHARACTERISTIC_UUID,
+            Characteristic::FirmwareVersion => FIRMWARE_REVISION_CHARACTERISTIC_UUID,
+            Characteristic::HardwareRevision => HARDWARE_REVISION_CHARACTERISTIC_UUID,
+        }
+    }
+
+    pub fn from_uuid(uuid: u16) -> Option<Self> {
+        Self::ALL.into_iter().find(|characteristic| characteristic.uuid() == uuid)
+    }
+}
+
+/// Values the attribute table of the BLE stack serves
+pub trait Attributes {
+    type Error;
+
+    /// Replace the value of a characteristic, fails when it doesn't fit the declared type
+    fn set(&mut self, characteristic: Characteristic, value: &[u8]) -> Result<(), Self::Error>;
+}
+
+#[derive(Debug)]
+pub enum GattError<E> {
+    /// A value to serve couldn't be encoded
+    Protocol(ProtocolError),
+    /// The attribute table didn't take a value
+    Attributes(E),
+}
+
+impl<E> From<ProtocolError> for GattError<E> {
+    fn from(err: ProtocolError) -> Self {
+        GattError::Protocol(err)
+    }
+}
+
+/// What the device information service serves
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeviceInformation {
+    pub model: &'static str,
+    pub firmware_version: &'static str,
+    pub hardware_revision: &'static str,
+}
+
+/// What a read or write of the central did, for the stack to log and act on
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Access {
+    /// Nothing beyond serving the value
+    Served,
+    TimeSet(NaiveDateTime),
+    Negotiated(ProtocolHello),
+    Configured(PeripheralConfig),
+    Acknowledged(HistoryAck),
+    /// To be set on the command characteristic once the write is replied to, so the central
+    /// reads the response instead of its own request
+    Responded(CommandResponse),
+    /// The central has the whole history, the connection can be closed
+    Drained,
+    /// The central wrote a value that doesn't decode or verify, it is ignored
+    Invalid(Characteristic, ProtocolError),
+}
+
+/// State of a connection, the session holds what outlives it
+pub struct GattServer {
+    hello: ProtocolHello,
+    /// Until the central says otherwise it is assumed to speak the legacy protocol
+    capabilities: Capabilities,
+}
+
+impl GattServer {
+    /// Set every value the central can read before it connects
+    pub fn start<A: Attributes>(session: &mut Session<'_>, address: [u8; 6], device: &DeviceInformation, attributes: &mut A) -> Result<Self, GattError<A::Error>> {
+        let mut hello = ProtocolHello::current();
+        if session.signer.is_none() {
+            hello.capabilities.remove(Capabilities::AUTHENTICATED);
+        }
+
+        let server = GattServer { hello, capabilities: ProtocolHello::legacy().capabilities };
+        server.set_page(session, attributes)?;
+        set(attributes, Characteristic::Protocol, &hello.to_bytes())?;
+        set(attributes, Characteristic::Address, &address)?;
+        set_config(attributes, session.config)?;
+        set_latest(attributes, session.entries)?;
+        set_device_information(attributes, device)?;
+
+        Ok(server)
+    }
+
+    /// Capabilities negotiated with the central so far
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// Handle a read before it is replied to
+    pub fn read<C: Clock, A: Attributes>(&mut self, characteristic: Characteristic, clock: &C, session: &mut Session<'_>, attributes: &mut A) -> Result<Access, GattError<A::Error>> {
+        match characteristic {
+            Characteristic::Measurement => {
+                let page = session.cursor.page(session.entries, self.capabilities);
+
+                // Without paging the read itself acknowledges the page that was served,
+                // with paging an empty page tells the central it has everything
+                if self.capabilities.contains(Capabilities::PAGED_HISTORY) && !page.is_empty() {
+                    return Ok(Access::Served);
+                }
+
+                if let Some(last) = page.last() {
+                    session.cursor.ack(HistoryAck { timestamp: last.timestamp });
+                }
+                Ok(Access::Drained)
+            }
+            Characteristic::CurrentTime => {
+                set(attributes, Characteristic::CurrentTime, &CurrentTime::from_naivedatetime(clock.now()).to_bytes())?;
+                Ok(Access::Served)
+            }
+            _ => Ok(Access::Served),
+        }
+    }
+
+    /// Handle a write before it is replied to
+    pub fn write<C: Clock, A: Attributes>(&mut self, characteristic: Characteristic, data: &[u8], clock: &mut C, session: &mut Session<'_>, attributes: &mut A) -> Result<Access, GattError<A::Error>> {
+        let invalid = |err| Ok(Access::Invalid(characteristic, err));
+
+        match characteristic {
+            Characteristic::CurrentTime => match CurrentTime::try_from_bytes(data).and_then(|time| time.to_naivedatetime()) {
+                Ok(now) => {
+                    clock.set(now);
+                    session.time_synced = true;
+                    Ok(Access::TimeSet(now))
+                }
+                Err(err) => invalid(err),
+            },
+            Characteristic::Protocol => match ProtocolHello::from_bytes(data) {
+                Ok(central) => {
+                    let negotiated = self.hello.negotiate(&central);
+                    self.capabilities = negotiated.capabilities;
+                    self.set_page(session, attributes)?;
+                    Ok(Access::Negotiated(negotiated))
+                }
+                Err(err) => invalid(err),
+            },
+            Characteristic::Config => match session.write_config(data) {
+                Ok(()) => {
+                    set_config(attributes, session.config)?;
+                    Ok(Access::Configured(*session.config))
+                }
+                Err(err) => invalid(err),
+            },
+            Characteristic::Command => {
+                let status = match session.command_request(data) {
+                    Ok(request) if !session.accepted.contains(&request.command) => CommandStatus::Rejected,
+                    Ok(request) if session.commands.contains(&request.command) => CommandStatus::Accepted,
+                    Ok(request) => match session.commands.push(request.command) {
+                        Ok(()) => CommandStatus::Accepted,
+                        Err(_) => CommandStatus::Rejected,
+                    },
+                    Err(ProtocolError::Unauthenticated | ProtocolError::InvalidTag | ProtocolError::Replayed { .. }) => CommandStatus::Rejected,
+                    Err(_) => CommandStatus::Unsupported,
+                };
+                Ok(Access::Responded(CommandResponse::for_request(data, status)))
+            }
+            Characteristic::HistoryAck => match HistoryAck::from_bytes(data) {
+                Ok(ack) => {
+                    session.cursor.ack(ack);
+                    self.set_page(session, attributes)?;
+                    Ok(Access::Acknowledged(ack))
+                }
+                Err(err) => invalid(err),
+            },
+            _ => Ok(Access::Served),
+        }
+    }
+
+    /// Serve the oldest page of measurements the central hasn't acknowledged yet
+    ///
+    /// Every page served is signed with a fresh counter, so a central never accepts the same page twice.
+    fn set_page<A: Attributes>(&self, session: &mut Session<'_>, attributes: &mut A) -> Result<(), GattError<A::Error>> {
+        let page = session.cursor.page(session.entries, self.capabilities);
+        let mut buffer = [0u8; MAX_AUTHENTICATED_FRAME_LEN];
+        let mut len = Frame::encode(self.capabilities, page, &mut buffer)?;
+
+        if let Some(signer) = session.signer.as_deref_mut().filter(|_| self.capabilities.contains(Capabilities::AUTHENTICATED)) {
+            len = signer.sign(&mut buffer, len)?;
+        }
+
+        set(attributes, Characteristic::Measurement, &buffer[..len])
+    }
+}
+
+/// Set the response to a command, once the write of the request is replied to
+pub fn respond<A: Attributes>(attributes: &mut A, response: &CommandResponse) -> Result<(), GattError<A::Error>> {
+    set(attributes, Characteristic::Command, &response.to_bytes())
+}
+
+fn set<A: Attributes>(attributes: &mut A, characteristic: Characteristic, value: &[u8]) -> Result<(), GattError<A::Error>> {
+    attributes.set(characteristic, value).map_err(GattError::Attributes)
+}
+
+/// Serve the configuration in use, so a central can read back what it wrote
+fn set_config<A: Attributes>(attributes: &mut A, config: &PeripheralConfig) -> Result<(), GattError<A::Error>> {
+    let mut buffer = [0u8; PeripheralConfig::MAX_TLV_LEN];
+    let len = config.to_tlv(&mut buffer)?;
+    set(attributes, Characteristic::Config, &buffer[..len])
+}
+
+/// Serve the latest measurement on the standard services, channels without a value read as not known.
+/// The battery level has no such value, it is the latest one measured rather than a 0% that would read as a flat battery
+fn set_latest<A: Attributes>(attributes: &mut A, entries: &[MeasurementSerieEntry]) -> Result<(), GattError<A::Error>> {
+    let measurement = entries.last().map(|entry| &entry.measurement);
+    set(attributes, Characteristic::Temperature, &ess::temperature(measurement.and_then(|m| m.temperature)))?;
+    set(attributes, Characteristic::Humidity, &ess::humidity(measurement.and_then(|m| m.humidity)))?;
+    set(attributes, Characteristic::Illuminance, &ess::illuminance(measurement.and_then(|m| m.lux)))?;
+
+    if let Some(percentage) = entries.iter().rev().find_map(|entry| entry.measurement.battery_percentage()) {
+        set(attributes, Characteristic::BatteryLevel, &battery::battery_level(percentage))?;
+    }
+
+    Ok(())
+}
+
+fn set_device_information<A: Attributes>(attributes: &mut A, device: &DeviceInformation) -> Result<(), GattError<A::Error>> {
+    // Strings longer than a characteristic holds are cut off
+    fn value(s: &str) -> &[u8] {
+        let bytes = s.as_bytes();
+        &bytes[..bytes.len().min(MAX_DEVICE_INFORMATION_LEN)]
+    }
+
+    set(attributes, Characteristic::Model, value(device.model))?;
+    set(attributes, Characteristic::FirmwareVersion, value(device.firmware_version))?;
+    set(attributes, Characteristic::HardwareRevision, value(device.hardware_revision))
+}
+
+/// Advertising data and scan response of the peripheral
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AdvertisingData {
+    pub adv_data: Vec<u8, MAX_ADVERTISING_LEN>,
+    pub scan_data: Vec<u8, MAX_ADVERTISING_LEN>,
+}
+
+impl AdvertisingData {
+    /// Service UUIDs and name, for a central to find the peripheral and connect
+    pub fn connectable() -> Self {
+        let mut adv_data = Vec::new();
+        flags(&mut adv_data);
+        services(&mut adv_data);
+        Self { adv_data, scan_data: Vec::new() }
+    }
+
+    /// The latest measurement as service data, centrals scanning passively pick it up without connecting.
+    /// The broadcast takes up the advertising data, the service UUIDs and name move to the scan response.
+    pub fn broadcasting(broadcast: &Broadcast) -> Result<Self, ProtocolError> {
+        let mut payload = [0u8; MAX_BROADCAST_LEN];
+        let len = broadcast.to_bytes(&mut payload)?;
+
+        let mut adv_data = Vec::new();
+        flags(&mut adv_data);
+        let uuid = Broadcast::SERVICE_UUID_16.to_le_bytes();
+        structure(&mut adv_data, AD_TYPE_SERVICE_DATA_16, &[&uuid, &payload[..len]]);
+
+        let mut scan_data = Vec::new();
+        services(&mut scan_data);
+        Ok(Self { adv_data, scan_data })
+    }
+}
+
+fn flags(data: &mut Vec<u8, MAX_ADVERTISING_LEN>) {
+    structure(data, AD_TYPE_FLAGS, &[&[AD_FLAGS]]);
+}
+
+fn services(data: &mut Vec<u8, MAX_ADVERTISING_LEN>) {
+    let uuids = [CURRENT_TIME_SERVICE_UUID.to_le_bytes(), MEASUREMENT_SERVICE_UUID_16.to_le_bytes()];
+    structure(data, AD_TYPE_COMPLETE_SERVICE_UUIDS_16, &[&uuids[0], &uuids[1]]);
+    structure(data, AD_TYPE_COMPLETE_LOCAL_NAME, &[DEVICE_NAME.as_bytes()]);
+}
+
+/// Append an AD structure, every one the peripheral advertises fits by construction
+fn structure(data: &mut Vec<u8, MAX_ADVERTISING_LEN>, kind: u8, parts: &[&[u8]]) {
+    let len = 1 + parts.iter().map(|part| part.len()).sum::<usize>();
+    let _ = data.push(len as u8);
+    let _ = data.push(kind);
+    for part in parts {
+        let _ = data.extend_from_slice(part);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use edge_protocol::auth::downlink_key;
+    use edge_protocol::{AuthKey, Command, CommandRequest, HistoryCursor, Measurement, Signer, Verifier};
+    use std::collections::BTreeMap;
+
+    use crate::CommandQueue;
+
+    const KEY: AuthKey = [0x42; 32];
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+
+    const DEVICE: DeviceInformation = DeviceInformation { model: "Mycelium", firmware_version: "0.1.0-test", hardware_revision: "test" };
+
+    #[derive(Default)]
+    struct MockAttributes(BTreeMap<u16, std::vec::Vec<u8>>);
+
+    impl Attributes for MockAttributes {
+        type Error = ();
+
+        fn set(&mut self, characteristic: Characteristic, value: &[u8]) -> Result<(), ()> {
+            self.0.insert(characteristic.uuid(), value.to_vec());
+            Ok(())
+        }
+    }
+
+    impl MockAttributes {
+        fn get(&self, characteristic: Characteristic) -> &[u8] {
+            self.0.get(&characteristic.uuid()).map(|value| value.as_slice()).unwrap_or_default()
+        }
+    }
+
+    struct MockClock(NaiveDateTime);
+
+    impl Clock for MockClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0
+        }
+
+        fn set(&mut self, now: NaiveDateTime) {
+            self.0 = now;
+        }
+    }
+
+    fn entries(count: usize) -> std::vec::Vec<MeasurementSerieEntry> {
+        (0..count)
+            .map(|i| MeasurementSerieEntry {
+                timestamp: chrono::DateTime::from_timestamp(1_700_000_000 + i as i64 * 60, 0).unwrap().naive_utc(),
+                measurement: Measurement { temperature: Some(i as f32), battery_mv: Some(3900), ..Measurement::EMPTY },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_paged_sync_drains_the_history() {
+        let entries = entries(23);
+        let mut cursor = HistoryCursor::new();
+        let mut signer = Signer::new(KEY, 0);
+        let mut config = PeripheralConfig::DEFAULT;
+        let mut session = Session {
+            entries: &entries,
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: Some(&mut signer),
+            config: &mut config,
+            downlink_verifier: None,
+            accepted: &[Command::Identify],
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+        let mut clock = MockClock(NaiveDateTime::default());
+        let mut attributes = MockAttributes::default();
+
+        let mut server = GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        assert_eq!(attributes.get(Characteristic::Address), &MAC);
+        assert_eq!(attributes.get(Characteristic::Model), b"Mycelium");
+        assert_eq!(attributes.get(Characteristic::BatteryLevel).len(), 1);
+
+        let hello = ProtocolHello::from_bytes(attributes.get(Characteristic::Protocol)).unwrap();
+        assert_eq!(hello, ProtocolHello::current());
+        let access = server.write(Characteristic::Protocol, &hello.to_bytes(), &mut clock, &mut session, &mut attributes).unwrap();
+        assert_eq!(access, Access::Negotiated(hello));
+
+        let now = chrono::DateTime::from_timestamp(1_700_010_000, 0).unwrap().naive_utc();
+        let access = server.write(Characteristic::CurrentTime, &CurrentTime::from_naivedatetime(now).to_bytes(), &mut clock, &mut session, &mut attributes).unwrap();
+        assert_eq!(access, Access::TimeSet(now));
+        assert!(session.time_synced);
+
+        let mut verifier = Verifier::new(KEY, 0);
+        let mut received = vec![];
+        loop {
+            let access = server.read(Characteristic::Measurement, &clock, &mut session, &mut attributes).unwrap();
+            let frame = Frame::decode(verifier.verify(attributes.get(Characteristic::Measurement)).unwrap()).unwrap();
+            let page = frame.entries().collect::<Result<std::vec::Vec<_>, _>>().unwrap();
+            if access == Access::Drained {
+                assert!(page.is_empty());
+                break;
+            }
+
+            let ack = HistoryAck { timestamp: page.last().unwrap().timestamp };
+            received.extend(page);
+            let access = server.write(Characteristic::HistoryAck, &ack.to_bytes(), &mut clock, &mut session, &mut attributes).unwrap();
+            assert_eq!(access, Access::Acknowledged(ack));
+        }
+
+        assert_eq!(received, entries);
+        assert!(session.cursor.page(session.entries, server.capabilities()).is_empty());
+    }
+
+    #[test]
+    fn test_legacy_read_acknowledges_the_page_it_served() {
+        let entries = entries(3);
+        let mut cursor = HistoryCursor::new();
+        let mut config = PeripheralConfig::DEFAULT;
+        let mut session = Session {
+            entries: &entries,
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: None,
+            config: &mut config,
+            downlink_verifier: None,
+            accepted: &[],
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+        let clock = MockClock(NaiveDateTime::default());
+        let mut attributes = MockAttributes::default();
+
+        let mut server = GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        assert!(!ProtocolHello::from_bytes(attributes.get(Characteristic::Protocol)).unwrap().capabilities.contains(Capabilities::AUTHENTICATED));
+
+        let frame = Frame::decode(attributes.get(Characteristic::Measurement)).unwrap();
+        assert_eq!(frame.entries().count(), 3);
+        assert_eq!(server.read(Characteristic::Measurement, &clock, &mut session, &mut attributes).unwrap(), Access::Drained);
+        assert_eq!(session.cursor.acked_until(), Some(entries[2].timestamp));
+    }
+
+    #[test]
+    fn test_commands_and_invalid_writes() {
+        let entries = entries(0);
+        let mut cursor = HistoryCursor::new();
+        let mut config = PeripheralConfig::DEFAULT;
+        let mut session = Session {
+            entries: &entries,
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: None,
+            config: &mut config,
+            downlink_verifier: None,
+            accepted: &[Command::Identify],
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+        let mut clock = MockClock(NaiveDateTime::default());
+        let mut attributes = MockAttributes::default();
+        let mut server = GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+
+        let identify = CommandRequest { command: Command::Identify, id: 1 }.to_bytes();
+        let Ok(Access::Responded(response)) = server.write(Characteristic::Command, &identify, &mut clock, &mut session, &mut attributes) else {
+            panic!("Command not answered");
+        };
+        assert_eq!(response.status, CommandStatus::Accepted);
+        assert_eq!(&session.commands[..], &[Command::Identify]);
+
+        let reboot = CommandRequest { command: Command::Reboot, id: 2 }.to_bytes();
+        let Ok(Access::Responded(response)) = server.write(Characteristic::Command, &reboot, &mut clock, &mut session, &mut attributes) else {
+            panic!("Command not answered");
+        };
+        assert_eq!((response.id, response.status), (2, CommandStatus::Rejected));
+        respond(&mut attributes, &response).unwrap();
+        assert_eq!(attributes.get(Characteristic::Command), response.to_bytes());
+
+        let access = server.write(Characteristic::HistoryAck, &[0; 3], &mut clock, &mut session, &mut attributes).unwrap();
+        assert!(matches!(access, Access::Invalid(Characteristic::HistoryAck, ProtocolError::InvalidLength { .. })));
+    }
+
+    #[test]
+    fn test_keyed_peripheral_only_reboots_on_a_signed_request() {
+        let entries = entries(0);
+        let mut cursor = HistoryCursor::new();
+        let mut config = PeripheralConfig::DEFAULT;
+        let mut session = Session {
+            entries: &entries,
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: None,
+            config: &mut config,
+            downlink_verifier: Some(Verifier::new(downlink_key(&KEY), 0)),
+            accepted: &[Command::Identify, Command::Reboot],
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+        let mut clock = MockClock(NaiveDateTime::default());
+        let mut attributes = MockAttributes::default();
+        let mut server = GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        let mut command = |session: &mut Session<'_>, data: &[u8]| match server.write(Characteristic::Command, data, &mut clock, session, &mut attributes) {
+            Ok(Access::Responded(response)) => response.status,
+            access => panic!("Command not answered: {:?}", access),
+        };
+
+        let reboot = CommandRequest { command: Command::Reboot, id: 1 };
+        assert_eq!(command(&mut session, &reboot.to_bytes()), CommandStatus::Rejected);
+        assert_eq!(command(&mut session, &CommandRequest { command: Command::Identify, id: 2 }.to_bytes()), CommandStatus::Accepted);
+
+        let mut buffer = [0u8; CommandRequest::MAX_SIGNED_LEN];
+        let len = reboot.to_signed_bytes(&[0x24; 32], 1, &mut buffer).unwrap();
+        assert_eq!(command(&mut session, &buffer[..len]), CommandStatus::Rejected);
+        assert_eq!(&session.commands[..], &[Command::Identify]);
+
+        let len = reboot.to_signed_bytes(&KEY, 1, &mut buffer).unwrap();
+        assert_eq!(command(&mut session, &buffer[..len]), CommandStatus::Accepted);
+        assert_eq!(&session.commands[..], &[Command::Identify, Command::Reboot]);
+        assert_eq!(session.downlink_verifier.as_ref().map(Verifier::counter), Some(1));
+    }
+
+    #[test]
+    fn test_battery_level_is_the_latest_measured() {
+        let mut entries = entries(3);
+        entries[2].measurement.battery_mv = None;
+        entries[1].measurement.battery_mv = Some(3700);
+        let mut cursor = HistoryCursor::new();
+        let mut config = PeripheralConfig::DEFAULT;
+        let mut session = Session {
+            entries: &entries,
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: None,
+            config: &mut config,
+            downlink_verifier: None,
+            accepted: &[],
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+        let mut attributes = MockAttributes::default();
+
+        GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        assert_eq!(attributes.get(Characteristic::BatteryLevel), &battery::battery_level(entries[1].measurement.battery_percentage().unwrap()));
+        assert_eq!(attributes.get(Characteristic::Temperature), &ess::temperature(Some(2.0)));
+
+        // Never measured, the level isn't served at all
+        let unmeasured: std::vec::Vec<_> = entries.iter().map(|entry| MeasurementSerieEntry { measurement: Measurement { battery_mv: None, ..entry.measurement }, ..*entry }).collect();
+        let mut attributes = MockAttributes::default();
+        session.entries = &unmeasured;
+        GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        assert!(attributes.get(Characteristic::BatteryLevel).is_empty());
+    }
+
+    #[test]
+    fn test_absent_channels_read_as_not_known() {
+        let entries = entries(2);
+        let mut cursor = HistoryCursor::new();
+        let mut config = PeripheralConfig::DEFAULT;
+        let mut session = Session {
+            entries: &[],
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: None,
+            config: &mut config,
+            downlink_verifier: None,
+            accepted: &[],
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+
+        // Nothing sampled yet, none of the channels reads as a zero
+        let mut attributes = MockAttributes::default();
+        GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        assert_eq!(attributes.get(Characteristic::Temperature), &ess::TEMPERATURE_NOT_KNOWN.to_le_bytes());
+        assert_eq!(attributes.get(Characteristic::Humidity), &ess::HUMIDITY_NOT_KNOWN.to_le_bytes());
+        assert_eq!(attributes.get(Characteristic::Illuminance), &ess::ILLUMINANCE_NOT_KNOWN.to_le_bytes()[..3]);
+
+        let mut attributes = MockAttributes::default();
+        session.entries = &entries;
+        GattServer::start(&mut session, MAC, &DEVICE, &mut attributes).unwrap();
+        assert_eq!(attributes.get(Characteristic::Temperature), &ess::temperature(Some(1.0)));
+        assert_eq!(attributes.get(Characteristic::Humidity), &ess::HUMIDITY_NOT_KNOWN.to_le_bytes());
+    }
+
+    #[test]
+    fn test_advertising_data() {
+        let connectable = AdvertisingData::connectable();
+        assert_eq!(&connectable.adv_data[..3], &[2, AD_TYPE_FLAGS, AD_FLAGS]);
+        assert_eq!(&connectable.adv_data[3..9], &[5, AD_TYPE_COMPLETE_SERVICE_UUIDS_16, 0x05, 0x18, 0xf6, 0xff]);
+        assert_eq!(&connectable.adv_data[9..11], &[1 + DEVICE_NAME.len() as u8, AD_TYPE_COMPLETE_LOCAL_NAME]);
+        assert!(connectable.scan_data.is_empty());
+
+        let broadcast = Broadcast::new(1, 0, MAC, Measurement { temperature: Some(21.5), ..Measurement::EMPTY });
+        let broadcasting = AdvertisingData::broadcasting(&broadcast).unwrap();
+        assert_eq!(&broadcasting.adv_data[4..7], &[AD_TYPE_SERVICE_DATA_16, 0xf6, 0xff]);
+        assert_eq!(Broadcast::from_bytes(&broadcasting.adv_data[7..]), Ok(broadcast));
+        assert_eq!(&broadcasting.scan_data[..], &connectable.adv_data[3..]);
+    }
+}
@@ -0,0 +1,776 @@
+//! Logic of the peripheral, independent of the board it runs on.
+//!
+//! The peripheral wakes from deep sleep, does the work of its current state and goes back
+//! to sleep. [`Device`] holds everything kept across sleeps, every wake-up calls the method
+//! of the current state with the clock, sensors and BLE transport of the board. The
+//! [`Effects`] it returns are carried out by the firmware before it sleeps again.
+#![cfg_attr(not(test), no_std)]
+#![deny(unsafe_code)]
+
+pub mod gatt;
+
+use chrono::NaiveDateTime;
+use edge_protocol::auth::downlink_key;
+use edge_protocol::{AuthKey, Broadcast, Command, CommandRequest, HistoryCursor, Measurement, MeasurementSerieEntry, PeripheralConfig, ProtocolError, Signer, Verifier, MAX_COMPACT_FRAME_ENTRIES};
+use heapless::Vec;
+use timeseries::Series;
+
+pub type Measurements = Series<MAX_COMPACT_FRAME_ENTRIES, NaiveDateTime, Measurement>;
+
+pub type MeasurementSerieEntryVec = Vec<MeasurementSerieEntry, MAX_COMPACT_FRAME_ENTRIES>;
+
+/// Commands accepted during a single session, carried out once it ends
+pub type CommandQueue = Vec<Command, 4>;
+
+/// There is nothing to sample into before the clock is set
+const AWAITING_TIME_SYNC_COMMANDS: [Command; 3] = [Command::Identify, Command::Reboot, Command::FactoryReset];
+
+const FLUSH_COMMANDS: [Command; 4] = [Command::Identify, Command::SampleNow, Command::Reboot, Command::FactoryReset];
+
+#[derive(Clone)]
+pub enum DeviceState {
+    AwaitingTimeSync,
+    Buffering(Measurements),
+    /// Full series waiting for a central, the cursor tracks the entries it acknowledged so far
+    Flush(Measurements, HistoryCursor)
+}
+
+pub trait Clock {
+    fn now(&self) -> NaiveDateTime;
+    fn set(&mut self, now: NaiveDateTime);
+}
+
+#[allow(async_fn_in_trait)]
+pub trait Sensors {
+    async fn sample(&mut self) -> Measurement;
+}
+
+/// What the peripheral serves a central during one connection, and what the central left behind
+pub struct Session<'a> {
+    pub entries: &'a [MeasurementSerieEntry],
+    /// Moved forward by the acknowledgements of the central
+    pub cursor: &'a mut HistoryCursor,
+    pub broadcast: Option<Broadcast>,
+    /// Without a signer the peripheral has no key provisioned and serves unauthenticated frames
+    pub signer: Option<&'a mut Signer>,
+    /// Replaced by a configuration the central writes
+    pub config: &'a mut PeripheralConfig,
+    /// Without a verifier the peripheral has no key provisioned and applies unsigned configurations and commands
+    pub downlink_verifier: Option<Verifier>,
+    /// Commands the peripheral can carry out in its current state, others are rejected
+    pub accepted: &'a [Command],
+    pub commands: CommandQueue,
+    /// Set when the central wrote the current time
+    pub time_synced: bool,
+}
+
+impl Session<'_> {
+    /// Apply a configuration the central wrote
+    pub fn write_config(&mut self, data: &[u8]) -> Result<(), ProtocolError> {
+        *self.config = written_config(data, self.downlink_verifier.as_mut())?;
+        Ok(())
+    }
+
+    /// Decode a command the central wrote
+    pub fn command_request(&mut self, data: &[u8]) -> Result<CommandRequest, ProtocolError> {
+        written_command(data, self.downlink_verifier.as_mut())
+    }
+}
+
+/// Decode a configuration the central wrote, it has to be signed with the downlink key once the peripheral has a key
+pub fn written_config(data: &[u8], verifier: Option<&mut Verifier>) -> Result<PeripheralConfig, ProtocolError> {
+    match verifier {
+        Some(verifier) => PeripheralConfig::from_signed_tlv(data, verifier),
+        None => PeripheralConfig::from_tlv(data),
+    }
+}
+
+/// Decode a command the central wrote. Once the peripheral has a key, a command that disrupts it
+/// has to be signed with the downlink key, anyone in range could reboot or reset it otherwise.
+pub fn written_command(data: &[u8], verifier: Option<&mut Verifier>) -> Result<CommandRequest, ProtocolError> {
+    match verifier {
+        Some(verifier) if data.len() > CommandRequest::LEN => CommandRequest::from_signed_bytes(data, verifier),
+        Some(_) => match CommandRequest::from_bytes(data)? {
+            request if request.command.requires_authentication() => Err(ProtocolError::Unauthenticated),
+            request => Ok(request),
+        },
+        None => CommandRequest::from_bytes(data),
+    }
+}
+
+#[allow(async_fn_in_trait)]
+pub trait Transport {
+    /// MAC of the peripheral, carried in the broadcast since the static random address overwrites its top bits
+    fn mac(&self) -> [u8; 6];
+
+    /// Advertise and serve a central until it disconnects or the session times out
+    async fn serve<C: Clock>(&mut self, clock: &mut C, session: &mut Session<'_>);
+}
+
+/// Work left to the firmware once a wake-up is done
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Effects {
+    /// Blink the LED, the central asked the station to identify itself
+    pub identify: bool,
+    /// Restart instead of sleeping, the device is already reset when the central asked for a factory reset
+    pub reboot: bool,
+    /// A sample was taken while no room was left to keep it
+    pub dropped_sample: bool,
+}
+
+/// Everything the peripheral keeps across deep sleeps
+#[derive(Clone)]
+pub struct Device {
+    pub state: DeviceState,
+    /// Configuration written by the central, applied from the next wake-up
+    pub config: PeripheralConfig,
+    /// Newest sample, advertised as broadcast during the next flush
+    pub latest: Option<MeasurementSerieEntry>,
+    /// Sequence number of the newest sample, lets centrals tell new values from repeated advertisements
+    pub broadcast_sequence: u8,
+    /// Counter of the last signed measurement frame, lost on power loss and seeded again from the clock
+    pub auth_counter: u32,
+    /// Counter of the last signed write of the central, a recorded one can't be applied again
+    pub downlink_counter: u32,
+}
+
+impl Device {
+    pub const INITIAL: Self = Self {
+        state: DeviceState::AwaitingTimeSync,
+        config: PeripheralConfig::DEFAULT,
+        latest: None,
+        broadcast_sequence: 0,
+        auth_counter: 0,
+        downlink_counter: 0,
+    };
+
+    /// Wait for a central to set the clock, only then measurements can be timestamped
+    pub async fn await_time_sync<C: Clock, T: Transport>(&mut self, clock: &mut C, transport: &mut T, key: Option<AuthKey>) -> Effects {
+        if !matches!(self.state, DeviceState::AwaitingTimeSync) {
+            return Effects::default();
+        }
+
+        let mut cursor = HistoryCursor::new();
+        let mut config = self.config;
+        let mut session = Session {
+            entries: &[],
+            cursor: &mut cursor,
+            broadcast: None,
+            signer: None,
+            config: &mut config,
+            downlink_verifier: self.downlink_verifier(key),
+            accepted: &AWAITING_TIME_SYNC_COMMANDS,
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+
+        transport.serve(clock, &mut session).await;
+
+        let Session { commands, time_synced, downlink_verifier, .. } = session;
+        self.applied(config, downlink_verifier);
+
+        // A central that connected without setting the time leaves the clock at boot time,
+        // every measurement would be stamped wrong so the device keeps waiting
+        if time_synced {
+            self.state = DeviceState::Buffering(Series::new(self.config.max_deviation));
+        }
+
+        self.finish(&commands, Effects::default())
+    }
+
+    /// Take a sample, the series is flushed once it holds the configured number of buckets
+    pub async fn buffer<C: Clock, S: Sensors>(&mut self, clock: &C, sensors: &mut S) -> Effects {
+        let DeviceState::Buffering(series) = &mut self.state else {
+            return Effects::default();
+        };
+
+        let m = sensors.sample().await;
+        series.append_monotonic(clock.now(), m);
+
+        if series.is_full() || series.buckets.len() >= self.config.flush_size as usize {
+            self.state = DeviceState::Flush(series.clone(), HistoryCursor::new());
+        }
+
+        self.record(clock.now(), m);
+
+        Effects::default()
+    }
+
+    /// Serve the series to a central, entries are only dropped once the central acknowledged them
+    pub async fn flush<C: Clock, S: Sensors, T: Transport>(&mut self, clock: &mut C, sensors: &mut S, transport: &mut T, key: Option<AuthKey>) -> Effects {
+        let DeviceState::Flush(series, cursor) = &self.state else {
+            return Effects::default();
+        };
+        let mut cursor = *cursor;
+        let max_deviation = series.max_deviation;
+        let entries = entries(series);
+
+        // The newest sample rather than the start of the last bucket, with its age so centrals can time it
+        let broadcast = self.latest.map(|entry| {
+            let age_secs = (clock.now() - entry.timestamp).num_seconds().clamp(0, u16::MAX as i64) as u16;
+            Broadcast::new(self.broadcast_sequence, age_secs, transport.mac(), entry.measurement)
+        });
+
+        let mut signer = key.map(|key| {
+            let mut signer = Signer::new(key, self.auth_counter);
+            signer.seed(clock.now().and_utc().timestamp());
+            signer
+        });
+
+        let mut config = self.config;
+        let mut session = Session {
+            entries: &entries,
+            cursor: &mut cursor,
+            broadcast,
+            signer: signer.as_mut(),
+            config: &mut config,
+            downlink_verifier: self.downlink_verifier(key),
+            accepted: &FLUSH_COMMANDS,
+            commands: CommandQueue::new(),
+            time_synced: false,
+        };
+
+        transport.serve(clock, &mut session).await;
+
+        let Session { commands, downlink_verifier, .. } = session;
+        self.applied(config, downlink_verifier);
+
+        if let Some(signer) = &signer {
+            self.auth_counter = signer.counter();
+        }
+
+        // A sample taken on request is flushed on the next wake-up instead of waiting for a full buffer
+        let sample_now = commands.contains(&Command::SampleNow);
+        let mut effects = Effects::default();
+
+        let m = sensors.sample().await;
+        self.record(clock.now(), m);
+
+        if cursor.is_drained(&entries) {
+            let mut series: Measurements = Series::new(self.config.max_deviation);
+            series.append_monotonic(clock.now(), m);
+
+            self.state = if sample_now {
+                DeviceState::Flush(series, HistoryCursor::new())
+            } else {
+                DeviceState::Buffering(series)
+            };
+        } else {
+            // Acknowledged entries make room, sampling goes on while the central is away.
+            // Consecutive buckets deviate from each other, so rebuilding with the deviation of the
+            // series keeps every pending entry even when the central changed the configuration.
+            let mut pending: Measurements = Series::new(max_deviation);
+            for entry in cursor.pending(&entries) {
+                pending.append_monotonic(entry.timestamp, entry.measurement);
+            }
+
+            if pending.is_full() {
+                effects.dropped_sample = true;
+            } else {
+                pending.append_monotonic(clock.now(), m);
+            }
+
+            self.state = DeviceState::Flush(pending, cursor);
+        }
+
+        self.finish(&commands, effects)
+    }
+
+    fn downlink_verifier(&self, key: Option<AuthKey>) -> Option<Verifier> {
+        key.map(|key| Verifier::new(downlink_key(&key), self.downlink_counter))
+    }
+
+    /// Keep the configuration of a session, along with the counter of the last write the central signed
+    fn applied(&mut self, config: PeripheralConfig, verifier: Option<Verifier>) {
+        self.config = config;
+        if let Some(verifier) = verifier {
+            self.downlink_counter = verifier.counter();
+        }
+    }
+
+    /// Keep a sample for the broadcast, the sequence only moves on with a new sample
+    fn record(&mut self, timestamp: NaiveDateTime, measurement: Measurement) {
+        self.latest = Some(MeasurementSerieEntry { timestamp, measurement });
+        self.broadcast_sequence = self.broadcast_sequence.wrapping_add(1);
+    }
+
+    fn finish(&mut self, commands: &[Command], mut effects: Effects) -> Effects {
+        effects.identify = commands.contains(&Command::Identify);
+
+        if commands.contains(&Command::FactoryReset) {
+            // The counters are kept so frames signed before the reset can't be replayed
+            *self = Device { auth_counter: self.auth_counter, downlink_counter: self.downlink_counter, ..Device::INITIAL };
+            effects.reboot = true;
+        } else if commands.contains(&Command::Reboot) {
+            effects.reboot = true;
+        }
+
+        effects
+    }
+}
+
+/// Entries of a series, one per bucket starting at the first sample of the bucket
+pub fn entries(series: &Measurements) -> MeasurementSerieEntryVec {
+    series
+        .buckets
+        .iter()
+        .map(|bucket| MeasurementSerieEntry { timestamp: bucket.range.start, measurement: bucket.value })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use edge_protocol::{static_random_address, Capabilities, HistoryAck};
+    use embassy_futures::block_on;
+
+    const KEY: AuthKey = [0x42; 32];
+
+    const MAC: [u8; 6] = [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56];
+
+    struct MockClock(NaiveDateTime);
+
+    impl Clock for MockClock {
+        fn now(&self) -> NaiveDateTime {
+            self.0
+        }
+
+        fn set(&mut self, now: NaiveDateTime) {
+            self.0 = now;
+        }
+    }
+
+    impl MockClock {
+        fn boot() -> Self {
+            MockClock(NaiveDateTime::default())
+        }
+
+        fn synced() -> Self {
+            MockClock(synced_time())
+        }
+
+        /// Sleep until the next wake-up
+        fn sleep(&mut self) {
+            self.0 += TimeDelta::seconds(10);
+        }
+    }
+
+    fn synced_time() -> NaiveDateTime {
+        chrono::DateTime::from_timestamp(1_700_000_000, 0).unwrap().naive_utc()
+    }
+
+    /// Every sample deviates from the previous one, so each starts a bucket
+    struct MockSensors {
+        samples: u32,
+    }
+
+    impl Sensors for MockSensors {
+        async fn sample(&mut self) -> Measurement {
+            self.samples += 1;
+            Measurement { temperature: Some(self.samples as f32 * 10.0), ..Measurement::EMPTY }
+        }
+    }
+
+    /// Central on the other side of the session
+    struct MockCentral {
+        /// A central that doesn't show up lets the session time out
+        present: bool,
+        time: Option<NaiveDateTime>,
+        config: Option<PeripheralConfig>,
+        /// Key the configuration is signed with, at the time of the session
+        config_key: Option<AuthKey>,
+        /// Whether the peripheral applied the configuration
+        config_written: Option<Result<(), ProtocolError>>,
+        commands: std::vec::Vec<Command>,
+        capabilities: Capabilities,
+        /// Number of pages acknowledged before the connection drops
+        acks: usize,
+        /// Counters of the frames signed during the last session
+        signed: std::vec::Vec<u32>,
+        broadcast: Option<Broadcast>,
+    }
+
+    impl MockCentral {
+        fn draining() -> Self {
+            MockCentral { present: true, acks: usize::MAX, ..Default::default() }
+        }
+    }
+
+    impl Default for MockCentral {
+        fn default() -> Self {
+            MockCentral {
+                present: false,
+                time: None,
+                config: None,
+                config_key: None,
+                config_written: None,
+                commands: vec![],
+                capabilities: Capabilities::SUPPORTED,
+                acks: 0,
+                signed: vec![],
+                broadcast: None,
+            }
+        }
+    }
+
+    impl Transport for MockCentral {
+        fn mac(&self) -> [u8; 6] {
+            MAC
+        }
+
+        async fn serve<C: Clock>(&mut self, clock: &mut C, session: &mut Session<'_>) {
+            self.broadcast = session.broadcast;
+            self.signed.clear();
+
+            if !self.present {
+                return;
+            }
+
+            if let Some(time) = self.time {
+                clock.set(time);
+                session.time_synced = true;
+            }
+
+            if let Some(config) = self.config {
+                let mut buffer = [0u8; PeripheralConfig::MAX_SIGNED_TLV_LEN];
+                let len = match &self.config_key {
+                    Some(key) => config.to_signed_tlv(key, clock.now().and_utc().timestamp() as u32, &mut buffer).unwrap(),
+                    None => config.to_tlv(&mut buffer).unwrap(),
+                };
+                self.config_written = Some(session.write_config(&buffer[..len]));
+            }
+
+            for command in &self.commands {
+                if session.accepted.contains(command) && !session.commands.contains(command) {
+                    let _ = session.commands.push(*command);
+                }
+            }
+
+            let mut acks = self.acks;
+            loop {
+                let page = session.cursor.page(session.entries, self.capabilities);
+                if let Some(signer) = session.signer.as_deref_mut() {
+                    self.signed.push(signer.next_counter());
+                }
+
+                match page.last() {
+                    Some(last) if acks > 0 => {
+                        session.cursor.ack(HistoryAck { timestamp: last.timestamp });
+                        acks -= 1;
+                    }
+                    _ => break,
+                }
+            }
+        }
+    }
+
+    fn buffering(samples: usize, clock: &mut MockClock, sensors: &mut MockSensors) -> Device {
+        let mut device = Device { state: DeviceState::Buffering(Series::new(Measurement::MAX_DEVIATION)), ..Device::INITIAL };
+        for _ in 0..samples {
+            block_on(device.buffer(clock, sensors));
+            clock.sleep();
+        }
+        device
+    }
+
+    fn flush(samples: usize, clock: &mut MockClock, sensors: &mut MockSensors) -> Device {
+        let mut device = buffering(0, clock, sensors);
+        device.config.flush_size = samples as u8;
+        for _ in 0..samples {
+            block_on(device.buffer(clock, sensors));
+            clock.sleep();
+        }
+        assert!(matches!(device.state, DeviceState::Flush(..)));
+        device
+    }
+
+    fn pending(device: &Device) -> MeasurementSerieEntryVec {
+        match &device.state {
+            DeviceState::Flush(series, cursor) => MeasurementSerieEntryVec::from_slice(cursor.pending(&entries(series))).unwrap(),
+            _ => panic!("Device isn't flushing"),
+        }
+    }
+
+    #[test]
+    fn test_time_sync_starts_buffering() {
+        let mut device = Device::INITIAL;
+        let mut clock = MockClock::boot();
+        let config = PeripheralConfig { sampling_period_secs: 60, ..PeripheralConfig::DEFAULT };
+        let mut central = MockCentral { present: true, time: Some(synced_time()), config: Some(config), ..Default::default() };
+
+        let effects = block_on(device.await_time_sync(&mut clock, &mut central, None));
+
+        assert_eq!(effects, Effects::default());
+        assert_eq!(clock.now(), synced_time());
+        assert_eq!(device.config, config);
+        assert!(matches!(&device.state, DeviceState::Buffering(series) if series.buckets.is_empty()));
+    }
+
+    #[test]
+    fn test_time_sync_timeout_keeps_waiting() {
+        let mut device = Device::INITIAL;
+        let mut clock = MockClock::boot();
+
+        block_on(device.await_time_sync(&mut clock, &mut MockCentral::default(), None));
+
+        assert!(matches!(device.state, DeviceState::AwaitingTimeSync));
+    }
+
+    #[test]
+    fn test_time_sync_without_time_keeps_waiting() {
+        let mut device = Device::INITIAL;
+        let mut clock = MockClock::boot();
+        let mut central = MockCentral { present: true, ..Default::default() };
+
+        block_on(device.await_time_sync(&mut clock, &mut central, None));
+
+        // Measurements stamped with the boot time would be uploaded at the wrong moment
+        assert!(matches!(device.state, DeviceState::AwaitingTimeSync));
+    }
+
+    #[test]
+    fn test_time_sync_rejects_sample_now() {
+        let mut device = Device::INITIAL;
+        let mut clock = MockClock::boot();
+        let mut central = MockCentral { present: true, time: Some(synced_time()), commands: vec![Command::SampleNow, Command::Identify], ..Default::default() };
+
+        let effects = block_on(device.await_time_sync(&mut clock, &mut central, None));
+
+        assert_eq!(effects, Effects { identify: true, ..Effects::default() });
+    }
+
+    #[test]
+    fn test_buffering_flushes_at_flush_size() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = buffering(0, &mut clock, &mut sensors);
+        device.config.flush_size = 3;
+
+        for samples in 1..3 {
+            block_on(device.buffer(&clock, &mut sensors));
+            clock.sleep();
+            assert!(matches!(&device.state, DeviceState::Buffering(series) if series.buckets.len() == samples));
+        }
+
+        block_on(device.buffer(&clock, &mut sensors));
+        assert!(matches!(&device.state, DeviceState::Flush(series, cursor) if series.buckets.len() == 3 && cursor.acked_until().is_none()));
+    }
+
+    #[test]
+    fn test_buffering_flushes_when_full() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let device = buffering(MAX_COMPACT_FRAME_ENTRIES, &mut clock, &mut sensors);
+
+        assert!(matches!(&device.state, DeviceState::Flush(series, _) if series.is_full()));
+    }
+
+    #[test]
+    fn test_flush_drained_starts_new_series() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let mut central = MockCentral::draining();
+
+        let effects = block_on(device.flush(&mut clock, &mut sensors, &mut central, None));
+
+        assert_eq!(effects, Effects::default());
+        assert!(matches!(&device.state, DeviceState::Buffering(series) if series.buckets.len() == 1));
+        assert_eq!(sensors.samples, 5);
+    }
+
+    #[test]
+    fn test_flush_timeout_keeps_entries_and_samples() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let before = pending(&device);
+
+        let effects = block_on(device.flush(&mut clock, &mut sensors, &mut MockCentral::default(), None));
+
+        // Nothing was acknowledged, the sample taken meanwhile is kept as well
+        let after = pending(&device);
+        assert!(!effects.dropped_sample);
+        assert_eq!(after[..4], before[..]);
+        assert_eq!(after.len(), 5);
+    }
+
+    #[test]
+    fn test_flush_partial_ack_makes_room() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(MAX_COMPACT_FRAME_ENTRIES, &mut clock, &mut sensors);
+        let before = pending(&device);
+        let capabilities = Capabilities::PAGED_HISTORY;
+        let mut central = MockCentral { present: true, acks: 1, capabilities, ..Default::default() };
+
+        let effects = block_on(device.flush(&mut clock, &mut sensors, &mut central, None));
+
+        // The first page was acknowledged and dropped, the rest stays pending next to the new sample
+        let page = edge_protocol::history::page_len(&before, capabilities);
+        let after = pending(&device);
+        assert!(!effects.dropped_sample);
+        assert_eq!(after[..before.len() - page], before[page..]);
+        assert_eq!(after.len(), before.len() - page + 1);
+
+        // A central draining the rest gets every entry that was pending
+        clock.sleep();
+        block_on(device.flush(&mut clock, &mut sensors, &mut MockCentral::draining(), None));
+        assert!(matches!(device.state, DeviceState::Buffering(_)));
+    }
+
+    #[test]
+    fn test_flush_full_series_reports_dropped_sample() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(MAX_COMPACT_FRAME_ENTRIES, &mut clock, &mut sensors);
+        let before = pending(&device);
+
+        let effects = block_on(device.flush(&mut clock, &mut sensors, &mut MockCentral::default(), None));
+
+        assert!(effects.dropped_sample);
+        assert_eq!(pending(&device), before);
+    }
+
+    #[test]
+    fn test_flush_sample_now_flushes_next_wakeup() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let mut central = MockCentral { commands: vec![Command::SampleNow], ..MockCentral::draining() };
+
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, None));
+
+        assert!(matches!(&device.state, DeviceState::Flush(series, _) if series.buckets.len() == 1));
+    }
+
+    #[test]
+    fn test_flush_applies_config_and_broadcasts() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let config = PeripheralConfig { flush_size: 2, ..PeripheralConfig::DEFAULT };
+        let mut central = MockCentral { config: Some(config), ..MockCentral::draining() };
+
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, None));
+
+        // The fourth sample, taken a sleep before the flush
+        let measurement = Measurement { temperature: Some(40.0), ..Measurement::EMPTY };
+        assert_eq!(device.config, config);
+        assert_eq!(central.broadcast, Some(Broadcast::new(4, 10, MAC, measurement)));
+        assert_eq!(device.broadcast_sequence, 5);
+    }
+
+    #[test]
+    fn test_flush_broadcasts_newest_sample_once() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = buffering(0, &mut clock, &mut sensors);
+        device.config.flush_size = 1;
+        block_on(device.buffer(&clock, &mut sensors));
+        let mut central = MockCentral::default();
+
+        // Wake-ups without a central advertise the sample of the wake-up before, never a stale bucket start
+        for sample in 1..4 {
+            clock.sleep();
+            block_on(device.flush(&mut clock, &mut sensors, &mut central, None));
+
+            let broadcast = central.broadcast.unwrap();
+            assert_eq!(broadcast.sequence, sample as u8);
+            assert_eq!(broadcast.measurement.temperature, Some(sample as f32 * 10.0));
+            assert_eq!(broadcast.age_secs, 10);
+            assert_eq!(broadcast.mac(static_random_address(MAC)), MAC);
+        }
+    }
+
+    #[test]
+    fn test_flush_keeps_auth_counter() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let mut central = MockCentral { commands: vec![Command::SampleNow], ..MockCentral::draining() };
+
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, Some(KEY)));
+
+        // A lost counter is seeded from the clock, ahead of anything signed before
+        let seed = synced_time().and_utc().timestamp() as u32;
+        let first = central.signed.clone();
+        assert!(first.iter().all(|counter| *counter > seed));
+        assert_eq!(device.auth_counter, *first.last().unwrap());
+
+        // The next flush continues from the stored counter, so a central never sees a counter twice
+        clock.sleep();
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, Some(KEY)));
+        assert!(central.signed[0] > *first.last().unwrap());
+    }
+
+    #[test]
+    fn test_factory_reset_keeps_auth_counter() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        device.config.sampling_period_secs = 60;
+        let mut central = MockCentral { commands: vec![Command::FactoryReset, Command::Identify], acks: 0, ..MockCentral::draining() };
+
+        let effects = block_on(device.flush(&mut clock, &mut sensors, &mut central, Some(KEY)));
+
+        assert_eq!(effects, Effects { identify: true, reboot: true, dropped_sample: false });
+        assert!(matches!(device.state, DeviceState::AwaitingTimeSync));
+        assert_eq!(device.config, PeripheralConfig::DEFAULT);
+        assert_eq!(device.broadcast_sequence, 0);
+        assert_eq!(device.auth_counter, *central.signed.last().unwrap());
+    }
+
+    #[test]
+    fn test_keyed_peripheral_only_applies_signed_config() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let before = device.config;
+        let config = PeripheralConfig { sampling_period_secs: 60, ..PeripheralConfig::DEFAULT };
+        let mut central = MockCentral { config: Some(config), acks: 0, ..MockCentral::draining() };
+
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, Some(KEY)));
+        assert!(central.config_written.unwrap().is_err());
+        assert_eq!(device.config, before);
+
+        central.config_key = Some(KEY);
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, Some(KEY)));
+        assert_eq!(central.config_written, Some(Ok(())));
+        assert_eq!(device.config, config);
+        assert_eq!(device.downlink_counter, clock.now().and_utc().timestamp() as u32);
+
+        // The same write recorded and played back later
+        device.config = before;
+        block_on(device.flush(&mut clock, &mut sensors, &mut central, Some(KEY)));
+        assert!(matches!(central.config_written, Some(Err(ProtocolError::Replayed { .. }))));
+        assert_eq!(device.config, before);
+    }
+
+    #[test]
+    fn test_reboot_keeps_state() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = flush(4, &mut clock, &mut sensors);
+        let mut central = MockCentral { commands: vec![Command::Reboot], acks: 1, ..MockCentral::draining() };
+
+        let effects = block_on(device.flush(&mut clock, &mut sensors, &mut central, None));
+
+        assert_eq!(effects, Effects { reboot: true, ..Effects::default() });
+        assert!(matches!(device.state, DeviceState::Buffering(_)));
+    }
+
+    #[test]
+    fn test_wakeup_in_other_state_does_nothing() {
+        let mut clock = MockClock::synced();
+        let mut sensors = MockSensors { samples: 0 };
+        let mut device = Device::INITIAL;
+
+        block_on(device.buffer(&clock, &mut sensors));
+        block_on(device.flush(&mut clock, &mut sensors, &mut MockCentral::draining(), None));
+
+        assert!(matches!(device.state, DeviceState::AwaitingTimeSync));
+        assert_eq!(sensors.samples, 0);
+    }
+}
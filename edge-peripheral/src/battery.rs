@@ -10,11 +10,12 @@ impl <'a, PIN : AdcChannel> BatteryMeasurement<'a, PIN> {
         BatteryMeasurement { adc: adc, adc_pin: pin }
     }
 
-    pub fn sample(&mut self) -> u8 {
+    /// Battery voltage in millivolts, clamped to the range covered by `ADC_LUT`
+    pub fn sample(&mut self) -> u16 {
         let reading = nb::block!(self.adc.read_oneshot(&mut self.adc_pin)).expect("Unable to read from ADC");
 
         if reading < ADC_LUT[0] {
-            return 0;
+            return LUT_MIN_MV;
         }
 
         for n in 0..ADC_LUT.len() - 1 {
@@ -22,21 +23,27 @@ impl <'a, PIN : AdcChannel> BatteryMeasurement<'a, PIN> {
             let lut_n_1 = ADC_LUT[n + 1];
 
             if reading >= lut_n && reading < lut_n_1 {
-                let v = 1.7 + n as f32 * 0.1
-                    + (reading - lut_n) as f32 * 0.1 / (lut_n_1 - lut_n) as f32;
+                let mv = LUT_MIN_MV as u32
+                    + n as u32 * LUT_STEP_MV as u32
+                    + (reading - lut_n) as u32 * LUT_STEP_MV as u32 / (lut_n_1 - lut_n) as u32;
 
-                let p = (v / 2200_f32) * 100_f32;
-
-                return p as u8;
+                return mv as u16;
             }
         }
 
-        return 100;
+        return LUT_MIN_MV + (ADC_LUT.len() as u16 - 1) * LUT_STEP_MV;
     }
     
 }
 
-const ADC_LUT: &[u16; 24] = &[
+/// Voltage of the first `ADC_LUT` entry
+const LUT_MIN_MV: u16 = 1700;
+
+/// Voltage between consecutive `ADC_LUT` entries
+const LUT_STEP_MV: u16 = 100;
+
+/// ADC readings of the battery voltage, from brownout up to a fully charged LiPo cell
+const ADC_LUT: &[u16; 26] = &[
     825,      // 1.7V (brownout below this)
     887,      // 1.8V 
     946,      // 1.9V
@@ -61,4 +68,6 @@ const ADC_LUT: &[u16; 24] = &[
     2086,     // 3.8V
     2145,     // 3.9V
     2200,     // 4.0V
+    2257,     // 4.1V (extrapolated from the slope below)
+    2314,     // 4.2V (extrapolated, fully charged)
 ];
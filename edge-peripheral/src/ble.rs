@@ -1,15 +1,32 @@
-use core::cell::UnsafeCell;
 use defmt::{info, warn, error, Debug2Format};
 use embassy_futures::select::select;
-use embassy_time::{Delay, Duration, Timer};
-use esp_hal::rtc_cntl::Rtc;
+use embassy_time::{Duration, Timer};
+use edge_peripheral_core::gatt::{self, Access, AdvertisingData, DeviceInformation, GattError, GattServer};
+use edge_peripheral_core::{Clock, Session, Transport};
 use trouble_host::prelude::*;
-use trouble_host::types::gatt_traits::FromGattError;
 
 use edge_protocol::*;
+// The trouble prelude exports a `PeripheralConfig` too, that one only starts the GAP server
+use edge_protocol::PeripheralConfig;
 use heapless::Vec;
 
-pub type MeasurementSerieEntryVec = Vec<MeasurementSerieEntry, 6>;
+const CONFIG_LEN: usize = PeripheralConfig::MAX_SIGNED_TLV_LEN;
+
+const COMMAND_LEN: usize = CommandRequest::MAX_SIGNED_LEN;
+
+const DEVICE_INFORMATION: DeviceInformation = DeviceInformation {
+    model: "Mycelium",
+    // Set by the build script from `git describe`, or the crate version outside a checkout
+    firmware_version: env!("MYCELIUM_FIRMWARE_VERSION"),
+    // Board revision, set at build time for boards other than the first
+    hardware_revision: match option_env!("MYCELIUM_HARDWARE_REVISION") {
+        Some(revision) => revision,
+        None => "1",
+    },
+};
+
+/// Time a central has to connect and sync before the peripheral goes back to sleep
+const SESSION_TIMEOUT: Duration = Duration::from_secs(10);
 
 /// Max number of connections
 const CONNECTIONS_MAX: usize = 1;
@@ -19,62 +36,14 @@ const L2CAP_CHANNELS_MAX: usize = 2; // Signal + att
 
 const L2CAP_MTU: usize = 255;
 
-// Thread-local buffer for preparing GATT data
-struct SyncUnsafeCell<T>(UnsafeCell<T>);
-
-unsafe impl<T> Sync for SyncUnsafeCell<T> {}
-
-static GATT_BUFFER: SyncUnsafeCell<[u8; 234]> =
-    SyncUnsafeCell(UnsafeCell::new([0; 234]));
-
-
-pub struct MeasurementSeries(MeasurementSerieEntryVec);
-
-
-impl Default for MeasurementSeries {
-    fn default() -> Self {
-        Self(Vec::new())
-    }
-}
-
-impl AsGatt for MeasurementSeries {
-    fn as_gatt(&self) -> &[u8] {
-        let buffer = unsafe { &mut *GATT_BUFFER.0.get() };
-
-        for (i, item) in self.0.iter().enumerate() {
-            buffer[i * 39..(i + 1) * 39].copy_from_slice(&item.to_tlv());
-        }
-
-        unsafe { core::slice::from_raw_parts(buffer.as_ptr(), 234) }
-
-    }
-    const MIN_SIZE: usize = 234;
-    const MAX_SIZE: usize = 234;
-}
-
-impl FromGatt for MeasurementSeries {
-    fn from_gatt(data: &[u8]) -> Result<Self, FromGattError> {
-        let mut measurements = Vec::<MeasurementSerieEntry, 6>::new();
-        for i in 0..6 {
-            let start = i * 33;
-            let end = start + 33;
-            let segment = &data[start..end];
-
-            let entry = MeasurementSerieEntry::from_tlv(segment).map_err(|_| FromGattError::InvalidLength)?;
-            if measurements.push(entry).is_err() {
-                break;
-            }
-        }
-
-        Ok(MeasurementSeries(measurements))
-    }
-}
-
 #[gatt_server]
 struct Server {
     address_service: AddressService,
     time_service: TimeService,
-    measurement_service: MeasurementService
+    measurement_service: MeasurementService,
+    environmental_sensing_service: EnvironmentalSensingService,
+    battery_service: BatteryService,
+    device_information_service: DeviceInformationService
 }
 
 #[gatt_service(uuid = ADDRESS_SERVICE_UUID_16)]
@@ -91,28 +60,107 @@ struct TimeService {
 }
 
 
+/// Environmental sensing service, the latest measurement for generic BLE tools
+#[gatt_service(uuid = BluetoothUuid16::new(ENVIRONMENTAL_SENSING_SERVICE_UUID))]
+struct EnvironmentalSensingService {
+    #[characteristic(uuid = BluetoothUuid16::new(TEMPERATURE_CHARACTERISTIC_UUID), read)]
+    temperature: [u8; ess::TEMPERATURE_LEN],
+    #[characteristic(uuid = BluetoothUuid16::new(HUMIDITY_CHARACTERISTIC_UUID), read)]
+    humidity: [u8; ess::HUMIDITY_LEN],
+    #[characteristic(uuid = BluetoothUuid16::new(ILLUMINANCE_CHARACTERISTIC_UUID), read)]
+    illuminance: [u8; ess::ILLUMINANCE_LEN]
+}
+
+/// Battery service
+#[gatt_service(uuid = BluetoothUuid16::new(BATTERY_SERVICE_UUID))]
+struct BatteryService {
+    #[characteristic(uuid = BluetoothUuid16::new(BATTERY_LEVEL_CHARACTERISTIC_UUID), read)]
+    level: [u8; 1]
+}
+
+/// Device information service, lets a central tell which firmware a station runs
+#[gatt_service(uuid = BluetoothUuid16::new(DEVICE_INFORMATION_SERVICE_UUID))]
+struct DeviceInformationService {
+    #[characteristic(uuid = BluetoothUuid16::new(MODEL_NUMBER_CHARACTERISTIC_UUID), read)]
+    model: Vec<u8, MAX_DEVICE_INFORMATION_LEN>,
+    #[characteristic(uuid = BluetoothUuid16::new(FIRMWARE_REVISION_CHARACTERISTIC_UUID), read)]
+    firmware_version: Vec<u8, MAX_DEVICE_INFORMATION_LEN>,
+    #[characteristic(uuid = BluetoothUuid16::new(HARDWARE_REVISION_CHARACTERISTIC_UUID), read)]
+    hardware_revision: Vec<u8, MAX_DEVICE_INFORMATION_LEN>
+}
+
 #[gatt_service(uuid = MEASUREMENT_SERVICE_UUID_16)]
 struct MeasurementService {
+    /// Encoded and signed frame of the page being served
     #[characteristic(uuid = MEASUREMENT_CHARACTERISTIC_UUID_16, read)]
-    measurement: MeasurementSeries
+    measurement: Vec<u8, MAX_AUTHENTICATED_FRAME_LEN>,
+    #[characteristic(uuid = PROTOCOL_CHARACTERISTIC_UUID_16, write, read)]
+    protocol: [u8; ProtocolHello::LEN],
+    #[characteristic(uuid = HISTORY_ACK_CHARACTERISTIC_UUID_16, write)]
+    history_ack: [u8; HistoryAck::LEN],
+    #[characteristic(uuid = CONFIG_CHARACTERISTIC_UUID_16, write, read)]
+    config: Vec<u8, CONFIG_LEN>,
+    #[characteristic(uuid = COMMAND_CHARACTERISTIC_UUID_16, write, read)]
+    command: Vec<u8, COMMAND_LEN>
+}
+
+/// BLE transport of the peripheral, a wake-up serves at most a single session
+pub struct Ble<C> {
+    controller: Option<C>,
+    address: [u8; 6],
+}
+
+impl<C: Controller> Ble<C> {
+    pub fn new(controller: C, address: [u8; 6]) -> Self {
+        Self { controller: Some(controller), address }
+    }
+}
+
+impl<C: Controller> Transport for Ble<C> {
+    fn mac(&self) -> [u8; 6] {
+        self.address
+    }
+
+    async fn serve<K: Clock>(&mut self, clock: &mut K, session: &mut Session<'_>) {
+        // The host stack takes ownership of the controller
+        let Some(controller) = self.controller.take() else {
+            warn!("BLE controller already used this wake-up");
+            return;
+        };
+
+        select(run(controller, clock, self.address, session), Timer::after(SESSION_TIMEOUT)).await;
+    }
 }
 
 /// Run the BLE stack.
-pub async fn run<C>(controller: C, rtc: &mut Rtc<'_>, address: [u8; 6], measurements: MeasurementSerieEntryVec)
+///
+/// The cursor of the session records which measurements the central acknowledged, so progress
+/// is kept when the connection drops or the session times out halfway through the history.
+///
+/// When a broadcast is given the latest measurement is advertised as service data,
+/// centrals scanning passively pick it up without connecting.
+///
+/// Without a signer the peripheral has no key provisioned and doesn't offer authenticated frames.
+/// A configuration written by the central replaces the one of the session.
+///
+/// Commands in `accepted` that the central sends are pushed to the session for the caller
+/// to carry out, others are answered as rejected.
+async fn run<C, K>(controller: C, clock: &mut K, address: [u8; 6], session: &mut Session<'_>)
 where
     C: Controller,
+    K: Clock,
 {
 
     let mut resources: HostResources<CONNECTIONS_MAX, L2CAP_CHANNELS_MAX, L2CAP_MTU> = HostResources::new();
-    let address_ = Address::random([0xff, 0x8f, 0x1b, 0x05, 0xe4, 0xff]);
+    let address_ = Address::random(static_random_address(address));
     let stack = trouble_host::new(controller, &mut resources).set_random_address(address_);
     let Host {
         mut peripheral, runner, ..
     } = stack.build();
 
     info!("Starting advertising and GATT service");
-    let server = Server::new_with_config(GapConfig::Peripheral(PeripheralConfig {
-        name: "Mycelium",
+    let server = Server::new_with_config(GapConfig::Peripheral(trouble_host::prelude::PeripheralConfig {
+        name: DEVICE_NAME,
         appearance: &appearance::UNKNOWN,
     }))
     .expect("Unable to start GATT service");
@@ -123,10 +171,10 @@ where
         
         info!("Advertising...");
 
-        match advertise("Mycelium", &mut peripheral, &server).await {
+        match advertise(&mut peripheral, &server, session.broadcast).await {
             Ok(conn) => {
                 info!("Got gatt connection");
-                match gatt_events_task(&server, &conn, rtc, address, measurements.clone()).await {
+                match gatt_events_task(&server, &conn, clock, address, session).await {
                     Ok(_) => (),
                     Err(e) => {
                         let e = defmt::Debug2Format(&e);
@@ -155,99 +203,133 @@ async fn ble_task<C: Controller>(mut runner: Runner<'_, C>) {
     }
 }
 
-// Stream Events until the connection closes.
+/// Stream Events until the connection closes.
 ///
-/// This function will handle the GATT events and process them.
-/// This is how we interact with read and write requests.
-async fn gatt_events_task(server: &Server<'_>, conn: &GattConnection<'_, '_>, rtc: &mut Rtc<'_>, address: [u8; 6], measurements: MeasurementSerieEntryVec) -> Result<(), Error> {
-
-    let series = MeasurementSeries(measurements.clone());
-    server.measurement_service.measurement.set(&server, &series).map_err(|_e| Error::Other);
-    server.address_service.address.set(&server, &address).map_err(|_e| Error::Other);
+/// What a read or write does is up to the GATT server of the core, the simulator serves it
+/// the same way on the host stack of the central.
+async fn gatt_events_task<K: Clock>(server: &Server<'_>, conn: &GattConnection<'_, '_>, clock: &mut K, address: [u8; 6], session: &mut Session<'_>) -> Result<(), Error> {
+    let mut attributes = server;
+    let mut gatt = GattServer::start(session, address, &DEVICE_INFORMATION, &mut attributes).map_err(gatt_error)?;
 
     let reason = loop {
         match conn.next().await {
             GattConnectionEvent::Gatt { event: Err(e) } => warn!("[gatt] error processing event: {:?}", e),
-            GattConnectionEvent::Gatt { event: Ok(event_) } => {
-                
-                match &event_ {
-                    GattEvent::Read(event) => {
-                        if event.handle() == server.measurement_service.measurement.handle {
-                            info!("[gatt] Read Event to measurement Characteristic");
-                            match event_.accept() {
-                                Ok(reply) => {
-                                    reply.send().await;
-                                    Timer::after_millis(300).await;
-                                    break
-                                },
-                                Err(e) => warn!("[gatt] error sending response: {:?}", e),
-                            };
-                        } else if event.handle() == server.time_service.current_time.handle {
-                            info!("[gatt] Read Event to current time Characteristic");
-                            let now = rtc.current_time();
-                            let ct = CurrentTime::from_naivedatetime(now);
-                            let value = ct.to_bytes();
-                            server.time_service.current_time.set(&server, &value).expect("Unable to set the time");
-                            match event_.accept() {
-                                Ok(reply) => reply.send().await,
-                                Err(e) => warn!("[gatt] error sending response: {:?}", e),
-                            }
-                        } else {
-                            match event_.accept() {
-                                Ok(reply) => reply.send().await,
-                                Err(e) => warn!("[gatt] error sending response: {:?}", e),
-                            }
-                        }
-
-                        
-                    }
-                    GattEvent::Write(event) => {
-                        if event.handle() == server.time_service.current_time.handle {
-                            let bytes = event.data();
-                            let ct = CurrentTime::from_bytes(&bytes);
-                             info!("[gatt] Write Event to current time Characteristic: {:?}", Debug2Format(&ct));
-
-                            rtc.set_current_time(ct.to_naivedatetime());
-                            match event_.accept() {
-                                Ok(reply) => reply.send().await,
-                                Err(e) => warn!("[gatt] error sending response: {:?}", e),
-                            };
-                        }
+            GattConnectionEvent::Gatt { event: Ok(event) } => {
+                let access = match &event {
+                    GattEvent::Read(read) => characteristic(server, read.handle()).map(|characteristic| gatt.read(characteristic, &*clock, session, &mut attributes)),
+                    GattEvent::Write(write) => {
+                        characteristic(server, write.handle()).map(|characteristic| gatt.write(characteristic, write.data(), clock, session, &mut attributes))
                     }
                 };
+                let access = access.transpose().map_err(gatt_error)?.unwrap_or(Access::Served);
+
+                match access {
+                    Access::TimeSet(now) => info!("[gatt] Write Event to current time Characteristic: {:?}", Debug2Format(&now)),
+                    Access::Negotiated(negotiated) => info!("[gatt] Write Event to protocol Characteristic: {:?}", Debug2Format(&negotiated)),
+                    Access::Configured(config) => info!("[gatt] Write Event to config Characteristic: {:?}", Debug2Format(&config)),
+                    Access::Acknowledged(ack) => info!("[gatt] Write Event to history ack Characteristic: {:?}", Debug2Format(&ack.timestamp)),
+                    Access::Responded(response) => info!("[gatt] Write Event to command Characteristic: {:?}", Debug2Format(&response.status)),
+                    Access::Invalid(characteristic, e) => warn!("[gatt] invalid write to {:?}: {:?}", Debug2Format(&characteristic), Debug2Format(&e)),
+                    Access::Served | Access::Drained => {}
+                }
+
+                match event.accept() {
+                    Ok(reply) => reply.send().await,
+                    Err(e) => warn!("[gatt] error sending response: {:?}", e),
+                };
+
+                match access {
+                    Access::Responded(response) => gatt::respond(&mut attributes, &response).map_err(gatt_error)?,
+                    Access::Drained => {
+                        Timer::after_millis(300).await;
+                        break None;
+                    }
+                    _ => {}
+                }
             }
+            GattConnectionEvent::Disconnected { reason } => break Some(reason),
             _ => {} // ignore other Gatt Connection Events
         }
     };
-    info!("[gatt] disconnected: {:?}", reason);
+    info!("[gatt] disconnected: {:?}", Debug2Format(&reason));
     Ok(())
 }
 
+/// Characteristic of the core a handle of the table belongs to, when the central can read or write it
+fn characteristic(server: &Server<'_>, handle: u16) -> Option<gatt::Characteristic> {
+    let measurement_service = &server.measurement_service;
+    let handles = [
+        (server.time_service.current_time.handle, gatt::Characteristic::CurrentTime),
+        (measurement_service.measurement.handle, gatt::Characteristic::Measurement),
+        (measurement_service.protocol.handle, gatt::Characteristic::Protocol),
+        (measurement_service.history_ack.handle, gatt::Characteristic::HistoryAck),
+        (measurement_service.config.handle, gatt::Characteristic::Config),
+        (measurement_service.command.handle, gatt::Characteristic::Command),
+    ];
+
+    handles.into_iter().find(|(characteristic, _)| *characteristic == handle).map(|(_, characteristic)| characteristic)
+}
+
+impl gatt::Attributes for &Server<'_> {
+    type Error = Error;
+
+    fn set(&mut self, characteristic: gatt::Characteristic, value: &[u8]) -> Result<(), Error> {
+        let server = *self;
+        match characteristic {
+            gatt::Characteristic::Address => set(server, &server.address_service.address, value),
+            gatt::Characteristic::CurrentTime => set(server, &server.time_service.current_time, value),
+            gatt::Characteristic::Measurement => set(server, &server.measurement_service.measurement, value),
+            gatt::Characteristic::Protocol => set(server, &server.measurement_service.protocol, value),
+            gatt::Characteristic::HistoryAck => set(server, &server.measurement_service.history_ack, value),
+            gatt::Characteristic::Config => set(server, &server.measurement_service.config, value),
+            gatt::Characteristic::Command => set(server, &server.measurement_service.command, value),
+            gatt::Characteristic::Temperature => set(server, &server.environmental_sensing_service.temperature, value),
+            gatt::Characteristic::Humidity => set(server, &server.environmental_sensing_service.humidity, value),
+            gatt::Characteristic::Illuminance => set(server, &server.environmental_sensing_service.illuminance, value),
+            gatt::Characteristic::BatteryLevel => set(server, &server.battery_service.level, value),
+            gatt::Characteristic::Model => set(server, &server.device_information_service.model, value),
+            gatt::Characteristic::FirmwareVersion => set(server, &server.device_information_service.firmware_version, value),
+            gatt::Characteristic::HardwareRevision => set(server, &server.device_information_service.hardware_revision, value),
+        }
+    }
+}
+
+fn set<T: FromGatt + for<'a> TryFrom<&'a [u8]>>(server: &Server<'_>, characteristic: &Characteristic<T>, value: &[u8]) -> Result<(), Error> {
+    let value = T::try_from(value).map_err(|_e| Error::Other)?;
+    characteristic.set(server, &value)
+}
+
+fn gatt_error(e: GattError<Error>) -> Error {
+    match e {
+        GattError::Protocol(e) => {
+            warn!("[gatt] unable to encode value: {:?}", Debug2Format(&e));
+            Error::Other
+        }
+        GattError::Attributes(e) => e,
+    }
+}
 
 /// Create an advertiser to use to connect to a BLE Central, and wait for it to connect.
 async fn advertise<'values, 'server, C: Controller>(
-    name: &'values str,
     peripheral: &mut Peripheral<'values, C>,
     server: &'server Server<'values>,
+    broadcast: Option<Broadcast>,
 ) -> Result<GattConnection<'values, 'server>, BleHostError<C::Error>> {
-    let mut advertiser_data = [0; 31];
-    let len = AdStructure::encode_slice(
-        &[
-            AdStructure::Flags(LE_GENERAL_DISCOVERABLE | BR_EDR_NOT_SUPPORTED),
-            AdStructure::ServiceUuids16(&[
-                CURRENT_TIME_SERVICE_UUID.to_le_bytes(), 
-                MEASUREMENT_SERVICE_UUID_16.to_le_bytes()
-            ]),
-            AdStructure::CompleteLocalName(name.as_bytes()),
-        ],
-        &mut advertiser_data[..],
-    )?;
+    let data = match broadcast.map(|broadcast| AdvertisingData::broadcasting(&broadcast)) {
+        Some(Ok(data)) => data,
+        Some(Err(e)) => {
+            warn!("[adv] unable to encode broadcast: {:?}", Debug2Format(&e));
+            AdvertisingData::connectable()
+        }
+        None => AdvertisingData::connectable(),
+    };
+
     let advertiser = peripheral
         .advertise(
             &Default::default(),
             Advertisement::ConnectableScannableUndirected {
-                adv_data: &advertiser_data[..len],
-                scan_data: &[],
+                adv_data: &data.adv_data,
+                scan_data: &data.scan_data,
             },
         )
         .await?;
@@ -255,4 +337,4 @@ async fn advertise<'values, 'server, C: Controller>(
     let conn = advertiser.accept().await?.with_attribute_server(server)?;
     info!("[adv] connection established");
     Ok(conn)
-}
\ No newline at end of file
+}
@@ -0,0 +1,23 @@
+use chrono::NaiveDateTime;
+use edge_peripheral_core::Clock;
+use esp_hal::rtc_cntl::Rtc;
+use esp_hal::rtc_cntl::sleep::{RtcSleepConfig, WakeSource};
+
+/// RTC of the board, keeps the time across deep sleeps
+pub struct RtcClock<'a>(pub Rtc<'a>);
+
+impl RtcClock<'_> {
+    pub fn sleep(&mut self, config: &RtcSleepConfig, wake_sources: &[&dyn WakeSource]) {
+        self.0.sleep(config, wake_sources);
+    }
+}
+
+impl Clock for RtcClock<'_> {
+    fn now(&self) -> NaiveDateTime {
+        self.0.current_time()
+    }
+
+    fn set(&mut self, now: NaiveDateTime) {
+        self.0.set_current_time(now);
+    }
+}
@@ -1,14 +1,15 @@
 use core::cell::RefCell;
 
 use bh1730fvc::{blocking::BH1730FVC};
-use defmt::info;
+use defmt::{warn, Display2Format};
 use embassy_time::{Delay, Timer};
 use embedded_hal_bus::i2c::RefCellDevice;
 use esp_hal::{analog::adc::AdcChannel, gpio::Output, i2c::master::I2c, Blocking};
+use edge_peripheral_core::Sensors;
 use edge_protocol::Measurement;
 use crate::battery::BatteryMeasurement;
 use crate::anyhow_utils::*;
-use crate::moisture::SoilSensor;
+use crate::moisture::{SoilSensor, SOIL_ADDR, TANK_ADDR};
 
 pub struct Gauge<'a, P : AdcChannel> {
     i2c_pcb: RefCell<I2c<'a, Blocking>>,
@@ -31,7 +32,9 @@ impl <'a, P : AdcChannel> Gauge<'a, P> {
         }
     }
 
-    pub async fn sample(&mut self) -> anyhow::Result<Measurement> {
+    /// Sample every sensor, a sensor that fails leaves its channel absent instead of
+    /// discarding the whole sample
+    pub async fn sample(&mut self) -> Measurement {
         self.pcb_pwr.set_high();
 
         Timer::after_millis(100).await;
@@ -41,44 +44,81 @@ impl <'a, P : AdcChannel> Gauge<'a, P> {
         let mut i2c_pcb_sht = RefCellDevice::new(&self.i2c_pcb);
         let mut i2c_pcb_bh1730fvc = RefCellDevice::new(&self.i2c_pcb);
         let mut i2c_ext_moisture = RefCellDevice::new(&self.i2c_ext);
+        let mut i2c_ext_tank = RefCellDevice::new(&self.i2c_ext);
 
-        let mut soil = SoilSensor::new(&mut i2c_ext_moisture);
+        let mut soil = SoilSensor::new(&mut i2c_ext_moisture, SOIL_ADDR);
+        let mut tank = SoilSensor::new(&mut i2c_ext_tank, TANK_ADDR);
 
-        let _ = soil.start().with_anyhow("Failed to start reading soil");
+        // A probe that didn't start would serve a stale reading, its channel is left absent
+        let soil_started = channel(soil.start().with_anyhow("Failed to start reading soil"));
+        let tank_started = channel(tank.start().with_anyhow("Failed to start reading tank"));
 
         Timer::after_millis(15).await;
 
-        let soil_pf = soil.read().await.with_anyhow("Unable to read soil")?;
+        let soil_pf = match soil_started {
+            Some(()) => channel(soil.read().await.with_anyhow("Unable to read soil")),
+            None => None,
+        };
+        let tank_pf = match tank_started {
+            Some(()) => channel(tank.read().await.with_anyhow("Unable to read tank")),
+            None => None,
+        };
 
         let mut sht = shtcx::blocking::shtc3(RefCellDevice::new(&self.i2c_pcb));
-        
-        let mut bh1730fvc = BH1730FVC::new(&mut delay, &mut i2c_pcb_bh1730fvc)
-            .with_anyhow("BH1730FVC init failed")?;
-        
-        sht.start_measurement(shtcx::blocking::PowerMode::NormalMode)
-            .with_anyhow("SHT start measurement failed")?;
 
-        bh1730fvc.set_mode(bh1730fvc::Mode::SingleShot, &mut i2c_pcb_bh1730fvc)
-            .with_anyhow("BH1730FVC set mode failed")?;
+        let sht_started = channel(
+            sht.start_measurement(shtcx::blocking::PowerMode::NormalMode)
+                .with_anyhow("SHT start measurement failed")
+        );
+
+        let bh1730fvc = channel(
+            BH1730FVC::new(&mut delay, &mut i2c_pcb_bh1730fvc)
+                .with_anyhow("BH1730FVC init failed")
+        ).and_then(|mut bh1730fvc| {
+            channel(
+                bh1730fvc.set_mode(bh1730fvc::Mode::SingleShot, &mut i2c_pcb_bh1730fvc)
+                    .with_anyhow("BH1730FVC set mode failed")
+            ).map(|_| bh1730fvc)
+        });
 
         Timer::after_millis(150).await;
 
-        let lux = bh1730fvc.read_ambient_light_intensity(&mut i2c_pcb_sht).with_anyhow("BH1730FVC read failed")?;
-        let battery = self.bm.sample();
+        let lux = bh1730fvc.and_then(|mut bh1730fvc| {
+            channel(bh1730fvc.read_ambient_light_intensity(&mut i2c_pcb_sht).with_anyhow("BH1730FVC read failed"))
+        });
+        let battery_mv = self.bm.sample();
         
-        let measurement = sht.get_measurement_result().with_anyhow("SHT read failed")?;
+        let sht_measurement = sht_started.and_then(|_| {
+            channel(sht.get_measurement_result().with_anyhow("SHT read failed"))
+        });
 
         self.pcb_pwr.set_low();
 
-        let measurement = Measurement {
-            battery,
+        Measurement {
+            battery: None,
             lux,
-            temperature: measurement.temperature.as_degrees_celsius(),
-            humidity: measurement.humidity.as_percent(),
-            soil_pf
-        };
+            temperature: sht_measurement.as_ref().map(|m| m.temperature.as_degrees_celsius()),
+            humidity: sht_measurement.as_ref().map(|m| m.humidity.as_percent()),
+            soil_pf,
+            tank_pf,
+            battery_mv: Some(battery_mv)
+        }
+    }
+}
 
-        Ok(measurement)
+impl <'a, P : AdcChannel> Sensors for Gauge<'a, P> {
+    async fn sample(&mut self) -> Measurement {
+        Gauge::sample(self).await
     }
 }
 
+/// Keep the value of a sensor read, logging the failure when it has none
+fn channel<T>(result: anyhow::Result<T>) -> Option<T> {
+    match result {
+        Ok(value) => Some(value),
+        Err(e) => {
+            warn!("{}", Display2Format(&e));
+            None
+        }
+    }
+}
@@ -3,20 +3,20 @@
 
 pub mod ble;
 pub mod battery;
+pub mod clock;
 pub mod gauge;
-pub mod types;
 pub mod moisture;
 pub mod anyhow_utils;
 
 use core::cell::RefCell;
 use bt_hci::controller::ExternalController;
-use chrono::NaiveDateTime;
-use edge_protocol::{Measurement, MeasurementSerieEntry};
-use embassy_futures::select::{Either, select};
+use edge_peripheral_core::{Device, DeviceState, Effects};
+use edge_protocol::auth::{parse_key, station_key};
+use edge_protocol::AuthKey;
 use embassy_time::{Duration, Timer};
 use esp_hal::analog::adc::{Adc, AdcConfig};
 use esp_hal::gpio::{GpioPin, Output, OutputConfig};
-use defmt::{error, flush, info};
+use defmt::{error, flush, info, warn};
 use embassy_executor::Spawner;
 use esp_hal::i2c::master::BusTimeout;
 use esp_hal::ram;
@@ -32,116 +32,123 @@ use esp_wifi::ble::controller::BleConnector;
 use esp_wifi::{init, EspWifiController};
 use gauge::Gauge;
 use esp_println::{self as _};
-use heapless::Vec;
-use timeseries::Series;
 
 use crate::battery::BatteryMeasurement;
-use crate::types::DeviceState;
+use crate::ble::Ble;
+use crate::clock::RtcClock;
 
 
 // TODO: This is a hack to get the state of the device across the different states.
 // It is not thread safe and should be replaced with a more robust solution.
 // see: https://stackoverflow.com/questions/79177001/esp-no-std-rust-persist-data-during-deep-sleeps
 #[ram(rtc_fast)]
-static mut STATE: DeviceState = DeviceState::AwaitingTimeSync;
+static mut DEVICE: Device = Device::INITIAL;
+
+/// Fleet key the firmware is built with, 64 hex characters. The station signs with a key derived from it and its MAC.
+const AUTH_KEY: Option<&str> = option_env!("MYCELIUM_AUTH_KEY");
+
+/// Number of times the LED blinks when the central asks the station to identify itself
+const IDENTIFY_BLINKS: usize = 10;
 
 #[esp_hal_embassy::main]
 async fn main(_spawner: Spawner) {
 
     let mut cfg = RtcSleepConfig::deep();
     cfg.set_rtc_fastmem_pd_en(false);
-    let wakeup_source = TimerWakeupSource::new(core::time::Duration::from_secs(1 * 10));
-    let state = unsafe { &STATE };
-    let boot_args = DeviceBootArgs::boot(&state);
+    let mut device = unsafe { DEVICE.clone() };
+    let wakeup_source = TimerWakeupSource::new(device.config.sampling_period());
+    let boot_args = DeviceBootArgs::boot(&device.state);
     
-    match boot_args {
-        DeviceBootArgs::AwaitingTimeSync { mut rtc, mac, ble } => {
-            
+    let (mut clock, effects) = match boot_args {
+        DeviceBootArgs::AwaitingTimeSync { rtc, mac, ble, mut led } => {
             info!("Awaiting time sync");
 
-            let future = select(
-                ble::run(ble, &mut rtc, mac.clone(), Vec::new()),
-                Timer::after(Duration::from_secs(10))
-            );
-
-            match future.await {
-                Either::First(_) => {
-                    info!("Awaiting time sync: done");
-                    unsafe {
-                        let series: Measurements = Series::new(Measurement::MAX_DEVIATION);
-                        let new_state = DeviceState::Buffering(series);
-                        STATE = new_state;
-                    }
-                },
-                Either::Second(_) => {}
-            };
-
-            rtc.sleep(&cfg, &[&wakeup_source]);
+            let mut clock = RtcClock(rtc);
+            let effects = device.await_time_sync(&mut clock, &mut Ble::new(ble, mac), auth_key(&mac)).await;
+
+            if !matches!(device.state, DeviceState::AwaitingTimeSync) {
+                info!("Awaiting time sync: done");
+            }
+
+            store(&device);
+            identify(&mut led, &effects).await;
+
+            (clock, effects)
         }
-        DeviceBootArgs::Buffering { mut rtc, mut gauge, measurements, mut rng } => {
-            info!("Buffering, current num entries {0}", measurements.buckets.len());
-
-            let measurement = gauge.sample().await;
-
-            match measurement {
-                Ok(m) => {
-                    let mut new_measurements = (*measurements).clone();
-                    
-                    new_measurements.append_monotonic(rtc.current_time(), m);
-
-                    let new_state = if new_measurements.is_full() {
-                        DeviceState::Flush(new_measurements)
-                    } else {  
-                        DeviceState::Buffering(new_measurements)
-                    };
-
-                    unsafe {
-                        STATE = new_state;
-                    }
-                },
-                Err(_) => {}
-            };
-
-            rtc.sleep(&cfg, &[&wakeup_source]);
+        DeviceBootArgs::Buffering { rtc, mut gauge } => {
+            if let DeviceState::Buffering(measurements) = &device.state {
+                info!("Buffering, current num entries {0}", measurements.buckets.len());
+            }
+
+            let clock = RtcClock(rtc);
+            let effects = device.buffer(&clock, &mut gauge).await;
+
+            store(&device);
+
+            (clock, effects)
         }
-        DeviceBootArgs::Flush { mut rtc, mac, mut gauge, measurements, ble, mut rng } => {
+        DeviceBootArgs::Flush { rtc, mac, mut gauge, ble, mut led } => {
             info!("Flushing");
 
-            let entries: Vec<MeasurementSerieEntry, 6> = measurements
-                .buckets
-                .iter()
-                .map(|entry| MeasurementSerieEntry { timestamp: entry.range.start, measurement: entry.value })
-                .collect();
-            
-            let future = select(
-                ble::run(ble, &mut rtc, mac.clone(), entries),
-                Timer::after(Duration::from_secs(10))
-            );
-
-            match future.await {
-                Either::First(_) => {
-                    match gauge.sample().await {
-                        Ok(m) => {
-                            let mut new_measurements: Measurements = Series::new(Measurement::MAX_DEVIATION);
-
-                            new_measurements.append_monotonic(rtc.current_time(), m);
-
-                            unsafe {
-                                STATE = DeviceState::Buffering(new_measurements);
-                            }
-                        },
-                        Err(_) => {}
-                    }
-                },
-                Either::Second(_) => {}
-            };
-        
+            let mut clock = RtcClock(rtc);
+            let effects = device.flush(&mut clock, &mut gauge, &mut Ble::new(ble, mac), auth_key(&mac)).await;
+
+            if let DeviceState::Flush(measurements, _) = &device.state {
+                info!("{0} entries waiting for the next flush", measurements.buckets.len());
+            }
+            if effects.dropped_sample {
+                warn!("History full, sample dropped until the central acknowledges it");
+            }
+
+            store(&device);
+            identify(&mut led, &effects).await;
 
-            rtc.sleep(&cfg, &[&wakeup_source]);
+            (clock, effects)
         }
     };
+
+    if effects.reboot {
+        info!("Rebooting");
+        flush();
+        esp_hal::system::software_reset();
+    }
+
+    clock.sleep(&cfg, &[&wakeup_source]);
 }
 
+/// Keep the device in RTC fast memory, the only memory that survives deep sleep
+fn store(device: &Device) {
+    unsafe {
+        DEVICE = device.clone();
+    }
+}
+
+/// Key to sign measurement frames with, when one is provisioned
+fn auth_key(mac: &[u8; 6]) -> Option<AuthKey> {
+    match AUTH_KEY.map(parse_key) {
+        Some(Ok(key)) => Some(station_key(&key, mac)),
+        Some(Err(_)) => {
+            error!("Invalid auth key, serving unauthenticated frames");
+            None
+        }
+        None => None,
+    }
+}
+
+/// Blink the LED when the central asked the station to identify itself
+async fn identify(led: &mut Output<'_>, effects: &Effects) {
+    if !effects.identify {
+        return;
+    }
+
+    info!("Identifying");
+    for _ in 0..IDENTIFY_BLINKS {
+        led.set_high();
+        Timer::after(Duration::from_millis(200)).await;
+        led.set_low();
+        Timer::after(Duration::from_millis(200)).await;
+    }
+}
 
 #[panic_handler]
 fn panic(info: &core::panic::PanicInfo) -> ! {
@@ -166,19 +173,18 @@ macro_rules! mk_static {
     }};
 }
 
-type Measurements = Series<6, NaiveDateTime, Measurement>;
-
+/// Hardware a wake-up needs for the state the device is in
 pub enum DeviceBootArgs<'a> {
-    AwaitingTimeSync { rtc: Rtc<'a>, mac: [u8; 6], ble: ExternalController<BleConnector<'a>, 20> },
-    Buffering { rtc: Rtc<'a>, gauge: Gauge<'a, GpioPin<34>>, measurements: &'a Measurements, rng: Rng },
-    Flush { rtc: Rtc<'a>, mac: [u8; 6], gauge: Gauge<'a, GpioPin<34>>, measurements: &'a Measurements, ble: ExternalController<BleConnector<'a>, 20>, rng: Rng }
+    AwaitingTimeSync { rtc: Rtc<'a>, mac: [u8; 6], ble: ExternalController<BleConnector<'a>, 20>, led: Output<'a> },
+    Buffering { rtc: Rtc<'a>, gauge: Gauge<'a, GpioPin<34>> },
+    Flush { rtc: Rtc<'a>, mac: [u8; 6], gauge: Gauge<'a, GpioPin<34>>, ble: ExternalController<BleConnector<'a>, 20>, led: Output<'a> }
 }
 
 impl <'a> DeviceBootArgs<'a> {
-    pub fn boot(state: &'a DeviceState) -> Self {
+    pub fn boot(state: &DeviceState) -> Self {
 
         let cpu_clock = match state {
-            DeviceState::AwaitingTimeSync | DeviceState::Flush(_) => CpuClock::max(),
+            DeviceState::AwaitingTimeSync | DeviceState::Flush(..) => CpuClock::max(),
             DeviceState::Buffering(_) => CpuClock::_80MHz,
         };
         let config = esp_hal::Config::default().with_cpu_clock(cpu_clock);
@@ -212,9 +218,11 @@ impl <'a> DeviceBootArgs<'a> {
                 let connector = BleConnector::new(&esp_wifi_ctrl, bluetooth);
                 let ble: ExternalController<_, 20> = ExternalController::new(connector);
 
-                Self::AwaitingTimeSync { rtc, mac, ble }
+                let led = Output::new(peripherals.GPIO2, esp_hal::gpio::Level::Low, OutputConfig::default());
+
+                Self::AwaitingTimeSync { rtc, mac, ble, led }
             }
-            DeviceState::Buffering(measurements) => {
+            DeviceState::Buffering(_) => {
 
                 let adc_pin = peripherals.GPIO34;
                 let mut adc_config = AdcConfig::new();
@@ -254,9 +262,9 @@ impl <'a> DeviceBootArgs<'a> {
                 let gauge = Gauge::new(i2c_pcb_refcell, i2c_ext_refcell, pcb_pwr, battery);
 
 
-                Self::Buffering { rtc, gauge, measurements, rng }
+                Self::Buffering { rtc, gauge }
             },
-            DeviceState::Flush(measurements) => {
+            DeviceState::Flush(..) => {
         
     
                 let esp_wifi_ctrl = &*mk_static!(
@@ -310,7 +318,10 @@ impl <'a> DeviceBootArgs<'a> {
                 let battery = BatteryMeasurement::new(adc, pin);
                 let gauge = Gauge::new(i2c_pcb_refcell, i2c_ext_refcell, pcb_pwr, battery);
 
-                Self::Flush { rtc, mac, gauge, measurements, ble: controller, rng }
+                // Status LED, blinks when the central asks the station to identify itself
+                let led = Output::new(peripherals.GPIO2, esp_hal::gpio::Level::Low, OutputConfig::default());
+
+                Self::Flush { rtc, mac, gauge, ble: controller, led }
             }
         }
     }
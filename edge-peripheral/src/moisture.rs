@@ -2,17 +2,23 @@ use embassy_time::Timer;
 use embedded_hal::{i2c::{I2c, SevenBitAddress}};
 use crate::anyhow_utils::*;
 
-const ADDR: u8 = 0x55;
+/// Address of the capacitive probe in the soil
+pub const SOIL_ADDR: u8 = 0x55;
 
-/// Soil sensor state
+/// Address of the capacitive probe in the water tank, the same sensor strapped to another address
+pub const TANK_ADDR: u8 = 0x56;
+
+/// Capacitive sensor state
 pub struct SoilSensor<I2C> {
-    i2c: I2C
+    i2c: I2C,
+    addr: u8
 }
 
 impl<I2C> SoilSensor<I2C> {
-    pub fn new(i2c: I2C) -> Self {
+    pub fn new(i2c: I2C, addr: u8) -> Self {
         Self {
-            i2c
+            i2c,
+            addr
         }
     }
 
@@ -21,7 +27,7 @@ impl<I2C> SoilSensor<I2C> {
         I2C: I2c<SevenBitAddress>
     {
         self.i2c
-            .write(ADDR, &[0x10 | 0x01, 0x01])
+            .write(self.addr, &[0x10 | 0x01, 0x01])
             .with_anyhow("Unable to start soil conversion")?;
 
         Ok(())
@@ -32,14 +38,14 @@ impl<I2C> SoilSensor<I2C> {
         I2C: I2c<SevenBitAddress>
     {
         self.i2c
-            .write(ADDR, &[0x10 | 0x02])
+            .write(self.addr, &[0x10 | 0x02])
             .with_anyhow("Unable to trigger soil read")?;
 
         Timer::after_micros(150).await;
 
         let mut buf = [0u8; 3];
         self.i2c
-            .read(ADDR, &mut buf)
+            .read(self.addr, &mut buf)
             .with_anyhow("Unable to read soil data")?;
 
         let d0 = buf[0];
@@ -0,0 +1,272 @@
+//! Per-device authentication of measurement frames.
+//!
+//! Firmware is built with a fleet key, every peripheral derives its own key from it and
+//! its MAC with [`station_key`]. A central holding the fleet key derives the same key when
+//! a station first syncs, a key can also be provisioned for a single station. When both sides negotiate
+//! `AUTHENTICATED` the peripheral appends a trailer to every frame it serves:
+//!
+//! | frame ... | counter (4, LE) | tag (8) |
+//!
+//! The tag is HMAC-SHA256 over the counter and the frame, truncated to 8 bytes. The
+//! counter increments for every signed frame, a central only accepts counters newer
+//! than the last one it accepted so a recorded frame can't be replayed.
+//!
+//! Writes of the central carry the same trailer, signed with [`downlink_key`] so a frame of
+//! the peripheral can't be written back to it.
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::frame::MAX_FRAME_LEN;
+use crate::ProtocolError;
+
+pub const AUTH_KEY_LEN: usize = 32;
+pub const AUTH_TAG_LEN: usize = 8;
+pub const AUTH_TRAILER_LEN: usize = 4 + AUTH_TAG_LEN;
+
+/// Largest frame including the authentication trailer
+pub const MAX_AUTHENTICATED_FRAME_LEN: usize = MAX_FRAME_LEN + AUTH_TRAILER_LEN;
+
+pub type AuthKey = [u8; AUTH_KEY_LEN];
+
+/// Parse a key from 64 hex characters
+pub fn parse_key(hex: &str) -> Result<AuthKey, ProtocolError> {
+    let invalid_length = ProtocolError::InvalidLength { field: "auth key", expected: AUTH_KEY_LEN * 2, actual: hex.len() };
+    if hex.len() != AUTH_KEY_LEN * 2 {
+        return Err(invalid_length);
+    }
+
+    let mut key = [0u8; AUTH_KEY_LEN];
+    for (byte, chunk) in key.iter_mut().zip(hex.as_bytes().chunks(2)) {
+        let chunk = core::str::from_utf8(chunk).map_err(|_| invalid_length)?;
+        *byte = u8::from_str_radix(chunk, 16).map_err(|_| invalid_length)?;
+    }
+
+    Ok(key)
+}
+
+/// Key of the station with the given MAC, HMAC-SHA256 of the MAC under the fleet key
+pub fn station_key(fleet_key: &AuthKey, mac: &[u8; 6]) -> AuthKey {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(fleet_key).expect("HMAC accepts keys of any length");
+    hmac.update(b"mycelium station key");
+    hmac.update(mac);
+    hmac.finalize().into_bytes().into()
+}
+
+/// Key the central signs its writes to a station with, derived from the key of the station
+pub fn downlink_key(station_key: &AuthKey) -> AuthKey {
+    let mut hmac = <Hmac<Sha256> as Mac>::new_from_slice(station_key).expect("HMAC accepts keys of any length");
+    hmac.update(b"mycelium downlink key");
+    hmac.finalize().into_bytes().into()
+}
+
+fn mac(key: &AuthKey, counter: u32, frame: &[u8]) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(&counter.to_le_bytes());
+    mac.update(frame);
+    mac
+}
+
+/// Append the trailer to the frame at the start of the buffer, returns the total length
+pub fn sign(key: &AuthKey, counter: u32, buffer: &mut [u8], frame_len: usize) -> Result<usize, ProtocolError> {
+    let required = frame_len + AUTH_TRAILER_LEN;
+    if buffer.len() < required {
+        return Err(ProtocolError::BufferTooSmall { required, available: buffer.len() });
+    }
+
+    let tag = mac(key, counter, &buffer[..frame_len]).finalize().into_bytes();
+
+    buffer[frame_len..frame_len + 4].copy_from_slice(&counter.to_le_bytes());
+    buffer[frame_len + 4..required].copy_from_slice(&tag[..AUTH_TAG_LEN]);
+
+    Ok(required)
+}
+
+/// Split an authenticated frame into the frame, counter and tag without verifying it
+pub fn split(data: &[u8]) -> Result<(&[u8], u32, &[u8]), ProtocolError> {
+    if data.len() < AUTH_TRAILER_LEN {
+        return Err(ProtocolError::Truncated);
+    }
+
+    let (frame, trailer) = data.split_at(data.len() - AUTH_TRAILER_LEN);
+    let (counter, tag) = trailer.split_at(4);
+    let counter = u32::from_le_bytes([counter[0], counter[1], counter[2], counter[3]]);
+
+    Ok((frame, counter, tag))
+}
+
+/// Peripheral side, signs frames with an incrementing counter
+#[derive(Debug, Clone)]
+pub struct Signer {
+    key: AuthKey,
+    counter: u32,
+}
+
+impl Signer {
+    pub fn new(key: AuthKey, counter: u32) -> Self {
+        Self { key, counter }
+    }
+
+    /// Counter of the last signed frame
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    /// Start a lost counter at the current unix time, ahead of every counter used before
+    /// as long as fewer frames were signed than seconds passed
+    pub fn seed(&mut self, unix_time: i64) {
+        if self.counter == 0 {
+            self.counter = u32::try_from(unix_time).unwrap_or(0);
+        }
+    }
+
+    pub fn key(&self) -> &AuthKey {
+        &self.key
+    }
+
+    /// Advance the counter for the next frame to sign
+    pub fn next_counter(&mut self) -> u32 {
+        self.counter = self.counter.saturating_add(1);
+        self.counter
+    }
+
+    /// Sign the frame at the start of the buffer with the next counter
+    pub fn sign(&mut self, buffer: &mut [u8], frame_len: usize) -> Result<usize, ProtocolError> {
+        let counter = self.next_counter();
+        sign(&self.key, counter, buffer, frame_len)
+    }
+}
+
+/// Central side, verifies frames of a single station and rejects replays
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    key: AuthKey,
+    counter: u32,
+}
+
+impl Verifier {
+    /// The counter is the last one accepted from the station
+    pub fn new(key: AuthKey, counter: u32) -> Self {
+        Self { key, counter }
+    }
+
+    /// Counter of the last accepted frame
+    pub fn counter(&self) -> u32 {
+        self.counter
+    }
+
+    pub fn key(&self) -> &AuthKey {
+        &self.key
+    }
+
+    /// Verify an authenticated frame and return the frame without its trailer
+    pub fn verify<'a>(&mut self, data: &'a [u8]) -> Result<&'a [u8], ProtocolError> {
+        let (frame, counter, tag) = split(data)?;
+
+        mac(&self.key, counter, frame)
+            .verify_truncated_left(tag)
+            .map_err(|_| ProtocolError::InvalidTag)?;
+
+        if counter <= self.counter {
+            return Err(ProtocolError::Replayed { counter, last: self.counter });
+        }
+
+        self.counter = counter;
+
+        Ok(frame)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY: AuthKey = [0x42; AUTH_KEY_LEN];
+
+    fn signed(signer: &mut Signer, frame: &[u8]) -> Vec<u8> {
+        let mut buffer = [0u8; MAX_AUTHENTICATED_FRAME_LEN];
+        buffer[..frame.len()].copy_from_slice(frame);
+        let len = signer.sign(&mut buffer, frame.len()).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    #[test]
+    fn test_verify_signed_frames() {
+        let mut signer = Signer::new(KEY, 10);
+        let mut verifier = Verifier::new(KEY, 10);
+
+        let first = signed(&mut signer, b"frame one");
+        let second = signed(&mut signer, b"frame two");
+
+        assert_eq!(verifier.verify(&first), Ok(&b"frame one"[..]));
+        assert_eq!(verifier.verify(&second), Ok(&b"frame two"[..]));
+        assert_eq!(verifier.counter(), 12);
+    }
+
+    #[test]
+    fn test_reject_replayed_frame() {
+        let mut signer = Signer::new(KEY, 0);
+        let mut verifier = Verifier::new(KEY, 0);
+
+        let frame = signed(&mut signer, b"frame");
+        assert!(verifier.verify(&frame).is_ok());
+        assert_eq!(verifier.verify(&frame), Err(ProtocolError::Replayed { counter: 1, last: 1 }));
+    }
+
+    #[test]
+    fn test_reject_tampered_frame_and_other_key() {
+        let mut signer = Signer::new(KEY, 0);
+        let mut frame = signed(&mut signer, b"frame");
+
+        assert_eq!(Verifier::new([0x24; AUTH_KEY_LEN], 0).verify(&frame), Err(ProtocolError::InvalidTag));
+
+        frame[0] ^= 1;
+        assert_eq!(Verifier::new(KEY, 0).verify(&frame), Err(ProtocolError::InvalidTag));
+
+        // Raising the counter to get past the replay check invalidates the tag
+        let mut frame = signed(&mut signer, b"frame");
+        let counter = frame.len() - AUTH_TRAILER_LEN;
+        frame[counter] += 1;
+        assert_eq!(Verifier::new(KEY, 0).verify(&frame), Err(ProtocolError::InvalidTag));
+
+        assert_eq!(Verifier::new(KEY, 0).verify(&[0; 4]), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn test_seed_lost_counter() {
+        let mut signer = Signer::new(KEY, 0);
+        signer.seed(1_700_000_000);
+        assert_eq!(signer.counter(), 1_700_000_000);
+
+        // A counter that survived isn't moved
+        signer.seed(1_800_000_000);
+        assert_eq!(signer.counter(), 1_700_000_000);
+    }
+
+    #[test]
+    fn test_station_keys_differ_per_mac() {
+        let key = station_key(&KEY, &[0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]);
+
+        assert_eq!(key, station_key(&KEY, &[0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]));
+        assert_ne!(key, station_key(&KEY, &[0x24, 0x0a, 0xc4, 0x12, 0x34, 0x57]));
+        assert_ne!(key, station_key(&[0x24; AUTH_KEY_LEN], &[0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]));
+        assert_ne!(key, KEY);
+    }
+
+    #[test]
+    fn test_downlink_frames_dont_verify_as_uplink() {
+        let mut signer = Signer::new(downlink_key(&KEY), 0);
+        let frame = signed(&mut signer, b"config");
+
+        assert_eq!(Verifier::new(KEY, 0).verify(&frame), Err(ProtocolError::InvalidTag));
+        assert_eq!(Verifier::new(downlink_key(&KEY), 0).verify(&frame), Ok(&b"config"[..]));
+    }
+
+    #[test]
+    fn test_parse_key() {
+        let hex = "42".repeat(AUTH_KEY_LEN);
+        assert_eq!(parse_key(&hex), Ok(KEY));
+        assert!(parse_key("42").is_err());
+        assert!(parse_key(&"zz".repeat(AUTH_KEY_LEN)).is_err());
+    }
+}
@@ -0,0 +1,99 @@
+//! Battery charge estimation from the cell voltage.
+//!
+//! Peripherals report the battery in millivolts, the charge is derived from a
+//! typical single cell LiPo discharge curve so it can be corrected in one place.
+
+/// Cell voltage in millivolts at which each charge percentage is reached, highest first
+const LIPO_DISCHARGE_CURVE: [(u16, u8); 21] = [
+    (4200, 100),
+    (4150, 95),
+    (4110, 90),
+    (4080, 85),
+    (4020, 80),
+    (3980, 75),
+    (3950, 70),
+    (3910, 65),
+    (3870, 60),
+    (3850, 55),
+    (3840, 50),
+    (3820, 45),
+    (3800, 40),
+    (3790, 35),
+    (3770, 30),
+    (3750, 25),
+    (3730, 20),
+    (3710, 15),
+    (3690, 10),
+    (3610, 5),
+    (3270, 0),
+];
+
+/// Charge below which a station should be flagged for a new battery
+pub const LOW_BATTERY_PERCENTAGE: u8 = 20;
+
+/// Encode the Battery Level characteristic (0x2A19) of the Battery Service, a uint8 percentage
+pub fn battery_level(percentage: u8) -> [u8; 1] {
+    [percentage.min(100)]
+}
+
+/// Estimate the charge percentage of a LiPo cell, interpolating linearly between curve points
+pub fn lipo_percentage(millivolts: u16) -> u8 {
+    let (full_mv, full) = LIPO_DISCHARGE_CURVE[0];
+    if millivolts >= full_mv {
+        return full;
+    }
+
+    for window in LIPO_DISCHARGE_CURVE.windows(2) {
+        let (high_mv, high) = window[0];
+        let (low_mv, low) = window[1];
+
+        if millivolts >= low_mv {
+            let span = (high_mv - low_mv) as u32;
+            let offset = (millivolts - low_mv) as u32;
+            return low + ((high - low) as u32 * offset / span) as u8;
+        }
+    }
+
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lipo_percentage_curve_points() {
+        for (millivolts, percentage) in LIPO_DISCHARGE_CURVE {
+            assert_eq!(lipo_percentage(millivolts), percentage);
+        }
+    }
+
+    #[test]
+    fn test_lipo_percentage_interpolates() {
+        assert_eq!(lipo_percentage(3995), 76);
+        assert_eq!(lipo_percentage(3440), 2);
+    }
+
+    #[test]
+    fn test_battery_level() {
+        assert_eq!(battery_level(lipo_percentage(3995)), [76]);
+        assert_eq!(battery_level(120), [100]);
+    }
+
+    #[test]
+    fn test_lipo_percentage_clamps() {
+        assert_eq!(lipo_percentage(4350), 100);
+        assert_eq!(lipo_percentage(3000), 0);
+        assert_eq!(lipo_percentage(0), 0);
+    }
+
+    #[test]
+    fn test_lipo_percentage_is_monotonic() {
+        let mut previous = 0;
+        for millivolts in 3000..4300 {
+            let percentage = lipo_percentage(millivolts);
+            assert!(percentage >= previous);
+            previous = percentage;
+        }
+    }
+}
@@ -0,0 +1,218 @@
+//! Connectionless broadcast of the latest measurement in advertising service data.
+//!
+//! The payload is carried in a 16 bit service data AD structure (type `0x16`) under
+//! the measurement service UUID, so a central can read it from a passive scan:
+//!
+//! | version (1) | sequence (1) | age (2, LE) | MAC MSB (1) | compact measurement |
+//!
+//! The measurement uses the fixed point layout of a compact series entry, without the
+//! timestamp delta. The sequence number increments for every new sample and wraps, a
+//! central uses it to tell a new value from a repeated advertisement. The age holds the
+//! seconds between the sample and the start of advertising, the central times the value
+//! with it to within the advertising window.
+//!
+//! A passive scan only sees the static random address, which overwrites the two most
+//! significant bits of the MAC. The payload carries the original most significant byte,
+//! so the central keys a broadcast by the same MAC as a connected sync.
+
+use crate::compact::{self, MAX_COMPACT_MEASUREMENT_LEN};
+use crate::{Measurement, ProtocolError, MEASUREMENT_SERVICE_UUID_16};
+
+/// Version of the broadcast payload layout
+pub const BROADCAST_VERSION: u8 = 2;
+
+/// Size of the payload before the measurement
+const BROADCAST_HEADER_LEN: usize = 5;
+
+/// Largest broadcast payload, fits the 31 byte advertising data next to the flags.
+/// The service UUIDs move to the scan response while broadcasting.
+pub const MAX_BROADCAST_LEN: usize = BROADCAST_HEADER_LEN + MAX_COMPACT_MEASUREMENT_LEN;
+
+// Flags and the service data with every channel present
+const _: () = assert!(3 + (2 + 2 + MAX_BROADCAST_LEN) <= 31);
+
+/// AD type of service data with a 16 bit UUID
+const AD_TYPE_SERVICE_DATA_16: u8 = 0x16;
+
+/// Static random address a peripheral advertises with, derived from its MAC.
+///
+/// Little endian as it goes over HCI, with the two most significant bits set as a static
+/// random address requires. A passive scan only sees this address, not the MAC itself.
+pub fn static_random_address(mac: [u8; 6]) -> [u8; 6] {
+    let mut address = mac;
+    address.reverse();
+    address[5] |= 0xC0;
+    address
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Broadcast {
+    pub sequence: u8,
+    /// Seconds between the sample and the start of advertising, saturates
+    pub age_secs: u16,
+    /// Most significant byte of the MAC, its top bits are overwritten in the static random address
+    pub mac_msb: u8,
+    pub measurement: Measurement,
+}
+
+impl Broadcast {
+    /// Service data UUID the payload is advertised under
+    pub const SERVICE_UUID_16: u16 = MEASUREMENT_SERVICE_UUID_16;
+
+    /// Broadcast a sample of the peripheral with the given MAC
+    pub fn new(sequence: u8, age_secs: u16, mac: [u8; 6], measurement: Measurement) -> Self {
+        Broadcast { sequence, age_secs, mac_msb: mac[0], measurement }
+    }
+
+    /// MAC of the peripheral, from the little endian static random address it advertised with
+    pub fn mac(&self, address: [u8; 6]) -> [u8; 6] {
+        let mut mac = address;
+        mac.reverse();
+        mac[0] = self.mac_msb;
+        mac
+    }
+
+    /// Encode the service data payload, returns the number of bytes written
+    pub fn to_bytes(&self, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        if buffer.len() < BROADCAST_HEADER_LEN {
+            return Err(ProtocolError::BufferTooSmall { required: BROADCAST_HEADER_LEN, available: buffer.len() });
+        }
+
+        let [age_lo, age_hi] = self.age_secs.to_le_bytes();
+        buffer[..BROADCAST_HEADER_LEN].copy_from_slice(&[BROADCAST_VERSION, self.sequence, age_lo, age_hi, self.mac_msb]);
+
+        let len = compact::encode_measurement(&self.measurement, &mut buffer[BROADCAST_HEADER_LEN..]).map_err(|err| match err {
+            ProtocolError::BufferTooSmall { required, available } => ProtocolError::BufferTooSmall {
+                required: required + BROADCAST_HEADER_LEN,
+                available: available + BROADCAST_HEADER_LEN,
+            },
+            err => err,
+        })?;
+
+        Ok(BROADCAST_HEADER_LEN + len)
+    }
+
+    /// Decode a service data payload
+    pub fn from_bytes(data: &[u8]) -> Result<Self, ProtocolError> {
+        let [version, ref rest @ ..] = *data else {
+            return Err(ProtocolError::Truncated);
+        };
+
+        if version != BROADCAST_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let [sequence, age_lo, age_hi, mac_msb, ref measurement @ ..] = *rest else {
+            return Err(ProtocolError::Truncated);
+        };
+
+        Ok(Broadcast {
+            sequence,
+            age_secs: u16::from_le_bytes([age_lo, age_hi]),
+            mac_msb,
+            measurement: compact::decode_measurement(measurement)?,
+        })
+    }
+
+    /// Find and decode the broadcast in raw advertising data, `None` when it isn't advertised
+    pub fn from_adv_data(data: &[u8]) -> Option<Result<Self, ProtocolError>> {
+        let uuid = Self::SERVICE_UUID_16.to_le_bytes();
+        let mut rest = data;
+
+        while let [len, tail @ ..] = rest {
+            let len = *len as usize;
+            if len == 0 {
+                break;
+            }
+            if tail.len() < len {
+                return Some(Err(ProtocolError::Truncated));
+            }
+
+            let (structure, next) = tail.split_at(len);
+            rest = next;
+
+            if let [AD_TYPE_SERVICE_DATA_16, lo, hi, payload @ ..] = structure {
+                if [*lo, *hi] == uuid {
+                    return Some(Self::from_bytes(payload));
+                }
+            }
+        }
+
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn broadcast() -> Broadcast {
+        Broadcast {
+            sequence: 42,
+            age_secs: 300,
+            mac_msb: 0xec,
+            measurement: Measurement {
+                battery: None,
+                lux: Some(1200.0),
+                temperature: Some(21.5),
+                humidity: Some(48.5),
+                soil_pf: Some(320.5),
+                tank_pf: Some(87.5),
+                battery_mv: Some(3912),
+            },
+        }
+    }
+
+    #[test]
+    fn test_broadcast_roundtrip() {
+        let mut buffer = [0u8; MAX_BROADCAST_LEN];
+        let len = broadcast().to_bytes(&mut buffer).unwrap();
+
+        // Every channel but the battery percentage
+        assert_eq!(len, MAX_BROADCAST_LEN - 1);
+        assert_eq!(Broadcast::from_bytes(&buffer[..len]), Ok(broadcast()));
+    }
+
+    #[test]
+    fn test_broadcast_rejects_unknown_version() {
+        let mut buffer = [0u8; MAX_BROADCAST_LEN];
+        let len = broadcast().to_bytes(&mut buffer).unwrap();
+        buffer[0] = 1;
+
+        assert_eq!(Broadcast::from_bytes(&buffer[..len]), Err(ProtocolError::UnsupportedVersion(1)));
+        assert_eq!(Broadcast::from_bytes(&[BROADCAST_VERSION, 42, 0x2c]), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn test_static_random_address() {
+        let address = static_random_address([0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]);
+        assert_eq!(address, [0x56, 0x34, 0x12, 0xc4, 0x0a, 0xe4]);
+    }
+
+    #[test]
+    fn test_mac_from_static_random_address() {
+        // Espressif MACs may have the top bits set already, or not at all
+        for mac in [[0xec, 0x94, 0xcb, 0x12, 0x34, 0x56], [0x24, 0x0a, 0xc4, 0x12, 0x34, 0x56]] {
+            let broadcast = Broadcast::new(1, 0, mac, Measurement::EMPTY);
+            assert_eq!(broadcast.mac(static_random_address(mac)), mac);
+        }
+    }
+
+    #[test]
+    fn test_broadcast_from_adv_data() {
+        let mut payload = [0u8; MAX_BROADCAST_LEN];
+        let len = broadcast().to_bytes(&mut payload).unwrap();
+
+        let uuid = Broadcast::SERVICE_UUID_16.to_le_bytes();
+        let mut adv = vec![2, 0x01, 0x06];
+        adv.extend_from_slice(&[(3 + len) as u8, AD_TYPE_SERVICE_DATA_16, uuid[0], uuid[1]]);
+        adv.extend_from_slice(&payload[..len]);
+        assert!(adv.len() <= 31);
+
+        assert_eq!(Broadcast::from_adv_data(&adv), Some(Ok(broadcast())));
+
+        // Advertising without the service data, and data cut off halfway a structure
+        assert_eq!(Broadcast::from_adv_data(&adv[..3]), None);
+        assert_eq!(Broadcast::from_adv_data(&adv[..6]), Some(Err(ProtocolError::Truncated)));
+    }
+}
@@ -0,0 +1,214 @@
+//! Commands a central sends to a specific peripheral.
+//!
+//! The central writes a request to the command characteristic and reads the response
+//! back from it:
+//!
+//! - request: | opcode (1) | id (1) |
+//! - response: | opcode (1) | id (1) | status (1) |
+//!
+//! The id is chosen by the central to match a response to its request. A peripheral
+//! accepts a command during the connection and carries it out once the connection ends,
+//! so the central always gets a response before the peripheral blinks, samples or reboots.
+//!
+//! Once a peripheral has a key, a command that disrupts it has to carry the trailer of
+//! [`crate::auth`] signed with the downlink key, an unsigned one is rejected.
+
+use crate::auth::{self, downlink_key, AuthKey, Verifier, AUTH_TRAILER_LEN};
+use crate::ProtocolError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Command {
+    /// Blink the LED so the station can be found
+    Identify = 1,
+    /// Take a measurement right away instead of waiting for the sampling period
+    SampleNow = 2,
+    /// Restart, keeping buffered measurements and configuration
+    Reboot = 3,
+    /// Drop buffered measurements and configuration, then restart
+    FactoryReset = 4,
+}
+
+impl Command {
+    pub fn opcode(&self) -> u8 {
+        *self as u8
+    }
+
+    /// Whether a peripheral with a key only carries out the command when the request is signed
+    pub fn requires_authentication(&self) -> bool {
+        matches!(self, Command::Reboot | Command::FactoryReset)
+    }
+}
+
+impl TryFrom<u8> for Command {
+    type Error = ProtocolError;
+
+    fn try_from(opcode: u8) -> Result<Self, Self::Error> {
+        match opcode {
+            1 => Ok(Command::Identify),
+            2 => Ok(Command::SampleNow),
+            3 => Ok(Command::Reboot),
+            4 => Ok(Command::FactoryReset),
+            _ => Err(ProtocolError::UnknownType(opcode)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandRequest {
+    pub command: Command,
+    pub id: u8,
+}
+
+impl CommandRequest {
+    pub const LEN: usize = 2;
+
+    pub const MAX_SIGNED_LEN: usize = Self::LEN + AUTH_TRAILER_LEN;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [self.command.opcode(), self.id]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let [opcode, id]: [u8; Self::LEN] = bytes.try_into().map_err(|_| ProtocolError::InvalidLength {
+            field: "command request",
+            expected: Self::LEN,
+            actual: bytes.len(),
+        })?;
+
+        Ok(Self { command: Command::try_from(opcode)?, id })
+    }
+
+    /// Encode the request signed with the downlink key of the station, returns the number of bytes written
+    pub fn to_signed_bytes(&self, station_key: &AuthKey, counter: u32, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let available = buffer.len();
+        let request = buffer.get_mut(..Self::LEN).ok_or(ProtocolError::BufferTooSmall { required: Self::MAX_SIGNED_LEN, available })?;
+        request.copy_from_slice(&self.to_bytes());
+        auth::sign(&downlink_key(station_key), counter, buffer, Self::LEN)
+    }
+
+    /// Decode a signed request, the verifier holds the downlink key and the counter of the last write
+    pub fn from_signed_bytes(data: &[u8], verifier: &mut Verifier) -> Result<Self, ProtocolError> {
+        Self::from_bytes(verifier.verify(data)?)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum CommandStatus {
+    /// Accepted, carried out once the connection ends
+    Accepted = 0,
+    /// The opcode isn't known to the peripheral
+    Unsupported = 1,
+    /// The peripheral can't carry out the command in its current state
+    Rejected = 2,
+}
+
+impl TryFrom<u8> for CommandStatus {
+    type Error = ProtocolError;
+
+    fn try_from(status: u8) -> Result<Self, Self::Error> {
+        match status {
+            0 => Ok(CommandStatus::Accepted),
+            1 => Ok(CommandStatus::Unsupported),
+            2 => Ok(CommandStatus::Rejected),
+            _ => Err(ProtocolError::UnknownType(status)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandResponse {
+    /// Opcode as sent, which may be one the peripheral doesn't know
+    pub opcode: u8,
+    pub id: u8,
+    pub status: CommandStatus,
+}
+
+impl CommandResponse {
+    pub const LEN: usize = 3;
+
+    /// Response to raw request bytes, an unknown opcode is answered as unsupported
+    pub fn for_request(request: &[u8], status: CommandStatus) -> Self {
+        let opcode = request.first().copied().unwrap_or(0);
+        let id = request.get(1).copied().unwrap_or(0);
+
+        Self { opcode, id, status }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        [self.opcode, self.id, self.status as u8]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let [opcode, id, status]: [u8; Self::LEN] = bytes.try_into().map_err(|_| ProtocolError::InvalidLength {
+            field: "command response",
+            expected: Self::LEN,
+            actual: bytes.len(),
+        })?;
+
+        Ok(Self { opcode, id, status: CommandStatus::try_from(status)? })
+    }
+
+    /// Whether this responds to the given request
+    pub fn answers(&self, request: &CommandRequest) -> bool {
+        self.opcode == request.command.opcode() && self.id == request.id
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_request_roundtrip() {
+        for command in [Command::Identify, Command::SampleNow, Command::Reboot, Command::FactoryReset] {
+            let request = CommandRequest { command, id: 7 };
+            assert_eq!(CommandRequest::from_bytes(&request.to_bytes()), Ok(request));
+        }
+
+        assert_eq!(CommandRequest::from_bytes(&[9, 1]), Err(ProtocolError::UnknownType(9)));
+        assert_eq!(
+            CommandRequest::from_bytes(&[1]),
+            Err(ProtocolError::InvalidLength { field: "command request", expected: 2, actual: 1 })
+        );
+    }
+
+    #[test]
+    fn test_command_response_roundtrip() {
+        let request = CommandRequest { command: Command::Reboot, id: 42 };
+        let response = CommandResponse::for_request(&request.to_bytes(), CommandStatus::Accepted);
+
+        assert!(response.answers(&request));
+        assert!(!response.answers(&CommandRequest { command: Command::Reboot, id: 43 }));
+        assert_eq!(CommandResponse::from_bytes(&response.to_bytes()), Ok(response));
+    }
+
+    #[test]
+    fn test_signed_command_request() {
+        let key = [0x42; 32];
+        let request = CommandRequest { command: Command::FactoryReset, id: 3 };
+        let mut buffer = [0u8; CommandRequest::MAX_SIGNED_LEN];
+        let len = request.to_signed_bytes(&key, 10, &mut buffer).unwrap();
+
+        // The response echoes the request, trailer or not
+        assert_eq!(CommandResponse::for_request(&buffer[..len], CommandStatus::Accepted).to_bytes(), [4, 3, 0]);
+
+        let mut verifier = Verifier::new(downlink_key(&key), 0);
+        assert_eq!(CommandRequest::from_signed_bytes(&buffer[..len], &mut verifier), Ok(request));
+        assert_eq!(CommandRequest::from_signed_bytes(&buffer[..len], &mut verifier), Err(ProtocolError::Replayed { counter: 10, last: 10 }));
+        assert_eq!(CommandRequest::from_signed_bytes(&request.to_bytes(), &mut Verifier::new(downlink_key(&key), 0)), Err(ProtocolError::Truncated));
+
+        // Signed with the key of the station rather than the downlink key
+        let mut verifier = Verifier::new(key, 0);
+        assert_eq!(CommandRequest::from_signed_bytes(&buffer[..len], &mut verifier), Err(ProtocolError::InvalidTag));
+    }
+
+    #[test]
+    fn test_unknown_opcode_is_answered() {
+        let response = CommandResponse::for_request(&[9, 3], CommandStatus::Unsupported);
+
+        assert_eq!(response.to_bytes(), [9, 3, 1]);
+        assert_eq!(CommandResponse::from_bytes(&[9, 3, 5]), Err(ProtocolError::UnknownType(5)));
+    }
+}
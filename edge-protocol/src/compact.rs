@@ -0,0 +1,406 @@
+//! Compact delta encoded measurement series.
+//!
+//! A compact series starts with the timestamp of its first entry, every entry then
+//! only carries the seconds elapsed since the previous one and its channels in fixed point:
+//!
+//! | base timestamp (8, LE) | entries ... |
+//!
+//! Every entry is laid out as:
+//!
+//! | delta (2, LE) | [escaped delta (4, LE)] | fields (1) | values ... |
+//!
+//! A delta of `0xFFFF` is followed by a signed 32 bit delta, for gaps longer than the
+//! u16 can hold or a clock that was corrected backwards. Values follow in the bit order
+//! of `MeasurementFields`, only for the channels set in the fields byte:
+//!
+//! - battery: u8 percentage
+//! - lux: u32 integer lux
+//! - temperature: i16 centi-degrees Celsius
+//! - humidity: u16 per-mille relative humidity
+//! - soil pf, tank pf: u16 deci-picofarad
+//! - battery mv: u16 millivolts
+//!
+//! Values outside of the fixed point range saturate. Unlike the TLV layout the compact
+//! layout can't skip unknown channels, a new channel requires a new capability.
+
+use chrono::DateTime;
+
+use crate::{Measurement, MeasurementFields, MeasurementSerieEntry, ProtocolError};
+
+/// Size of the base timestamp
+pub const COMPACT_HEADER_LEN: usize = 8;
+
+/// Delta marking an escaped signed 32 bit delta
+const DELTA_ESCAPE: u16 = u16::MAX;
+
+/// Largest compact measurement: fields and every channel
+pub const MAX_COMPACT_MEASUREMENT_LEN: usize = 1 + 1 + 4 + 2 + 2 + 2 + 2 + 2;
+
+/// Largest compact entry: escaped delta and measurement
+pub const MAX_COMPACT_ENTRY_LEN: usize = 2 + 4 + MAX_COMPACT_MEASUREMENT_LEN;
+
+/// Entry of a station sampling at its interval: plain delta and every channel
+pub const TYPICAL_COMPACT_ENTRY_LEN: usize = 2 + MAX_COMPACT_MEASUREMENT_LEN;
+
+/// Number of leading entries whose compact series fits within `available` bytes
+pub fn fitting(entries: &[MeasurementSerieEntry], available: usize) -> usize {
+    let mut scratch = [0u8; MAX_COMPACT_MEASUREMENT_LEN];
+    let mut len = COMPACT_HEADER_LEN;
+    let mut previous = None;
+
+    entries
+        .iter()
+        .take_while(|entry| {
+            let timestamp = entry.timestamp.and_utc().timestamp();
+            let delta = timestamp - previous.unwrap_or(timestamp);
+            previous = Some(timestamp);
+
+            len += delta_len(delta) + encode_measurement(&entry.measurement, &mut scratch).unwrap_or(MAX_COMPACT_MEASUREMENT_LEN);
+            len <= available
+        })
+        .count()
+}
+
+/// Encode entries into a compact series, returns the number of bytes written
+pub fn encode(entries: &[MeasurementSerieEntry], buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+    let mut writer = Writer { buffer, len: 0 };
+
+    let Some(first) = entries.first() else {
+        return Ok(0);
+    };
+
+    let mut previous = first.timestamp.and_utc().timestamp();
+    writer.put(&previous.to_le_bytes())?;
+
+    for entry in entries {
+        let timestamp = entry.timestamp.and_utc().timestamp();
+        let delta = timestamp - previous;
+        previous = timestamp;
+
+        match u16::try_from(delta) {
+            Ok(delta) if delta != DELTA_ESCAPE => writer.put(&delta.to_le_bytes())?,
+            _ => {
+                let delta = i32::try_from(delta).map_err(|_| ProtocolError::InvalidTimestamp(timestamp))?;
+                writer.put(&DELTA_ESCAPE.to_le_bytes())?;
+                writer.put(&delta.to_le_bytes())?;
+            }
+        }
+
+        writer.put_measurement(&entry.measurement)?;
+    }
+
+    Ok(writer.len)
+}
+
+/// Encode a single measurement, fields byte and values, returns the number of bytes written
+pub fn encode_measurement(measurement: &Measurement, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+    let mut writer = Writer { buffer, len: 0 };
+    writer.put_measurement(measurement)?;
+
+    Ok(writer.len)
+}
+
+/// Decode a single measurement written by [`encode_measurement`]
+pub fn decode_measurement(data: &[u8]) -> Result<Measurement, ProtocolError> {
+    Reader { data }.take_measurement()
+}
+
+/// Decode `count` entries from a compact series
+pub fn decode(data: &[u8], count: u8) -> CompactEntries<'_> {
+    CompactEntries {
+        reader: Reader { data },
+        previous: None,
+        remaining: count,
+    }
+}
+
+/// Size of an encoded delta, escaped when it doesn't fit the u16
+fn delta_len(delta: i64) -> usize {
+    match u16::try_from(delta) {
+        Ok(delta) if delta != DELTA_ESCAPE => 2,
+        _ => 2 + 4,
+    }
+}
+
+/// Scale and round a value, `as` saturates at the bounds of the target type
+fn fixed(value: f32, scale: f32) -> f32 {
+    let scaled = value * scale;
+    if scaled >= 0.0 { scaled + 0.5 } else { scaled - 0.5 }
+}
+
+struct Writer<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl Writer<'_> {
+    fn put(&mut self, bytes: &[u8]) -> Result<(), ProtocolError> {
+        let required = self.len + bytes.len();
+        if required > self.buffer.len() {
+            return Err(ProtocolError::BufferTooSmall { required, available: self.buffer.len() });
+        }
+
+        self.buffer[self.len..required].copy_from_slice(bytes);
+        self.len = required;
+
+        Ok(())
+    }
+
+    fn put_measurement(&mut self, measurement: &Measurement) -> Result<(), ProtocolError> {
+        self.put(&[measurement.fields().bits()])?;
+
+        if let Some(battery) = measurement.battery {
+            self.put(&[battery])?;
+        }
+        if let Some(lux) = measurement.lux {
+            self.put(&(fixed(lux, 1.0) as u32).to_le_bytes())?;
+        }
+        if let Some(temperature) = measurement.temperature {
+            self.put(&(fixed(temperature, 100.0) as i16).to_le_bytes())?;
+        }
+        if let Some(humidity) = measurement.humidity {
+            self.put(&(fixed(humidity, 10.0) as u16).to_le_bytes())?;
+        }
+        if let Some(soil_pf) = measurement.soil_pf {
+            self.put(&(fixed(soil_pf, 10.0) as u16).to_le_bytes())?;
+        }
+        if let Some(tank_pf) = measurement.tank_pf {
+            self.put(&(fixed(tank_pf, 10.0) as u16).to_le_bytes())?;
+        }
+        if let Some(battery_mv) = measurement.battery_mv {
+            self.put(&battery_mv.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], ProtocolError> {
+        if self.data.len() < N {
+            return Err(ProtocolError::Truncated);
+        }
+
+        let (value, rest) = self.data.split_at(N);
+        self.data = rest;
+
+        Ok(value.try_into().unwrap_or([0; N]))
+    }
+
+    fn take_measurement(&mut self) -> Result<Measurement, ProtocolError> {
+        let [bits] = self.take()?;
+        let fields = MeasurementFields::from_bits(bits).ok_or(ProtocolError::UnknownType(bits))?;
+
+        let mut measurement = Measurement::EMPTY;
+
+        if fields.contains(MeasurementFields::BATTERY) {
+            let [battery] = self.take()?;
+            measurement.battery = Some(battery);
+        }
+        if fields.contains(MeasurementFields::LUX) {
+            measurement.lux = Some(u32::from_le_bytes(self.take()?) as f32);
+        }
+        if fields.contains(MeasurementFields::TEMPERATURE) {
+            measurement.temperature = Some(i16::from_le_bytes(self.take()?) as f32 / 100.0);
+        }
+        if fields.contains(MeasurementFields::HUMIDITY) {
+            measurement.humidity = Some(u16::from_le_bytes(self.take()?) as f32 / 10.0);
+        }
+        if fields.contains(MeasurementFields::SOIL_PF) {
+            measurement.soil_pf = Some(u16::from_le_bytes(self.take()?) as f32 / 10.0);
+        }
+        if fields.contains(MeasurementFields::TANK_PF) {
+            measurement.tank_pf = Some(u16::from_le_bytes(self.take()?) as f32 / 10.0);
+        }
+        if fields.contains(MeasurementFields::BATTERY_MV) {
+            measurement.battery_mv = Some(u16::from_le_bytes(self.take()?));
+        }
+
+        Ok(measurement)
+    }
+}
+
+/// Iterator over the entries of a compact series, stops after the first malformed entry
+pub struct CompactEntries<'a> {
+    reader: Reader<'a>,
+    previous: Option<i64>,
+    remaining: u8,
+}
+
+impl CompactEntries<'_> {
+    fn next_entry(&mut self) -> Result<MeasurementSerieEntry, ProtocolError> {
+        let previous = match self.previous {
+            Some(previous) => previous,
+            None => i64::from_le_bytes(self.reader.take()?),
+        };
+
+        let delta = match u16::from_le_bytes(self.reader.take()?) {
+            DELTA_ESCAPE => i32::from_le_bytes(self.reader.take()?) as i64,
+            delta => delta as i64,
+        };
+
+        let timestamp = previous + delta;
+        self.previous = Some(timestamp);
+
+        let measurement = self.reader.take_measurement()?;
+
+        let timestamp = DateTime::from_timestamp(timestamp, 0)
+            .ok_or(ProtocolError::InvalidTimestamp(timestamp))?
+            .naive_utc();
+
+        Ok(MeasurementSerieEntry { timestamp, measurement })
+    }
+}
+
+impl Iterator for CompactEntries<'_> {
+    type Item = Result<MeasurementSerieEntry, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let entry = self.next_entry();
+        self.remaining = if entry.is_ok() { self.remaining - 1 } else { 0 };
+
+        Some(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(timestamp: i64, temperature: f32) -> MeasurementSerieEntry {
+        MeasurementSerieEntry {
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap().naive_utc(),
+            measurement: Measurement {
+                battery: None,
+                lux: Some(1234.0),
+                temperature: Some(temperature),
+                humidity: Some(48.3),
+                soil_pf: Some(321.4),
+                tank_pf: Some(87.5),
+                battery_mv: Some(3912),
+            },
+        }
+    }
+
+    fn roundtrip(entries: &[MeasurementSerieEntry]) -> Vec<MeasurementSerieEntry> {
+        let mut buffer = [0u8; 512];
+        let len = encode(entries, &mut buffer).unwrap();
+        decode(&buffer[..len], entries.len() as u8).map(|e| e.unwrap()).collect()
+    }
+
+    #[test]
+    fn test_compact_roundtrip() {
+        let entries = [entry(1_700_000_000, 21.57), entry(1_700_000_010, -4.25), entry(1_700_000_610, 22.0)];
+        let decoded = roundtrip(&entries);
+
+        assert_eq!(decoded.len(), 3);
+        for (expected, actual) in entries.iter().zip(decoded.iter()) {
+            assert_eq!(expected.timestamp, actual.timestamp);
+            assert_eq!(expected.measurement.lux, actual.measurement.lux);
+            assert!((expected.measurement.temperature.unwrap() - actual.measurement.temperature.unwrap()).abs() < 0.006);
+            assert!((expected.measurement.humidity.unwrap() - actual.measurement.humidity.unwrap()).abs() < 0.06);
+            assert!((expected.measurement.soil_pf.unwrap() - actual.measurement.soil_pf.unwrap()).abs() < 0.06);
+            assert!((expected.measurement.tank_pf.unwrap() - actual.measurement.tank_pf.unwrap()).abs() < 0.06);
+            assert_eq!(expected.measurement.battery_mv, actual.measurement.battery_mv);
+            assert_eq!(actual.measurement.battery, None);
+        }
+    }
+
+    #[test]
+    fn test_compact_entry_size() {
+        let entries = [entry(1_700_000_000, 20.0), entry(1_700_000_010, 20.0)];
+        let mut buffer = [0u8; 64];
+        let len = encode(&entries, &mut buffer).unwrap();
+
+        // Delta, fields, lux, four 16 bit channels and battery millivolts
+        assert_eq!(len, COMPACT_HEADER_LEN + 2 * (2 + 1 + 4 + 2 * 5));
+    }
+
+    #[test]
+    fn test_compact_fitting_counts_encoded_length() {
+        let entries = [entry(1_700_000_000, 20.0), entry(1_700_000_010, 20.0), entry(1_700_100_000, 20.0)];
+        let mut buffer = [0u8; 128];
+        let len = encode(&entries, &mut buffer).unwrap();
+
+        assert_eq!(fitting(&entries, len), 3);
+        // The escaped delta of the last entry doesn't fit one byte short
+        assert_eq!(fitting(&entries, len - 1), 2);
+        assert_eq!(fitting(&entries, COMPACT_HEADER_LEN + 2 * 17), 2);
+        assert_eq!(fitting(&entries, COMPACT_HEADER_LEN), 0);
+        assert_eq!(fitting(&[], 0), 0);
+    }
+
+    #[test]
+    fn test_compact_absent_channels() {
+        let mut partial = entry(1_700_000_000, 20.0);
+        partial.measurement.soil_pf = None;
+        partial.measurement.lux = None;
+        partial.measurement.battery = Some(55);
+
+        let decoded = roundtrip(&[partial]);
+        assert_eq!(decoded[0].measurement.fields(), partial.measurement.fields());
+        assert_eq!(decoded[0].measurement.battery, Some(55));
+        assert_eq!(decoded[0].measurement.soil_pf, None);
+        assert_eq!(decoded[0].measurement.lux, None);
+    }
+
+    #[test]
+    fn test_compact_escaped_deltas() {
+        // A gap longer than a u16 and a clock corrected backwards
+        let entries = [entry(1_700_000_000, 20.0), entry(1_700_100_000, 20.0), entry(1_700_099_000, 20.0)];
+        let decoded = roundtrip(&entries);
+
+        let timestamps: Vec<_> = decoded.iter().map(|e| e.timestamp).collect();
+        let expected: Vec<_> = entries.iter().map(|e| e.timestamp).collect();
+        assert_eq!(timestamps, expected);
+    }
+
+    #[test]
+    fn test_compact_saturates_out_of_range_values() {
+        let mut hot = entry(1_700_000_000, 400.0);
+        hot.measurement.lux = Some(-5.0);
+        hot.measurement.soil_pf = Some(10_000.0);
+
+        let decoded = roundtrip(&[hot]);
+        assert_eq!(decoded[0].measurement.temperature, Some(i16::MAX as f32 / 100.0));
+        assert_eq!(decoded[0].measurement.lux, Some(0.0));
+        assert_eq!(decoded[0].measurement.soil_pf, Some(u16::MAX as f32 / 10.0));
+    }
+
+    #[test]
+    fn test_compact_decode_errors() {
+        let entries = [entry(1_700_000_000, 20.0)];
+        let mut buffer = [0u8; 64];
+        let len = encode(&entries, &mut buffer).unwrap();
+
+        let decoded: Vec<_> = decode(&buffer[..len - 1], 1).collect();
+        assert_eq!(decoded, [Err(ProtocolError::Truncated)]);
+
+        // Asking for more entries than encoded stops at the first failure
+        let decoded: Vec<_> = decode(&buffer[..len], 3).collect();
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[1], Err(ProtocolError::Truncated));
+
+        // Unknown channel bits can't be skipped
+        buffer[COMPACT_HEADER_LEN + 2] = 0x80;
+        let decoded: Vec<_> = decode(&buffer[..len], 1).collect();
+        assert_eq!(decoded, [Err(ProtocolError::UnknownType(0x80))]);
+    }
+
+    #[test]
+    fn test_compact_encode_rejects_small_buffer() {
+        let entries = [entry(1_700_000_000, 20.0)];
+        let mut buffer = [0u8; COMPACT_HEADER_LEN + 4];
+
+        assert!(matches!(encode(&entries, &mut buffer), Err(ProtocolError::BufferTooSmall { .. })));
+        assert_eq!(encode(&[], &mut buffer), Ok(0));
+    }
+}
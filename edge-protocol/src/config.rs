@@ -0,0 +1,181 @@
+//! Remote configuration of a peripheral.
+//!
+//! The central writes the configuration characteristic during a sync, the value is a
+//! TLV record list so settings can be added without breaking older peripherals:
+//!
+//! - 1: sampling period, u32 seconds
+//! - 2: flush size, u8 number of buckets buffered before flushing
+//! - 3: maximum deviation, a TLV encoded [`Measurement`]
+//!
+//! Settings that are absent keep their default.
+//!
+//! A peripheral with a key only applies a configuration signed with its [`downlink_key`],
+//! the counter of the trailer is the unix time of the write and has to be newer than the
+//! one of the last configuration applied.
+
+use core::time::Duration;
+
+use crate::auth::{self, downlink_key, AuthKey, AUTH_TRAILER_LEN};
+use crate::frame::MAX_COMPACT_FRAME_ENTRIES;
+use crate::{Measurement, ProtocolError, TlvReader, TlvWriter, Verifier};
+
+/// Shortest sampling period a peripheral accepts
+pub const MIN_SAMPLING_PERIOD_SECS: u32 = 1;
+
+/// Longest sampling period a peripheral accepts, a day
+pub const MAX_SAMPLING_PERIOD_SECS: u32 = 24 * 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PeripheralConfig {
+    /// Time the peripheral sleeps between two samples
+    pub sampling_period_secs: u32,
+    /// Number of buckets buffered before the peripheral flushes, at most a compact frame
+    pub flush_size: u8,
+    /// Deviation from the current bucket that starts a new one, see [`Measurement::MAX_DEVIATION`]
+    pub max_deviation: Measurement,
+}
+
+impl Default for PeripheralConfig {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+impl PeripheralConfig {
+    pub const DEFAULT: Self = Self {
+        sampling_period_secs: 10,
+        flush_size: MAX_COMPACT_FRAME_ENTRIES as u8,
+        max_deviation: Measurement::MAX_DEVIATION,
+    };
+
+    /// Sampling period (2 + 4), flush size (2 + 1) and maximum deviation
+    pub const MAX_TLV_LEN: usize = 6 + 3 + 2 + Measurement::MAX_TLV_LEN;
+
+    /// Configuration followed by the authentication trailer
+    pub const MAX_SIGNED_TLV_LEN: usize = Self::MAX_TLV_LEN + AUTH_TRAILER_LEN;
+
+    pub fn sampling_period(&self) -> Duration {
+        Duration::from_secs(self.sampling_period_secs as u64)
+    }
+
+    pub fn validate(&self) -> Result<(), ProtocolError> {
+        if !(MIN_SAMPLING_PERIOD_SECS..=MAX_SAMPLING_PERIOD_SECS).contains(&self.sampling_period_secs) {
+            return Err(ProtocolError::OutOfRange("sampling period"));
+        }
+        if self.flush_size == 0 || self.flush_size as usize > MAX_COMPACT_FRAME_ENTRIES {
+            return Err(ProtocolError::OutOfRange("flush size"));
+        }
+
+        Ok(())
+    }
+
+    /// Encode the configuration to TLV format, returns the number of bytes written
+    pub fn to_tlv(&self, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let mut max_deviation = [0u8; Measurement::MAX_TLV_LEN];
+        let max_deviation_len = self.max_deviation.to_tlv(&mut max_deviation)?;
+
+        let mut writer = TlvWriter::new(buffer);
+        writer.push(1, &self.sampling_period_secs.to_le_bytes())?;
+        writer.push(2, &[self.flush_size])?;
+        writer.push(3, &max_deviation[..max_deviation_len])?;
+
+        Ok(writer.len())
+    }
+
+    /// Decode a configuration from TLV format, unknown types are skipped and the result is validated
+    pub fn from_tlv(data: &[u8]) -> Result<Self, ProtocolError> {
+        let mut config = Self::DEFAULT;
+
+        for tlv in TlvReader::new(data) {
+            let tlv = tlv?;
+
+            match tlv.tlv_type {
+                1 => config.sampling_period_secs = u32::from_le_bytes(tlv.value_array("sampling period")?),
+                2 => [config.flush_size] = tlv.value_array("flush size")?,
+                3 => config.max_deviation = Measurement::from_tlv(tlv.value)?,
+                _ => {}
+            }
+        }
+
+        config.validate()?;
+
+        Ok(config)
+    }
+
+    /// Encode the configuration signed with the downlink key of the station
+    pub fn to_signed_tlv(&self, station_key: &AuthKey, counter: u32, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let len = self.to_tlv(buffer)?;
+        auth::sign(&downlink_key(station_key), counter, buffer, len)
+    }
+
+    /// Decode a signed configuration, the verifier holds the downlink key and the counter of the last one applied
+    pub fn from_signed_tlv(data: &[u8], verifier: &mut Verifier) -> Result<Self, ProtocolError> {
+        Self::from_tlv(verifier.verify(data)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_config_roundtrip() {
+        let config = PeripheralConfig {
+            sampling_period_secs: 300,
+            flush_size: 4,
+            max_deviation: Measurement { lux: None, temperature: Some(0.5), ..Measurement::MAX_DEVIATION },
+        };
+
+        let mut buffer = [0u8; PeripheralConfig::MAX_TLV_LEN];
+        let len = config.to_tlv(&mut buffer).unwrap();
+
+        assert_eq!(PeripheralConfig::from_tlv(&buffer[..len]), Ok(config));
+    }
+
+    #[test]
+    fn test_config_defaults_absent_settings() {
+        // Only the sampling period, followed by a type a newer central might send
+        let data = [1, 4, 60, 0, 0, 0, 9, 1, 0];
+        let config = PeripheralConfig::from_tlv(&data).unwrap();
+
+        assert_eq!(config.sampling_period_secs, 60);
+        assert_eq!(config.flush_size, PeripheralConfig::DEFAULT.flush_size);
+        assert_eq!(config.max_deviation, Measurement::MAX_DEVIATION);
+    }
+
+    #[test]
+    fn test_config_rejects_out_of_range() {
+        assert_eq!(PeripheralConfig::from_tlv(&[2, 1, 0]), Err(ProtocolError::OutOfRange("flush size")));
+        assert_eq!(PeripheralConfig::from_tlv(&[2, 1, MAX_COMPACT_FRAME_ENTRIES as u8 + 1]), Err(ProtocolError::OutOfRange("flush size")));
+        assert_eq!(PeripheralConfig::from_tlv(&[1, 4, 0, 0, 0, 0]), Err(ProtocolError::OutOfRange("sampling period")));
+    }
+
+    #[test]
+    fn test_config_rejects_sampling_period_over_a_day() {
+        let day = PeripheralConfig { sampling_period_secs: MAX_SAMPLING_PERIOD_SECS, ..PeripheralConfig::DEFAULT };
+        assert_eq!(day.validate(), Ok(()));
+
+        let mut buffer = [0u8; PeripheralConfig::MAX_TLV_LEN];
+        let len = PeripheralConfig { sampling_period_secs: MAX_SAMPLING_PERIOD_SECS + 1, ..day }.to_tlv(&mut buffer).unwrap();
+        assert_eq!(PeripheralConfig::from_tlv(&buffer[..len]), Err(ProtocolError::OutOfRange("sampling period")));
+        assert_eq!(PeripheralConfig::from_tlv(&[1, 4, 0xff, 0xff, 0xff, 0xff]), Err(ProtocolError::OutOfRange("sampling period")));
+    }
+
+    #[test]
+    fn test_signed_config() {
+        const KEY: AuthKey = [0x42; 32];
+        let config = PeripheralConfig { sampling_period_secs: 300, ..PeripheralConfig::DEFAULT };
+
+        let mut buffer = [0u8; PeripheralConfig::MAX_SIGNED_TLV_LEN];
+        let len = config.to_signed_tlv(&KEY, 1_700_000_000, &mut buffer).unwrap();
+
+        let mut verifier = Verifier::new(downlink_key(&KEY), 1_600_000_000);
+        assert_eq!(PeripheralConfig::from_signed_tlv(&buffer[..len], &mut verifier), Ok(config));
+
+        // Written again, or unsigned, or signed with the key of the frames of the station
+        assert!(PeripheralConfig::from_signed_tlv(&buffer[..len], &mut verifier).is_err());
+        assert!(PeripheralConfig::from_signed_tlv(&buffer[..len - AUTH_TRAILER_LEN], &mut Verifier::new(downlink_key(&KEY), 0)).is_err());
+        let len = auth::sign(&KEY, 1_800_000_000, &mut buffer, len - AUTH_TRAILER_LEN).unwrap();
+        assert_eq!(PeripheralConfig::from_signed_tlv(&buffer[..len], &mut verifier), Err(ProtocolError::InvalidTag));
+    }
+}
@@ -0,0 +1,64 @@
+use core::fmt;
+
+/// Errors raised while encoding or decoding protocol data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProtocolError {
+    /// Input ended before a complete header or value could be read
+    Truncated,
+    /// A field has a different length than its type prescribes
+    InvalidLength {
+        field: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+    /// A type or opcode that must be understood is not known
+    UnknownType(u8),
+    /// A unix timestamp outside of the range chrono can represent
+    InvalidTimestamp(i64),
+    /// Date or time fields that don't form a valid date time
+    InvalidDate,
+    /// A field required to construct the value was not present
+    MissingField(&'static str),
+    /// A frame with a protocol version this crate doesn't speak
+    UnsupportedVersion(u8),
+    /// More entries than fit in a frame
+    TooManyEntries(usize),
+    /// The output buffer can't hold the encoded value
+    BufferTooSmall { required: usize, available: usize },
+    /// The authentication tag doesn't match the frame
+    InvalidTag,
+    /// A frame counter that isn't newer than the last accepted one
+    Replayed { counter: u32, last: u32 },
+    /// A setting outside of the range the peripheral can apply
+    OutOfRange(&'static str),
+    /// A write that has to be signed once the peripheral has a key isn't
+    Unauthenticated,
+}
+
+impl fmt::Display for ProtocolError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProtocolError::Truncated => write!(f, "truncated input"),
+            ProtocolError::InvalidLength { field, expected, actual } => {
+                write!(f, "invalid {} length: expected {} bytes, got {}", field, expected, actual)
+            }
+            ProtocolError::UnknownType(tlv_type) => write!(f, "unknown type {}", tlv_type),
+            ProtocolError::InvalidTimestamp(timestamp) => write!(f, "invalid timestamp {}", timestamp),
+            ProtocolError::InvalidDate => write!(f, "invalid date fields"),
+            ProtocolError::MissingField(field) => write!(f, "missing {}", field),
+            ProtocolError::UnsupportedVersion(version) => write!(f, "unsupported protocol version {}", version),
+            ProtocolError::TooManyEntries(count) => write!(f, "too many entries: {}", count),
+            ProtocolError::BufferTooSmall { required, available } => {
+                write!(f, "buffer too small: requires {} bytes, has {}", required, available)
+            }
+            ProtocolError::InvalidTag => write!(f, "invalid authentication tag"),
+            ProtocolError::Replayed { counter, last } => {
+                write!(f, "replayed frame: counter {} is not after {}", counter, last)
+            }
+            ProtocolError::OutOfRange(field) => write!(f, "{} out of range", field),
+            ProtocolError::Unauthenticated => write!(f, "unsigned write"),
+        }
+    }
+}
+
+impl core::error::Error for ProtocolError {}
@@ -0,0 +1,88 @@
+//! Characteristic values of the Bluetooth SIG Environmental Sensing Service.
+//!
+//! Generic BLE tools read these without knowing the custom measurement frame:
+//!
+//! - temperature (0x2A6E): sint16, 0.01 °C
+//! - humidity (0x2A6F): uint16, 0.01 %
+//! - illuminance (0x2AFB): uint24, 0.01 lux
+//!
+//! All values are little endian, a channel the peripheral doesn't measure is served as
+//! the "value is not known" code of its characteristic.
+
+pub const TEMPERATURE_NOT_KNOWN: i16 = i16::MIN;
+pub const HUMIDITY_NOT_KNOWN: u16 = u16::MAX;
+pub const ILLUMINANCE_NOT_KNOWN: u32 = 0xFF_FFFF;
+
+pub const TEMPERATURE_LEN: usize = 2;
+pub const HUMIDITY_LEN: usize = 2;
+pub const ILLUMINANCE_LEN: usize = 3;
+
+/// Scale a value to hundredths, rounded and clamped to the range of the characteristic
+fn hundredths(value: f32, min: f32, max: f32) -> f32 {
+    // `f32::round` isn't available without std
+    let scaled = value * 100.0;
+    let rounded = if scaled < 0.0 { scaled - 0.5 } else { scaled + 0.5 };
+    (rounded as i64 as f32).clamp(min, max)
+}
+
+/// Encode a temperature in °C
+pub fn temperature(celsius: Option<f32>) -> [u8; TEMPERATURE_LEN] {
+    let value = match celsius {
+        // The lowest value encodes "not known", absolute zero is well above it
+        Some(celsius) if !celsius.is_nan() => hundredths(celsius, -27315.0, i16::MAX as f32) as i16,
+        _ => TEMPERATURE_NOT_KNOWN,
+    };
+
+    value.to_le_bytes()
+}
+
+/// Encode a relative humidity in %
+pub fn humidity(percentage: Option<f32>) -> [u8; HUMIDITY_LEN] {
+    let value = match percentage {
+        Some(percentage) if !percentage.is_nan() => hundredths(percentage, 0.0, 10000.0) as u16,
+        _ => HUMIDITY_NOT_KNOWN,
+    };
+
+    value.to_le_bytes()
+}
+
+/// Encode an illuminance in lux
+pub fn illuminance(lux: Option<f32>) -> [u8; ILLUMINANCE_LEN] {
+    let value = match lux {
+        Some(lux) if !lux.is_nan() => hundredths(lux, 0.0, (ILLUMINANCE_NOT_KNOWN - 1) as f32) as u32,
+        _ => ILLUMINANCE_NOT_KNOWN,
+    };
+
+    let [b0, b1, b2, _] = value.to_le_bytes();
+    [b0, b1, b2]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_temperature() {
+        assert_eq!(temperature(Some(21.37)), 2137i16.to_le_bytes());
+        assert_eq!(temperature(Some(-5.006)), (-501i16).to_le_bytes());
+        assert_eq!(temperature(Some(-300.0)), (-27315i16).to_le_bytes());
+        assert_eq!(temperature(Some(400.0)), i16::MAX.to_le_bytes());
+        assert_eq!(temperature(None), [0x00, 0x80]);
+        assert_eq!(temperature(Some(f32::NAN)), [0x00, 0x80]);
+    }
+
+    #[test]
+    fn test_humidity() {
+        assert_eq!(humidity(Some(55.5)), 5550u16.to_le_bytes());
+        assert_eq!(humidity(Some(101.0)), 10000u16.to_le_bytes());
+        assert_eq!(humidity(Some(-1.0)), [0, 0]);
+        assert_eq!(humidity(None), [0xFF, 0xFF]);
+    }
+
+    #[test]
+    fn test_illuminance() {
+        assert_eq!(illuminance(Some(1234.56)), [0x40, 0xE2, 0x01]);
+        assert_eq!(illuminance(Some(1e9)), [0xFE, 0xFF, 0xFF]);
+        assert_eq!(illuminance(None), [0xFF, 0xFF, 0xFF]);
+    }
+}
@@ -0,0 +1,567 @@
+//! Versioned measurement frame served by the measurement characteristic.
+//!
+//! A frame starts with a header so a central can tell which protocol version
+//! and which capabilities a peripheral speaks, followed by the entries:
+//!
+//! | magic (1) | version (1) | capabilities (2, LE) | count (1) | entries ... |
+//!
+//! Every entry is prefixed with its own length so decoders never have to
+//! guess the entry layout. Peripherals running v1 firmware serve a bare
+//! concatenation of fixed size entries without a header, which is recognised
+//! by the absence of the magic byte.
+
+use bitflags::bitflags;
+
+use crate::compact::{self, CompactEntries, COMPACT_HEADER_LEN, TYPICAL_COMPACT_ENTRY_LEN};
+use crate::{MeasurementSerieEntry, ProtocolError};
+
+/// First byte of every versioned frame ('M'), a legacy frame starts with a TLV type instead
+pub const FRAME_MAGIC: u8 = 0x4D;
+
+/// Protocol version spoken by this crate
+pub const PROTOCOL_VERSION: u8 = 2;
+
+/// Version assigned to frames without a header
+pub const LEGACY_PROTOCOL_VERSION: u8 = 1;
+
+pub const FRAME_HEADER_LEN: usize = 5;
+
+/// Maximum number of entries in a single frame.
+///
+/// Peripherals predating the tank channel served six, six full entries don't fit a 255 byte read
+/// anymore. Decoding doesn't depend on it, the six entry frames of those peripherals still decode.
+pub const MAX_FRAME_ENTRIES: usize = 5;
+
+/// Size of an entry in a v1 frame, every channel is always present
+pub const LEGACY_ENTRY_LEN: usize = 39;
+
+/// Largest frame a peripheral serves, fits within a single 255 byte MTU read
+pub const MAX_FRAME_LEN: usize = FRAME_HEADER_LEN + MAX_FRAME_ENTRIES * (1 + MeasurementSerieEntry::MAX_TLV_LEN);
+
+const _: () = assert!(MAX_FRAME_LEN <= 255);
+const _: () = assert!(FRAME_HEADER_LEN + (MAX_FRAME_ENTRIES + 1) * (1 + MeasurementSerieEntry::MAX_TLV_LEN) > 255);
+
+/// Maximum number of entries in a compact frame, as many typical entries as fit.
+///
+/// Entries vary in length, a page takes as many as fit [`MAX_FRAME_LEN`], see [`crate::history::page_len`].
+pub const MAX_COMPACT_FRAME_ENTRIES: usize = (MAX_FRAME_LEN - FRAME_HEADER_LEN - COMPACT_HEADER_LEN) / TYPICAL_COMPACT_ENTRY_LEN;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Capabilities(u16);
+
+bitflags! {
+    impl Capabilities: u16 {
+        /// Peripheral accepts a current time write to correct its clock
+        const TIME_SYNC              = 0x0001;
+        /// Peripheral serves a buffered measurement series
+        const MEASUREMENT_SERIES     = 0x0002;
+        /// Entries are delta encoded in fixed point, see [`crate::compact`]
+        const COMPACT_SERIES         = 0x0004;
+        /// Peripheral serves its history in pages and drops entries only once acknowledged, see [`crate::history`]
+        const PAGED_HISTORY          = 0x0008;
+        /// Frames carry a counter and truncated HMAC trailer, see [`crate::auth`]
+        const AUTHENTICATED          = 0x0010;
+    }
+}
+
+impl Capabilities {
+    /// Capabilities implied by a peripheral that predates the versioned frame
+    pub const LEGACY: Self = Self::TIME_SYNC.union(Self::MEASUREMENT_SERIES);
+
+    /// Capabilities understood by this crate
+    pub const SUPPORTED: Self = Self::all();
+}
+
+/// Version and capabilities exchanged over the protocol characteristic.
+///
+/// The peripheral serves its own hello on read, the central writes its hello back
+/// and both sides continue with the negotiated version and capabilities.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProtocolHello {
+    pub version: u8,
+    pub capabilities: Capabilities,
+}
+
+impl ProtocolHello {
+    pub const LEN: usize = 3;
+
+    /// Hello describing this crate
+    pub const fn current() -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::SUPPORTED,
+        }
+    }
+
+    /// Hello implied by a peripheral without a protocol characteristic
+    pub const fn legacy() -> Self {
+        Self {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capabilities::LEGACY,
+        }
+    }
+
+    /// Highest common version and the capabilities both sides support
+    pub fn negotiate(&self, other: &ProtocolHello) -> ProtocolHello {
+        ProtocolHello {
+            version: self.version.min(other.version),
+            capabilities: self.capabilities & other.capabilities,
+        }
+    }
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        let capabilities = self.capabilities.bits().to_le_bytes();
+        [self.version, capabilities[0], capabilities[1]]
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() != Self::LEN {
+            return Err(ProtocolError::InvalidLength {
+                field: "protocol hello",
+                expected: Self::LEN,
+                actual: bytes.len(),
+            });
+        }
+
+        Ok(Self {
+            version: bytes[0],
+            capabilities: Capabilities::from_bits_truncate(u16::from_le_bytes([bytes[1], bytes[2]])),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub version: u8,
+    pub capabilities: Capabilities,
+    pub count: u8,
+}
+
+impl FrameHeader {
+    pub fn to_bytes(&self) -> [u8; FRAME_HEADER_LEN] {
+        let capabilities = self.capabilities.bits().to_le_bytes();
+        [FRAME_MAGIC, self.version, capabilities[0], capabilities[1], self.count]
+    }
+}
+
+/// A decoded frame header together with its undecoded entries
+#[derive(Debug, Clone, Copy)]
+pub struct Frame<'a> {
+    pub header: FrameHeader,
+    body: &'a [u8],
+}
+
+impl<'a> Frame<'a> {
+    /// Encode entries into a versioned frame, returns the number of bytes written.
+    ///
+    /// Entries use the compact layout when the capabilities contain `COMPACT_SERIES`.
+    pub fn encode(capabilities: Capabilities, entries: &[MeasurementSerieEntry], buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let compact = capabilities.contains(Capabilities::COMPACT_SERIES);
+        let max_entries = if compact { MAX_COMPACT_FRAME_ENTRIES } else { MAX_FRAME_ENTRIES };
+
+        if entries.len() > max_entries {
+            return Err(ProtocolError::TooManyEntries(entries.len()));
+        }
+
+        if buffer.len() < FRAME_HEADER_LEN {
+            return Err(ProtocolError::BufferTooSmall { required: FRAME_HEADER_LEN, available: buffer.len() });
+        }
+
+        let header = FrameHeader {
+            version: PROTOCOL_VERSION,
+            capabilities,
+            count: entries.len() as u8,
+        };
+        buffer[..FRAME_HEADER_LEN].copy_from_slice(&header.to_bytes());
+
+        if compact {
+            let len = compact::encode(entries, &mut buffer[FRAME_HEADER_LEN..]).map_err(|e| match e {
+                ProtocolError::BufferTooSmall { required, available } => ProtocolError::BufferTooSmall {
+                    required: FRAME_HEADER_LEN + required,
+                    available: FRAME_HEADER_LEN + available,
+                },
+                e => e,
+            })?;
+
+            return Ok(FRAME_HEADER_LEN + len);
+        }
+
+        let mut index = FRAME_HEADER_LEN;
+        for entry in entries {
+            let mut tlv = [0u8; MeasurementSerieEntry::MAX_TLV_LEN];
+            let len = entry.to_tlv(&mut tlv)?;
+
+            let required = index + 1 + len;
+            if buffer.len() < required {
+                return Err(ProtocolError::BufferTooSmall { required, available: buffer.len() });
+            }
+
+            buffer[index] = len as u8;
+            buffer[index + 1..required].copy_from_slice(&tlv[..len]);
+            index = required;
+        }
+
+        Ok(index)
+    }
+
+    /// Decode a frame, data without a header is treated as a v1 frame
+    pub fn decode(data: &'a [u8]) -> Result<Self, ProtocolError> {
+        if data.first() != Some(&FRAME_MAGIC) {
+            return Self::decode_legacy(data);
+        }
+
+        if data.len() < FRAME_HEADER_LEN {
+            return Err(ProtocolError::Truncated);
+        }
+
+        let version = data[1];
+        if version <= LEGACY_PROTOCOL_VERSION || version > PROTOCOL_VERSION {
+            return Err(ProtocolError::UnsupportedVersion(version));
+        }
+
+        let header = FrameHeader {
+            version,
+            capabilities: Capabilities::from_bits_truncate(u16::from_le_bytes([data[2], data[3]])),
+            count: data[4],
+        };
+
+        Ok(Frame {
+            header,
+            body: &data[FRAME_HEADER_LEN..],
+        })
+    }
+
+    fn decode_legacy(data: &'a [u8]) -> Result<Self, ProtocolError> {
+        if !data.len().is_multiple_of(LEGACY_ENTRY_LEN) {
+            return Err(ProtocolError::InvalidLength {
+                field: "legacy frame",
+                expected: data.len().next_multiple_of(LEGACY_ENTRY_LEN),
+                actual: data.len(),
+            });
+        }
+
+        // v1 peripherals pad unused slots with zeroes
+        let count = data
+            .chunks(LEGACY_ENTRY_LEN)
+            .take_while(|chunk| chunk.iter().any(|b| *b != 0))
+            .count();
+
+        let header = FrameHeader {
+            version: LEGACY_PROTOCOL_VERSION,
+            capabilities: Capabilities::LEGACY,
+            count: count as u8,
+        };
+
+        Ok(Frame {
+            header,
+            body: &data[..count * LEGACY_ENTRY_LEN],
+        })
+    }
+
+    /// Iterate over the entries, each decoded independently
+    pub fn entries(&self) -> FrameEntries<'a> {
+        if self.header.capabilities.contains(Capabilities::COMPACT_SERIES) {
+            return FrameEntries::Compact(compact::decode(self.body, self.header.count));
+        }
+
+        FrameEntries::Tlv(TlvEntries {
+            legacy: self.header.version == LEGACY_PROTOCOL_VERSION,
+            remaining: self.header.count,
+            body: self.body,
+        })
+    }
+}
+
+/// Entries of a frame in the layout announced by its header
+pub enum FrameEntries<'a> {
+    Tlv(TlvEntries<'a>),
+    Compact(CompactEntries<'a>),
+}
+
+impl<'a> Iterator for FrameEntries<'a> {
+    type Item = Result<MeasurementSerieEntry, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self {
+            FrameEntries::Tlv(entries) => entries.next(),
+            FrameEntries::Compact(entries) => entries.next(),
+        }
+    }
+}
+
+/// Length prefixed TLV entries, or fixed size entries in a v1 frame
+pub struct TlvEntries<'a> {
+    legacy: bool,
+    remaining: u8,
+    body: &'a [u8],
+}
+
+impl<'a> Iterator for TlvEntries<'a> {
+    type Item = Result<MeasurementSerieEntry, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+
+        let len = if self.legacy {
+            LEGACY_ENTRY_LEN
+        } else {
+            match self.body.split_first() {
+                Some((len, rest)) => {
+                    self.body = rest;
+                    *len as usize
+                }
+                None => {
+                    self.remaining = 0;
+                    return Some(Err(ProtocolError::Truncated));
+                }
+            }
+        };
+
+        if len > self.body.len() {
+            self.remaining = 0;
+            return Some(Err(ProtocolError::Truncated));
+        }
+
+        let (entry, rest) = self.body.split_at(len);
+        self.body = rest;
+
+        Some(MeasurementSerieEntry::from_tlv(entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Measurement, TlvWriter};
+    use chrono::DateTime;
+
+    /// Capabilities of a peripheral serving length prefixed TLV entries
+    const TLV_SERIES: Capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+
+    fn entry(timestamp: i64) -> MeasurementSerieEntry {
+        MeasurementSerieEntry {
+            timestamp: DateTime::from_timestamp(timestamp, 0).unwrap().naive_utc(),
+            measurement: Measurement {
+                battery: Some(80),
+                lux: Some(250.0),
+                temperature: Some(21.5),
+                humidity: Some(48.0),
+                soil_pf: Some(320.0),
+                tank_pf: Some(25.0),
+                battery_mv: Some(3900),
+            },
+        }
+    }
+
+    /// Entry as written by v1 firmware: every channel up to soil pf, no status
+    fn legacy_entry(timestamp: i64, buffer: &mut [u8]) {
+        let mut measurement = [0u8; LEGACY_ENTRY_LEN - 12];
+        let mut writer = TlvWriter::new(&mut measurement);
+        writer.push(1, &[80]).unwrap();
+        for (tlv_type, value) in [(2u8, 250.0f32), (3, 21.5), (4, 48.0), (5, 320.0)] {
+            writer.push(tlv_type, &value.to_le_bytes()).unwrap();
+        }
+
+        let mut writer = TlvWriter::new(buffer);
+        writer.push(1, &timestamp.to_le_bytes()).unwrap();
+        writer.push(2, &measurement).unwrap();
+        assert_eq!(writer.len(), LEGACY_ENTRY_LEN);
+    }
+
+    #[test]
+    fn test_frame_encode_decode_roundtrip() {
+        let entries = [entry(1_700_000_000), entry(1_700_000_600), entry(1_700_001_200)];
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+
+        let len = Frame::encode(TLV_SERIES, &entries, &mut buffer).unwrap();
+        assert_eq!(len, FRAME_HEADER_LEN + 3 * (1 + MeasurementSerieEntry::MAX_TLV_LEN));
+
+        let frame = Frame::decode(&buffer[..len]).unwrap();
+        assert_eq!(frame.header.version, PROTOCOL_VERSION);
+        assert_eq!(frame.header.capabilities, TLV_SERIES);
+        assert_eq!(frame.header.count, 3);
+
+        let decoded: Vec<_> = frame.entries().map(|e| e.unwrap()).collect();
+        assert_eq!(decoded.len(), 3);
+        for (expected, actual) in entries.iter().zip(decoded.iter()) {
+            assert_eq!(expected.timestamp, actual.timestamp);
+            assert_eq!(expected.measurement.soil_pf, actual.measurement.soil_pf);
+        }
+    }
+
+    #[test]
+    fn test_frame_roundtrip_with_absent_channels() {
+        let mut partial = entry(1_700_000_000);
+        partial.measurement.soil_pf = None;
+        partial.measurement.lux = None;
+        let entries = [partial, entry(1_700_000_600)];
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+
+        let len = Frame::encode(TLV_SERIES, &entries, &mut buffer).unwrap();
+        let frame = Frame::decode(&buffer[..len]).unwrap();
+
+        let decoded: Vec<_> = frame.entries().map(|e| e.unwrap()).collect();
+        assert_eq!(decoded[0].measurement, partial.measurement);
+        assert_eq!(decoded[1].measurement, entry(1_700_000_600).measurement);
+    }
+
+    #[test]
+    fn test_frame_max_len_fits_all_entries() {
+        let entries = [entry(0); MAX_FRAME_ENTRIES];
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+
+        let len = Frame::encode(TLV_SERIES, &entries, &mut buffer).unwrap();
+        assert_eq!(len, MAX_FRAME_LEN);
+    }
+
+    #[test]
+    fn test_frame_decode_more_than_max_entries() {
+        // Peripherals predating the tank channel fill frames with six entries
+        let mut older = entry(1_700_000_000);
+        older.measurement.tank_pf = None;
+        older.measurement.battery_mv = None;
+
+        let mut data = [0u8; FRAME_HEADER_LEN + 6 * (1 + MeasurementSerieEntry::MAX_TLV_LEN)];
+        data[..FRAME_HEADER_LEN].copy_from_slice(&FrameHeader { version: PROTOCOL_VERSION, capabilities: TLV_SERIES, count: 6 }.to_bytes());
+        let mut len = FRAME_HEADER_LEN;
+        let mut tlv = [0u8; MeasurementSerieEntry::MAX_TLV_LEN];
+        let entry_len = older.to_tlv(&mut tlv).unwrap();
+        for _ in 0..6 {
+            data[len] = entry_len as u8;
+            data[len + 1..len + 1 + entry_len].copy_from_slice(&tlv[..entry_len]);
+            len += 1 + entry_len;
+        }
+
+        let frame = Frame::decode(&data[..len]).unwrap();
+        assert_eq!(frame.entries().map(|e| e.unwrap()).collect::<Vec<_>>(), vec![older; 6]);
+    }
+
+    #[test]
+    fn test_frame_encode_rejects_small_buffer() {
+        let entries = [entry(0)];
+        let mut buffer = [0u8; FRAME_HEADER_LEN];
+
+        assert_eq!(
+            Frame::encode(TLV_SERIES, &entries, &mut buffer),
+            Err(ProtocolError::BufferTooSmall {
+                required: FRAME_HEADER_LEN + 1 + MeasurementSerieEntry::MAX_TLV_LEN,
+                available: FRAME_HEADER_LEN
+            })
+        );
+    }
+
+    #[test]
+    fn test_frame_decode_legacy_layout() {
+        // v1 peripherals serve six 39 byte entries, unused slots are zeroed
+        let mut data = [0u8; 6 * LEGACY_ENTRY_LEN];
+        legacy_entry(1_700_000_000, &mut data[..LEGACY_ENTRY_LEN]);
+        legacy_entry(1_700_000_600, &mut data[LEGACY_ENTRY_LEN..2 * LEGACY_ENTRY_LEN]);
+
+        let frame = Frame::decode(&data).unwrap();
+        assert_eq!(frame.header.version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(frame.header.capabilities, Capabilities::LEGACY);
+        assert_eq!(frame.header.count, 2);
+
+        let decoded: Vec<_> = frame.entries().map(|e| e.unwrap()).collect();
+        assert_eq!(decoded[0].timestamp, entry(1_700_000_000).timestamp);
+        assert_eq!(decoded[1].timestamp, entry(1_700_000_600).timestamp);
+        assert_eq!(decoded[0].measurement.soil_pf, Some(320.0));
+        assert_eq!(decoded[0].measurement.tank_pf, None);
+    }
+
+    #[test]
+    fn test_frame_decode_rejects_invalid_legacy_length() {
+        // The old 33 byte slicing layout is not a valid v1 frame
+        let data = [1u8; 198];
+        assert!(Frame::decode(&data).is_err());
+    }
+
+    #[test]
+    fn test_frame_decode_rejects_unknown_version() {
+        let data = [FRAME_MAGIC, PROTOCOL_VERSION + 1, 0, 0, 0];
+        assert_eq!(Frame::decode(&data).unwrap_err(), ProtocolError::UnsupportedVersion(PROTOCOL_VERSION + 1));
+
+        let data = [FRAME_MAGIC, LEGACY_PROTOCOL_VERSION, 0, 0, 0];
+        assert_eq!(Frame::decode(&data).unwrap_err(), ProtocolError::UnsupportedVersion(LEGACY_PROTOCOL_VERSION));
+    }
+
+    #[test]
+    fn test_frame_decode_truncated() {
+        assert_eq!(Frame::decode(&[FRAME_MAGIC, PROTOCOL_VERSION]).unwrap_err(), ProtocolError::Truncated);
+
+        let entries = [entry(1_700_000_000)];
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let len = Frame::encode(TLV_SERIES, &entries, &mut buffer).unwrap();
+
+        let frame = Frame::decode(&buffer[..len - 1]).unwrap();
+        let decoded: Vec<_> = frame.entries().collect();
+        assert_eq!(decoded.len(), 1);
+        assert_eq!(decoded[0].unwrap_err(), ProtocolError::Truncated);
+    }
+
+    #[test]
+    fn test_frame_unknown_capabilities_are_ignored() {
+        let data = [FRAME_MAGIC, PROTOCOL_VERSION, 0xff, 0xff, 0];
+        let frame = Frame::decode(&data).unwrap();
+        assert_eq!(frame.header.capabilities, Capabilities::SUPPORTED);
+        assert_eq!(frame.entries().count(), 0);
+    }
+
+    #[test]
+    fn test_protocol_hello_roundtrip() {
+        let hello = ProtocolHello::current();
+        let decoded = ProtocolHello::from_bytes(&hello.to_bytes()).unwrap();
+        assert_eq!(hello, decoded);
+
+        assert!(ProtocolHello::from_bytes(&[PROTOCOL_VERSION]).is_err());
+    }
+
+    #[test]
+    fn test_protocol_hello_negotiate() {
+        let central = ProtocolHello::current();
+        let peripheral = ProtocolHello {
+            version: PROTOCOL_VERSION,
+            capabilities: Capabilities::MEASUREMENT_SERIES,
+        };
+
+        let negotiated = central.negotiate(&peripheral);
+        assert_eq!(negotiated.version, PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, Capabilities::MEASUREMENT_SERIES);
+
+        let negotiated = central.negotiate(&ProtocolHello::legacy());
+        assert_eq!(negotiated.version, LEGACY_PROTOCOL_VERSION);
+        assert_eq!(negotiated.capabilities, Capabilities::LEGACY);
+    }
+
+    #[test]
+    fn test_frame_compact_roundtrip() {
+        let entries: Vec<_> = (0..MAX_COMPACT_FRAME_ENTRIES as i64).map(|i| entry(1_700_000_000 + i * 600)).collect();
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+
+        let len = Frame::encode(Capabilities::SUPPORTED, &entries, &mut buffer).unwrap();
+
+        let frame = Frame::decode(&buffer[..len]).unwrap();
+        assert!(frame.header.capabilities.contains(Capabilities::COMPACT_SERIES));
+        assert_eq!(frame.header.count as usize, MAX_COMPACT_FRAME_ENTRIES);
+
+        let decoded: Vec<_> = frame.entries().map(|e| e.unwrap()).collect();
+        for (expected, actual) in entries.iter().zip(decoded.iter()) {
+            assert_eq!(expected.timestamp, actual.timestamp);
+            assert_eq!(expected.measurement, actual.measurement);
+        }
+    }
+
+    #[test]
+    fn test_frame_compact_rejects_too_many_entries() {
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+        let too_many = [entry(0); MAX_COMPACT_FRAME_ENTRIES + 1];
+
+        assert_eq!(
+            Frame::encode(Capabilities::SUPPORTED, &too_many, &mut buffer),
+            Err(ProtocolError::TooManyEntries(MAX_COMPACT_FRAME_ENTRIES + 1))
+        );
+    }
+}
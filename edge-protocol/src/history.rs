@@ -0,0 +1,204 @@
+//! Paged history transfer with an acknowledgement cursor.
+//!
+//! When both sides negotiate `PAGED_HISTORY` the measurement characteristic serves
+//! the oldest page of entries the peripheral still holds. The central persists the
+//! page and writes a [`HistoryAck`] with the timestamp of the last entry it stored to
+//! the history ack characteristic. Only then the peripheral drops those entries and
+//! serves the next page, an empty page means the history is drained.
+//!
+//! Without `PAGED_HISTORY` a read of the measurement characteristic acknowledges
+//! everything it served, as v1 firmware did.
+
+use chrono::{DateTime, NaiveDateTime};
+
+use crate::frame::{Capabilities, FRAME_HEADER_LEN, MAX_COMPACT_FRAME_ENTRIES, MAX_FRAME_ENTRIES, MAX_FRAME_LEN};
+use crate::{compact, MeasurementSerieEntry, ProtocolError};
+
+/// Written by the central after it persisted a page
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryAck {
+    /// Timestamp of the last entry the central persisted
+    pub timestamp: NaiveDateTime,
+}
+
+impl HistoryAck {
+    pub const LEN: usize = 8;
+
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
+        self.timestamp.and_utc().timestamp().to_le_bytes()
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        let bytes: [u8; Self::LEN] = bytes.try_into().map_err(|_| ProtocolError::InvalidLength {
+            field: "history ack",
+            expected: Self::LEN,
+            actual: bytes.len(),
+        })?;
+
+        let timestamp = i64::from_le_bytes(bytes);
+        let timestamp = DateTime::from_timestamp(timestamp, 0)
+            .ok_or(ProtocolError::InvalidTimestamp(timestamp))?
+            .naive_utc();
+
+        Ok(Self { timestamp })
+    }
+}
+
+/// Number of leading entries on a page for the negotiated capabilities.
+///
+/// Compact entries vary in length, a page packs as many as fit a single frame.
+pub fn page_len(entries: &[MeasurementSerieEntry], capabilities: Capabilities) -> usize {
+    if capabilities.contains(Capabilities::COMPACT_SERIES) {
+        let entries = &entries[..entries.len().min(MAX_COMPACT_FRAME_ENTRIES)];
+        compact::fitting(entries, MAX_FRAME_LEN - FRAME_HEADER_LEN)
+    } else {
+        entries.len().min(MAX_FRAME_ENTRIES)
+    }
+}
+
+/// Tracks which entries of a buffered history the central acknowledged.
+///
+/// Entries are expected in ascending timestamp order, as a series appends them.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HistoryCursor {
+    acked_until: Option<NaiveDateTime>,
+}
+
+impl HistoryCursor {
+    pub const fn new() -> Self {
+        Self { acked_until: None }
+    }
+
+    /// Timestamp of the last acknowledged entry
+    pub fn acked_until(&self) -> Option<NaiveDateTime> {
+        self.acked_until
+    }
+
+    /// Acknowledge every entry up to and including the ack timestamp, the cursor never moves back
+    pub fn ack(&mut self, ack: HistoryAck) {
+        if self.acked_until.is_none_or(|acked| ack.timestamp > acked) {
+            self.acked_until = Some(ack.timestamp);
+        }
+    }
+
+    /// Entries not acknowledged yet
+    pub fn pending<'a>(&self, entries: &'a [MeasurementSerieEntry]) -> &'a [MeasurementSerieEntry] {
+        match self.acked_until {
+            Some(acked) => {
+                let start = entries.iter().position(|e| e.timestamp > acked).unwrap_or(entries.len());
+                &entries[start..]
+            }
+            None => entries,
+        }
+    }
+
+    /// Oldest pending entries that fit a single frame
+    pub fn page<'a>(&self, entries: &'a [MeasurementSerieEntry], capabilities: Capabilities) -> &'a [MeasurementSerieEntry] {
+        let pending = self.pending(entries);
+        &pending[..page_len(pending, capabilities)]
+    }
+
+    /// Whether every entry has been acknowledged
+    pub fn is_drained(&self, entries: &[MeasurementSerieEntry]) -> bool {
+        self.pending(entries).is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+    use crate::{Frame, Measurement};
+
+    fn entries(count: i64) -> Vec<MeasurementSerieEntry> {
+        (0..count)
+            .map(|i| MeasurementSerieEntry {
+                timestamp: DateTime::from_timestamp(1_700_000_000 + i * 60, 0).unwrap().naive_utc(),
+                measurement: Measurement::EMPTY,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_history_ack_roundtrip() {
+        let ack = HistoryAck { timestamp: entries(1)[0].timestamp };
+        assert_eq!(HistoryAck::from_bytes(&ack.to_bytes()), Ok(ack));
+
+        assert_eq!(
+            HistoryAck::from_bytes(&[0; 4]),
+            Err(ProtocolError::InvalidLength { field: "history ack", expected: 8, actual: 4 })
+        );
+        assert_eq!(HistoryAck::from_bytes(&i64::MAX.to_le_bytes()), Err(ProtocolError::InvalidTimestamp(i64::MAX)));
+    }
+
+    #[test]
+    fn test_cursor_pages_through_history() {
+        let entries = entries(12);
+        let capabilities = Capabilities::SUPPORTED.difference(Capabilities::COMPACT_SERIES);
+        let mut cursor = HistoryCursor::new();
+        let mut served = vec![];
+
+        while !cursor.is_drained(&entries) {
+            let page = cursor.page(&entries, capabilities);
+            assert!(page.len() <= MAX_FRAME_ENTRIES);
+            served.extend_from_slice(page);
+            cursor.ack(HistoryAck { timestamp: page.last().unwrap().timestamp });
+        }
+
+        assert_eq!(served, entries);
+        assert!(cursor.page(&entries, capabilities).is_empty());
+    }
+
+    #[test]
+    fn test_compact_pages_are_packed_by_encoded_length() {
+        let mut full = Measurement::EMPTY;
+        full.battery = Some(80);
+        full.lux = Some(1200.0);
+        full.temperature = Some(21.5);
+        full.humidity = Some(48.0);
+        full.soil_pf = Some(320.0);
+        full.tank_pf = Some(87.5);
+        full.battery_mv = Some(3900);
+        let capabilities = Capabilities::SUPPORTED;
+        let mut buffer = [0u8; MAX_FRAME_LEN];
+
+        // Sampled at the interval, a page holds as many entries as the series
+        let typical: Vec<_> = entries(MAX_COMPACT_FRAME_ENTRIES as i64).into_iter().map(|e| MeasurementSerieEntry { measurement: full, ..e }).collect();
+        assert_eq!(HistoryCursor::new().page(&typical, capabilities).len(), MAX_COMPACT_FRAME_ENTRIES);
+        assert!(Frame::encode(capabilities, &typical, &mut buffer).is_ok());
+
+        // Escaped deltas push the entries that don't fit to the next page
+        let sparse: Vec<_> = typical.iter().enumerate().map(|(i, e)| MeasurementSerieEntry { timestamp: e.timestamp + TimeDelta::days(i as i64), ..*e }).collect();
+        let page = HistoryCursor::new().page(&sparse, capabilities);
+        assert!(page.len() < MAX_COMPACT_FRAME_ENTRIES);
+        assert!(Frame::encode(capabilities, page, &mut buffer).is_ok());
+        assert!(Frame::encode(capabilities, &sparse[..page.len() + 1], &mut buffer).is_err());
+    }
+
+    #[test]
+    fn test_cursor_keeps_unacknowledged_entries() {
+        let entries = entries(4);
+        let mut cursor = HistoryCursor::new();
+
+        // A page read without an ack is served again
+        let page = cursor.page(&entries, Capabilities::SUPPORTED);
+        assert_eq!(page.len(), 4);
+        assert_eq!(cursor.page(&entries, Capabilities::SUPPORTED), page);
+
+        // A partial ack only drops the persisted entries
+        cursor.ack(HistoryAck { timestamp: entries[1].timestamp });
+        assert_eq!(cursor.pending(&entries), &entries[2..]);
+    }
+
+    #[test]
+    fn test_cursor_never_moves_back() {
+        let entries = entries(4);
+        let mut cursor = HistoryCursor::new();
+
+        cursor.ack(HistoryAck { timestamp: entries[2].timestamp });
+        cursor.ack(HistoryAck { timestamp: entries[0].timestamp });
+
+        assert_eq!(cursor.acked_until(), Some(entries[2].timestamp));
+        assert_eq!(cursor.pending(&entries), &entries[3..]);
+    }
+}
@@ -5,6 +5,30 @@ use bitflags::bitflags;
 use chrono::prelude::*;
 use timeseries::Deviate;
 
+pub mod auth;
+pub mod battery;
+pub mod broadcast;
+pub mod command;
+pub mod compact;
+pub mod config;
+pub mod error;
+pub mod ess;
+pub mod frame;
+pub mod history;
+pub mod tlv;
+
+pub use auth::{AuthKey, Signer, Verifier, MAX_AUTHENTICATED_FRAME_LEN};
+pub use broadcast::{static_random_address, Broadcast, MAX_BROADCAST_LEN};
+pub use command::{Command, CommandRequest, CommandResponse, CommandStatus};
+pub use config::PeripheralConfig;
+pub use error::ProtocolError;
+pub use frame::{Capabilities, Frame, FrameEntries, FrameHeader, ProtocolHello, FRAME_HEADER_LEN, MAX_COMPACT_FRAME_ENTRIES, MAX_FRAME_ENTRIES, MAX_FRAME_LEN};
+pub use history::{HistoryAck, HistoryCursor};
+pub use tlv::{Tlv, TlvReader, TlvWriter};
+
+/// Local name stations advertise, in the scan response while they broadcast
+pub const DEVICE_NAME: &str = "Mycelium";
+
 // BLE Address Service (custom service)
 pub const ADDRESS_SERVICE_UUID_16: u16 = 0xFFF5;
 pub const ADDRESS_CHARACTERISTIC_UUID_16: u16 = 0xFFF7;
@@ -12,94 +36,85 @@ pub const ADDRESS_CHARACTERISTIC_UUID_16: u16 = 0xFFF7;
 // BLE Measurement Service (custom service)
 pub const MEASUREMENT_SERVICE_UUID_16: u16 = 0xFFF6;
 pub const MEASUREMENT_CHARACTERISTIC_UUID_16: u16 =  0xFFF8;
+pub const PROTOCOL_CHARACTERISTIC_UUID_16: u16 = 0xFFF9;
+pub const HISTORY_ACK_CHARACTERISTIC_UUID_16: u16 = 0xFFFA;
+pub const CONFIG_CHARACTERISTIC_UUID_16: u16 = 0xFFFB;
+pub const COMMAND_CHARACTERISTIC_UUID_16: u16 = 0xFFFC;
 
 // BLE Current Time Service (standard BLE service)
 pub const CURRENT_TIME_SERVICE_UUID: u16 = 0x1805;
 pub const CURRENT_TIME_CHARACTERISTIC_UUID: u16 = 0x2a2b;
 
+// BLE Environmental Sensing Service (standard BLE service)
+pub const ENVIRONMENTAL_SENSING_SERVICE_UUID: u16 = 0x181a;
+pub const TEMPERATURE_CHARACTERISTIC_UUID: u16 = 0x2a6e;
+pub const HUMIDITY_CHARACTERISTIC_UUID: u16 = 0x2a6f;
+pub const ILLUMINANCE_CHARACTERISTIC_UUID: u16 = 0x2afb;
+
+// BLE Battery Service (standard BLE service)
+pub const BATTERY_SERVICE_UUID: u16 = 0x180f;
+pub const BATTERY_LEVEL_CHARACTERISTIC_UUID: u16 = 0x2a19;
+
+// BLE Device Information Service (standard BLE service)
+pub const DEVICE_INFORMATION_SERVICE_UUID: u16 = 0x180a;
+pub const MODEL_NUMBER_CHARACTERISTIC_UUID: u16 = 0x2a24;
+pub const FIRMWARE_REVISION_CHARACTERISTIC_UUID: u16 = 0x2a26;
+pub const HARDWARE_REVISION_CHARACTERISTIC_UUID: u16 = 0x2a27;
+
+/// Longest string served by a device information characteristic
+pub const MAX_DEVICE_INFORMATION_LEN: usize = 32;
+
 
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct MeasurementSerieEntry {
     pub timestamp: NaiveDateTime,
     pub measurement: Measurement
 }
 
 impl MeasurementSerieEntry {
-    /// Encode measurement series entry to TLV format
-    pub fn to_tlv(&self) -> [u8; 39] {
-        let mut tlv = [0u8; 39];
-        let mut index = 0;
-        
+    /// Largest TLV encoded entry: timestamp (2 + 8) and measurement (2 + 37)
+    pub const MAX_TLV_LEN: usize = 2 + 8 + 2 + Measurement::MAX_TLV_LEN;
+
+    /// Encode measurement series entry to TLV format, returns the number of bytes written
+    pub fn to_tlv(&self, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let mut measurement = [0u8; Measurement::MAX_TLV_LEN];
+        let measurement_len = self.measurement.to_tlv(&mut measurement)?;
+
+        let mut writer = TlvWriter::new(buffer);
+
         // Timestamp (Type: 1) - 8 bytes for i64 timestamp
-        tlv[index] = 1; // Type
-        index += 1;
-        tlv[index] = 8; // Length
-        index += 1;
-        let timestamp_bytes = self.timestamp.and_utc().timestamp().to_le_bytes();
-        tlv[index..index + 8].copy_from_slice(&timestamp_bytes);
-        index += 8;
-        
-        // Measurement (Type: 2) - 21 bytes for measurement
-        tlv[index] = 2; // Type
-        index += 1;
-        tlv[index] = 27; // Length
-        index += 1;
-        tlv[index..index + 27].copy_from_slice(&self.measurement.to_tlv());
-        
-        tlv
+        writer.push(1, &self.timestamp.and_utc().timestamp().to_le_bytes())?;
+
+        // Measurement (Type: 2)
+        writer.push(2, &measurement[..measurement_len])?;
+
+        Ok(writer.len())
     }
     
-    /// Decode measurement series entry from TLV format
-    pub fn from_tlv(data: &[u8]) -> Result<Self, &'static str> {
-        let mut index = 0;
+    /// Decode measurement series entry from TLV format, unknown types are skipped
+    pub fn from_tlv(data: &[u8]) -> Result<Self, ProtocolError> {
         let mut timestamp = None;
         let mut measurement = None;
-        
-        while index < data.len() {
-            if index + 1 >= data.len() {
-                return Err("Incomplete TLV data");
-            }
-            
-            let tlv_type = data[index];
-            index += 1;
-            let length = data[index] as usize;
-            index += 1;
-            
-            if index + length > data.len() {
-                return Err("TLV length exceeds data bounds");
-            }
-            
-            let value = &data[index..index + length];
-            
-            match tlv_type {
+
+        for tlv in TlvReader::new(data) {
+            let tlv = tlv?;
+
+            match tlv.tlv_type {
                 1 => { // Timestamp
-                    if length != 8 {
-                        return Err("Invalid timestamp length");
-                    }
-                    let timestamp_i64 = i64::from_le_bytes([
-                        value[0], value[1], value[2], value[3],
-                        value[4], value[5], value[6], value[7]
-                    ]);
+                    let timestamp_i64 = i64::from_le_bytes(tlv.value_array("timestamp")?);
                     timestamp = Some(DateTime::from_timestamp(timestamp_i64, 0)
-                        .ok_or("Invalid timestamp")?.naive_utc());
+                        .ok_or(ProtocolError::InvalidTimestamp(timestamp_i64))?.naive_utc());
                 }
                 2 => { // Measurement
-                    if length != 21 {
-                        return Err("Invalid measurement length");
-                    }
-                    measurement = Some(Measurement::from_tlv(value)?);
-                }
-                _ => {
-                    return Err("Unknown TLV type");
+                    measurement = Some(Measurement::from_tlv(tlv.value)?);
                 }
+                _ => {}
             }
-            
-            index += length;
         }
-        
-        let timestamp = timestamp.ok_or("Missing timestamp")?;
-        let measurement = measurement.ok_or("Missing measurement")?;
-        
+
+        let timestamp = timestamp.ok_or(ProtocolError::MissingField("timestamp"))?;
+        let measurement = measurement.ok_or(ProtocolError::MissingField("measurement"))?;
+
         Ok(MeasurementSerieEntry {
             timestamp,
             measurement
@@ -109,145 +124,232 @@ impl MeasurementSerieEntry {
 
 
 
-#[derive(Clone, Copy)]
+/// A single sample, channels are `None` when their sensor couldn't be read
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Measurement {
-    pub battery: u8,
-    pub lux: f32,
-    pub temperature: f32,
-    pub humidity: f32,
-    pub soil_pf: f32
+    pub battery: Option<u8>,
+    pub lux: Option<f32>,
+    pub temperature: Option<f32>,
+    pub humidity: Option<f32>,
+    pub soil_pf: Option<f32>,
+    pub tank_pf: Option<f32>,
+    pub battery_mv: Option<u16>
+}
+
+/// A channel deviates when its difference exceeds the maximum, or when it appears or disappears
+fn channel_deviates<T: Copy>(a: Option<T>, b: Option<T>, max: Option<T>, exceeds: impl Fn(T, T, T) -> bool) -> bool {
+    match (a, b, max) {
+        (Some(a), Some(b), Some(max)) => exceeds(a, b, max),
+        (Some(_), Some(_), None) => false,
+        (None, None, _) => false,
+        _ => true,
+    }
+}
+
+fn f32_exceeds(a: f32, b: f32, max: f32) -> bool {
+    (a - b).abs() > max
 }
 
 impl Deviate for Measurement {
     fn deviate(&self, other: &Self, max_deviation: &Self) -> bool {
-        (self.temperature - other.temperature).abs() > max_deviation.temperature ||
-        (self.humidity - other.humidity).abs() > max_deviation.humidity ||
-        self.battery.abs_diff(other.battery) > max_deviation.battery ||
-        (self.lux - other.lux).abs() > max_deviation.lux
+        channel_deviates(self.temperature, other.temperature, max_deviation.temperature, f32_exceeds) ||
+        channel_deviates(self.humidity, other.humidity, max_deviation.humidity, f32_exceeds) ||
+        channel_deviates(self.battery, other.battery, max_deviation.battery, |a, b, max| a.abs_diff(b) > max) ||
+        channel_deviates(self.lux, other.lux, max_deviation.lux, f32_exceeds) ||
+        channel_deviates(self.battery_mv, other.battery_mv, max_deviation.battery_mv, |a, b, max| a.abs_diff(b) > max)
     }
 }
 
 impl Measurement {
     pub const MAX_DEVIATION: Self = Self {
-        battery: 1,
-        lux: 100.0,
-        temperature: 1.0,
-        humidity: 0.1,
-        soil_pf: 0.1
+        battery: Some(1),
+        lux: Some(100.0),
+        temperature: Some(1.0),
+        humidity: Some(0.1),
+        // The probes wander between samples, a bucket each time would flush the series early
+        soil_pf: None,
+        tank_pf: None,
+        battery_mv: Some(50)
     };
+
+    /// Measurement without any channel
+    pub const EMPTY: Self = Self {
+        battery: None,
+        lux: None,
+        temperature: None,
+        humidity: None,
+        soil_pf: None,
+        tank_pf: None,
+        battery_mv: None
+    };
+
+    /// Channels that hold a value
+    pub fn fields(&self) -> MeasurementFields {
+        let mut fields = MeasurementFields::empty();
+        fields.set(MeasurementFields::BATTERY, self.battery.is_some());
+        fields.set(MeasurementFields::LUX, self.lux.is_some());
+        fields.set(MeasurementFields::TEMPERATURE, self.temperature.is_some());
+        fields.set(MeasurementFields::HUMIDITY, self.humidity.is_some());
+        fields.set(MeasurementFields::SOIL_PF, self.soil_pf.is_some());
+        fields.set(MeasurementFields::TANK_PF, self.tank_pf.is_some());
+        fields.set(MeasurementFields::BATTERY_MV, self.battery_mv.is_some());
+        fields
+    }
+
+    /// Battery charge, derived from the voltage when the peripheral reports it
+    pub fn battery_percentage(&self) -> Option<u8> {
+        self.battery_mv.map(battery::lipo_percentage).or(self.battery)
+    }
 }
 
 impl Measurement {
-    /// Encode measurement to TLV format
-    pub fn to_tlv(&self) -> [u8; 27] {
-        let mut tlv = [0u8; 27];
-        let mut index = 0;
-        
+    /// Largest TLV encoded measurement: battery (2 + 1), five f32 channels (2 + 4) and battery millivolts (2 + 2).
+    /// The status (2 + 1) is only written when a channel is absent, so it never adds to the maximum.
+    pub const MAX_TLV_LEN: usize = 3 + 5 * 6 + 4;
+
+    /// Encode measurement to TLV format, returns the number of bytes written.
+    ///
+    /// Absent channels are omitted and listed as such in a status record (Type: 6)
+    /// holding the bitmap of channels that were measured.
+    pub fn to_tlv(&self, buffer: &mut [u8]) -> Result<usize, ProtocolError> {
+        let mut writer = TlvWriter::new(buffer);
+
         // Battery (Type: 1)
-        tlv[index] = 1; // Type
-        index += 1;
-        tlv[index] = 1; // Length
-        index += 1;
-        tlv[index] = self.battery;
-        index += 1;
-        
+        if let Some(battery) = self.battery {
+            writer.push(1, &[battery])?;
+        }
+
         // Lux (Type: 2)
-        tlv[index] = 2; // Type
-        index += 1;
-        tlv[index] = 4; // Length
-        index += 1;
-        tlv[index..index + 4].copy_from_slice(&self.lux.to_le_bytes());
-        index += 4;
-        
+        if let Some(lux) = self.lux {
+            writer.push(2, &lux.to_le_bytes())?;
+        }
+
         // Temperature (Type: 3)
-        tlv[index] = 3; // Type
-        index += 1;
-        tlv[index] = 4; // Length
-        index += 1;
-        tlv[index..index + 4].copy_from_slice(&self.temperature.to_le_bytes());
-        index += 4;
-        
+        if let Some(temperature) = self.temperature {
+            writer.push(3, &temperature.to_le_bytes())?;
+        }
+
         // Humidity (Type: 4)
-        tlv[index] = 4; // Type
-        index += 1;
-        tlv[index] = 4; // Length
-        index += 1;
-        tlv[index..index + 4].copy_from_slice(&self.humidity.to_le_bytes());
-        index += 4;
+        if let Some(humidity) = self.humidity {
+            writer.push(4, &humidity.to_le_bytes())?;
+        }
 
         // Soil_pf (Type: 5)
-        tlv[index] = 5; // Type
-        index += 1;
-        tlv[index] = 4; // Length
-        index += 1;
-        tlv[index..index + 4].copy_from_slice(&self.soil_pf.to_le_bytes());
-        
-        tlv
+        if let Some(soil_pf) = self.soil_pf {
+            writer.push(5, &soil_pf.to_le_bytes())?;
+        }
+
+        // Tank_pf (Type: 7)
+        if let Some(tank_pf) = self.tank_pf {
+            writer.push(7, &tank_pf.to_le_bytes())?;
+        }
+
+        // Battery_mv (Type: 8)
+        if let Some(battery_mv) = self.battery_mv {
+            writer.push(8, &battery_mv.to_le_bytes())?;
+        }
+
+        // Status (Type: 6)
+        let fields = self.fields();
+        if fields != MeasurementFields::all() {
+            writer.push(6, &[fields.bits()])?;
+        }
+
+        Ok(writer.len())
     }
     
-    /// Decode measurement from TLV format
-    pub fn from_tlv(data: &[u8]) -> Result<Self, &'static str> {
-        let mut measurement = Measurement {
-            battery: 0,
-            lux: 0.0,
-            temperature: 0.0,
-            humidity: 0.0,
-            soil_pf: 0.0
-        };
-        
-        let mut i = 0;
-        while i < data.len() {
-            if i + 2 > data.len() {
-                return Err("Incomplete TLV header");
-            }
-            
-            let tlv_type = data[i];
-            let length = data[i + 1] as usize;
-            
-            if i + 2 + length > data.len() {
-                return Err("Incomplete TLV data");
-            }
-            
-            let value_data = &data[i + 2..i + 2 + length];
-            
-            match tlv_type {
+    /// Decode measurement from TLV format, unknown types are skipped
+    pub fn from_tlv(data: &[u8]) -> Result<Self, ProtocolError> {
+        Self::from_tlv_with_fields(data).map(|(measurement, _)| measurement)
+    }
+
+    /// Decode measurement from TLV format and report which fields were present.
+    ///
+    /// Without a status record every channel found is present. With one, channels it
+    /// marks as absent are dropped and channels it marks as measured must be found.
+    pub fn from_tlv_with_fields(data: &[u8]) -> Result<(Self, MeasurementFields), ProtocolError> {
+        let mut measurement = Measurement::EMPTY;
+        let mut status = None;
+
+        for tlv in TlvReader::new(data) {
+            let tlv = tlv?;
+
+            match tlv.tlv_type {
                 1 => { // Battery
-                    if length != 1 {
-                        return Err("Invalid battery length");
-                    }
-                    measurement.battery = value_data[0];
+                    let [battery] = tlv.value_array("battery")?;
+                    measurement.battery = Some(battery);
                 },
                 2 => { // Lux
-                    if length != 4 {
-                        return Err("Invalid lux length");
-                    }
-                    measurement.lux = f32::from_le_bytes([value_data[0], value_data[1], value_data[2], value_data[3]]);
+                    measurement.lux = Some(f32::from_le_bytes(tlv.value_array("lux")?));
                 },
                 3 => { // Temperature
-                    if length != 4 {
-                        return Err("Invalid temperature length");
-                    }
-                    measurement.temperature = f32::from_le_bytes([value_data[0], value_data[1], value_data[2], value_data[3]]);
+                    measurement.temperature = Some(f32::from_le_bytes(tlv.value_array("temperature")?));
                 },
                 4 => { // Humidity
-                    if length != 4 {
-                        return Err("Invalid humidity length");
-                    }
-                    measurement.humidity = f32::from_le_bytes([value_data[0], value_data[1], value_data[2], value_data[3]]);
+                    measurement.humidity = Some(f32::from_le_bytes(tlv.value_array("humidity")?));
                 },
                 5 => { // Soil_pf
-                    if length != 4 {
-                        return Err("Invalid soil pf length");
-                    }
-                    measurement.soil_pf = f32::from_le_bytes([value_data[0], value_data[1], value_data[2], value_data[3]]);
+                    measurement.soil_pf = Some(f32::from_le_bytes(tlv.value_array("soil pf")?));
+                },
+                7 => { // Tank_pf
+                    measurement.tank_pf = Some(f32::from_le_bytes(tlv.value_array("tank pf")?));
+                },
+                8 => { // Battery_mv
+                    measurement.battery_mv = Some(u16::from_le_bytes(tlv.value_array("battery mv")?));
                 },
-                _ => return Err("Unknown TLV type"),
+                6 => { // Status
+                    let [bits] = tlv.value_array("status")?;
+                    status = Some(MeasurementFields::from_bits_truncate(bits));
+                },
+                _ => {}
             }
-            
-            i += 2 + length;
         }
-        
-        Ok(measurement)
+
+        if let Some(status) = status {
+            measurement.retain(status)?;
+        }
+
+        Ok((measurement, measurement.fields()))
+    }
+
+    /// Drop channels missing from `fields`, fails when a channel in `fields` has no value
+    fn retain(&mut self, fields: MeasurementFields) -> Result<(), ProtocolError> {
+        fn channel<T>(value: &mut Option<T>, measured: bool, field: &'static str) -> Result<(), ProtocolError> {
+            match (value.is_some(), measured) {
+                (false, true) => Err(ProtocolError::MissingField(field)),
+                (true, false) => {
+                    *value = None;
+                    Ok(())
+                }
+                _ => Ok(()),
+            }
+        }
+
+        channel(&mut self.battery, fields.contains(MeasurementFields::BATTERY), "battery")?;
+        channel(&mut self.lux, fields.contains(MeasurementFields::LUX), "lux")?;
+        channel(&mut self.temperature, fields.contains(MeasurementFields::TEMPERATURE), "temperature")?;
+        channel(&mut self.humidity, fields.contains(MeasurementFields::HUMIDITY), "humidity")?;
+        channel(&mut self.soil_pf, fields.contains(MeasurementFields::SOIL_PF), "soil pf")?;
+        channel(&mut self.tank_pf, fields.contains(MeasurementFields::TANK_PF), "tank pf")?;
+        channel(&mut self.battery_mv, fields.contains(MeasurementFields::BATTERY_MV), "battery mv")?;
+
+        Ok(())
+    }
+}
+
+/// Channels present in a measurement, also the value of its status record
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MeasurementFields(u8);
+
+bitflags! {
+    impl MeasurementFields: u8 {
+        const BATTERY                = 0x01;
+        const LUX                    = 0x02;
+        const TEMPERATURE            = 0x04;
+        const HUMIDITY               = 0x08;
+        const SOIL_PF                = 0x10;
+        const TANK_PF                = 0x20;
+        const BATTERY_MV             = 0x40;
     }
 }
 
@@ -343,14 +445,14 @@ impl CurrentTime {
         }
     }
 
-    pub fn to_naivedatetime(&self) -> NaiveDateTime {
-        let date = NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.day as u32).expect("Unable to create date");
-        let time = NaiveTime::from_hms_opt(self.hour as u32, self.minute as u32, self.second as u32).expect("Unable to create time");
+    pub fn to_naivedatetime(&self) -> Result<NaiveDateTime, ProtocolError> {
+        let date = NaiveDate::from_ymd_opt(self.year as i32, self.month as u32, self.day as u32).ok_or(ProtocolError::InvalidDate)?;
+        let time = NaiveTime::from_hms_opt(self.hour as u32, self.minute as u32, self.second as u32).ok_or(ProtocolError::InvalidDate)?;
 
-        NaiveDateTime::new(date, time)
+        Ok(NaiveDateTime::new(date, time))
     }
     
-    pub fn to_bytes(&self) -> [u8; 10] {
+    pub fn to_bytes(&self) -> [u8; Self::LEN] {
         let year_bytes = self.year.to_le_bytes();
         [
             year_bytes[0],
@@ -366,6 +468,18 @@ impl CurrentTime {
         ]
     }
 
+    pub const LEN: usize = 10;
+
+    /// Decode a current time characteristic value, fails when fewer than 10 bytes are given
+    pub fn try_from_bytes(bytes: &[u8]) -> Result<Self, ProtocolError> {
+        if bytes.len() < Self::LEN {
+            return Err(ProtocolError::Truncated);
+        }
+
+        Ok(Self::from_bytes(bytes))
+    }
+
+    /// Decode a current time characteristic value, panics when fewer than 10 bytes are given
     pub fn from_bytes(bytes: &[u8]) -> Self {
         let year = u16::from_le_bytes([bytes[0], bytes[1]]);
         let month = bytes[2];
@@ -395,6 +509,18 @@ impl CurrentTime {
 mod tests {
     use super::*;
 
+    fn encode_measurement(measurement: &Measurement) -> Vec<u8> {
+        let mut buffer = [0u8; Measurement::MAX_TLV_LEN];
+        let len = measurement.to_tlv(&mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
+    fn encode_entry(entry: &MeasurementSerieEntry) -> Vec<u8> {
+        let mut buffer = [0u8; MeasurementSerieEntry::MAX_TLV_LEN];
+        let len = entry.to_tlv(&mut buffer).unwrap();
+        buffer[..len].to_vec()
+    }
+
     #[test]
     fn test_encode_decode_roundtrip() {
         let ct = CurrentTime {
@@ -464,6 +590,36 @@ mod tests {
             .contains(AdjustReason::TIMEZONE_CHANGE));
     }
 
+    #[test]
+    fn test_try_from_bytes_rejects_short_input() {
+        let bytes = [0xE9, 0x07, 0x06, 0x09, 0x0F, 0x2A, 0x1E, 0x01, 0x00];
+        assert_eq!(CurrentTime::try_from_bytes(&bytes), Err(ProtocolError::Truncated));
+        assert_eq!(CurrentTime::try_from_bytes(&[]), Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn test_try_from_bytes_roundtrip() {
+        let ct = CurrentTime::from_naivedatetime(DateTime::from_timestamp(1_700_000_000, 0).unwrap().naive_utc());
+        let decoded = CurrentTime::try_from_bytes(&ct.to_bytes()).unwrap();
+        assert_eq!(ct, decoded);
+        assert_eq!(decoded.to_naivedatetime().unwrap(), DateTime::from_timestamp(1_700_000_000, 0).unwrap().naive_utc());
+    }
+
+    #[test]
+    fn test_to_naivedatetime_invalid_date() {
+        let mut ct = CurrentTime::unix_epoch();
+        ct.month = 13;
+        assert_eq!(ct.to_naivedatetime(), Err(ProtocolError::InvalidDate));
+
+        let mut ct = CurrentTime::unix_epoch();
+        ct.hour = 24;
+        assert_eq!(ct.to_naivedatetime(), Err(ProtocolError::InvalidDate));
+
+        // All zeroes is what an uninitialised characteristic reads as
+        let ct = CurrentTime::try_from_bytes(&[0u8; 10]).unwrap();
+        assert_eq!(ct.to_naivedatetime(), Err(ProtocolError::InvalidDate));
+    }
+
     #[test]
     fn test_day_of_week_unknown_handling() {
         let mut bytes = [0u8; 10];
@@ -475,61 +631,127 @@ mod tests {
     #[test]
     fn test_measurement_tlv_encode_decode() {
         let measurement = Measurement {
-            battery: 85,
-            lux: 1234.56,
-            temperature: 23.5,
-            humidity: 45.2,
+            battery: Some(85),
+            lux: Some(1234.56),
+            temperature: Some(23.5),
+            humidity: Some(45.2),
+            soil_pf: Some(310.5),
+            tank_pf: Some(12.5),
+            battery_mv: Some(3900),
         };
         
-        let tlv_data = measurement.to_tlv();
+        let tlv_data = encode_measurement(&measurement);
         let decoded = Measurement::from_tlv(&tlv_data).unwrap();
         
-        assert_eq!(measurement.battery, decoded.battery);
-        assert!((measurement.lux - decoded.lux).abs() < f32::EPSILON);
-        assert!((measurement.temperature - decoded.temperature).abs() < f32::EPSILON);
-        assert!((measurement.humidity - decoded.humidity).abs() < f32::EPSILON);
+        assert_eq!(measurement, decoded);
     }
     
     #[test]
     fn test_measurement_tlv_invalid_data() {
         // Test with incomplete data
         let result = Measurement::from_tlv(&[1, 1]); // Missing value
-        assert!(result.is_err());
+        assert_eq!(result.err(), Some(ProtocolError::Truncated));
         
-        // Test with unknown type
-        let result = Measurement::from_tlv(&[99, 1, 0]); // Unknown type 99
-        assert!(result.is_err());
+        // Test with length exceeding the data
+        let result = Measurement::from_tlv(&[2, 4, 0, 0]); // Lux with only 2 bytes
+        assert_eq!(result.err(), Some(ProtocolError::Truncated));
         
         // Test with wrong length for battery
         let result = Measurement::from_tlv(&[1, 2, 0, 0]); // Battery with length 2
-        assert!(result.is_err());
+        assert_eq!(result.err(), Some(ProtocolError::InvalidLength { field: "battery", expected: 1, actual: 2 }));
     }
     
     #[test]
     fn test_measurement_tlv_empty() {
         let measurement = Measurement {
-            battery: 0,
-            lux: 0.0,
-            temperature: 0.0,
-            humidity: 0.0,
+            battery: Some(0),
+            lux: Some(0.0),
+            temperature: Some(0.0),
+            humidity: Some(0.0),
+            soil_pf: Some(0.0),
+            tank_pf: Some(0.0),
+            battery_mv: Some(0),
         };
         
-        let tlv_data = measurement.to_tlv();
+        let tlv_data = encode_measurement(&measurement);
         let decoded = Measurement::from_tlv(&tlv_data).unwrap();
         
-        assert_eq!(measurement.battery, decoded.battery);
-        assert_eq!(measurement.lux, decoded.lux);
-        assert_eq!(measurement.temperature, decoded.temperature);
-        assert_eq!(measurement.humidity, decoded.humidity);
+        assert_eq!(measurement, decoded);
+    }
+
+    #[test]
+    fn test_measurement_tlv_skips_unknown_types() {
+        let measurement = Measurement {
+            battery: Some(60),
+            lux: Some(42.0),
+            temperature: Some(19.5),
+            humidity: Some(51.0),
+            soil_pf: Some(300.0),
+            tank_pf: Some(15.0),
+            battery_mv: Some(3900),
+        };
+
+        let mut data = vec![99, 1, 0xff]; // Unknown type 99
+        data.extend(encode_measurement(&measurement));
+        data.extend([100, 3, 1, 2, 3]); // Unknown type 100
+
+        let (decoded, fields) = Measurement::from_tlv_with_fields(&data).unwrap();
+
+        assert_eq!(fields, MeasurementFields::all());
+        assert_eq!(measurement, decoded);
+    }
+
+    #[test]
+    fn test_measurement_tlv_reports_present_fields() {
+        // Temperature and humidity only
+        let mut data = [0u8; 12];
+        data[..2].copy_from_slice(&[3, 4]);
+        data[2..6].copy_from_slice(&21.5f32.to_le_bytes());
+        data[6..8].copy_from_slice(&[4, 4]);
+        data[8..12].copy_from_slice(&40.0f32.to_le_bytes());
+
+        let (decoded, fields) = Measurement::from_tlv_with_fields(&data).unwrap();
+
+        assert_eq!(fields, MeasurementFields::TEMPERATURE | MeasurementFields::HUMIDITY);
+        assert_eq!(decoded.temperature, Some(21.5));
+        assert_eq!(decoded.humidity, Some(40.0));
+        assert_eq!(decoded.battery, None);
+        assert_eq!(decoded.soil_pf, None);
+    }
+
+    #[test]
+    fn test_measurement_serie_entry_tlv_skips_unknown_types() {
+        let entry = MeasurementSerieEntry {
+            timestamp: DateTime::from_timestamp(1640995200, 0).unwrap().naive_utc(),
+            measurement: Measurement {
+                battery: Some(75),
+                lux: Some(987.65),
+                temperature: Some(18.3),
+                humidity: Some(62.1),
+                soil_pf: Some(280.0),
+                tank_pf: Some(20.0),
+                battery_mv: Some(3900),
+            },
+        };
+
+        let mut data = encode_entry(&entry);
+        data.extend([42, 2, 0xde, 0xad]); // Unknown type 42
+
+        let decoded = MeasurementSerieEntry::from_tlv(&data).unwrap();
+        assert_eq!(entry.timestamp, decoded.timestamp);
+        assert_eq!(entry.measurement.humidity, decoded.measurement.humidity);
     }
 
     #[test]
     fn test_measurement_serie_entry_tlv_encode_decode() {
         let measurement = Measurement {
-            battery: 75,
-            lux: 987.65,
-            temperature: 18.3,
-            humidity: 62.1,
+            battery: Some(75),
+            lux: Some(987.65),
+            temperature: Some(18.3),
+            humidity: Some(62.1),
+            soil_pf: Some(280.0),
+            tank_pf: Some(20.0),
+            battery_mv: Some(3900),
         };
         
         let entry = MeasurementSerieEntry {
@@ -537,21 +759,18 @@ mod tests {
             measurement,
         };
         
-        let tlv_data = entry.to_tlv();
+        let tlv_data = encode_entry(&entry);
         let decoded = MeasurementSerieEntry::from_tlv(&tlv_data).unwrap();
         
         assert_eq!(entry.timestamp, decoded.timestamp);
-        assert_eq!(entry.measurement.battery, decoded.measurement.battery);
-        assert!((entry.measurement.lux - decoded.measurement.lux).abs() < f32::EPSILON);
-        assert!((entry.measurement.temperature - decoded.measurement.temperature).abs() < f32::EPSILON);
-        assert!((entry.measurement.humidity - decoded.measurement.humidity).abs() < f32::EPSILON);
+        assert_eq!(entry.measurement, decoded.measurement);
     }
     
     #[test]
     fn test_measurement_serie_entry_tlv_invalid_data() {
         // Test with incomplete data
         let result = MeasurementSerieEntry::from_tlv(&[1, 8]); // Missing timestamp value
-        assert!(result.is_err());
+        assert_eq!(result.err(), Some(ProtocolError::Truncated));
         
         // Test with missing measurement
         let mut incomplete_data = [0u8; 10];
@@ -559,24 +778,34 @@ mod tests {
         incomplete_data[1] = 8; // Length: 8
         // Timestamp bytes would go here, but we're testing missing measurement
         let result = MeasurementSerieEntry::from_tlv(&incomplete_data);
-        assert!(result.is_err());
+        assert_eq!(result.err(), Some(ProtocolError::MissingField("measurement")));
         
-        // Test with unknown TLV type
+        // Test with only an unknown TLV type
         let result = MeasurementSerieEntry::from_tlv(&[99, 1, 0]); // Unknown type 99
-        assert!(result.is_err());
+        assert_eq!(result.err(), Some(ProtocolError::MissingField("timestamp")));
         
         // Test with wrong timestamp length
         let result = MeasurementSerieEntry::from_tlv(&[1, 4, 0, 0, 0, 0]); // Timestamp with length 4
-        assert!(result.is_err());
+        assert_eq!(result.err(), Some(ProtocolError::InvalidLength { field: "timestamp", expected: 8, actual: 4 }));
+
+        // Test with a timestamp chrono can't represent
+        let mut data = [0u8; 10];
+        data[..2].copy_from_slice(&[1, 8]);
+        data[2..].copy_from_slice(&i64::MAX.to_le_bytes());
+        let result = MeasurementSerieEntry::from_tlv(&data);
+        assert_eq!(result.err(), Some(ProtocolError::InvalidTimestamp(i64::MAX)));
     }
     
     #[test]
     fn test_measurement_serie_entry_tlv_empty_values() {
         let measurement = Measurement {
-            battery: 0,
-            lux: 0.0,
-            temperature: 0.0,
-            humidity: 0.0,
+            battery: Some(0),
+            lux: Some(0.0),
+            temperature: Some(0.0),
+            humidity: Some(0.0),
+            soil_pf: Some(0.0),
+            tank_pf: Some(0.0),
+            battery_mv: Some(0),
         };
         
         let entry = MeasurementSerieEntry {
@@ -584,14 +813,101 @@ mod tests {
             measurement,
         };
         
-        let tlv_data = entry.to_tlv();
+        let tlv_data = encode_entry(&entry);
         let decoded = MeasurementSerieEntry::from_tlv(&tlv_data).unwrap();
         
         assert_eq!(entry.timestamp, decoded.timestamp);
-        assert_eq!(entry.measurement.battery, decoded.measurement.battery);
-        assert_eq!(entry.measurement.lux, decoded.measurement.lux);
-        assert_eq!(entry.measurement.temperature, decoded.measurement.temperature);
-        assert_eq!(entry.measurement.humidity, decoded.measurement.humidity);
+        assert_eq!(entry.measurement, decoded.measurement);
+    }
+
+    #[test]
+    fn test_measurement_tlv_absent_channels() {
+        let measurement = Measurement {
+            battery: Some(70),
+            lux: Some(120.0),
+            temperature: Some(22.0),
+            humidity: Some(55.0),
+            soil_pf: None,
+            tank_pf: Some(18.0),
+            battery_mv: Some(3900),
+        };
+
+        let tlv_data = encode_measurement(&measurement);
+        // Soil pf is omitted and a status record lists the measured channels
+        assert_eq!(tlv_data.len(), Measurement::MAX_TLV_LEN - 6 + 3);
+        assert_eq!(&tlv_data[tlv_data.len() - 3..], &[6, 1, 0x6f]);
+
+        let (decoded, fields) = Measurement::from_tlv_with_fields(&tlv_data).unwrap();
+        assert_eq!(decoded, measurement);
+        assert_eq!(fields, MeasurementFields::all() - MeasurementFields::SOIL_PF);
+
+        let decoded = Measurement::from_tlv(&encode_measurement(&Measurement::EMPTY)).unwrap();
+        assert_eq!(decoded, Measurement::EMPTY);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_measurement_tlv_status_is_authoritative() {
+        // Lux value present but the status marks it as not measured
+        let mut data = vec![2, 4];
+        data.extend(5.0f32.to_le_bytes());
+        data.extend([6, 1, 0x00]);
+        assert_eq!(Measurement::from_tlv(&data).unwrap().lux, None);
+
+        // Status claims temperature was measured but its value is missing
+        let result = Measurement::from_tlv(&[6, 1, MeasurementFields::TEMPERATURE.bits()]);
+        assert_eq!(result.err(), Some(ProtocolError::MissingField("temperature")));
+
+        // Wrong status length
+        let result = Measurement::from_tlv(&[6, 2, 0, 0]);
+        assert_eq!(result.err(), Some(ProtocolError::InvalidLength { field: "status", expected: 1, actual: 2 }));
+    }
+
+    #[test]
+    fn test_measurement_deviate_with_absent_channels() {
+        let measurement = Measurement {
+            battery: Some(80),
+            lux: Some(100.0),
+            temperature: Some(20.0),
+            humidity: Some(50.0),
+            soil_pf: Some(300.0),
+            tank_pf: Some(15.0),
+            battery_mv: Some(3900),
+        };
+
+        assert!(!measurement.deviate(&measurement, &Measurement::MAX_DEVIATION));
+
+        let mut warmer = measurement;
+        warmer.temperature = Some(22.0);
+        assert!(measurement.deviate(&warmer, &Measurement::MAX_DEVIATION));
+
+        let mut broken_sensor = measurement;
+        broken_sensor.humidity = None;
+        assert!(measurement.deviate(&broken_sensor, &Measurement::MAX_DEVIATION));
+        assert!(!broken_sensor.deviate(&broken_sensor, &Measurement::MAX_DEVIATION));
+
+        // The probes don't start a bucket of their own
+        let mut wetter = measurement;
+        wetter.soil_pf = Some(250.0);
+        wetter.tank_pf = None;
+        assert!(!measurement.deviate(&wetter, &Measurement::MAX_DEVIATION));
+
+        // A threshold of None never deviates on value
+        let mut max_deviation = Measurement::MAX_DEVIATION;
+        max_deviation.temperature = None;
+        assert!(!measurement.deviate(&warmer, &max_deviation));
+    }
+
+    #[test]
+    fn test_measurement_battery_percentage() {
+        let mut measurement = Measurement::EMPTY;
+        assert_eq!(measurement.battery_percentage(), None);
+
+        // Older peripherals report a percentage themselves
+        measurement.battery = Some(42);
+        assert_eq!(measurement.battery_percentage(), Some(42));
+
+        // The voltage takes precedence when present
+        measurement.battery_mv = Some(4200);
+        assert_eq!(measurement.battery_percentage(), Some(100));
+    }
+}
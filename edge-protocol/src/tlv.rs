@@ -0,0 +1,176 @@
+//! Forward compatible TLV reader and writer.
+//!
+//! Every record is encoded as `| type (1) | length (1) | value (length) |`. The reader
+//! yields records regardless of their type so decoders can skip types they don't know,
+//! while still refusing records that run past the end of the data.
+
+use crate::ProtocolError;
+
+/// A single TLV record borrowed from the underlying data
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tlv<'a> {
+    pub tlv_type: u8,
+    pub value: &'a [u8],
+}
+
+impl<'a> Tlv<'a> {
+    /// Value as a fixed size array, fails when the length doesn't match
+    pub fn value_array<const N: usize>(&self, field: &'static str) -> Result<[u8; N], ProtocolError> {
+        self.value.try_into().map_err(|_| ProtocolError::InvalidLength {
+            field,
+            expected: N,
+            actual: self.value.len(),
+        })
+    }
+}
+
+/// Iterator over TLV records, stops after the first malformed record
+pub struct TlvReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> TlvReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+}
+
+impl<'a> Iterator for TlvReader<'a> {
+    type Item = Result<Tlv<'a>, ProtocolError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        if self.data.len() < 2 {
+            self.data = &[];
+            return Some(Err(ProtocolError::Truncated));
+        }
+
+        let tlv_type = self.data[0];
+        let length = self.data[1] as usize;
+
+        if 2 + length > self.data.len() {
+            self.data = &[];
+            return Some(Err(ProtocolError::Truncated));
+        }
+
+        let value = &self.data[2..2 + length];
+        self.data = &self.data[2 + length..];
+
+        Some(Ok(Tlv { tlv_type, value }))
+    }
+}
+
+/// Appends TLV records to a buffer, fails instead of writing past its end
+pub struct TlvWriter<'a> {
+    buffer: &'a mut [u8],
+    len: usize,
+}
+
+impl<'a> TlvWriter<'a> {
+    pub fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, len: 0 }
+    }
+
+    /// Append a record, values are limited to 255 bytes
+    pub fn push(&mut self, tlv_type: u8, value: &[u8]) -> Result<(), ProtocolError> {
+        if value.len() > u8::MAX as usize {
+            return Err(ProtocolError::InvalidLength {
+                field: "tlv value",
+                expected: u8::MAX as usize,
+                actual: value.len(),
+            });
+        }
+
+        let required = self.len + 2 + value.len();
+        if required > self.buffer.len() {
+            return Err(ProtocolError::BufferTooSmall { required, available: self.buffer.len() });
+        }
+
+        self.buffer[self.len] = tlv_type;
+        self.buffer[self.len + 1] = value.len() as u8;
+        self.buffer[self.len + 2..required].copy_from_slice(value);
+        self.len = required;
+
+        Ok(())
+    }
+
+    /// Number of bytes written so far
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reader_yields_all_records() {
+        let data = [1, 1, 0xaa, 42, 2, 0x01, 0x02, 3, 0];
+        let records: Vec<_> = TlvReader::new(&data).map(|r| r.unwrap()).collect();
+
+        assert_eq!(records.len(), 3);
+        assert_eq!(records[0], Tlv { tlv_type: 1, value: &[0xaa] });
+        assert_eq!(records[1], Tlv { tlv_type: 42, value: &[0x01, 0x02] });
+        assert_eq!(records[2], Tlv { tlv_type: 3, value: &[] });
+    }
+
+    #[test]
+    fn test_reader_rejects_truncated_header() {
+        let data = [1, 1, 0xaa, 2];
+        let records: Vec<_> = TlvReader::new(&data).collect();
+
+        assert_eq!(records.len(), 2);
+        assert!(records[0].is_ok());
+        assert_eq!(records[1], Err(ProtocolError::Truncated));
+    }
+
+    #[test]
+    fn test_reader_rejects_length_out_of_bounds() {
+        let data = [7, 200, 0, 0];
+        let mut reader = TlvReader::new(&data);
+
+        assert_eq!(reader.next(), Some(Err(ProtocolError::Truncated)));
+        assert!(reader.next().is_none());
+    }
+
+    #[test]
+    fn test_value_array() {
+        let tlv = Tlv { tlv_type: 1, value: &[1, 2, 3, 4] };
+
+        assert_eq!(tlv.value_array::<4>("test").unwrap(), [1, 2, 3, 4]);
+        assert_eq!(
+            tlv.value_array::<2>("test"),
+            Err(ProtocolError::InvalidLength { field: "test", expected: 2, actual: 4 })
+        );
+    }
+
+    #[test]
+    fn test_writer_roundtrip() {
+        let mut buffer = [0u8; 16];
+        let mut writer = TlvWriter::new(&mut buffer);
+        writer.push(1, &[0xaa]).unwrap();
+        writer.push(3, &[]).unwrap();
+        let len = writer.len();
+
+        assert_eq!(len, 5);
+        let records: Vec<_> = TlvReader::new(&buffer[..len]).map(|r| r.unwrap()).collect();
+        assert_eq!(records, [Tlv { tlv_type: 1, value: &[0xaa] }, Tlv { tlv_type: 3, value: &[] }]);
+    }
+
+    #[test]
+    fn test_writer_rejects_overflow() {
+        let mut buffer = [0u8; 4];
+        let mut writer = TlvWriter::new(&mut buffer);
+
+        assert_eq!(writer.push(1, &[1, 2, 3]), Err(ProtocolError::BufferTooSmall { required: 5, available: 4 }));
+        assert!(writer.is_empty());
+    }
+}